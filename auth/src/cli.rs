@@ -1,5 +1,5 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand};
 use enum_as_inner::EnumAsInner;
 
 use crate::command::new::{self, New};
@@ -11,6 +11,14 @@ use crate::command::new::{self, New};
 pub struct Opts {
     #[clap(subcommand)]
     pub command: Command,
+    /// Log more details (-v for debug, -vv for trace).
+    ///
+    /// `RUST_LOG` takes precedence if set.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Only log warnings and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
 }
 
 #[derive(Debug, Subcommand, EnumAsInner)]
@@ -20,6 +28,7 @@ pub enum Command {
 
 pub fn run() -> Result<()> {
     let opts = Opts::parse();
+    common::logging::init(opts.verbose, opts.quiet)?;
 
     match opts.command {
         Command::New(ref sub) => new::run(&opts, &sub),
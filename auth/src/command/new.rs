@@ -1,13 +1,56 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use jwt_simple::prelude::*;
+use serde_json::json;
 
+use auth::{Scope, TokenClaims};
 use crate::cli::Opts;
 
 #[derive(Debug, Clone, Parser)]
-pub struct New {}
+pub struct New {
+    /// The subject of the token.
+    ///
+    /// Limits like the number of concurrent uploads apply per subject.
+    #[clap(long)]
+    subject: Option<String>,
 
-pub fn run(_global: &Opts, _opts: &New) -> Result<()> {
+    /// The maximum number of concurrent uploads of the subject.
+    #[clap(long)]
+    max_concurrent_uploads: Option<usize>,
+
+    /// Allow the subject to delete store paths.
+    ///
+    /// Without `--push` or `--pull`, the token is still granted both.
+    #[clap(long)]
+    allow_delete: bool,
+
+    /// Allow the subject to push store paths.
+    ///
+    /// Without `--push` or `--pull`, the token is granted both.
+    #[clap(long)]
+    push: bool,
+
+    /// Allow the subject to read from the cache when reads require
+    /// authentication.
+    ///
+    /// Without `--push` or `--pull`, the token is granted both.
+    #[clap(long)]
+    pull: bool,
+
+    /// The output format.
+    ///
+    /// `json` also includes the claims of the token.
+    #[clap(long, alias = "output-format", value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+pub fn run(_global: &Opts, opts: &New) -> Result<()> {
     // create a new key for the `HS256` JWT algorithm
     let key = HS256Key::generate();
 
@@ -17,13 +60,42 @@ pub fn run(_global: &Opts, _opts: &New) -> Result<()> {
         key.to_bytes(),
     )?;
 
-    println!("Key:   {}", key_b64);
-
     // create token
     let days = 365;
-    let claims = Claims::create(Duration::from_days(days));
+    let mut scopes: Vec<_> = [(opts.push, Scope::Push), (opts.pull, Scope::Pull)]
+        .into_iter()
+        .filter_map(|(granted, scope)| granted.then_some(scope))
+        .collect();
+    if opts.allow_delete {
+        // Tokens without scopes aren't granted `delete`, so the
+        // implicit ones must be listed
+        if scopes.is_empty() {
+            scopes = vec![Scope::Push, Scope::Pull];
+        }
+        scopes.push(Scope::Delete);
+    }
+    let custom = TokenClaims {
+        scopes: (!scopes.is_empty()).then_some(scopes),
+        max_concurrent_uploads: opts.max_concurrent_uploads,
+    };
+    let mut claims = Claims::with_custom_claims(custom, Duration::from_days(days));
+    if let Some(subject) = &opts.subject {
+        claims = claims.with_subject(subject);
+    }
     let token = key.authenticate(claims)?;
 
+    if opts.format == OutputFormat::Json {
+        let claims = key.verify_token::<TokenClaims>(&token, None)?;
+        let output = json!({
+            "key": key_b64,
+            "token": token,
+            "claims": claims,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("Key:   {}", key_b64);
     println!("Token: {}", token);
     println!("This token is valid for {} days.", days);
 
@@ -1,12 +1,238 @@
-use anyhow::Result;
-use jwt_simple::prelude::Base64;
-use jwt_simple::reexports::ct_codecs::Decoder;
+use anyhow::{anyhow, Result};
+use jwt_simple::prelude::{Base64, ECDSAP256PublicKeyLike, JWTClaims, RSAPublicKeyLike};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder};
+use serde::{Serialize, Deserialize};
 
-pub use jwt_simple::prelude::{HS256Key, NoCustomClaims, MACLike};
+pub use jwt_simple::prelude::{ES256PublicKey, HS256Key, NoCustomClaims, MACLike, RS256PublicKey};
 pub use jwt_simple::Error as JWTError;
 
+/// A key that verifies tokens.
+#[derive(Debug, Clone)]
+pub enum TokenKey {
+    /// A secret shared with the issuer, for HS256 tokens.
+    Hs256(HS256Key),
+    /// An RSA public key, for RS256 tokens.
+    Rs256(RS256PublicKey),
+    /// A P-256 EC public key, for ES256 tokens.
+    Es256(ES256PublicKey),
+}
+impl TokenKey {
+    /// Verifies a token and returns its claims.
+    ///
+    /// Tokens whose header names an algorithm other than the one of
+    /// the key are rejected.
+    pub fn verify_token(&self, token: &str) -> Result<JWTClaims<TokenClaims>, JWTError> {
+        match self {
+            Self::Hs256(key) => key.verify_token(token, None),
+            Self::Rs256(key) => key.verify_token(token, None),
+            Self::Es256(key) => key.verify_token(token, None),
+        }
+    }
+}
+
 pub fn decode_token_hs256_secret_base64(s: &str) -> Result<HS256Key> {
     let mut buf = [0u8; 64];
     let secret = Base64::decode(&mut buf, s, None)?;
     Ok(HS256Key::from_bytes(&secret))
 }
+
+/// Loads the public key of an external token issuer.
+///
+/// The key may be in PEM format or a JWK. An RSA key verifies RS256
+/// tokens and a P-256 EC key verifies ES256 tokens.
+pub fn decode_token_public_key(s: &str) -> Result<TokenKey> {
+    let s = s.trim();
+    if s.starts_with('{') {
+        return decode_jwk(s);
+    }
+
+    if let Ok(key) = RS256PublicKey::from_pem(s) {
+        return Ok(TokenKey::Rs256(key));
+    }
+    if let Ok(key) = ES256PublicKey::from_pem(s) {
+        return Ok(TokenKey::Es256(key));
+    }
+
+    Err(anyhow!("Token public key is neither an RSA nor a P-256 EC key in PEM format"))
+}
+
+/// A public key in JWK format.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+fn decode_jwk(s: &str) -> Result<TokenKey> {
+    let jwk: Jwk = serde_json::from_str(s)?;
+    let decode = |value: Option<String>, name: &str| -> Result<Vec<u8>> {
+        let value = value.ok_or_else(|| anyhow!("JWK is missing \"{}\"", name))?;
+        Ok(Base64UrlSafeNoPadding::decode_to_vec(value, None)?)
+    };
+
+    match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+        ("RSA", _) => {
+            let n = decode(jwk.n, "n")?;
+            let e = decode(jwk.e, "e")?;
+            Ok(TokenKey::Rs256(RS256PublicKey::from_components(&n, &e)?))
+        }
+        ("EC", Some("P-256")) => {
+            // Uncompressed SEC1 point
+            let mut point = vec![0x04];
+            point.extend(decode(jwk.x, "x")?);
+            point.extend(decode(jwk.y, "y")?);
+            Ok(TokenKey::Es256(ES256PublicKey::from_bytes(&point)?))
+        }
+        (kty, crv) => Err(anyhow!(
+            "Unsupported JWK key type \"{}\"{}",
+            kty,
+            crv.map(|crv| format!(" with curve \"{}\"", crv)).unwrap_or_default()
+        )),
+    }
+}
+
+/// A permission granted by a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Uploading store paths.
+    Push,
+    /// Reading from the cache when reads require authentication.
+    Pull,
+    /// Deleting store paths.
+    ///
+    /// Unlike the other scopes, this is only granted explicitly.
+    Delete,
+}
+
+/// Custom claims in nixcache tokens.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// The scopes granted to the subject.
+    ///
+    /// Tokens without scopes are granted all of them except `delete`.
+    #[serde(rename = "nixcache:scopes")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub scopes: Option<Vec<Scope>>,
+
+    /// The maximum number of concurrent uploads of the subject.
+    ///
+    /// Overrides the server default.
+    #[serde(rename = "nixcache:max-concurrent-uploads")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub max_concurrent_uploads: Option<usize>,
+}
+impl TokenClaims {
+    /// Returns whether the subject was granted a scope.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => scope != Scope::Delete,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt_simple::prelude::{
+        Claims, Duration, ECDSAP256KeyPairLike, ES256KeyPair, RS256KeyPair, RSAKeyPairLike,
+    };
+    use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder};
+
+    use super::*;
+
+    fn claims() -> JWTClaims<TokenClaims> {
+        Claims::with_custom_claims(TokenClaims::default(), Duration::from_hours(1))
+    }
+
+    fn encode(data: &[u8]) -> String {
+        Base64UrlSafeNoPadding::encode_to_string(data).unwrap()
+    }
+
+    #[test]
+    fn test_pem_rsa() {
+        let key_pair = RS256KeyPair::generate(2048).unwrap();
+        let key = decode_token_public_key(&key_pair.public_key().to_pem().unwrap()).unwrap();
+
+        assert!(matches!(key, TokenKey::Rs256(_)));
+        assert!(key.verify_token(&key_pair.sign(claims()).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_pem_ec() {
+        let key_pair = ES256KeyPair::generate();
+        let key = decode_token_public_key(&key_pair.public_key().to_pem().unwrap()).unwrap();
+
+        assert!(matches!(key, TokenKey::Es256(_)));
+        assert!(key.verify_token(&key_pair.sign(claims()).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_jwk_rsa() {
+        let key_pair = RS256KeyPair::generate(2048).unwrap();
+        let components = key_pair.public_key().to_components();
+        let jwk = format!(
+            r#"{{"kty": "RSA", "n": "{}", "e": "{}"}}"#,
+            encode(&components.n),
+            encode(&components.e),
+        );
+        let key = decode_token_public_key(&jwk).unwrap();
+
+        assert!(matches!(key, TokenKey::Rs256(_)));
+        assert!(key.verify_token(&key_pair.sign(claims()).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_jwk_ec() {
+        let key_pair = ES256KeyPair::generate();
+        // Uncompressed SEC1 point
+        let point = key_pair.public_key().public_key().to_bytes_uncompressed();
+        let jwk = format!(
+            r#"{{"kty": "EC", "crv": "P-256", "x": "{}", "y": "{}"}}"#,
+            encode(&point[1..33]),
+            encode(&point[33..]),
+        );
+        let key = decode_token_public_key(&jwk).unwrap();
+
+        assert!(matches!(key, TokenKey::Es256(_)));
+        assert!(key.verify_token(&key_pair.sign(claims()).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_jwk_unsupported() {
+        assert!(decode_token_public_key(r#"{"kty": "OKP", "crv": "Ed25519", "x": "AA"}"#).is_err());
+        assert!(decode_token_public_key(r#"{"kty": "EC", "crv": "P-384", "x": "AA", "y": "AA"}"#).is_err());
+        assert!(decode_token_public_key(r#"{"kty": "RSA", "e": "AQAB"}"#).is_err());
+    }
+
+    #[test]
+    fn test_algorithm_mismatch() {
+        let key_pair = RS256KeyPair::generate(2048).unwrap();
+        let key = TokenKey::Rs256(key_pair.public_key());
+
+        // A token signed with some secret must not pass as RS256
+        let secret = HS256Key::generate();
+        assert!(key.verify_token(&secret.authenticate(claims()).unwrap()).is_err());
+
+        let ec_key_pair = ES256KeyPair::generate();
+        assert!(key.verify_token(&ec_key_pair.sign(claims()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_delete_scope() {
+        let unscoped = TokenClaims::default();
+        assert!(unscoped.has_scope(Scope::Push));
+        assert!(unscoped.has_scope(Scope::Pull));
+        assert!(!unscoped.has_scope(Scope::Delete));
+
+        let delete = TokenClaims { scopes: Some(vec![Scope::Delete]), ..Default::default() };
+        assert!(delete.has_scope(Scope::Delete));
+        assert!(!delete.has_scope(Scope::Push));
+    }
+}
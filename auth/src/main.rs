@@ -6,3 +6,4 @@ use anyhow::Result;
 fn main() -> Result<()> {
     cli::run()
 }
+
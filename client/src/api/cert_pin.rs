@@ -0,0 +1,185 @@
+//! TLS certificate pinning.
+//!
+//! A pin is the SHA-256 hash of the DER encoding of the public key
+//! (SubjectPublicKeyInfo) in the server's certificate, written as
+//! `sha256:` followed by the base64 encoding of the hash. This is the
+//! same hash as in HPKP and curl's `--pinnedpubkey`. Pinning the key
+//! instead of the whole certificate keeps the pin valid when the
+//! certificate is renewed with the same key. It can be computed with:
+//!
+//! ```sh
+//! openssl s_client -connect cache.example.com:443 </dev/null \
+//!     | openssl x509 -pubkey -noout \
+//!     | openssl pkey -pubin -outform der \
+//!     | openssl dgst -sha256 -binary \
+//!     | base64
+//! ```
+//!
+//! The certificate must still be valid and issued by a trusted CA.
+//! The pin only narrows down which certificate is accepted. Pinned
+//! connections trust the same roots as unpinned ones, the Mozilla
+//! roots bundled through `webpki-roots`, so a server with a certificate
+//! from a private CA can't be reached with or without a pin.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, CertificateError, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use x509_parser::parse_x509_certificate;
+
+/// A pinned public key hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertPin([u8; 32]);
+
+impl CertPin {
+    /// Parses a pin in the `sha256:<base64>` format.
+    pub fn parse(s: &str) -> Result<Self> {
+        let hash = s
+            .strip_prefix("sha256:")
+            .ok_or_else(|| anyhow!("Certificate pin must start with \"sha256:\""))?;
+        let hash: [u8; 32] = BASE64.decode(hash)?
+            .try_into()
+            .map_err(|_| anyhow!("Certificate pin must be a base64-encoded SHA-256 hash"))?;
+
+        Ok(Self(hash))
+    }
+
+    /// Returns the pin of the public key in a certificate.
+    ///
+    /// Returns `None` if the certificate is malformed.
+    fn of(cert: &Certificate) -> Option<Self> {
+        let (_, cert) = parse_x509_certificate(&cert.0).ok()?;
+        Some(Self(Sha256::digest(cert.public_key().raw).into()))
+    }
+
+    /// Returns a TLS configuration that only accepts the pinned certificate.
+    ///
+    /// The roots are those that reqwest uses with its `rustls-tls`
+    /// feature, so pinning doesn't change which CAs are trusted.
+    pub fn tls_config(self) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }));
+
+        let verifier = PinnedCertVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pin: self,
+        };
+
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        config
+    }
+}
+
+impl fmt::Display for CertPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256:{}", BASE64.encode(self.0))
+    }
+}
+
+/// Verifies certificates as usual, then checks the pin.
+struct PinnedCertVerifier {
+    inner: WebPkiVerifier,
+    pin: CertPin,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+
+        let actual = CertPin::of(end_entity)
+            .ok_or(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if actual != self.pin {
+            let mismatch = PinMismatch { expected: self.pin, actual };
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(mismatch))));
+        }
+
+        Ok(verified)
+    }
+}
+
+/// The server presented a certificate other than the pinned one.
+struct PinMismatch {
+    expected: CertPin,
+    actual: CertPin,
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The certificate pin does not match (expected {}, got {})", self.expected, self.actual)
+    }
+}
+
+// rustls formats certificate errors with `Debug`
+impl fmt::Debug for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl StdError for PinMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificates for `cache.example.com`, made with OpenSSL.
+    ///
+    /// The first two share a key and differ in their serial numbers,
+    /// like a renewed certificate. The third has another key.
+    const CERTS: [&str; 3] = [
+        "MIIBezCCASKgAwIBAgIBATAKBggqhkjOPQQDAjAcMRowGAYDVQQDDBFjYWNoZS5leGFtcGxlLmNvbTAgFw0yNjEwMTYwODUzNDlaGA8yMTI2MDkyMjA4NTM0OVowHDEaMBgGA1UEAwwRY2FjaGUuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQaiwhLRpbs+myi1z8RNaUXFckXT0/TtRqcYSG5HCA17t1Vzymq2PI4mgOOpgYmTyEfg9vTbyzL58dRGtTXCydGo1MwUTAdBgNVHQ4EFgQUpBzV1/Cxku/GmdvZzUDMAkFxmm8wHwYDVR0jBBgwFoAUpBzV1/Cxku/GmdvZzUDMAkFxmm8wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiBTzYiPt6CHHHWEaF5eXmELQ/RZdHpLv3w6K0roNYyolAIgMmjAJfmSg+5hozLD7MQraero0tY8axeIVpE9cSLvB7k=",
+        "MIIBfDCCASKgAwIBAgIBAjAKBggqhkjOPQQDAjAcMRowGAYDVQQDDBFjYWNoZS5leGFtcGxlLmNvbTAgFw0yNjEwMTYwODUzNDlaGA8yMTI2MDkyMjA4NTM0OVowHDEaMBgGA1UEAwwRY2FjaGUuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQaiwhLRpbs+myi1z8RNaUXFckXT0/TtRqcYSG5HCA17t1Vzymq2PI4mgOOpgYmTyEfg9vTbyzL58dRGtTXCydGo1MwUTAdBgNVHQ4EFgQUpBzV1/Cxku/GmdvZzUDMAkFxmm8wHwYDVR0jBBgwFoAUpBzV1/Cxku/GmdvZzUDMAkFxmm8wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiEA+yPixkw+ZOdFnJP0p6AhR0kgqtIja9t7lIC/mWi4ZCcCICVR7LonubCcVuXgVT1A5AMYR79Hdk0ylQ8W/alebRKZ",
+        "MIIBfTCCASKgAwIBAgIBATAKBggqhkjOPQQDAjAcMRowGAYDVQQDDBFjYWNoZS5leGFtcGxlLmNvbTAgFw0yNjEwMTYwODUzNDlaGA8yMTI2MDkyMjA4NTM0OVowHDEaMBgGA1UEAwwRY2FjaGUuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAR9LCddNuhMkwU/d9h2ZuY0BejbByruuI9pNIV1vAR4C5jB2jNJSgvrCtf8KWo3EHPl8VZ/NjFbJbPrq/bc4Z0ko1MwUTAdBgNVHQ4EFgQUZ7WfXlCLPHpLqc+dFrU6a0WMUdUwHwYDVR0jBBgwFoAUZ7WfXlCLPHpLqc+dFrU6a0WMUdUwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEAw3kHzfy/KO3BayEpMS/rJidomhtU0TrTdqaQK+bAieUCIQDgOd/zOkriT0O/oYNfCHOo3AnafIwkc/MP/yY6tM7UbA==",
+    ];
+
+    fn certificate(i: usize) -> Certificate {
+        Certificate(BASE64.decode(CERTS[i]).unwrap())
+    }
+
+    #[test]
+    fn test_of() {
+        // As computed with the OpenSSL commands in the module docs
+        let pin = CertPin::of(&certificate(0)).unwrap();
+        assert_eq!("sha256:ACE1GSqMuhd42L/fbMYYDpWV0xcUVOAckBW53m19Ktg=", pin.to_string());
+
+        // A renewed certificate with the same key has the same pin
+        assert_ne!(certificate(0), certificate(1));
+        assert_eq!(Some(pin), CertPin::of(&certificate(1)));
+        assert_ne!(Some(pin), CertPin::of(&certificate(2)));
+
+        // Malformed certificates are rejected
+        let cert = certificate(0);
+        assert!(CertPin::of(&Certificate(cert.0[..cert.0.len() - 1].to_vec())).is_none());
+        assert!(CertPin::of(&Certificate(b"certificate".to_vec())).is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let pin = CertPin::of(&certificate(0)).unwrap();
+        assert_eq!(pin, CertPin::parse(&pin.to_string()).unwrap());
+
+        assert!(CertPin::parse("sha1:AAAA").is_err());
+        assert!(CertPin::parse("sha256:AAAA").is_err());
+        assert!(CertPin::parse("sha256:not base64").is_err());
+    }
+}
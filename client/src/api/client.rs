@@ -1,5 +1,5 @@
 use std::error::Error as StdError;
-use anyhow::Result;
+use std::time::Duration;
 use bytes::Bytes;
 use const_format::concatcp;
 use futures::{
@@ -8,9 +8,11 @@ use futures::{
 };
 use reqwest::{header::HeaderValue, Body, Client as HttpClient, Url};
 
-use common::v1::{header, upload_path, cache_config::CacheConfig};
+use libnixstore::StorePathHash;
+use common::v1::{header, get_missing_paths, list_paths, upload_path, cache_config::CacheConfig};
 use crate::config::ServerConfig;
-use super::error::Error;
+use super::cert_pin::CertPin;
+use super::error::ClientError;
 
 /// The User-Agent string.
 const USER_AGENT: &str = concatcp!("Nixcache {}", env!("CARGO_PKG_VERSION"));
@@ -18,6 +20,12 @@ const USER_AGENT: &str = concatcp!("Nixcache {}", env!("CARGO_PKG_VERSION"));
 /// The size threshold to send the upload info as part of the PUT body.
 const NAR_INFO_PREAMBLE_THRESHOLD: usize = 4 * 1024; // 4 KiB
 
+/// The number of store path hashes to list per request.
+const LIST_PATHS_PAGE_SIZE: usize = 10000;
+
+/// How long to wait for the server when checking connectivity.
+const CONNECTIVITY_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The API client.
 #[derive(Debug, Clone)]
 pub struct Client {
@@ -30,20 +38,29 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn from_server_config(config: ServerConfig) -> Result<Self> {
-        let client = reqwest::Client::builder()
-            .user_agent(USER_AGENT)
-            .build()?;
+    pub fn from_server_config(config: ServerConfig) -> Result<Self, ClientError> {
+        let endpoint = Url::parse(&config.endpoint)?;
+
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT);
+        if let Some(pin) = &config.cert_pin {
+            let pin = CertPin::parse(pin).map_err(ClientError::InvalidCertPin)?;
+            if endpoint.scheme() != "https" {
+                tracing::warn!("The certificate pin has no effect because {} does not use HTTPS", endpoint);
+            }
+            builder = builder.use_preconfigured_tls(pin.tls_config());
+        }
+        let client = builder.build()?;
 
         Ok(Self {
-            endpoint: Url::parse(&config.endpoint)?,
+            endpoint,
             token: config.token,
             client,
         })
     }
 
     /// Returns the configuration of a cache.
-    pub async fn get_cache_config(&self) -> Result<CacheConfig> {
+    pub async fn get_cache_config(&self) -> Result<CacheConfig, ClientError> {
         let endpoint = self
             .endpoint
             .join("_api/v1/cache-config")?;
@@ -54,18 +71,106 @@ impl Client {
             let cache_config = res.json().await?;
             Ok(cache_config)
         } else {
-            let api_error = Error::try_from_response(res).await?;
-            Err(api_error.into())
+            Err(ClientError::from_response(res).await)
+        }
+    }
+
+    /// Checks that the cache responds to requests.
+    ///
+    /// This uses an API route that any valid token may access, since
+    /// tokens that may only push cannot read from private caches.
+    pub async fn check_connectivity(&self) -> Result<(), ClientError> {
+        let endpoint = self.endpoint.join("_api/v1/cache-config")?;
+
+        let mut req = self.client.get(endpoint).timeout(CONNECTIVITY_TIMEOUT);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+
+        let res = req.send().await?;
+
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(ClientError::from_response(res).await)
+        }
+    }
+
+    /// Returns the store path hashes of all paths in the cache.
+    ///
+    /// The server lists them without reading any NAR.
+    pub async fn list_paths(&self) -> Result<Vec<String>, ClientError> {
+        let endpoint = self.endpoint.join("_api/v1/list-paths")?;
+
+        let mut store_path_hashes = Vec::new();
+        let mut cursor = None;
+        loop {
+            let query = list_paths::Query {
+                cursor,
+                limit: Some(LIST_PATHS_PAGE_SIZE),
+            };
+
+            let mut req = self.client.get(endpoint.clone()).query(&query);
+            if let Some(token) = &self.token {
+                req = req.bearer_auth(token);
+            }
+
+            let res = req.send().await?;
+            if !res.status().is_success() {
+                return Err(ClientError::from_response(res).await);
+            }
+
+            // Older servers list all paths at once without a cursor
+            let page: list_paths::Response = res.json().await?;
+            store_path_hashes.extend(page.store_path_hashes);
+
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(store_path_hashes)
+    }
+
+    /// Returns the store paths that are missing from the cache.
+    pub async fn get_missing_paths(
+        &self,
+        store_path_hashes: Vec<StorePathHash>,
+    ) -> Result<Vec<StorePathHash>, ClientError> {
+        let endpoint = self.endpoint.join("_api/v1/get-missing-paths")?;
+        let payload = get_missing_paths::Request {
+            store_path_hashes,
+        };
+
+        let mut req = self.client.post(endpoint).json(&payload);
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+
+        let res = req.send().await?;
+
+        if res.status().is_success() {
+            let response: get_missing_paths::Response = res.json().await?;
+            Ok(response.missing_paths)
+        } else {
+            Err(ClientError::from_response(res).await)
         }
     }
 
     /// Uploads a path.
+    ///
+    /// Retries of the same upload should pass the same `idempotency_key`
+    /// so that the server does not process them again. If `verbose` is
+    /// set, the response includes per-chunk details.
     pub async fn upload_path<S>(
         &self,
         nar_info: upload_path::Request,
         stream: S,
         force_preamble: bool,
-    ) -> Result<Option<upload_path::Response>>
+        idempotency_key: Option<&str>,
+        verbose: bool,
+    ) -> Result<Option<upload_path::Response>, ClientError>
     where
         S: TryStream<Ok = Bytes> + Send + Sync + 'static,
         S::Error: Into<Box<dyn StdError + Send + Sync>> + Send + Sync,
@@ -78,6 +183,12 @@ impl Client {
         if let Some(token) = &self.token {
             req = req.bearer_auth(token);
         }
+        if let Some(key) = idempotency_key {
+            req = req.header(header::IDEMPOTENCY_KEY, HeaderValue::from_str(key)?);
+        }
+        if verbose {
+            req = req.header(header::VERBOSE_RESPONSE, "1");
+        }
 
         if force_preamble || upload_info_json.len() >= NAR_INFO_PREAMBLE_THRESHOLD {
             let preamble = Bytes::from(upload_info_json);
@@ -102,8 +213,7 @@ impl Client {
                 Err(_) => Ok(None),
             }
         } else {
-            let api_error = Error::try_from_response(res).await?;
-            Err(api_error.into())
+            Err(ClientError::from_response(res).await)
         }
     }
 }
@@ -1,9 +1,79 @@
-use anyhow::Result;
 use std::error::Error as StdError;
 use std::fmt;
 use serde::Deserialize;
 use displaydoc::Display;
-use reqwest::{Response, StatusCode};
+use reqwest::{header::InvalidHeaderValue, Response, StatusCode};
+
+/// Client error.
+///
+/// This is returned by all public methods of the API client so
+/// that callers can decide whether an operation is worth retrying.
+#[derive(Debug, Display)]
+pub enum ClientError {
+    /// Network error: {0}
+    Network(reqwest::Error),
+
+    /// Authentication failed: {0}
+    Unauthorized(Error),
+
+    /// Server error: {0}
+    Api(Error),
+
+    /// Invalid URL: {0}
+    InvalidUrl(url::ParseError),
+
+    /// Invalid header value: {0}
+    InvalidHeader(InvalidHeaderValue),
+
+    /// Serialization error: {0}
+    Serialization(serde_json::Error),
+
+    /// Invalid certificate pin: {0}
+    InvalidCertPin(anyhow::Error),
+}
+impl StdError for ClientError {}
+impl ClientError {
+    /// Creates an error from an unsuccessful response.
+    pub async fn from_response(response: Response) -> Self {
+        match Error::try_from_response(response).await {
+            Ok(e) if e.is_auth_error() => Self::Unauthorized(e),
+            Ok(e) => Self::Api(e),
+            Err(e) => e,
+        }
+    }
+
+    /// Returns whether the operation may succeed if retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Network(e) => e.is_timeout() || e.is_connect(),
+            Self::Api(e) => {
+                let status = e.status();
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+impl From<reqwest::Error> for ClientError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Network(error)
+    }
+}
+impl From<url::ParseError> for ClientError {
+    fn from(error: url::ParseError) -> Self {
+        Self::InvalidUrl(error)
+    }
+}
+impl From<InvalidHeaderValue> for ClientError {
+    fn from(error: InvalidHeaderValue) -> Self {
+        Self::InvalidHeader(error)
+    }
+}
+impl From<serde_json::Error> for ClientError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Serialization(error)
+    }
+}
 
 /// API error.
 #[derive(Debug, Display)]
@@ -15,7 +85,7 @@ pub enum Error {
 }
 impl StdError for Error {}
 impl Error {
-    pub async fn try_from_response(response: Response) -> Result<Self> {
+    pub async fn try_from_response(response: Response) -> Result<Self, ClientError> {
         let status = response.status();
         let text = response.text().await?;
         match serde_json::from_str(&text) {
@@ -23,11 +93,23 @@ impl Error {
             Err(_) => Ok(Self::Unstructured(status, text)),
         }
     }
+
+    /// Returns the HTTP status code of the error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Structured(e) => StatusCode::from_u16(e.code)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            Self::Unstructured(status, _) => *status,
+        }
+    }
+
+    fn is_auth_error(&self) -> bool {
+        matches!(self.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct StructuredApiError {
-    #[allow(dead_code)]
     code: u16,
     error: String,
     message: String,
@@ -37,4 +119,3 @@ impl fmt::Display for StructuredApiError {
         write!(f, "{}: {}", self.error, self.message)
     }
 }
-
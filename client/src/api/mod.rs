@@ -1,4 +1,6 @@
 pub mod error;
 pub mod client;
+pub mod cert_pin;
 
 pub use client::Client;
+pub use error::ClientError;
@@ -1,8 +1,9 @@
 use anyhow::Result;
 use std::path::PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand};
 use enum_as_inner::EnumAsInner;
 
+use crate::command::diff::{self, Diff};
 use crate::command::init::{self, Init};
 use crate::command::push::{self, Push};
 use crate::command::r#use::{self, Use};
@@ -17,10 +18,19 @@ pub struct Opts {
     /// Path to the 'config.toml'.
     #[arg(short, long)]
     pub config: Option<PathBuf>,
+    /// Log more details (-v for debug, -vv for trace).
+    ///
+    /// `RUST_LOG` takes precedence if set.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Only log warnings and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
 }
 
 #[derive(Debug, Subcommand, EnumAsInner)]
 pub enum Command {
+    Diff(Diff),
     Init(Init),
     Push(Push),
     Use(Use),
@@ -28,8 +38,10 @@ pub enum Command {
 
 pub async fn run() -> Result<()> {
     let opts = Opts::parse();
+    common::logging::init(opts.verbose, opts.quiet)?;
 
     match opts.command {
+        Command::Diff(_) => diff::run(opts).await,
         Command::Init(_) => init::run(opts).await,
         Command::Push(_) => push::run(opts).await,
         Command::Use(_) => r#use::run(opts).await,
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use clap::Parser;
+use serde_json::json;
+
+use crate::api::Client;
+use crate::cli::Opts;
+use crate::config::{Config, ServerConfig};
+
+/// Compare the store paths in two caches.
+#[derive(Debug, Parser)]
+pub struct Diff {
+    /// Endpoint of the first server.
+    server_a: String,
+    /// Endpoint of the second server.
+    server_b: String,
+    /// Token for the first server.
+    ///
+    /// Defaults to the configured token if the endpoint is the
+    /// configured server.
+    #[clap(long)]
+    token_a: Option<String>,
+    /// Token for the second server.
+    ///
+    /// Defaults to the configured token if the endpoint is the
+    /// configured server.
+    #[clap(long)]
+    token_b: Option<String>,
+    /// List the store path hashes that differ.
+    #[clap(long)]
+    list: bool,
+    /// Output the differences as JSON.
+    #[clap(long)]
+    json: bool,
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    let sub = opts.command.as_diff().unwrap();
+    let configured = Config::load(opts.config).ok().map(|config| config.data.server);
+
+    let server_a = server_config(&sub.server_a, &sub.token_a, &configured);
+    let server_b = server_config(&sub.server_b, &sub.token_b, &configured);
+
+    let api_a = Client::from_server_config(server_a)?;
+    let api_b = Client::from_server_config(server_b)?;
+
+    let (paths_a, paths_b) = futures::try_join!(api_a.list_paths(), api_b.list_paths())?;
+    let paths_a: HashSet<String> = paths_a.into_iter().collect();
+    let paths_b: HashSet<String> = paths_b.into_iter().collect();
+
+    let mut only_in_a: Vec<&String> = paths_a.difference(&paths_b).collect();
+    let mut only_in_b: Vec<&String> = paths_b.difference(&paths_a).collect();
+    only_in_a.sort();
+    only_in_b.sort();
+    let in_both = paths_a.intersection(&paths_b).count();
+
+    if sub.json {
+        let output = json!({
+            "only_in_a": only_in_a,
+            "only_in_b": only_in_b,
+            "in_both": in_both,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("Only in {}: {}", sub.server_a, only_in_a.len());
+    if sub.list {
+        for hash in &only_in_a {
+            println!("  {}", hash);
+        }
+    }
+
+    println!("Only in {}: {}", sub.server_b, only_in_b.len());
+    if sub.list {
+        for hash in &only_in_b {
+            println!("  {}", hash);
+        }
+    }
+
+    println!("In both: {}", in_both);
+
+    Ok(())
+}
+
+fn server_config(endpoint: &str, token: &Option<String>, configured: &Option<ServerConfig>) -> ServerConfig {
+    let configured = configured
+        .as_ref()
+        .filter(|server| server.endpoint == endpoint);
+    let token = token.clone().or_else(|| configured.and_then(|server| server.token.clone()));
+
+    ServerConfig {
+        endpoint: endpoint.to_string(),
+        token,
+        cert_pin: configured.and_then(|server| server.cert_pin.clone()),
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use clap::Parser;
 
+use crate::api::cert_pin::CertPin;
 use crate::cli::Opts;
 use crate::config::{ServerConfig, ConfigData, Config};
 
@@ -13,12 +14,16 @@ pub struct Init {
     /// Cache auth token.
     #[clap(short, long)]
     token: Option<String>,
+    /// Expected hash of the public key of the server's TLS certificate (`sha256:<base64>`).
+    #[clap(long)]
+    cert_pin: Option<String>,
 }
 impl Into<ConfigData> for Init {
     fn into(self) -> ConfigData {
         let server = ServerConfig {
             endpoint: self.url,
             token: self.token,
+            cert_pin: self.cert_pin,
         };
 
         ConfigData {
@@ -31,6 +36,10 @@ pub async fn run(opts: Opts) -> Result<()> {
     let sub: &Init = opts.command.as_init().unwrap();
 
     let path = opts.config;
+    if let Some(pin) = &sub.cert_pin {
+        CertPin::parse(pin)?;
+    }
+
     let data: ConfigData = sub.clone().into();
 
     let config = Config::new(path, data.into())?;
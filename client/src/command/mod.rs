@@ -1,3 +1,4 @@
+pub mod diff;
 pub mod init;
 pub mod push;
 pub mod r#use;
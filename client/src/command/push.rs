@@ -1,24 +1,38 @@
 use anyhow::{anyhow, Result};
-use std::sync::Arc;
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use async_channel as channel;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use bytes::Bytes;
 use futures::future::join_all;
-use futures::stream::{Stream, TryStreamExt};
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
 use indicatif::{MultiProgress, HumanBytes, ProgressBar, ProgressState, ProgressStyle};
 use tokio::task::{spawn, JoinHandle};
-use clap::Parser;
+use tokio_util::io::ReaderStream;
+use clap::{ArgAction, Parser};
+use serde::{Deserialize, Serialize};
 
-use libnixstore::{StorePathHash, NixStore, StorePath, ValidPathInfo};
+use libnixstore::{Hash, StorePathHash, NixStore, StorePath, ValidPathInfo};
 use common::v1::upload_path::{Request, Response, ResponseKind};
-use crate::api::Client;
+use crate::api::{Client, ClientError};
 use crate::cli::Opts;
 use crate::config::Config;
+use crate::push_state::PushState;
+
+/// The maximum number of attempts to upload a path on retryable errors.
+const MAX_UPLOAD_ATTEMPTS: usize = 3;
+
+/// The number of queued paths per upload worker.
+const JOB_QUEUE_FACTOR: usize = 2;
+
+/// The number of paths to check for in the cache with each request.
+const MISSING_PATHS_BATCH_SIZE: usize = 1000;
 
 /// Push closures to a binary cache.
 #[derive(Debug, Parser)]
@@ -28,20 +42,82 @@ pub struct Push {
     /// Push the specified paths only and do not compute closures.
     #[clap(long)]
     no_closure: bool,
+    /// With --no-closure, fail if references of the pushed paths are missing from the cache.
+    #[clap(long, requires = "no_closure")]
+    require_closure_complete: bool,
     /// The maximum number of parallel upload processes.
     #[clap(short = 'j', long, default_value = "5")]
     jobs: usize,
+    /// The maximum number of path info queries to the Nix store at once.
+    #[clap(long, default_value = "16")]
+    query_jobs: usize,
+    /// Print the total NAR size of the paths to push and exit without uploading.
+    #[clap(long)]
+    print_closure_size: bool,
+    /// Upload an uncompressed NAR file instead of store paths, bypassing the Nix store.
+    #[clap(long, requires = "narinfo", conflicts_with_all = ["paths", "no_closure", "print_closure_size"])]
+    from_nar: Option<PathBuf>,
+    /// The narinfo describing the NAR passed to --from-nar.
+    #[clap(long, requires = "from_nar")]
+    narinfo: Option<PathBuf>,
+    /// Push the paths in a `nix path-info --json` file, using its metadata instead of querying the store.
+    #[clap(long, conflicts_with_all = ["paths", "no_closure", "print_closure_size", "from_nar"])]
+    from_path_info: Option<PathBuf>,
+    /// Print the result of each pushed path as a line of JSON, including per-chunk details.
+    #[clap(long)]
+    json: bool,
+    /// Check that the cache is reachable before pushing anything.
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    check_connectivity: bool,
+    /// Skip paths already pushed by an interrupted push of the same paths (default).
+    #[clap(long, overrides_with = "no_resume")]
+    resume: bool,
+    /// Push all paths again, discarding the progress of an interrupted push.
+    #[clap(long, overrides_with = "resume")]
+    no_resume: bool,
+    /// Print a summary of deduplication and compression after pushing.
+    #[clap(long)]
+    print_stats: bool,
 }
 
 pub async fn run(opts: Opts) -> Result<()> {
     let sub: &Push = opts.command.as_push().unwrap();
-    if sub.jobs == 0 {
+    if sub.jobs == 0 || sub.query_jobs == 0 {
         return Err(anyhow!("The number of jobs cannot be 0"));
     }
 
     let config = Config::load(opts.config)?;
+    let api = Client::from_server_config(config.data.server.clone())?;
+
+    if sub.check_connectivity && !sub.print_closure_size {
+        api.check_connectivity().await.map_err(|e| {
+            anyhow!("The cache \"{}\" is unreachable: {}", config.data.server.endpoint, e)
+        })?;
+    }
+
+    if let (Some(nar), Some(narinfo)) = (&sub.from_nar, &sub.narinfo) {
+        return push_nar_file(&api, nar, narinfo, sub.json).await;
+    }
 
     let store = Arc::new(NixStore::connect()?);
+
+    if let Some(path_info_file) = &sub.from_path_info {
+        let push_config = PushConfig {
+            num_workers: sub.jobs,
+            num_query_workers: sub.query_jobs,
+            json: sub.json,
+        };
+        return push_path_info_file(
+            store,
+            api,
+            push_config,
+            path_info_file,
+            &config.data.server.endpoint,
+            sub.resume || !sub.no_resume,
+            sub.print_stats,
+        ).await;
+    }
+
     let roots = sub
         .paths
         .clone()
@@ -49,17 +125,95 @@ pub async fn run(opts: Opts) -> Result<()> {
         .map(|p| store.follow_store_path(p))
         .collect::<std::result::Result<Vec<_>, _>>()?;
 
-    let api = Client::from_server_config(config.data.server.clone())?;
-
     let push_config = PushConfig {
         num_workers: sub.jobs,
+        num_query_workers: sub.query_jobs,
+        json: sub.json,
     };
 
     let mp = MultiProgress::new();
-    let pusher = Pusher::new(store, api, mp, push_config);
-    let plan = pusher
-        .plan(roots, sub.no_closure)
+
+    if sub.print_closure_size {
+        let pusher = Pusher::new(store, api.clone(), mp, push_config, None);
+
+        // Path infos are only needed for their sizes, so don't keep them around
+        let closure = pusher.closure(roots, sub.no_closure).await?;
+        let num_paths = closure.len();
+        let closure_size: u64 = pusher
+            .query_path_infos(closure)
+            .try_fold(0, |size, path_info| async move { Ok(size + path_info.nar_size) })
+            .await?;
+
+        eprintln!("📦 {num_paths} paths, {closure_size} ({bytes} bytes)",
+            num_paths = num_paths,
+            closure_size = HumanBytes(closure_size),
+            bytes = closure_size,
+        );
+
+        return Ok(());
+    }
+
+    let state = Arc::new(PushState::open(
+        &config.data.server.endpoint,
+        &roots,
+        sub.no_closure,
+        sub.resume || !sub.no_resume,
+    )?);
+    if state.num_pushed() > 0 {
+        eprintln!("⏩ Resuming an interrupted push, {} paths were already pushed", state.num_pushed());
+    }
+
+    let pusher = Pusher::new(store, api.clone(), mp, push_config, Some(state.clone()));
+
+    if !sub.no_closure {
+        // Nothing needs the full plan, so start uploading as soon
+        // as the first path infos come in. The job queue is bounded,
+        // so only a handful of path infos are in memory at once
+        // regardless of the size of the closure.
+        let mut closure = pusher.closure(roots, false).await?;
+        if closure.is_empty() {
+            eprintln!("🤷 Nothing selected.");
+            return finish(pusher, state, sub.print_stats).await;
+        }
+
+        closure.retain(|path| !state.is_pushed(&path.to_hash()));
+        let closure = pusher.filter_missing(closure).await?;
+        if closure.is_empty() {
+            eprintln!("✅ All done!");
+            return finish(pusher, state, sub.print_stats).await;
+        }
+
+        eprintln!("⚙️ Pushing {num_paths} paths \"{server}\" ...",
+            server = config.data.server.endpoint,
+            num_paths = closure.len(),
+        );
+
+        let mut path_infos = pusher.query_path_infos(closure);
+        while let Some(path_info) = path_infos.try_next().await? {
+            pusher.queue(path_info).await?;
+        }
+
+        return finish(pusher, state, sub.print_stats).await;
+    }
+
+    // Only the explicitly specified paths are pushed, so the plan is small
+    let mut plan = pusher
+        .plan(roots, true)
         .await?;
+    plan.store_path_map.retain(|hash, _| !state.is_pushed(hash));
+
+    let missing = plan.missing_references(&api).await?;
+    if !missing.is_empty() {
+        let level = if sub.require_closure_complete { "❌" } else { "⚠️" };
+        eprintln!("{} {} references are missing from the cache:", level, missing.len());
+        for path in &missing {
+            eprintln!("  - {}", path.as_os_str().to_string_lossy());
+        }
+
+        if sub.require_closure_complete {
+            return Err(anyhow!("The pushed paths would be incomplete on the cache"));
+        }
+    }
 
     if plan.store_path_map.is_empty() {
         if plan.num_all_paths == 0 {
@@ -70,7 +224,7 @@ pub async fn run(opts: Opts) -> Result<()> {
             );
         }
 
-        return Ok(());
+        return finish(pusher, state, sub.print_stats).await;
     } else {
         eprintln!("⚙️ Pushing {num_missing_paths} paths \"{server}\" ...",
             server = config.data.server.endpoint,
@@ -82,12 +236,278 @@ pub async fn run(opts: Opts) -> Result<()> {
         pusher.queue(path_info).await?;
     }
 
-    let results = pusher.wait().await;
+    finish(pusher, state, sub.print_stats).await
+}
+
+/// Waits for a push to complete, discarding its progress if all paths were pushed.
+async fn finish(pusher: Pusher, state: Arc<PushState>, print_stats: bool) -> Result<()> {
+    let (results, stats) = pusher.wait().await;
+    if print_stats {
+        stats.print();
+    }
     results.into_values().collect::<Result<Vec<()>>>()?;
 
+    // The workers have exited, so this is the only reference left
+    if let Ok(state) = Arc::try_unwrap(state) {
+        state.finish()?;
+    }
+
     Ok(())
 }
 
+/// Uploads a NAR file described by a narinfo, without a Nix store.
+async fn push_nar_file(api: &Client, nar: &Path, narinfo: &Path, json: bool) -> Result<()> {
+    let narinfo = std::fs::read_to_string(narinfo)?;
+    let upload_info = parse_narinfo(&narinfo)?;
+
+    let file = tokio::fs::File::open(nar).await?;
+    let file_size = file.metadata().await?.len();
+    if file_size != upload_info.nar_size as u64 {
+        return Err(anyhow!(
+            "The NAR file is {} bytes, but the narinfo says {} bytes",
+            file_size,
+            upload_info.nar_size,
+        ));
+    }
+
+    let store_path = upload_info.store_path.clone();
+    let r = api
+        .upload_path(upload_info, ReaderStream::new(file), true, None, json)
+        .await?;
+
+    match r.as_ref() {
+        Some(Response { kind: ResponseKind::Deduplicated, .. }) => eprintln!("✅ {} (deduplicated)", store_path),
+        Some(Response { frac_deduplicated: Some(frac), .. }) if *frac > 0.0 => {
+            eprintln!("✅ {} ({:.0}% deduplicated)", store_path, frac * 100.0)
+        }
+        _ => eprintln!("✅ {}", store_path),
+    }
+
+    if let (true, Some(r)) = (json, &r) {
+        println!("{}", json_result(&store_path, r)?);
+    }
+
+    Ok(())
+}
+
+/// Builds an upload request from a narinfo.
+///
+/// Only uncompressed NARs are supported, so the `Compression` field
+/// must be absent or `none`.
+fn parse_narinfo(narinfo: &str) -> Result<Request> {
+    let mut store_path = None;
+    let mut nar_hash = None;
+    let mut nar_size = None;
+    let mut references = Vec::new();
+    let mut system = None;
+    let mut deriver = None;
+    let mut sigs = Vec::new();
+    let mut ca = None;
+
+    for line in narinfo.lines().filter(|line| !line.is_empty()) {
+        let (key, value) = line
+            .split_once(": ")
+            .ok_or_else(|| anyhow!("Invalid narinfo line: {}", line))?;
+
+        match key {
+            "StorePath" => store_path = Some(value.to_string()),
+            "NarHash" => nar_hash = Some(Hash::from_typed(value)?),
+            "NarSize" => nar_size = Some(value.parse::<usize>()?),
+            "References" => {
+                references = value.split_whitespace().map(str::to_string).collect();
+            }
+            "System" => system = Some(value.to_string()),
+            "Deriver" if value != "unknown-deriver" => deriver = Some(value.to_string()),
+            "Sig" => sigs.push(value.to_string()),
+            "CA" => ca = Some(value.to_string()),
+            "Compression" if value != "none" => {
+                return Err(anyhow!("Compressed NARs are not supported (Compression: {})", value));
+            }
+            _ => {}
+        }
+    }
+
+    let store_path = store_path.ok_or_else(|| anyhow!("The narinfo has no StorePath"))?;
+    let base_name = Path::new(&store_path)
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid store path: {}", store_path))?;
+    let store_path_hash = StorePath::from_base_name(PathBuf::from(base_name))?.to_hash();
+
+    Ok(Request {
+        store_path_hash,
+        store_path,
+        references,
+        system,
+        deriver,
+        sigs,
+        ca,
+        nar_hash: nar_hash.ok_or_else(|| anyhow!("The narinfo has no NarHash"))?,
+        nar_size: nar_size.ok_or_else(|| anyhow!("The narinfo has no NarSize"))?,
+    })
+}
+
+/// Pushes the paths described by a `nix path-info --json` file.
+///
+/// Like with `--no-closure`, only the listed paths are pushed, and
+/// paths already in the cache are skipped.
+async fn push_path_info_file(
+    store: Arc<NixStore>,
+    api: Client,
+    push_config: PushConfig,
+    path_info_file: &Path,
+    server: &str,
+    resume: bool,
+    print_stats: bool,
+) -> Result<()> {
+    let json = std::fs::read_to_string(path_info_file)?;
+    let mut path_infos = parse_path_info_json(&json)?;
+
+    // The file may be stale
+    for path_info in &path_infos {
+        let full_path = store.get_full_path(&path_info.path);
+        if tokio::fs::symlink_metadata(&full_path).await.is_err() {
+            return Err(anyhow!("{} does not exist in the store", full_path.to_string_lossy()));
+        }
+    }
+
+    if path_infos.is_empty() {
+        eprintln!("🤷 Nothing selected.");
+        return Ok(());
+    }
+
+    let paths: Vec<StorePath> = path_infos.iter().map(|path_info| path_info.path.clone()).collect();
+    let state = Arc::new(PushState::open(server, &paths, true, resume)?);
+    if state.num_pushed() > 0 {
+        eprintln!("⏩ Resuming an interrupted push, {} paths were already pushed", state.num_pushed());
+    }
+
+    let pusher = Pusher::new(store, api, MultiProgress::new(), push_config, Some(state.clone()));
+
+    let paths = paths.into_iter().filter(|path| !state.is_pushed(&path.to_hash())).collect();
+    let missing: HashSet<StorePathHash> = pusher.filter_missing(paths).await?
+        .iter()
+        .map(StorePath::to_hash)
+        .collect();
+    path_infos.retain(|path_info| missing.contains(&path_info.path.to_hash()));
+    if path_infos.is_empty() {
+        eprintln!("✅ All done!");
+        return finish(pusher, state, print_stats).await;
+    }
+
+    eprintln!("⚙️ Pushing {num_paths} paths \"{server}\" ...",
+        server = server,
+        num_paths = path_infos.len(),
+    );
+
+    for path_info in path_infos {
+        pusher.queue(path_info).await?;
+    }
+
+    finish(pusher, state, print_stats).await
+}
+
+/// The output of `nix path-info --json`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonPathInfos {
+    /// Nix 2.19 and later: An object keyed by store path.
+    ///
+    /// Invalid paths are `null`.
+    Map(BTreeMap<String, Option<JsonPathInfo>>),
+    /// Before Nix 2.19: An array of path infos.
+    List(Vec<JsonPathInfo>),
+}
+
+/// A path in the output of `nix path-info --json`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonPathInfo {
+    /// The store path, before Nix 2.19.
+    #[serde(default)]
+    path: Option<String>,
+    /// Whether the path is valid, before Nix 2.19.
+    #[serde(default)]
+    valid: Option<bool>,
+    /// The NAR hash, either typed (`sha256:...`) or SRI (`sha256-...`).
+    #[serde(default)]
+    nar_hash: Option<String>,
+    #[serde(default)]
+    nar_size: Option<u64>,
+    /// Full store paths of the references.
+    #[serde(default)]
+    references: Vec<String>,
+    #[serde(default)]
+    signatures: Vec<String>,
+    #[serde(default)]
+    ca: Option<String>,
+    /// Full store path of the deriver.
+    #[serde(default)]
+    deriver: Option<String>,
+}
+
+/// Parses the output of `nix path-info --json`.
+fn parse_path_info_json(json: &str) -> Result<Vec<ValidPathInfo>> {
+    let path_infos: Vec<(String, Option<JsonPathInfo>)> = match serde_json::from_str(json)? {
+        JsonPathInfos::Map(map) => map.into_iter().collect(),
+        JsonPathInfos::List(list) => list
+            .into_iter()
+            .map(|path_info| {
+                let path = path_info.path.clone()
+                    .ok_or_else(|| anyhow!("A path info has no path"))?;
+                let path_info = if path_info.valid == Some(false) { None } else { Some(path_info) };
+                Ok((path, path_info))
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    path_infos
+        .into_iter()
+        .map(|(path, path_info)| {
+            let path_info = path_info
+                .ok_or_else(|| anyhow!("{} is not a valid path", path))?;
+            let nar_hash = path_info.nar_hash
+                .ok_or_else(|| anyhow!("{} has no NAR hash", path))?;
+
+            Ok(ValidPathInfo {
+                path: StorePath::from_base_name(base_name(&path)?)?,
+                nar_hash: parse_nar_hash(&nar_hash)?,
+                nar_size: path_info.nar_size
+                    .ok_or_else(|| anyhow!("{} has no NAR size", path))?,
+                references: path_info.references
+                    .iter()
+                    .map(|reference| base_name(reference))
+                    .collect::<Result<_>>()?,
+                sigs: path_info.signatures,
+                ca: path_info.ca,
+                deriver: path_info.deriver
+                    .map(|deriver| base_name(&deriver))
+                    .transpose()?,
+            })
+        })
+        .collect()
+}
+
+/// Parses a NAR hash in either typed or SRI format.
+fn parse_nar_hash(s: &str) -> Result<Hash> {
+    match s.strip_prefix("sha256-") {
+        Some(sri) => {
+            let bytes: [u8; 32] = BASE64.decode(sri)?
+                .try_into()
+                .map_err(|_| anyhow!("Invalid SHA-256 hash: {}", s))?;
+            Ok(Hash::Sha256(bytes))
+        }
+        None => Ok(Hash::from_typed(s)?),
+    }
+}
+
+/// Returns the base name of a full store path.
+fn base_name(path: &str) -> Result<PathBuf> {
+    Path::new(path)
+        .file_name()
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Invalid store path: {}", path))
+}
+
 type JobSender = channel::Sender<ValidPathInfo>;
 type JobReceiver = channel::Receiver<ValidPathInfo>;
 
@@ -96,6 +516,12 @@ type JobReceiver = channel::Receiver<ValidPathInfo>;
 pub struct PushConfig {
     /// The number of workers to spawn.
     pub num_workers: usize,
+
+    /// The number of path info queries to run at once.
+    pub num_query_workers: usize,
+
+    /// Print the result of each path as a line of JSON.
+    pub json: bool,
 }
 
 /// Configuration for a push session.
@@ -116,10 +542,38 @@ pub struct PushSessionConfig {
 pub struct Pusher {
     api: Client,
     store: Arc<NixStore>,
-    workers: Vec<JoinHandle<HashMap<StorePath, Result<()>>>>,
+    workers: Vec<JoinHandle<(HashMap<StorePath, Result<()>>, PushStats)>>,
     sender: JobSender,
+    config: PushConfig,
+}
+
+/// Statistics of the paths pushed by a `Pusher`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PushStats {
+    /// The number of pushed paths.
+    pub num_paths: usize,
+    /// The total size of the NARs.
+    pub nar_size: u64,
+    /// The number of bytes sent in upload requests.
+    ///
+    /// This includes the bodies of retried attempts and of paths the
+    /// server deduplicated, since the NAR is sent either way.
+    pub transferred_size: u64,
+    /// The size of the NARs in chunks that already existed on the server.
+    pub deduplicated_size: u64,
+    /// The size of the files newly stored on the server.
+    ///
+    /// This is the NAR size for paths whose compressed size is unknown.
+    pub stored_size: u64,
+    /// The total compressed size of the NARs whose compressed size is known.
+    pub file_size: u64,
+    /// The total size of the NARs whose compressed size is known.
+    pub compressed_nar_size: u64,
 }
 
+/// The full set of paths to push.
+///
+/// Every path info is held in memory, so closures are streamed instead.
 #[derive(Debug)]
 pub struct PushPlan {
     /// Store paths to push.
@@ -129,9 +583,13 @@ pub struct PushPlan {
 }
 
 /// Wrapper to update a progress bar as a NAR is streamed.
+///
+/// Errors from the local store are recorded so that a failed upload
+/// can be told apart from a server problem.
 struct NarStreamProgress<S> {
     stream: S,
     bar: ProgressBar,
+    store_error: Arc<Mutex<Option<String>>>,
 }
 
 impl Pusher {
@@ -140,8 +598,9 @@ impl Pusher {
         api: Client,
         mp: MultiProgress,
         config: PushConfig,
+        state: Option<Arc<PushState>>,
     ) -> Self {
-        let (sender, receiver) = channel::unbounded();
+        let (sender, receiver) = channel::bounded(config.num_workers * JOB_QUEUE_FACTOR);
         let mut workers = Vec::new();
 
         for _ in 0..config.num_workers {
@@ -151,6 +610,7 @@ impl Pusher {
                 api.clone(),
                 mp.clone(),
                 config,
+                state.clone(),
             )));
         }
 
@@ -159,30 +619,34 @@ impl Pusher {
             store,
             workers,
             sender,
+            config,
         }
     }
 
     /// Queues a store path to be pushed.
+    ///
+    /// Waits while the queue is full, so path infos are only
+    /// held in memory shortly before they are uploaded.
     pub async fn queue(&self, path_info: ValidPathInfo) -> Result<()> {
         self.sender.send(path_info).await.map_err(|e| anyhow!(e))
     }
 
-    /// Waits for all workers to terminate, returning all results.
+    /// Waits for all workers to terminate, returning all results
+    /// and the statistics of the pushed paths.
     ///
     /// TODO: Stream the results with another channel
-    pub async fn wait(self) -> HashMap<StorePath, Result<()>> {
+    pub async fn wait(self) -> (HashMap<StorePath, Result<()>>, PushStats) {
         drop(self.sender);
 
-        let results = join_all(self.workers)
+        join_all(self.workers)
             .await
             .into_iter()
             .map(|joinresult| joinresult.unwrap())
-            .fold(HashMap::new(), |mut acc, results| {
+            .fold((HashMap::new(), PushStats::default()), |(mut acc, mut stats), (results, worker_stats)| {
                 acc.extend(results);
-                acc
-            });
-
-        results
+                stats.merge(&worker_stats);
+                (acc, stats)
+            })
     }
 
     /// Creates a push plan.
@@ -196,18 +660,40 @@ impl Pusher {
             &self.api,
             roots,
             no_closure,
+            self.config.num_query_workers,
         )
         .await
     }
 
+    /// Returns the store paths that are missing from the cache.
+    pub async fn filter_missing(&self, paths: Vec<StorePath>) -> Result<Vec<StorePath>> {
+        PushPlan::filter_missing(&self.api, paths).await
+    }
+
+    /// Computes the store paths to push.
+    pub async fn closure(
+        &self,
+        roots: Vec<StorePath>,
+        no_closure: bool,
+    ) -> Result<Vec<StorePath>> {
+        PushPlan::closure(&self.store, roots, no_closure).await
+    }
+
+    /// Queries the path infos of store paths, yielding each as soon as it's available.
+    pub fn query_path_infos(&self, paths: Vec<StorePath>) -> BoxStream<'static, Result<ValidPathInfo>> {
+        PushPlan::query_path_infos(self.store.clone(), paths, self.config.num_query_workers)
+    }
+
     async fn worker(
         receiver: JobReceiver,
         store: Arc<NixStore>,
         api: Client,
         mp: MultiProgress,
-        _config: PushConfig,
-    ) -> HashMap<StorePath, Result<()>> {
+        config: PushConfig,
+        state: Option<Arc<PushState>>,
+    ) -> (HashMap<StorePath, Result<()>>, PushStats) {
         let mut results = HashMap::new();
+        let mut stats = PushStats::default();
 
         loop {
             let path_info = match receiver.recv().await {
@@ -219,73 +705,221 @@ impl Pusher {
             };
 
             let store_path = path_info.path.clone();
+            let nar_size = path_info.nar_size;
 
             let r = upload_path(
                 path_info,
                 store.clone(),
                 api.clone(),
                 mp.clone(),
+                config.json,
             )
             .await;
 
-            results.insert(store_path, r);
+            if let Ok((response, transferred_size)) = &r {
+                stats.add(nar_size, *transferred_size, response);
+
+                if let Some(state) = &state {
+                    if let Err(e) = state.record(&store_path.to_hash()) {
+                        tracing::warn!("Failed to record the progress of the push: {}", e);
+                    }
+                }
+            }
+
+            results.insert(store_path, r.map(|_| ()));
         }
 
-        results
+        (results, stats)
+    }
+}
+
+impl PushStats {
+    /// Adds the result of pushing a path.
+    fn add(&mut self, nar_size: u64, transferred_size: u64, response: &Response) {
+        let frac_deduplicated = match response.kind {
+            ResponseKind::Deduplicated => 1.0,
+            _ => response.frac_deduplicated.unwrap_or(0.0),
+        };
+        let file_size = response.file_size.map(|size| size as u64);
+
+        self.num_paths += 1;
+        self.nar_size += nar_size;
+        self.transferred_size += transferred_size;
+        self.deduplicated_size += (nar_size as f64 * frac_deduplicated).round() as u64;
+        self.stored_size += (file_size.unwrap_or(nar_size) as f64 * (1.0 - frac_deduplicated)).round() as u64;
+
+        if let Some(file_size) = file_size {
+            self.file_size += file_size;
+            self.compressed_nar_size += nar_size;
+        }
+    }
+
+    /// Adds the statistics of another set of paths.
+    fn merge(&mut self, other: &Self) {
+        self.num_paths += other.num_paths;
+        self.nar_size += other.nar_size;
+        self.transferred_size += other.transferred_size;
+        self.deduplicated_size += other.deduplicated_size;
+        self.stored_size += other.stored_size;
+        self.file_size += other.file_size;
+        self.compressed_nar_size += other.compressed_nar_size;
+    }
+
+    /// Returns the fraction of the NARs that was deduplicated.
+    pub fn frac_deduplicated(&self) -> f64 {
+        self.deduplicated_size as f64 / self.nar_size.max(1) as f64
+    }
+
+    /// Returns the ratio of NAR size to compressed size, if known.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.file_size == 0 {
+            return None;
+        }
+        Some(self.compressed_nar_size as f64 / self.file_size as f64)
+    }
+
+    /// Prints a summary to stderr.
+    fn print(&self) {
+        eprintln!("📊 {} paths, {} of NARs", self.num_paths, HumanBytes(self.nar_size));
+        eprintln!("   {} transferred", HumanBytes(self.transferred_size));
+        eprintln!("   {} newly stored", HumanBytes(self.stored_size));
+        eprintln!("   {} deduplicated ({:.1}%)", HumanBytes(self.deduplicated_size), self.frac_deduplicated() * 100.0);
+        match self.compression_ratio() {
+            Some(ratio) => eprintln!("   {:.2}x compression", ratio),
+            None => eprintln!("   Unknown compression"),
+        }
     }
 }
 
 impl PushPlan {
     /// Creates a plan.
+    ///
+    /// Paths that are already in the cache are left out.
     async fn plan(
         store: Arc<NixStore>,
-        _api: &Client,
+        api: &Client,
         roots: Vec<StorePath>,
         no_closure: bool,
+        num_query_workers: usize,
     ) -> Result<Self> {
-        // Compute closure
-        let closure = if no_closure {
-            roots
-        } else {
-            store
-                .compute_fs_closure_multi(roots, false, false, false)
-                .await?
-        };
-
-        let store_path_map: HashMap<StorePathHash, ValidPathInfo> = {
-            let futures = closure
-                .iter()
-                .map(|path| {
-                    let store = store.clone();
-                    let path = path.clone();
-                    let path_hash = path.to_hash();
-
-                    async move {
-                        let path_info = store.query_path_info(path).await?;
-                        Ok((path_hash, path_info))
-                    }
-                })
-                .collect::<Vec<_>>();
+        let closure = Self::closure(&store, roots, no_closure).await?;
+        let num_all_paths = closure.len();
+        let missing = Self::filter_missing(api, closure).await?;
 
-            join_all(futures).await.into_iter().collect::<Result<_>>()?
-        };
+        let store_path_map: HashMap<StorePathHash, ValidPathInfo> =
+            Self::query_path_infos(store, missing, num_query_workers)
+                .map_ok(|path_info| (path_info.path.to_hash(), path_info))
+                .try_collect()
+                .await?;
 
-        let num_all_paths = store_path_map.len();
         Ok(Self {
             store_path_map,
             num_all_paths,
         })
     }
+
+    /// Computes the closure of the roots, unless `no_closure` is set.
+    async fn closure(
+        store: &NixStore,
+        roots: Vec<StorePath>,
+        no_closure: bool,
+    ) -> Result<Vec<StorePath>> {
+        if no_closure {
+            Ok(roots)
+        } else {
+            Ok(store
+                .compute_fs_closure_multi(roots, false, false, false)
+                .await?)
+        }
+    }
+
+    /// Returns the store paths that are missing from the cache.
+    async fn filter_missing(api: &Client, paths: Vec<StorePath>) -> Result<Vec<StorePath>> {
+        Self::filter_missing_with(paths, |hashes| api.get_missing_paths(hashes)).await
+    }
+
+    /// Returns the store paths that `get_missing` reports as missing.
+    ///
+    /// The hashes are looked up in batches, and the paths keep their order.
+    async fn filter_missing_with<F, Fut>(paths: Vec<StorePath>, mut get_missing: F) -> Result<Vec<StorePath>>
+    where
+        F: FnMut(Vec<StorePathHash>) -> Fut,
+        Fut: Future<Output = Result<Vec<StorePathHash>, ClientError>>,
+    {
+        let mut missing = HashSet::new();
+        for batch in paths.chunks(MISSING_PATHS_BATCH_SIZE) {
+            let hashes = batch.iter().map(StorePath::to_hash).collect();
+            missing.extend(get_missing(hashes).await?);
+        }
+
+        Ok(paths
+            .into_iter()
+            .filter(|path| missing.contains(&path.to_hash()))
+            .collect())
+    }
+
+    /// Queries the path infos of store paths.
+    ///
+    /// Up to `num_query_workers` queries run at once, and path infos are
+    /// yielded in completion order.
+    fn query_path_infos(
+        store: Arc<NixStore>,
+        paths: Vec<StorePath>,
+        num_query_workers: usize,
+    ) -> BoxStream<'static, Result<ValidPathInfo>> {
+        stream::iter(paths)
+            .map(move |path| {
+                let store = store.clone();
+                async move { Ok(store.query_path_info(path).await?) }
+            })
+            .buffer_unordered(num_query_workers)
+            .boxed()
+    }
+
+    /// Returns references of the planned paths that are neither
+    /// planned nor present in the cache.
+    async fn missing_references(&self, api: &Client) -> Result<Vec<StorePath>> {
+        let mut references = HashMap::new();
+        for path_info in self.store_path_map.values() {
+            for reference in &path_info.references {
+                let reference = StorePath::from_base_name(reference.clone())?;
+                let hash = reference.to_hash();
+                if !self.store_path_map.contains_key(&hash) {
+                    references.insert(hash, reference);
+                }
+            }
+        }
+
+        // Reading narinfos may need the `pull` scope, which pushing doesn't
+        let hashes: Vec<_> = references.keys().cloned().collect();
+        let mut missing = Vec::new();
+        for batch in hashes.chunks(MISSING_PATHS_BATCH_SIZE) {
+            for hash in api.get_missing_paths(batch.to_vec()).await? {
+                missing.extend(references.remove(&hash));
+            }
+        }
+
+        Ok(missing)
+    }
 }
 
 /// Uploads a single path to a cache.
+///
+/// Returns the response of the server and the number of bytes sent
+/// in the request bodies of all attempts. If `json` is set, the result
+/// is printed to stdout as a line of JSON.
 pub async fn upload_path(
     path_info: ValidPathInfo,
     store: Arc<NixStore>,
     api: Client,
     mp: MultiProgress,
-) -> Result<()> {
+    json: bool,
+) -> Result<(Response, u64)> {
     let path = &path_info.path;
+    let system = match &path_info.deriver {
+        Some(deriver) => query_system(&store, deriver).await,
+        None => None,
+    };
     let upload_info = {
         let full_path = store
             .get_full_path(path)
@@ -303,12 +937,21 @@ pub async fn upload_path(
             })
             .collect::<Result<Vec<String>, anyhow::Error>>()?;
 
+        let deriver = path_info
+            .deriver
+            .map(|pb| {
+                pb.to_str()
+                    .ok_or_else(|| anyhow!("Deriver contains non-UTF-8"))
+                    .map(|s| s.to_owned())
+            })
+            .transpose()?;
+
         Request {
             store_path_hash: path.to_hash(),
             store_path: full_path,
             references,
-            system: None,  // TODO
-            deriver: None, // TODO
+            system,
+            deriver,
             sigs: path_info.sigs,
             ca: path_info.ca,
             nar_hash: path_info.nar_hash.to_owned(),
@@ -340,18 +983,60 @@ pub async fn upload_path(
         );
     let bar = mp.add(ProgressBar::new(path_info.nar_size));
     bar.set_style(style);
-    let nar_stream = NarStreamProgress::new(store.nar_from_path(path.to_owned()).map_err(Into::into), bar.clone())
-        .map_ok(Bytes::from);
 
+    // Shared by all attempts so that the server can deduplicate retries
+    let idempotency_key = format!(
+        "{}-{}",
+        upload_info.store_path_hash.as_str(),
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos(),
+    );
+
+    // The upload info is sent as a preamble before the NAR
+    let preamble_size = serde_json::to_string(&upload_info)?.len() as u64;
+
+    let mut attempt = 1;
+    let mut transferred_size = 0;
     let start = Instant::now();
-    match api
-        .upload_path(upload_info, nar_stream, true)
-        .await
-    {
-        Ok(r) => {
+    let (result, store_error) = loop {
+        let store_error = Arc::new(Mutex::new(None));
+        let nar_stream = NarStreamProgress::new(
+            store.nar_from_path(path.to_owned()).map_err(Into::into),
+            bar.clone(),
+            store_error.clone(),
+        )
+        .map_ok(Bytes::from);
+
+        let result = api
+            .upload_path(upload_info.clone(), nar_stream, true, Some(&idempotency_key), json)
+            .await;
+        let store_error = store_error.lock().unwrap().take();
+        transferred_size += preamble_size + bar.position();
+
+        match result {
+            Err(e) if e.is_retryable() && store_error.is_none() && attempt < MAX_UPLOAD_ATTEMPTS => {
+                mp.suspend(|| {
+                    eprintln!(
+                        "🔁 {}: {} (retrying, attempt {}/{})",
+                        path.as_os_str().to_string_lossy(),
+                        e,
+                        attempt + 1,
+                        MAX_UPLOAD_ATTEMPTS,
+                    );
+                });
+                attempt += 1;
+                bar.reset();
+            }
+            r => break (r, store_error),
+        }
+    };
+
+    match (result, store_error) {
+        (Ok(r), _) => {
             let r = r.unwrap_or(Response {
                 kind: ResponseKind::Uploaded,
                 file_size: None,
+                frac_deduplicated: None,
+                chunks: None,
             });
 
             let info_string: String = match r.kind {
@@ -360,34 +1045,99 @@ pub async fn upload_path(
                     let elapsed = start.elapsed();
                     let seconds = elapsed.as_secs_f64();
                     let speed = (path_info.nar_size as f64 / seconds) as u64;
-                    format!("{}/s", HumanBytes(speed))
+                    match r.frac_deduplicated {
+                        Some(frac) if frac > 0.0 => {
+                            format!("{}/s, {:.0}% deduplicated", HumanBytes(speed), frac * 100.0)
+                        }
+                        _ => format!("{}/s", HumanBytes(speed)),
+                    }
                 }
             };
 
+            let json_line = if json {
+                Some(json_result(&upload_info.store_path, &r)?)
+            } else {
+                None
+            };
+
             mp.suspend(|| {
                 eprintln!(
                     "✅ {} ({})",
                     path.as_os_str().to_string_lossy(),
                     info_string
                 );
+                if let Some(line) = json_line {
+                    println!("{}", line);
+                }
             });
             bar.finish_and_clear();
 
-            Ok(())
+            Ok((r, transferred_size))
         }
-        Err(e) => {
+        (Err(_), Some(store_error)) => {
+            let e = if store.get_full_path(path).exists() {
+                anyhow!("Failed to read the NAR from the local store: {}", store_error)
+            } else {
+                anyhow!("Store path vanished during upload, it was probably garbage-collected locally")
+            };
+
             mp.suspend(|| {
                 eprintln!("❌ {}: {}", path.as_os_str().to_string_lossy(), e);
             });
             bar.finish_and_clear();
             Err(e)
         }
+        (Err(e), None) => {
+            mp.suspend(|| {
+                eprintln!("❌ {}: {}", path.as_os_str().to_string_lossy(), e);
+                if let ClientError::Unauthorized(_) = e {
+                    eprintln!("   Hint: Check that the token for this server has push permission.");
+                }
+            });
+            bar.finish_and_clear();
+            Err(e.into())
+        }
+    }
+}
+
+/// The result of pushing a path, as printed with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonPushResult<'a> {
+    store_path: &'a str,
+    #[serde(flatten)]
+    response: &'a Response,
+}
+
+/// Serializes the result of pushing a path into a line of JSON.
+fn json_result(store_path: &str, response: &Response) -> Result<String> {
+    Ok(serde_json::to_string(&JsonPushResult { store_path, response })?)
+}
+
+/// Returns the system of the derivation that produced a path.
+///
+/// The system is only informational, so this returns `None` if
+/// the derivation cannot be read.
+async fn query_system(store: &NixStore, deriver: &Path) -> Option<String> {
+    let drv_path = match StorePath::from_base_name(deriver.to_path_buf()) {
+        Ok(drv_path) => drv_path,
+        Err(e) => {
+            tracing::debug!("Invalid deriver {}: {}", deriver.to_string_lossy(), e);
+            return None;
+        }
+    };
+
+    match store.query_derivation_system(drv_path).await {
+        Ok(system) => system,
+        Err(e) => {
+            tracing::debug!("Failed to read {}: {}", deriver.to_string_lossy(), e);
+            None
+        }
     }
 }
 
 impl<S: Stream<Item = Result<Vec<u8>>>> NarStreamProgress<S> {
-    fn new(stream: S, bar: ProgressBar) -> Self {
-        Self { stream, bar }
+    fn new(stream: S, bar: ProgressBar, store_error: Arc<Mutex<Option<String>>>) -> Self {
+        Self { stream, bar, store_error }
     }
 }
 
@@ -397,8 +1147,9 @@ impl<S: Stream<Item = Result<Vec<u8>>> + Unpin> Stream for NarStreamProgress<S>
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match Pin::new(&mut self.stream).as_mut().poll_next(cx) {
             Poll::Ready(Some(data)) => {
-                if let Ok(data) = &data {
-                    self.bar.inc(data.len() as u64);
+                match &data {
+                    Ok(data) => self.bar.inc(data.len() as u64),
+                    Err(e) => *self.store_error.lock().unwrap() = Some(e.to_string()),
                 }
 
                 Poll::Ready(Some(data))
@@ -414,3 +1165,134 @@ fn average_speed(bytes: u64, duration: Duration) -> String {
     let speed = bytes as f64 * 1000_f64 / duration.as_millis() as f64;
     format!("{}/s", HumanBytes(speed as u64))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NARINFO: &str = "\
+StorePath: /nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+URL: nar/0nqgf15qfiacfxrgm2wkw0gwwncjqqzzalj8rs14w9srkydkjsk9.nar
+Compression: none
+NarHash: sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci
+NarSize: 206104
+References: 563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56 xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10
+Deriver: vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv
+Sig: cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcFQcHVAw==
+";
+
+    #[test]
+    fn test_push_stats() {
+        let response = |kind, file_size, frac_deduplicated| Response {
+            kind,
+            file_size,
+            frac_deduplicated,
+            chunks: None,
+        };
+
+        let mut stats = PushStats::default();
+        stats.add(1000, 1100, &response(ResponseKind::Uploaded, Some(250), Some(0.0)));
+        stats.add(1000, 1100, &response(ResponseKind::Deduplicated, None, None));
+
+        let mut other = PushStats::default();
+        // Retried once
+        other.add(2000, 3100, &response(ResponseKind::Uploaded, Some(500), Some(0.5)));
+        stats.merge(&other);
+
+        assert_eq!(3, stats.num_paths);
+        assert_eq!(4000, stats.nar_size);
+        assert_eq!(5300, stats.transferred_size);
+        assert_eq!(2000, stats.deduplicated_size);
+        assert_eq!(500, stats.stored_size);
+        assert_eq!(0.5, stats.frac_deduplicated());
+        assert_eq!(Some(4.0), stats.compression_ratio());
+        assert_eq!(None, PushStats::default().compression_ratio());
+    }
+
+    #[tokio::test]
+    async fn test_filter_missing() {
+        let paths: Vec<_> = (0..MISSING_PATHS_BATCH_SIZE + 1)
+            .map(|i| StorePath::from_base_name(format!("{:032}-path-{}", i, i).into()).unwrap())
+            .collect();
+
+        let mut batch_sizes = Vec::new();
+        let missing = PushPlan::filter_missing_with(paths.clone(), |hashes| {
+            batch_sizes.push(hashes.len());
+
+            // Every other path is missing, reported in reverse order
+            let missing: Vec<_> = hashes.into_iter().step_by(2).rev().collect();
+            async move { Ok(missing) }
+        }).await.unwrap();
+
+        assert_eq!(vec![MISSING_PATHS_BATCH_SIZE, 1], batch_sizes);
+        let expected: Vec<_> = paths.into_iter().step_by(2).collect();
+        assert_eq!(expected, missing);
+    }
+
+    #[test]
+    fn test_parse_narinfo() {
+        let request = parse_narinfo(NARINFO).unwrap();
+
+        assert_eq!("xcp9cav49dmsjbwdjlmkjxj10gkpx553", request.store_path_hash.as_str());
+        assert_eq!("/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10", request.store_path);
+        assert_eq!(2, request.references.len());
+        assert_eq!(Some("vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv"), request.deriver.as_deref());
+        assert_eq!(1, request.sigs.len());
+        assert_eq!(206104, request.nar_size);
+    }
+
+    #[test]
+    fn test_parse_path_info_json() {
+        // Nix 2.19 and later
+        let map = r#"{
+  "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10": {
+    "narHash": "sha256-keEprBlZ0GKtCT0rH4tlr64PcSBW/j6seOxTD/ahu5o=",
+    "narSize": 206104,
+    "deriver": "/nix/store/vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv",
+    "references": [
+      "/nix/store/563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56",
+      "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10"
+    ],
+    "signatures": ["cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcFQcHVAw=="]
+  }
+}"#;
+
+        // Before Nix 2.19
+        let list = r#"[
+  {
+    "path": "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10",
+    "narHash": "sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci",
+    "narSize": 206104,
+    "deriver": "/nix/store/vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv",
+    "references": [
+      "/nix/store/563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56",
+      "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10"
+    ],
+    "signatures": ["cache.nixos.org-1:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcFQcHVAw=="],
+    "valid": true
+  }
+]"#;
+
+        let expected_hash = Hash::from_typed("sha256:16mvl7v0ylzcg2n3xzjn41qhzbmgcn5iyarx16nn5l2r36n2kqci").unwrap();
+        for json in [map, list] {
+            let path_infos = parse_path_info_json(json).unwrap();
+            assert_eq!(1, path_infos.len());
+
+            let path_info = &path_infos[0];
+            assert_eq!("xcp9cav49dmsjbwdjlmkjxj10gkpx553", path_info.path.to_hash().as_str());
+            assert_eq!(expected_hash, path_info.nar_hash);
+            assert_eq!(206104, path_info.nar_size);
+            assert_eq!(PathBuf::from("563528481rvhc5kxwipjmg6rqrl95mdx-glibc-2.33-56"), path_info.references[0]);
+            assert_eq!(1, path_info.sigs.len());
+            assert_eq!(Some(PathBuf::from("vvb4wxmnjixmrkhmj2xb75z62hrr41i7-hello-2.10.drv")), path_info.deriver);
+        }
+
+        assert!(parse_path_info_json(r#"{ "/nix/store/xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10": null }"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_narinfo_compressed() {
+        let narinfo = NARINFO.replace("Compression: none", "Compression: xz");
+        assert!(parse_narinfo(&narinfo).is_err());
+    }
+}
@@ -110,12 +110,21 @@ pub struct ConfigData {
 pub struct ServerConfig {
     pub endpoint: String,
     pub token: Option<String>,
+    /// The expected hash of the public key of the server's TLS certificate.
+    ///
+    /// See `api::cert_pin` for the format. The certificate must still
+    /// be issued by a CA in `webpki-roots`.
+    #[serde(rename = "cert-pin")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub cert_pin: Option<String>,
 }
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             endpoint: "http://localhost:8080".to_string(),
             token: None,
+            cert_pin: None,
         }
     }
 }
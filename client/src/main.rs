@@ -4,16 +4,12 @@ mod config;
 mod api;
 mod nix_config;
 mod nix_netrc;
+mod push_state;
 
 use anyhow::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_logging()?;
     cli::run().await
 }
 
-fn init_logging() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    Ok(())
-}
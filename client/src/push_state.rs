@@ -0,0 +1,160 @@
+//! Progress of pushes, for resuming interrupted ones.
+//!
+//! The hashes of pushed paths are appended to a file as each upload
+//! completes, so an interrupted push can skip them when run again
+//! without reading their NARs from the store. The file is named
+//! after the server and the pushed roots, and is removed once a push
+//! completes.
+//!
+//! Files are kept under `$XDG_STATE_HOME/nixcache/push`.
+
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use sha2::{Digest, Sha256};
+use xdg::BaseDirectories;
+
+use libnixstore::{StorePath, StorePathHash};
+
+/// Application prefix in XDG base directories.
+const XDG_PREFIX: &str = "nixcache";
+
+/// The progress of a push.
+#[derive(Debug)]
+pub struct PushState {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Paths pushed before this push started.
+    pushed: HashSet<StorePathHash>,
+}
+
+impl PushState {
+    /// Opens the progress of pushing `roots` to `server`.
+    ///
+    /// Unless `resume` is set, earlier progress is discarded.
+    pub fn open(server: &str, roots: &[StorePath], no_closure: bool, resume: bool) -> Result<Self> {
+        let xdg_dirs = BaseDirectories::with_prefix(XDG_PREFIX)?;
+        let path = xdg_dirs.place_state_file(format!("push/{}", state_key(server, roots, no_closure)))?;
+
+        Self::open_at(path, resume)
+    }
+
+    fn open_at(path: PathBuf, resume: bool) -> Result<Self> {
+        let pushed = match (resume, fs::read_to_string(&path)) {
+            (true, Ok(data)) => data
+                .lines()
+                .filter_map(|line| StorePathHash::new(line.to_string()).ok())
+                .collect(),
+            (true, Err(e)) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => HashSet::new(),
+        };
+
+        let mut options = OpenOptions::new();
+        if resume {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        let file = options.create(true).open(&path)?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            pushed,
+        })
+    }
+
+    /// Returns the number of paths pushed before this push started.
+    pub fn num_pushed(&self) -> usize {
+        self.pushed.len()
+    }
+
+    /// Returns whether a path was pushed before this push started.
+    pub fn is_pushed(&self, hash: &StorePathHash) -> bool {
+        self.pushed.contains(hash)
+    }
+
+    /// Records that a path was pushed.
+    pub fn record(&self, hash: &StorePathHash) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", hash.as_str())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Discards the progress of a completed push.
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Returns the name of the file holding the progress of a push.
+fn state_key(server: &str, roots: &[StorePath], no_closure: bool) -> String {
+    let mut roots: Vec<_> = roots.iter().map(|root| root.as_os_str()).collect();
+    roots.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(server.as_bytes());
+    hasher.update(if no_closure { "\nno-closure" } else { "\nclosure" });
+    for root in roots {
+        hasher.update(b"\n");
+        hasher.update(root.as_bytes());
+    }
+
+    hasher.finalize().iter().fold(String::new(), |mut key, byte| {
+        write!(key, "{:02x}", byte).unwrap();
+        key
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let path = std::env::temp_dir().join(format!("nixcache-test-push-state-{}", std::process::id()));
+        let a = StorePathHash::new("ia70ss13m22znbl8khrf2hq72qmh5drr".to_string()).unwrap();
+        let b = StorePathHash::new("xcp9cav49dmsjbwdjlmkjxj10gkpx553".to_string()).unwrap();
+
+        let state = PushState::open_at(path.clone(), true).unwrap();
+        assert_eq!(0, state.num_pushed());
+        state.record(&a).unwrap();
+        drop(state);
+
+        let state = PushState::open_at(path.clone(), true).unwrap();
+        assert!(state.is_pushed(&a));
+        assert!(!state.is_pushed(&b));
+        state.record(&b).unwrap();
+        drop(state);
+
+        let state = PushState::open_at(path.clone(), true).unwrap();
+        assert_eq!(2, state.num_pushed());
+        drop(state);
+
+        let state = PushState::open_at(path.clone(), false).unwrap();
+        assert_eq!(0, state.num_pushed());
+        state.finish().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_state_key() {
+        let path = |name: &str| StorePath::from_base_name(PathBuf::from(name)).unwrap();
+        let hello = path("xcp9cav49dmsjbwdjlmkjxj10gkpx553-hello-2.10");
+        let ruby = path("ia70ss13m22znbl8khrf2hq72qmh5drr-ruby-2.7.5");
+
+        let key = state_key("https://cache.example.com", &[hello.clone(), ruby.clone()], false);
+        assert_eq!(64, key.len());
+        assert_eq!(key, state_key("https://cache.example.com", &[ruby.clone(), hello.clone()], false));
+        assert_ne!(key, state_key("https://cache.example.com", &[hello.clone(), ruby.clone()], true));
+        assert_ne!(key, state_key("https://other.example.com", &[hello, ruby], false));
+    }
+}
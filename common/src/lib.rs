@@ -1,4 +1,6 @@
+#[cfg(feature = "v1")]
 pub mod v1;
+pub mod logging;
 pub mod mime;
 pub mod signing;
 
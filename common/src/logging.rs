@@ -0,0 +1,29 @@
+//! Logging setup shared by the binaries.
+
+use anyhow::{anyhow, Result};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::EnvFilter;
+
+/// Returns the log level for the `-v` and `-q` flags.
+pub fn log_level(verbose: u8, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    }
+}
+
+/// Initializes logging for the `-v` and `-q` flags.
+///
+/// `RUST_LOG` takes precedence if set.
+pub fn init(verbose: u8, quiet: bool) -> Result<()> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(log_level(verbose, quiet).into())
+        .from_env_lossy();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .try_init()
+        .map_err(|e| anyhow!(e))
+}
@@ -68,8 +68,17 @@ pub enum Error {
     /// The payload portion of the string is blank.
     BlankPayload,
 
-    /// Base64 decode error: {0}
-    Base64DecodeError(DecodeError),
+    /// Malformed {usage}: Expected "{{keyName}}:{{base64Payload}}" with an ed25519 payload, but the payload is not valid base64: {error}
+    Base64DecodeError {
+        error: DecodeError,
+        usage: &'static str,
+    },
+
+    /// Unsupported {usage} algorithm "{algorithm}": Only ed25519 in the form "{{keyName}}:{{base64Payload}}" is supported
+    UnsupportedAlgorithm {
+        algorithm: String,
+        usage: &'static str,
+    },
 
     /// Invalid base64 payload length: Expected {expected} ({usage}), got {actual}
     InvalidPayloadLength {
@@ -110,6 +119,21 @@ impl Keypair {
         })
     }
 
+    /// Returns the name of the keypair.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the same keypair under another name.
+    pub fn with_name(&self, name: &str) -> Result<Self> {
+        validate_name(name)?;
+
+        Ok(Self {
+            name: name.to_string(),
+            keypair: self.keypair.clone(),
+        })
+    }
+
     /// Returns the canonical representation of the keypair.
     ///
     /// This results in a 64-byte base64 payload that contains both the private
@@ -213,6 +237,17 @@ impl PublicKey {
     }
 }
 
+/// Returns whether a key name follows the `{host}-{n}` convention.
+///
+/// For example, `cache.nixos.org-1`. Nix doesn't enforce it, but
+/// `nix-store --generate-binary-cache-key` suggests it.
+pub fn is_conventional_name(name: &str) -> bool {
+    match name.rsplit_once('-') {
+        Some((host, n)) => !host.is_empty() && !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    }
+}
+
 /// Validates the name/label of a signing key.
 ///
 /// A valid name cannot be empty and must not contain colons (:).
@@ -248,9 +283,21 @@ fn decode_string<'s>(
         }
     }
 
+    let payload = &colon_and_payload[1..];
+
+    // Other schemes tag the payload with the algorithm, like
+    // `{keyName}:{algorithm}:{payload}`, while ed25519 is implied
+    if let Some((algorithm, _)) = payload.split_once(':') {
+        return Err(Error::UnsupportedAlgorithm {
+            algorithm: algorithm.to_string(),
+            usage,
+        }
+        .into());
+    }
+
     let bytes = BASE64_STANDARD
-        .decode(&colon_and_payload[1..])
-        .map_err(Error::Base64DecodeError)?;
+        .decode(payload)
+        .map_err(|error| Error::Base64DecodeError { error, usage })?;
 
     if bytes.len() != expected_payload_length {
         return Err(Error::InvalidPayloadLength {
@@ -268,6 +315,28 @@ fn decode_string<'s>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_conventional_name() {
+        assert!(is_conventional_name("cache.nixos.org-1"));
+        assert!(is_conventional_name("demo.nixcache-0"));
+        assert!(!is_conventional_name("attic-test"));
+        assert!(!is_conventional_name("cache.nixos.org"));
+        assert!(!is_conventional_name("-1"));
+    }
+
+    #[test]
+    fn test_with_name() {
+        let keypair = Keypair::generate("old-1").unwrap();
+        let renamed = keypair.with_name("new-1").unwrap();
+
+        let signature = renamed.sign(b"hello");
+        assert!(signature.starts_with("new-1:"));
+        assert!(keypair.to_public_key().verify(b"hello", &signature).is_err());
+        assert!(renamed.to_public_key().verify(b"hello", &signature).is_ok());
+
+        assert!(keypair.with_name("bad:name").is_err());
+    }
+
     #[test]
     fn test_generate_key() {
         let keypair = Keypair::generate("attic-test").expect("Could not generate key");
@@ -334,4 +403,24 @@ mod tests {
 
         keypair.verify(message, "attic-test:lo9EfNIL4eGRuNh7DTbAAffWPpI2SlYC/8uP7JnhgmfRIUNGhSbFe8qEaKN0mFS02TuhPpXFPNtRkFcCp0hGAQ==").unwrap_err();
     }
+
+    #[test]
+    fn test_malformed_signature() {
+        let public = Keypair::generate("attic-test").unwrap().to_public_key();
+        let message = b"hello world";
+
+        let error = |signature: &str| {
+            public.verify(message, signature)
+                .unwrap_err()
+                .downcast::<Error>()
+                .unwrap()
+        };
+
+        assert!(matches!(
+            error("attic-test:rsa:AAAA"),
+            Error::UnsupportedAlgorithm { algorithm, .. } if algorithm == "rsa"
+        ));
+        assert!(matches!(error("attic-test:not base64!"), Error::Base64DecodeError { .. }));
+        assert!(matches!(error("attic-test:AAAA"), Error::InvalidPayloadLength { .. }));
+    }
 }
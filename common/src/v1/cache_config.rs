@@ -25,6 +25,13 @@ pub struct CacheConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub public_key: Option<String>,
 
+    /// The name of the signing key.
+    ///
+    /// Clients must use this name in `trusted-public-keys`.
+    /// This is read-only and may not be available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_key_name: Option<String>,
+
     /// Whether the cache is public or not.
     ///
     /// Anonymous clients are implicitly granted the "pull"
@@ -45,6 +52,12 @@ pub struct CacheConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub priority: Option<i32>,
 
+    /// Whether clients should query the cache for many paths at once.
+    ///
+    /// This is read-only and may not be available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub want_mass_query: Option<bool>,
+
     /// A list of signing key names of upstream caches.
     ///
     /// The list serves as a hint to clients to avoid uploading
@@ -55,6 +68,21 @@ pub struct CacheConfig {
     /// The retention period of the cache.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub retention_period: Option<RetentionPeriodConfig>,
+
+    /// The compression applied to NARs uploaded to the cache.
+    ///
+    /// One of `none`, `brotli`, `zstd` or `xz`.
+    /// This is read-only and may not be available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+
+    /// The effective compression level.
+    ///
+    /// If the server chose a level itself, this is the level it chose.
+    /// This is read-only and is not available if the compressor's
+    /// own default is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression_level: Option<i32>,
 }
 
 /// Configuration of retention period.
@@ -0,0 +1,34 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/// Results of compressing a sample with every compression type.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// The total size of the sample.
+    pub sample_size: usize,
+
+    /// The number of chunks in the sample.
+    pub sample_chunks: usize,
+
+    /// The results by compression type.
+    ///
+    /// Example key: `zstd`.
+    pub results: BTreeMap<String, CompressionResult>,
+}
+
+/// The result of compressing a sample with one compression type.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompressionResult {
+    /// The compression level, or `None` for the default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+
+    /// The total compressed size.
+    pub compressed_size: usize,
+
+    /// The compressed size divided by the sample size.
+    pub ratio: f64,
+
+    /// The total time spent compressing, in milliseconds.
+    pub ms: f64,
+}
@@ -0,0 +1,9 @@
+use serde::{Serialize, Deserialize};
+
+/// The result of deleting a store path.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// The number of chunks that were deleted because no other
+    /// store path referenced them.
+    pub chunks_freed: usize,
+}
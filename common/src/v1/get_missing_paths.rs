@@ -0,0 +1,18 @@
+use serde::{Serialize, Deserialize};
+
+use libnixstore::StorePathHash;
+
+/// Store paths to check for in a cache.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    /// The hash portions of the store paths.
+    pub store_path_hashes: Vec<StorePathHash>,
+}
+
+/// Store paths missing from a cache.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// The hash portions of the requested store paths that are not
+    /// in the cache.
+    pub missing_paths: Vec<StorePathHash>,
+}
@@ -0,0 +1,34 @@
+use serde::{Serialize, Deserialize};
+
+/// Query of the store paths in a cache.
+///
+/// Without a limit, all store paths are listed at once.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Query {
+    /// Only list store paths with hashes after this one.
+    ///
+    /// This is the `next_cursor` of the previous page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+
+    /// The maximum number of store paths to list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// List of store paths in a cache, ordered by hash.
+///
+/// Unlike the pages in `paths`, this only has the hashes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// The hash portions of the store paths.
+    pub store_path_hashes: Vec<String>,
+
+    /// The cursor of the next page.
+    ///
+    /// This is not set on the last page, and never set by older
+    /// servers, which list all store paths at once.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
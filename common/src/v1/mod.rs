@@ -4,7 +4,22 @@ pub mod header {
 
     /// Header containing the size of the upload info at the beginning of the body.
     pub const NAR_INFO_PREAMBLE_SIZE: &str = "X-Nixcache-Nar-Info-Preamble-Size";
+
+    /// Header containing a key that identifies retries of the same upload.
+    pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+    /// Header requesting per-chunk details in the upload response.
+    pub const VERBOSE_RESPONSE: &str = "X-Nixcache-Verbose-Response";
+
+    /// Header declaring the compression of the upload body.
+    pub const UPLOAD_COMPRESSION: &str = "X-Nixcache-Upload-Compression";
 }
 
 pub mod upload_path;
+pub mod delete_path;
 pub mod cache_config;
+pub mod list_paths;
+pub mod paths;
+pub mod get_missing_paths;
+pub mod stats;
+pub mod compression_bench;
@@ -0,0 +1,47 @@
+use serde::{Serialize, Deserialize};
+
+/// Query of a page of store paths in a cache.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Query {
+    /// Only list store paths with hashes after this one.
+    ///
+    /// This is the `next_cursor` of the previous page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+
+    /// The maximum number of store paths to list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// A page of store paths in a cache, ordered by hash.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// The store paths on this page.
+    pub paths: Vec<PathInfo>,
+
+    /// The cursor of the next page.
+    ///
+    /// This is not set on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// A store path in a cache.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathInfo {
+    /// The hash portion of the store path.
+    pub store_path_hash: String,
+
+    /// The size of the NAR.
+    pub nar_size: usize,
+
+    /// The base paths of the store paths it references.
+    pub references: Vec<String>,
+
+    /// When the store path was uploaded, in seconds since the Unix epoch.
+    ///
+    /// This is not available for store paths uploaded by older servers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+}
@@ -0,0 +1,22 @@
+use serde::{Serialize, Deserialize};
+
+/// Server statistics.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// Statistics of the narinfo cache, if enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub narinfo_cache: Option<NarInfoCacheStats>,
+}
+
+/// Statistics of the in-memory narinfo cache.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NarInfoCacheStats {
+    /// The number of requests served from the cache.
+    pub hits: u64,
+
+    /// The number of requests that had to go to storage.
+    pub misses: u64,
+
+    /// The number of cached narinfos.
+    pub entries: usize,
+}
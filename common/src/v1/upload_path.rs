@@ -17,7 +17,7 @@ use libnixstore::{StorePathHash, Hash};
 /// Regardless of client compression, the server will always decompress
 /// the NAR to validate the NAR hash before applying the server-configured
 /// compression again.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
     /// The hash portion of the store path.
     pub store_path_hash: StorePathHash,
@@ -54,7 +54,7 @@ pub struct Request {
 }
 
 #[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Response {
     #[serde_as(deserialize_as = "DefaultOnError")]
     pub kind: ResponseKind,
@@ -62,9 +62,34 @@ pub struct Response {
     /// The compressed size of the NAR, in bytes.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_size: Option<usize>,
+
+    /// The fraction of the NAR that was in chunks that already existed.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frac_deduplicated: Option<f64>,
+
+    /// The chunks of the NAR.
+    ///
+    /// This is only returned if `X-Nixcache-Verbose-Response` is set.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<ChunkResult>>,
+}
+
+/// The result of uploading a chunk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkResult {
+    /// The hash of the compressed chunk.
+    pub file_hash: Hash,
+
+    /// The compressed size of the chunk, in bytes.
+    pub file_size: usize,
+
+    /// Whether the chunk already existed and was not uploaded again.
+    pub deduplicated: bool,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum ResponseKind {
     /// The path was uploaded.
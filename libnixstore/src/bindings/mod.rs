@@ -207,6 +207,14 @@ mod ffi {
             store_path: &[u8],
         ) -> Result<UniquePtr<CPathInfo>>;
 
+        /// Returns the system of a derivation.
+        ///
+        /// Returns an empty string if the derivation is not in the store.
+        fn query_derivation_system(
+            self: Pin<&mut CNixStore>,
+            base_name: &[u8],
+        ) -> Result<String>;
+
         /// Computes the closure of a valid path.
         ///
         /// If `flip_directions` is true, the set of paths that can reach `store_path` is
@@ -267,5 +275,10 @@ mod ffi {
 
         /// Returns the CA field of the store path.
         fn ca(self: Pin<&mut CPathInfo>) -> String;
+
+        /// Returns the base name of the deriver of the store path.
+        ///
+        /// Returns an empty string if the deriver is unknown.
+        fn deriver(self: Pin<&mut CPathInfo>) -> String;
     }
 }
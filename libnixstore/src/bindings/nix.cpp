@@ -73,6 +73,14 @@ RString CPathInfo::ca() {
 	}
 }
 
+RString CPathInfo::deriver() {
+	if (this->pi->deriver) {
+		return RString(std::string(this->pi->deriver->to_string()));
+	} else {
+		return RString("");
+	}
+}
+
 // =========
 // CNixStore
 // =========
@@ -100,6 +108,18 @@ std::unique_ptr<CPathInfo> CNixStore::query_path_info(RBasePathSlice base_name)
 	return std::make_unique<CPathInfo>(r);
 }
 
+RString CNixStore::query_derivation_system(RBasePathSlice base_name) {
+	auto store_path = store_path_from_rust(base_name);
+
+	// Derivations are often garbage-collected or never copied
+	if (!this->store->isValidPath(store_path)) {
+		return RString("");
+	}
+
+	auto drv = this->store->readDerivation(store_path);
+	return RString(drv.platform);
+}
+
 std::unique_ptr<std::vector<std::string>> CNixStore::compute_fs_closure(RBasePathSlice base_name, bool flip_direction, bool include_outputs, bool include_derivers) {
 	std::set<nix::StorePath> out;
 
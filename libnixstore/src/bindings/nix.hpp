@@ -14,6 +14,7 @@
 #include <mutex>
 #include <set>
 #include <nix/store-api.hh>
+#include <nix/derivations.hh>
 #include <nix/local-store.hh>
 #include <nix/remote-store.hh>
 #include <nix/uds-remote-store.hh>
@@ -51,6 +52,7 @@ public:
 	std::unique_ptr<std::vector<std::string>> sigs();
 	std::unique_ptr<std::vector<std::string>> references();
 	RString ca();
+	RString deriver();
 };
 
 class CNixStore {
@@ -60,6 +62,7 @@ public:
 
 	RString store_dir();
 	std::unique_ptr<CPathInfo> query_path_info(RBasePathSlice base_name);
+	RString query_derivation_system(RBasePathSlice base_name);
 	std::unique_ptr<std::vector<std::string>> compute_fs_closure(
 		RBasePathSlice base_name,
 		bool flip_direction,
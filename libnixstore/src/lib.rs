@@ -74,6 +74,11 @@ lazy_static! {
         Regex::new(&format!("^{}$", STORE_PATH_HASH_REGEX_FRAGMENT)).unwrap()
     };
 
+    /// Regex for a store path hash of any non-zero length.
+    static ref NONSTANDARD_STORE_PATH_HASH_REGEX: Regex = {
+        Regex::new("^[0123456789abcdfghijklmnpqrsvwxyz]+$").unwrap()
+    };
+
     /// Regex for a valid store base name.
     ///
     /// A base name consists of two parts: A hash and a human-readable
@@ -144,6 +149,9 @@ pub struct ValidPathInfo {
 
     /// Content Address.
     pub ca: Option<String>,
+
+    /// Base name of the derivation that produced the path, if known.
+    pub deriver: Option<PathBuf>,
 }
 
 impl StorePath {
@@ -213,9 +221,39 @@ impl StorePath {
     }
 }
 
+/// How strictly store path hashes are validated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorePathHashPolicy {
+    /// Hashes must be exactly `STORE_PATH_HASH_LEN` characters long.
+    #[default]
+    Strict,
+
+    /// Hashes may be of any non-zero length.
+    ///
+    /// This is useful for experimental stores with different hash
+    /// lengths. The character set is still validated.
+    AllowNonstandardLength,
+}
+
 impl StorePathHash {
     /// Creates a store path hash from a string.
     pub fn new(hash: String) -> Result<Self> {
+        Self::new_with_policy(hash, StorePathHashPolicy::Strict)
+    }
+
+    /// Creates a store path hash from a string, validating it according to a policy.
+    pub fn new_with_policy(hash: String, policy: StorePathHashPolicy) -> Result<Self> {
+        if policy == StorePathHashPolicy::AllowNonstandardLength {
+            if !NONSTANDARD_STORE_PATH_HASH_REGEX.is_match(&hash) {
+                return Err(Error::InvalidStorePathHash {
+                    hash,
+                    reason: "Hash is of invalid format",
+                });
+            }
+
+            return Ok(Self(hash));
+        }
+
         if hash.as_bytes().len() != STORE_PATH_HASH_LEN {
             return Err(Error::InvalidStorePathHash {
                 hash,
@@ -254,13 +292,19 @@ impl StorePathHash {
 
 impl<'de> Deserialize<'de> for StorePathHash {
     /// Deserializes a potentially-invalid store path hash.
+    ///
+    /// Only the character set is validated, since the accepted length
+    /// depends on the receiver. Receivers that only accept standard
+    /// hashes must check them again with `new_with_policy`.
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: de::Deserializer<'de>,
     {
         use de::Error;
-        String::deserialize(deserializer)
-            .and_then(|s| Self::new(s).map_err(|e| Error::custom(e.to_string())))
+        String::deserialize(deserializer).and_then(|s| {
+            Self::new_with_policy(s, StorePathHashPolicy::AllowNonstandardLength)
+                .map_err(|e| Error::custom(e.to_string()))
+        })
     }
 }
 
@@ -189,6 +189,20 @@ impl NixStore {
         .unwrap()
     }
 
+    /// Returns the system of a derivation.
+    ///
+    /// Returns `None` if the derivation is not in the store.
+    pub async fn query_derivation_system(&self, drv_path: StorePath) -> Result<Option<String>> {
+        let inner = self.inner.clone();
+
+        spawn_blocking(move || {
+            let system = inner.store().query_derivation_system(drv_path.as_base_name_bytes())?;
+            Ok(if system.is_empty() { None } else { Some(system) })
+        })
+        .await
+        .unwrap()
+    }
+
     /// Returns detailed information on a path.
     pub async fn query_path_info(&self, store_path: StorePath) -> Result<ValidPathInfo> {
         let inner = self.inner.clone();
@@ -219,6 +233,7 @@ impl NixStore {
                 })
                 .collect();
             let ca = c_path_info.pin_mut().ca();
+            let deriver = c_path_info.pin_mut().deriver();
 
             Ok(ValidPathInfo {
                 path: store_path,
@@ -227,6 +242,7 @@ impl NixStore {
                 references,
                 sigs,
                 ca: if ca.is_empty() { None } else { Some(ca) },
+                deriver: if deriver.is_empty() { None } else { Some(PathBuf::from(deriver)) },
             })
         })
         .await
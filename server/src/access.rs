@@ -1,36 +1,122 @@
+use std::marker::PhantomData;
 use std::sync::Arc;
 use axum::{
     headers::{Authorization, authorization::Bearer},
     TypedHeader,
     extract::FromRequestParts,
+    extract::rejection::TypedHeaderRejectionReason,
     http::request::Parts,
 };
 use async_trait::async_trait;
 
-use auth::{MACLike, NoCustomClaims};
+use auth::Scope;
 use crate::State;
 use crate::error::{ServerError, ErrorKind};
 
-pub struct RequireAuth;
+/// Requires a valid token that was granted the scope of `S`, if any.
+///
+/// Missing and invalid tokens are rejected with 401 Unauthorized,
+/// and tokens without the scope with 403 Forbidden.
+pub struct RequireAuth<S = AnyScope>(PhantomData<fn() -> S>);
+
+/// The scope required by `RequireAuth`.
+pub trait RequiredScope {
+    const SCOPE: Option<Scope>;
+}
+
+/// Any valid token is accepted.
+pub struct AnyScope;
+impl RequiredScope for AnyScope {
+    const SCOPE: Option<Scope> = None;
+}
+
+/// The token must be granted the `push` scope.
+pub struct Push;
+impl RequiredScope for Push {
+    const SCOPE: Option<Scope> = Some(Scope::Push);
+}
+
+/// The token must be granted the `pull` scope.
+pub struct Pull;
+impl RequiredScope for Pull {
+    const SCOPE: Option<Scope> = Some(Scope::Pull);
+}
+
+/// The token must be granted the `delete` scope.
+pub struct Delete;
+impl RequiredScope for Delete {
+    const SCOPE: Option<Scope> = Some(Scope::Delete);
+}
+
+/// The authenticated subject of a request.
+///
+/// This is added to the request extensions by `RequireAuth`.
+#[derive(Debug, Clone)]
+pub struct Subject {
+    /// The subject of the token.
+    ///
+    /// Tokens without a subject share the empty subject.
+    pub name: String,
+    /// The maximum number of concurrent uploads set in the token.
+    pub max_concurrent_uploads: Option<usize>,
+}
 
 #[async_trait]
-impl FromRequestParts<Arc<State>> for RequireAuth {
+impl<S: RequiredScope> FromRequestParts<Arc<State>> for RequireAuth<S> {
     type Rejection = ServerError;
 
     async fn from_request_parts(parts: &mut Parts, state: &Arc<State>) -> Result<Self, Self::Rejection> {
-        match &state.config.token_hs256_secret {
+        match &state.config.token_key {
             Some(key) => {
                 let TypedHeader(Authorization(bearer)) =
                     TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
                         .await
-                        .map_err(|_| ServerError::from(ErrorKind::InvalidToken))?;
+                        .map_err(|e| if matches!(e.reason(), TypedHeaderRejectionReason::Missing) {
+                            ServerError::from(ErrorKind::Unauthorized)
+                        } else {
+                            ServerError::from(ErrorKind::InvalidToken)
+                        })?;
 
-                let _claims = key.verify_token::<NoCustomClaims>(bearer.token(), None)
+                let claims = key.verify_token(bearer.token())
                     .map_err(ServerError::auth_error)?;
 
-                Ok(Self)
+                if let Some(scope) = S::SCOPE {
+                    if !claims.custom.has_scope(scope) {
+                        return Err(ErrorKind::Forbidden.into());
+                    }
+                }
+
+                parts.extensions.insert(Subject {
+                    name: claims.subject.unwrap_or_default(),
+                    max_concurrent_uploads: claims.custom.max_concurrent_uploads,
+                });
+
+                Ok(Self(PhantomData))
             },
-            None => Ok(Self),
+            None => Ok(Self(PhantomData)),
+        }
+    }
+}
+
+/// Rejects requests in read-only mode.
+///
+/// This is applied to the routes that modify the cache. Other routes,
+/// including `POST` routes that only read, keep working.
+pub struct RejectWrites;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RejectWrites {
+    type Rejection = ServerError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let state = parts.extensions
+            .get::<Arc<State>>()
+            .ok_or(ErrorKind::InternalServerError)?;
+
+        if state.config.read_only {
+            return Err(ErrorKind::ReadOnly.into());
         }
+
+        Ok(Self)
     }
 }
@@ -5,32 +5,46 @@
 //! The implementation is based on the specifications at <https://github.com/fzakaria/nix-http-binary-cache-api-spec>.
 
 use anyhow::anyhow;
-use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::io::{Cursor, Error as IoError, ErrorKind as IoErrorKind};
+use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::collections::VecDeque;
 use axum::{
     body::StreamBody,
     extract::{Extension, Path},
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, head},
     Router,
 };
+use bytes::Bytes;
 use serde::Serialize;
-use tokio::io::AsyncReadExt;
-use tokio_util::io::ReaderStream;
-use futures::TryStreamExt;
-use futures::stream::BoxStream;
+use tokio::io::{AsyncBufRead, AsyncReadExt, BufReader};
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+use futures::{future, StreamExt, TryStreamExt};
+use futures::stream::{self, BoxStream, Stream};
 use tracing::instrument;
 
 use libnixstore::StorePathHash;
 use common::mime;
 use crate::error::{ErrorKind, ServerResult, ServerError};
 use crate::{nix_manifest, State, narinfo::NarInfo};
+use crate::narinfo::cache::CachedNarInfo;
 use crate::storage::{StorageBackend, Download};
 use crate::api::{UploadedNar, UploadedChunk};
 use crate::chunking::merge_chunks;
+use crate::compression::{content_coding, get_compressor_fn, get_decompressor_fn};
+use crate::config::{CompressionConfig, CompressionType, DownloadConfig, ListingGeneration};
+use crate::stream::prefetch;
+use crate::nar_listing::{self, ListingBuilder};
+
+/// Size above which NAR manifests are parsed as they are read.
+///
+/// Smaller manifests are read into memory before being parsed.
+const STREAMING_MANIFEST_THRESHOLD: usize = 1024 * 1024; // 1 MiB
 
 /// Nix cache information.
 ///
@@ -86,163 +100,934 @@ async fn get_nix_cache_info() -> ServerResult<NixCacheInfo> {
 /// `/:path`, which may be one of
 /// - GET  `/{storePathHash}.narinfo`
 /// - HEAD `/{storePathHash}.narinfo`
-/// - GET  `/{storePathHash}.ls`      (NOT IMPLEMENTED)
+/// - GET  `/{storePathHash}.ls`
 #[instrument(skip_all, fields(path))]
 #[axum_macros::debug_handler]
 async fn get_store_path_info(
     Extension(state): Extension<Arc<State>>,
     Path(path): Path<String>,
-) -> ServerResult<NarInfo> {
+) -> ServerResult<Response> {
     let components: Vec<&str> = path.splitn(2, '.').collect();
     if components.len() != 2 {
         return Err(ErrorKind::NotFound.into());
     }
-    if components[1] != "narinfo" {
-        return Err(ErrorKind::NotFound.into());
-    }
-    let store_path_hash = StorePathHash::new(components[0].to_string())
+    let policy = state.config.store_path_hash_policy();
+    let store_path_hash = StorePathHash::new_with_policy(components[0].to_string(), policy)
         .map_err(|e| ErrorKind::RequestError(anyhow!(
             "Could not parse store path hash : {}", e
         )))?;
 
+    match components[1] {
+        "narinfo" => Ok(get_narinfo(&state, store_path_hash).await?.into_response()),
+        "ls" => get_listing(&state, store_path_hash).await,
+        _ => Err(ErrorKind::NotFound.into()),
+    }
+}
+
+/// Gets the narinfo of a store path.
+///
+/// The chunks are not checked, since that would cost a request to
+/// the storage per chunk. A NAR whose chunks are missing is instead
+/// not found when it's downloaded.
+async fn get_narinfo(state: &State, store_path_hash: StorePathHash) -> ServerResult<NarInfo> {
     tracing::debug!("Received request for {}.narinfo", store_path_hash.as_str());
 
-    // Get NAR
+    let cached = state.narinfo_cache.as_ref().and_then(|c| c.get(store_path_hash.as_str()));
+    let stale = match cached {
+        Some(CachedNarInfo { narinfo, expired: false }) => return Ok(narinfo),
+        Some(CachedNarInfo { narinfo, expired: true }) => Some(narinfo),
+        None => None,
+    };
+
     let backend = state.storage();
-    let nar = backend
-        .download_nar(store_path_hash.to_string())
-        .await?;
-
-    let mut narinfo = match nar {
-        Download::AsyncRead(mut stream) => {
-            let mut nar = Vec::new();
-            stream.read_to_end(&mut nar).await
-                .map_err(ServerError::storage_error)?;
-            let nar: UploadedNar = serde_json::from_slice(&nar)
-                .map_err(ServerError::storage_error)?;
-            nar.into_narinfo(&store_path_hash)
-        },
-        Download::Stream(stream) => {
-            use futures::AsyncReadExt;
-
-            let mut nar = Vec::new();
-            stream.into_async_read().read_to_end(&mut nar).await
-                .map_err(ServerError::storage_error)?;
-            let nar: UploadedNar = serde_json::from_slice(&nar)
-                .map_err(ServerError::storage_error)?;
-            nar.into_narinfo(&store_path_hash)
+    let nar = match download_uploaded_nar(&**backend, &store_path_hash).await {
+        Ok(nar) => nar,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound) => {
+            // Deleted by another server
+            if let Some(cache) = &state.narinfo_cache {
+                cache.remove(store_path_hash.as_str());
+            }
+            return Err(e);
+        }
+        Err(e) => match stale {
+            Some(narinfo) => {
+                tracing::warn!("Serving expired narinfo of {}: {}", store_path_hash.as_str(), e);
+                return Ok(narinfo);
+            }
+            None => return Err(e),
         },
     };
 
+    let mut narinfo = nar.into_narinfo(&store_path_hash);
+
     if narinfo.signature().is_none() {
         narinfo.sign(&state.config.keypair);
     }
 
+    if let Some(cache) = &state.narinfo_cache {
+        cache.insert(store_path_hash.to_string(), narinfo.clone());
+    }
+
     Ok(narinfo)
 }
 
+/// Gets the file listing of a store path.
+///
+/// Depending on `listing.generation`, the listing may have been stored
+/// when the NAR was uploaded. Otherwise it is built from the NAR and
+/// stored for subsequent requests, unless the server is read-only.
+async fn get_listing(state: &State, store_path_hash: StorePathHash) -> ServerResult<Response> {
+    tracing::debug!("Received request for {}.ls", store_path_hash.as_str());
+
+    if state.config.listing.generation == ListingGeneration::Disabled {
+        return Err(ErrorKind::NotFound.into());
+    }
+
+    let backend = state.storage();
+    let nar_name = store_path_hash.to_string();
+
+    let data = match backend.download_listing(nar_name.clone()).await? {
+        Some(data) => data,
+        None => {
+            let nar = download_uploaded_nar(&**backend, &store_path_hash).await?;
+
+            let num_prefetch = num_prefetch(&state.config.download, &nar.chunks);
+            let chunks: VecDeque<_> = nar.chunks.into();
+            let mut merged = merge_chunks(
+                chunks,
+                stream_chunk_decompressed,
+                backend.clone(),
+                num_prefetch,
+                state.reassembly_budget.clone(),
+            );
+
+            let mut builder = ListingBuilder::new();
+            while let Some(data) = merged.try_next().await.map_err(ServerError::storage_error)? {
+                builder.update(&data).map_err(ErrorKind::StorageError)?;
+            }
+            let listing = builder.finish().map_err(ErrorKind::StorageError)?;
+
+            // Another server may own the storage in read-only mode
+            if !state.config.read_only {
+                if let Err(e) = nar_listing::store(&**backend, nar_name, &listing).await {
+                    tracing::warn!("Failed to store file listing: {}", e);
+                }
+            }
+
+            serde_json::to_vec(&listing)
+                .map_err(ServerError::storage_error)?
+                .into()
+        }
+    };
+
+    Ok(([(header::CONTENT_TYPE, "application/json")], data).into_response())
+}
+
 /// Gets a NAR.
 ///
 /// - GET `:cache/nar/{storePathHash}.nar`
+/// - GET `:cache/nar/{storePathHash}.nar.{xz,zst,br}`
 ///
 /// Here we use the store path hash not the NAR hash or file hash
 /// for better logging. In reality, the files are deduplicated by
 /// content-addressing.
+///
+/// Chunks are compressed individually, which unmodified Nix clients
+/// cannot consume. When a compressed NAR is requested through the file
+/// extension, or through `Accept-Encoding` with
+/// `download.recompress-for-accept-encoding`, every chunk is
+/// decompressed and the whole NAR is recompressed on the fly into a
+/// single coherent stream. This is CPU-intensive and the result is
+/// not cached. NARs stored in a single chunk are served as stored
+/// when requested with the extension of their compression, which is
+/// the URL in their narinfo.
+///
+/// NARs served as stored support single `Range` requests, so
+/// interrupted downloads can be resumed.
+///
+/// If the storage backend supports it, NARs served as stored from a
+/// single chunk are served by redirecting to the storage directly.
 #[instrument(skip_all, fields(cache_name, path))]
 async fn get_nar(
     Extension(state): Extension<Arc<State>>,
     Path(path): Path<String>,
+    headers: HeaderMap,
 ) -> ServerResult<Response> {
-    let components: Vec<&str> = path.splitn(2, '.').collect();
-    if components.len() != 2 {
-        return Err(ErrorKind::NotFound.into());
-    }
-    if components[1] != "nar" {
-        return Err(ErrorKind::NotFound.into());
-    }
-    let store_path_hash = StorePathHash::new(components[0].to_string())
-        .map_err(|e| ErrorKind::RequestError(anyhow!(
-            "Could not parse store path hash : {}", e
-        )))?;
+    let (store_path_hash, recompression) = parse_nar_path(&path, &headers, &state)?;
 
-    tracing::debug!("Received request for {}.nar", store_path_hash.as_str());
+    tracing::debug!("Received request for {}", path);
 
     // Get NAR
     let backend = state.storage();
 
-    let nar = backend
-        .download_nar(store_path_hash.to_string())
-        .await?;
-
-    let nar: UploadedNar = match nar {
-        Download::AsyncRead(mut stream) => {
-            let mut nar = Vec::new();
-            stream.read_to_end(&mut nar).await
-                .map_err(ServerError::storage_error)?;
-            serde_json::from_slice(&nar)
-                .map_err(ServerError::storage_error)?
-        },
-        Download::Stream(stream) => {
-            use futures::AsyncReadExt;
+    let nar = download_uploaded_nar(&**backend, &store_path_hash).await?;
 
-            let mut nar = Vec::new();
-            stream.into_async_read().read_to_end(&mut nar).await
-                .map_err(ServerError::storage_error)?;
-            serde_json::from_slice(&nar)
-                .map_err(ServerError::storage_error)?
-        },
+    if state.index.is_some() {
+        state.access_times.touch(store_path_hash.to_string());
+    }
+
+    let as_stored = is_served_as_stored(&nar, recompression);
+
+    if let (true, [chunk]) = (as_stored, nar.chunks.as_slice()) {
+        // Ranges are handled by the storage as the client resends the
+        // request to the new location
+        if let Some(url) = backend.presign_chunk(chunk.file_hash.to_typed_base32()).await? {
+            return Ok((
+                StatusCode::TEMPORARY_REDIRECT,
+                [(header::LOCATION, url), (header::CACHE_CONTROL, "no-store".to_string())],
+            ).into_response());
+        }
+    }
+
+    if let (false, Some(recompression)) = (as_stored, recompression) {
+        return recompress_nar(
+            nar,
+            backend,
+            &state.config.compression,
+            &state.config.download,
+            state.reassembly_budget.clone(),
+            recompression,
+        ).await;
+    }
+
+    // Ranges of decompressed chunks don't map to ranges of the stored chunks
+    let file_size: u64 = nar.chunks.iter().map(|chunk| chunk.file_size as u64).sum();
+    let range = if as_stored {
+        requested_range(&headers, file_size)
+    } else {
+        RangeRequest::Full
     };
+    match range {
+        RangeRequest::Full => {}
+        RangeRequest::Partial(range) => {
+            return get_nar_range(nar, backend, &state, range, file_size).await;
+        }
+        RangeRequest::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", file_size))],
+            ).into_response());
+        }
+    }
 
     // Stream merged chunks
-    if nar.chunks.len() == 1 {
+    let mut response = if as_stored && nar.chunks.len() == 1 {
         // single chunk
         let chunk = &nar.chunks[0];
-        match backend.download_chunk(chunk.file_hash.to_typed_base32()).await? {
+        let chunk = backend
+            .download_chunk(chunk.file_hash.to_typed_base32())
+            .await?
+            .ok_or(ErrorKind::NotFound)?;
+
+        match chunk {
             Download::AsyncRead(stream) => {
                 let stream = ReaderStream::new(stream);
-                let body = StreamBody::new(stream);
-                Ok(body.into_response())
-            },
-            Download::Stream(stream) => {
-                let body = StreamBody::new(stream);
-                Ok(body.into_response())
+                StreamBody::new(stream).into_response()
             },
+            Download::Stream(stream) => StreamBody::new(stream).into_response(),
         }
     } else {
         // reassemble NAR
+        let num_prefetch = num_prefetch(&state.config.download, &nar.chunks);
+        let chunks: VecDeque<_> = nar.chunks.into();
+
+        let merged = merge_chunks(chunks, stream_chunk_decompressed, backend, num_prefetch, state.reassembly_budget.clone());
+        let merged = start_reassembly(maybe_prefetch(merged, &state.config.download)).await?;
+        StreamBody::new(merged).into_response()
+    };
+
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(mime::NAR));
+    if as_stored {
+        response.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+    Ok(response)
+}
 
-        fn io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> IoError {
-            IoError::new(IoErrorKind::Other, e)
-        }
-
-        let streamer = |chunk: UploadedChunk, storage: Arc<Box<dyn StorageBackend + 'static>>| async move {
-            match storage
-                .download_chunk(chunk.file_hash.to_typed_base32())
-                .await
-                .map_err(io_error)?
-            {
-                Download::AsyncRead(stream) => {
-                    let stream: BoxStream<_> = Box::pin(ReaderStream::new(stream));
-                    Ok(stream)
-                },
-                Download::Stream(stream) => Ok(stream),
+/// Gets the headers of a NAR without its contents.
+///
+/// - HEAD `:cache/nar/{storePathHash}.nar`
+/// - HEAD `:cache/nar/{storePathHash}.nar.{xz,zst,br}`
+///
+/// The headers match those of a GET, except that `Content-Length` is
+/// also set unless the NAR would be recompressed on the fly.
+#[instrument(skip_all, fields(path))]
+async fn head_nar(
+    Extension(state): Extension<Arc<State>>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> ServerResult<Response> {
+    let (store_path_hash, recompression) = parse_nar_path(&path, &headers, &state)?;
+    let nar = download_uploaded_nar(&**state.storage(), &store_path_hash).await?;
+    let as_stored = is_served_as_stored(&nar, recompression);
+
+    let mut response = StatusCode::OK.into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime::NAR));
+
+    match recompression {
+        _ if as_stored => {
+            let file_size: u64 = nar.chunks.iter().map(|chunk| chunk.file_size as u64).sum();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(file_size));
+            headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        }
+        None => {
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(nar.nar_size as u64));
+        }
+        Some(Recompression::ContentEncoding(ctype)) => {
+            if let Some(coding) = content_coding(ctype) {
+                headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(coding));
             }
+        }
+        Some(Recompression::Extension(_)) => {}
+    }
+
+    Ok(response)
+}
+
+/// Returns the URL of a NAR relative to the cache, as in its narinfo.
+///
+/// NARs are served by store path hash in every `storage.key-by` mode,
+/// since each store path has its own NAR metadata even if it shares
+/// chunks with others. This is the inverse of `parse_nar_path`.
+pub(crate) fn nar_url(store_path_hash: &StorePathHash, compression: CompressionType) -> String {
+    match compression.nar_extension() {
+        Some(ext) => format!("nar/{}.nar.{}", store_path_hash.as_str(), ext),
+        None => format!("nar/{}.nar", store_path_hash.as_str()),
+    }
+}
+
+/// Parses the path of a NAR and how it's requested to be compressed.
+fn parse_nar_path(
+    path: &str,
+    headers: &HeaderMap,
+    state: &State,
+) -> ServerResult<(StorePathHash, Option<Recompression>)> {
+    let components: Vec<&str> = path.splitn(2, '.').collect();
+    if components.len() != 2 {
+        return Err(ErrorKind::NotFound.into());
+    }
+    let recompression = match components[1] {
+        // Nix advertises whatever its libcurl supports, so this would
+        // otherwise recompress plain NARs for every Nix client
+        "nar" if state.config.download.recompress_for_accept_encoding => {
+            accept_encoding(headers).map(Recompression::ContentEncoding)
+        }
+        "nar" => None,
+        ext => match ext.strip_prefix("nar.").and_then(CompressionType::from_nar_extension) {
+            Some(ctype) => Some(Recompression::Extension(ctype)),
+            None => return Err(ErrorKind::NotFound.into()),
+        },
+    };
+    let policy = state.config.store_path_hash_policy();
+    let store_path_hash = StorePathHash::new_with_policy(components[0].to_string(), policy)
+        .map_err(|e| ErrorKind::RequestError(anyhow!(
+            "Could not parse store path hash : {}", e
+        )))?;
+
+    Ok((store_path_hash, recompression))
+}
+
+/// Returns whether the stored chunks are exactly what was requested.
+fn is_served_as_stored(nar: &UploadedNar, recompression: Option<Recompression>) -> bool {
+    match recompression {
+        None => nar.chunks.iter().all(|chunk| chunk.compression.r#type == CompressionType::None),
+        Some(Recompression::Extension(ctype)) => nar.chunks.len() == 1 && nar.compression() == ctype,
+        Some(Recompression::ContentEncoding(_)) => false,
+    }
+}
+
+/// Streams a byte range of an uncompressed NAR.
+///
+/// Chunks entirely before or after the range are skipped, and only
+/// the overlapping parts of the remaining chunks are downloaded.
+async fn get_nar_range(
+    nar: UploadedNar,
+    backend: Arc<Box<dyn StorageBackend>>,
+    state: &State,
+    range: Range<u64>,
+    file_size: u64,
+) -> ServerResult<Response> {
+    let num_prefetch = num_prefetch(&state.config.download, &nar.chunks);
+    let mut parts = chunk_ranges(nar.chunks, &range);
+
+    let body = if parts.len() == 1 {
+        let (chunk, chunk_range) = parts.pop_front().unwrap();
+        match backend
+            .download_chunk_range(chunk.file_hash.to_typed_base32(), chunk_range)
+            .await?
+            .ok_or(ErrorKind::NotFound)?
+        {
+            Download::AsyncRead(stream) => StreamBody::new(Box::pin(ReaderStream::new(stream)) as BoxStream<_>),
+            Download::Stream(stream) => StreamBody::new(stream),
+        }
+    } else {
+        let merged = merge_chunks(parts, stream_chunk_range, backend, num_prefetch, state.reassembly_budget.clone());
+        StreamBody::new(start_reassembly(maybe_prefetch(merged, &state.config.download)).await?)
+    };
+
+    let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, file_size);
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        [
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::CONTENT_RANGE, content_range),
+            (header::CONTENT_LENGTH, (range.end - range.start).to_string()),
+        ],
+        body,
+    ).into_response())
+}
+
+/// Opens a byte range of a chunk for streaming.
+async fn stream_chunk_range(
+    (chunk, range): (UploadedChunk, Range<u64>),
+    storage: Arc<Box<dyn StorageBackend>>,
+) -> Result<BoxStream<'static, Result<Bytes, IoError>>, IoError> {
+    match storage
+        .download_chunk_range(chunk.file_hash.to_typed_base32(), range)
+        .await
+        .map_err(io_error)?
+        .ok_or_else(|| missing_chunk(&chunk))?
+    {
+        Download::AsyncRead(stream) => Ok(Box::pin(ReaderStream::new(stream))),
+        Download::Stream(stream) => Ok(stream),
+    }
+}
+
+/// A byte range requested with `Range`.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// No range, or one that we ignore.
+    Full,
+    /// A satisfiable range.
+    Partial(Range<u64>),
+    /// A range that starts past the end of the file.
+    Unsatisfiable,
+}
+
+/// Returns the byte range requested with `Range`.
+///
+/// Only a single range is supported. Requests for multiple ranges
+/// or with a malformed header are served in full.
+fn requested_range(headers: &HeaderMap, size: u64) -> RangeRequest {
+    let spec = match headers.get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return RangeRequest::Full,
+    };
+
+    let (first, last) = match spec.split_once('-') {
+        Some((first, last)) => (first.trim(), last.trim()),
+        None => return RangeRequest::Full,
+    };
+
+    if first.is_empty() {
+        // bytes=-{suffix-length}
+        return match last.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if size == 0 => RangeRequest::Unsatisfiable,
+            Ok(len) => RangeRequest::Partial(size.saturating_sub(len)..size),
+            Err(_) => RangeRequest::Full,
         };
+    }
 
-        let chunks: VecDeque<_> = nar.chunks.into();
+    let start = match first.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return RangeRequest::Full,
+    };
+    let end = if last.is_empty() {
+        size
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(size),
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= size {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(start..end)
+    }
+}
+
+/// Maps a byte range of a NAR to the byte ranges of its chunks.
+///
+/// Chunks that do not overlap with the range are omitted.
+fn chunk_ranges(chunks: Vec<UploadedChunk>, range: &Range<u64>) -> VecDeque<(UploadedChunk, Range<u64>)> {
+    let mut offset = 0;
+    chunks
+        .into_iter()
+        .filter_map(|chunk| {
+            let start = offset;
+            let end = start + chunk.file_size as u64;
+            offset = end;
+
+            if end <= range.start || start >= range.end {
+                return None;
+            }
+
+            let chunk_range = range.start.max(start) - start..range.end.min(end) - start;
+            Some((chunk, chunk_range))
+        })
+        .collect()
+}
+
+/// How a recompressed NAR was requested.
+#[derive(Debug, Clone, Copy)]
+enum Recompression {
+    /// Through the file extension (`.nar.zst`).
+    ///
+    /// The body is the compressed NAR itself.
+    Extension(CompressionType),
+    /// Through `Accept-Encoding`.
+    ///
+    /// The body is served with a matching `Content-Encoding`.
+    ContentEncoding(CompressionType),
+}
+
+/// Decompresses all chunks and recompresses the reassembled NAR.
+async fn recompress_nar(
+    nar: UploadedNar,
+    backend: Arc<Box<dyn StorageBackend>>,
+    compression_config: &CompressionConfig,
+    download_config: &DownloadConfig,
+    reassembly_budget: Option<Arc<Semaphore>>,
+    recompression: Recompression,
+) -> ServerResult<Response> {
+    let ctype = match recompression {
+        Recompression::Extension(ctype) => ctype,
+        Recompression::ContentEncoding(ctype) => ctype,
+    };
+
+    // Use the configured level if it applies to the requested type
+    let level = if compression_config.r#type == ctype {
+        compression_config.level()
+    } else {
+        CompressionConfig { r#type: ctype, level: None, ..Default::default() }.level()
+    };
+
+    let num_prefetch = num_prefetch(download_config, &nar.chunks);
+    let chunks: VecDeque<_> = nar.chunks.into();
+    let merged = merge_chunks(chunks, stream_chunk_decompressed, backend, num_prefetch, reassembly_budget);
+    let merged = start_reassembly(maybe_prefetch(merged, download_config)).await?;
+
+    let compressor = get_compressor_fn(ctype, level);
+    let stream = ReaderStream::new(compressor(StreamReader::new(merged)));
+
+    let mut response = StreamBody::new(stream).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime::NAR));
+    if let Recompression::ContentEncoding(ctype) = recompression {
+        if let Some(coding) = content_coding(ctype) {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(coding));
+        }
+    }
+
+    Ok(response)
+}
+
+/// Returns the number of chunks to download ahead when reassembling a NAR.
+pub(crate) fn num_prefetch(config: &DownloadConfig, chunks: &[UploadedChunk]) -> usize {
+    let total_size: usize = chunks.iter().map(|chunk| chunk.file_size).sum();
+    config.nar_reassembly_prefetch(total_size / chunks.len().max(1))
+}
+
+/// Reads a reassembled NAR ahead of the client, if configured.
+fn maybe_prefetch<S>(stream: S, config: &DownloadConfig) -> BoxStream<'static, Result<Bytes, IoError>>
+where
+    S: Stream<Item = Result<Bytes, IoError>> + Send + 'static,
+{
+    match config.prefetch_buffer_bytes {
+        Some(budget) => prefetch(stream, budget),
+        None => Box::pin(stream),
+    }
+}
 
-        // TODO: Make num_prefetch configurable
-        // The ideal size depends on the average chunk size
-        let merged = merge_chunks(chunks, streamer, backend, 2);
-        let body = StreamBody::new(merged);
-        Ok(body.into_response())
+/// Waits for the first bytes of a reassembled NAR.
+///
+/// A NAR whose first chunk is missing is then not found, instead of
+/// failing after the response has started. Chunks missing further
+/// in can only abort the download.
+async fn start_reassembly(
+    mut stream: BoxStream<'static, Result<Bytes, IoError>>,
+) -> ServerResult<BoxStream<'static, Result<Bytes, IoError>>> {
+    match stream.next().await {
+        Some(Ok(first)) => Ok(Box::pin(stream::once(future::ready(Ok(first))).chain(stream))),
+        Some(Err(e)) if e.kind() == IoErrorKind::NotFound => {
+            tracing::warn!("{}", e);
+            Err(ErrorKind::NotFound.into())
+        }
+        Some(Err(e)) => Err(ServerError::storage_error(e)),
+        None => Ok(stream),
     }
 }
 
+/// Opens a chunk for streaming, decompressing it along the way.
+pub(crate) async fn stream_chunk_decompressed(
+    chunk: UploadedChunk,
+    storage: Arc<Box<dyn StorageBackend>>,
+) -> Result<BoxStream<'static, Result<Bytes, IoError>>, IoError> {
+    let stream: Box<dyn AsyncBufRead + Unpin + Send> = match storage
+        .download_chunk(chunk.file_hash.to_typed_base32())
+        .await
+        .map_err(io_error)?
+        .ok_or_else(|| missing_chunk(&chunk))?
+    {
+        Download::AsyncRead(stream) => Box::new(BufReader::new(stream)),
+        Download::Stream(stream) => Box::new(StreamReader::new(stream)),
+    };
+
+    let decompressor = get_decompressor_fn(chunk.compression.r#type);
+    Ok(Box::pin(ReaderStream::new(decompressor(stream))))
+}
+
+/// Downloads and parses the NAR metadata of a store path.
+///
+/// Manifests larger than `STREAMING_MANIFEST_THRESHOLD` are parsed
+/// on the blocking thread pool as they are downloaded, so that
+/// pathological manifests are never buffered in full.
+pub(crate) async fn download_uploaded_nar(
+    backend: &dyn StorageBackend,
+    store_path_hash: &StorePathHash,
+) -> ServerResult<UploadedNar> {
+    let mut stream = backend
+        .download_nar(store_path_hash.to_string())
+        .await?
+        .ok_or(ErrorKind::NotFound)?
+        .into_async_read();
+
+    let mut prefix = Vec::new();
+    (&mut stream).take(STREAMING_MANIFEST_THRESHOLD as u64 + 1)
+        .read_to_end(&mut prefix)
+        .await
+        .map_err(ServerError::storage_error)?;
+
+    if prefix.len() <= STREAMING_MANIFEST_THRESHOLD {
+        return serde_json::from_slice(&prefix)
+            .map_err(ServerError::storage_error);
+    }
+
+    let reader = SyncIoBridge::new(Cursor::new(prefix).chain(stream));
+    spawn_blocking(move || serde_json::from_reader(std::io::BufReader::new(reader)))
+        .await
+        .map_err(ServerError::storage_error)?
+        .map_err(ServerError::storage_error)
+}
+
+/// Returns the preferred compression accepted by the client.
+///
+/// Only codings that are also Nix compression types are considered.
+fn accept_encoding(headers: &HeaderMap) -> Option<CompressionType> {
+    let accepted: Vec<&str> = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next()?;
+            let rejected = params.any(|param| match param.split_once('=') {
+                // Unparsable weights are taken as a rejection
+                Some((key, q)) if key.trim().eq_ignore_ascii_case("q") => {
+                    q.trim().parse::<f32>().map_or(true, |q| q <= 0.0)
+                }
+                _ => false,
+            });
+            if rejected { None } else { Some(name) }
+        })
+        .collect();
+
+    [CompressionType::Zstd, CompressionType::Brotli]
+        .into_iter()
+        .find(|ctype| {
+            let coding = content_coding(*ctype).unwrap();
+            accepted.iter().any(|name| name.eq_ignore_ascii_case(coding))
+        })
+}
+
+fn io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> IoError {
+    IoError::new(IoErrorKind::Other, e)
+}
+
+fn missing_chunk(chunk: &UploadedChunk) -> IoError {
+    IoError::new(IoErrorKind::NotFound, format!(
+        "Chunk {} does not exist", chunk.file_hash.to_typed_base32()
+    ))
+}
+
 pub fn router() -> Router {
     Router::new()
         .route("/nix-cache-info", get(get_nix_cache_info))
         .route("/:path", head(get_store_path_info))
         .route("/:path", get(get_store_path_info))
+        .route("/nar/:path", head(head_nar))
         .route("/nar/:path", get(get_nar))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use libnixstore::Hash;
+    use tokio_test::block_on;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::narinfo;
+    use crate::storage::local::{LocalBackend, LocalStorageConfig};
+    use crate::testing::{uploaded_chunk, TestDir, TestState, STORE_PATH_HASH};
+
+    fn range_headers(range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let accepted = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accept_encoding(&headers)
+        };
+
+        assert_eq!(Some(CompressionType::Zstd), accepted("deflate, gzip, br, zstd"));
+        assert_eq!(Some(CompressionType::Brotli), accepted("br"));
+        assert_eq!(Some(CompressionType::Brotli), accepted("zstd;q=0, BR"));
+        assert_eq!(Some(CompressionType::Zstd), accepted("zstd; q=0.001"));
+        assert_eq!(None, accepted("zstd; q = 0.000, br;Q=0"));
+        assert_eq!(None, accepted("zstd;q=invalid"));
+        assert_eq!(None, accepted("gzip, deflate"));
+    }
+
+    #[test]
+    fn test_accept_encoding_opt_in() {
+        block_on(async {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("zstd"));
+            let path = "p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar";
+
+            let state = TestState::new("").await;
+            let (_, recompression) = parse_nar_path(path, &headers, &state).unwrap();
+            assert!(recompression.is_none());
+
+            let state = TestState::new("download = { recompress-for-accept-encoding = true }").await;
+            let (_, recompression) = parse_nar_path(path, &headers, &state).unwrap();
+            assert!(matches!(recompression, Some(Recompression::ContentEncoding(CompressionType::Zstd))));
+
+            // Extensions are always honored
+            let (_, recompression) = parse_nar_path("p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar.xz", &HeaderMap::new(), &state).unwrap();
+            assert!(matches!(recompression, Some(Recompression::Extension(CompressionType::Xz))));
+        });
+    }
+
+    #[test]
+    fn test_requested_range() {
+        let cases = [
+            ("bytes=0-9", RangeRequest::Partial(0..10)),
+            ("bytes=10-", RangeRequest::Partial(10..100)),
+            ("bytes=90-200", RangeRequest::Partial(90..100)),
+            ("bytes=-10", RangeRequest::Partial(90..100)),
+            ("bytes=-200", RangeRequest::Partial(0..100)),
+            ("bytes=100-", RangeRequest::Unsatisfiable),
+            ("bytes=-0", RangeRequest::Unsatisfiable),
+            ("bytes=0-1,5-6", RangeRequest::Full),
+            ("bytes=5-1", RangeRequest::Full),
+            ("items=0-9", RangeRequest::Full),
+        ];
+
+        for (range, expected) in cases {
+            assert_eq!(expected, requested_range(&range_headers(range), 100), "{}", range);
+        }
+
+        assert_eq!(RangeRequest::Full, requested_range(&HeaderMap::new(), 100));
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        let chunks: Vec<_> = [10, 20, 30]
+            .into_iter()
+            .enumerate()
+            .map(|(i, file_size)| UploadedChunk {
+                file_size,
+                ..uploaded_chunk(&[i as u8])
+            })
+            .collect();
+
+        let ranges = |range: Range<u64>| -> Vec<(u64, Range<u64>)> {
+            chunk_ranges(chunks.clone(), &range)
+                .into_iter()
+                .map(|(chunk, range)| (chunk.file_size as u64, range))
+                .collect()
+        };
+
+        assert_eq!(vec![(10, 0..10), (20, 0..20), (30, 0..30)], ranges(0..60));
+        assert_eq!(vec![(20, 5..20), (30, 0..1)], ranges(15..31));
+        assert_eq!(vec![(20, 0..20)], ranges(10..30));
+        assert_eq!(vec![(30, 29..30)], ranges(59..60));
+    }
+
+    #[test]
+    fn test_download_uploaded_nar() {
+        let dir = TestDir::new("manifest");
+        let config: LocalStorageConfig = toml::from_str(&format!("path = {:?}", dir.path())).unwrap();
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+
+            for num_chunks in [1, 20_000] {
+                let chunks: Vec<_> = (0..num_chunks)
+                    .map(|i: u32| uploaded_chunk(&i.to_le_bytes()))
+                    .collect();
+                let nar = UploadedNar::builder().chunks(chunks).build();
+                let data = serde_json::to_vec(&nar).unwrap();
+                assert_eq!(num_chunks > 1, data.len() > STREAMING_MANIFEST_THRESHOLD);
+
+                let store_path_hash = StorePathHash::new(STORE_PATH_HASH.to_string()).unwrap();
+                backend.upload_nar(store_path_hash.to_string(), &mut Cursor::new(data)).await.unwrap();
+
+                let downloaded = download_uploaded_nar(&backend, &store_path_hash).await.unwrap();
+                assert_eq!(num_chunks as usize, downloaded.chunks.len());
+                assert_eq!(nar.chunks[0].file_hash, downloaded.chunks[0].file_hash);
+            }
+        });
+    }
+
+    #[test]
+    fn test_get_nar_missing_chunks() {
+        block_on(async {
+            let state = TestState::new("").await;
+            let backend = state.storage();
+
+            let chunk = uploaded_chunk(b"chunk");
+            UploadedNar::builder().nar(b"chunk").chunk(chunk.clone()).store(&**backend).await;
+
+            // The narinfo is served without checking the chunks
+            let store_path_hash = StorePathHash::new(STORE_PATH_HASH.to_string()).unwrap();
+            get_narinfo(&state, store_path_hash).await.unwrap();
+
+            let router = crate::router(state.clone());
+            let request = Request::get("/nar/p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar").body(Body::empty()).unwrap();
+            assert_eq!(StatusCode::NOT_FOUND, router.clone().oneshot(request).await.unwrap().status());
+
+            let mut compressed = Vec::new();
+            let compressor = get_compressor_fn(CompressionType::Zstd, chunk.compression.level());
+            compressor(BufReader::new(Cursor::new(b"chunk".to_vec()))).read_to_end(&mut compressed).await.unwrap();
+            backend.upload_chunk(chunk.file_hash.to_typed_base32(), &mut Cursor::new(compressed)).await.unwrap();
+            let request = Request::get("/nar/p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar").body(Body::empty()).unwrap();
+            assert_eq!(StatusCode::OK, router.oneshot(request).await.unwrap().status());
+        });
+    }
+
+    #[test]
+    fn test_get_narinfo_stale() {
+        block_on(async {
+            let state = TestState::new("narinfo-cache = { size = 10, ttl = 0 }").await;
+            let backend = state.storage();
+            let store_path_hash = StorePathHash::new(STORE_PATH_HASH.to_string()).unwrap();
+
+            let nar = UploadedNar::builder().store(&**backend).await;
+            get_narinfo(&state, store_path_hash.clone()).await.unwrap();
+
+            // Storage fails after the narinfo expired
+            backend.upload_nar(STORE_PATH_HASH.to_string(), &mut Cursor::new(b"garbage")).await.unwrap();
+            let narinfo = get_narinfo(&state, store_path_hash.clone()).await.unwrap();
+            assert_eq!(nar.nar_hash, narinfo.nar_hash);
+
+            // Deleted by another server
+            backend.delete_nar(STORE_PATH_HASH.to_string()).await.unwrap();
+            let err = get_narinfo(&state, store_path_hash.clone()).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::NotFound));
+            assert!(state.narinfo_cache.as_ref().unwrap().get(STORE_PATH_HASH).is_none());
+        });
+    }
+
+    #[test]
+    fn test_get_nar_compressed() {
+        block_on(async {
+            let state = TestState::new("").await;
+            let backend = state.storage();
+
+            let nar_data = b"nix-archive-1 ".repeat(1000);
+            let compression = CompressionConfig { r#type: CompressionType::Zstd, ..Default::default() };
+
+            let mut chunks = Vec::new();
+            for data in nar_data.chunks(4000) {
+                let compressor = get_compressor_fn(CompressionType::Zstd, compression.level());
+                let mut compressed = Vec::new();
+                compressor(BufReader::new(Cursor::new(data.to_vec())))
+                    .read_to_end(&mut compressed)
+                    .await
+                    .unwrap();
+                assert_ne!(data, compressed.as_slice());
+
+                let chunk = UploadedChunk {
+                    compression: compression.clone(),
+                    ..uploaded_chunk(&compressed)
+                };
+                backend.upload_chunk(chunk.file_hash.to_typed_base32(), &mut Cursor::new(compressed)).await.unwrap();
+                chunks.push(chunk);
+            }
+            assert_eq!(4, chunks.len());
+
+            let nar = UploadedNar::builder().nar(&nar_data).chunks(chunks).store(&**backend).await;
+
+            let response = get_nar(
+                Extension(state.clone()),
+                Path("p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar".to_string()),
+                range_headers("bytes=0-9"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            assert!(response.headers().get(header::ACCEPT_RANGES).is_none());
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(nar_data, body);
+
+            let response = head_nar(
+                Extension(state.clone()),
+                Path("p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar".to_string()),
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap();
+            let content_length = nar_data.len().to_string();
+            assert_eq!(Some(content_length.as_str()), response.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()));
+            assert_eq!(Some(mime::NAR), response.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()));
+
+            // Recompressed on the fly
+            let response = head_nar(
+                Extension(state.clone()),
+                Path("p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar.zst".to_string()),
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap();
+            assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+            // NARs in several chunks are advertised uncompressed so that
+            // Nix downloads never recompress them
+            let store_path_hash = StorePathHash::new(STORE_PATH_HASH.to_string()).unwrap();
+            let narinfo = get_narinfo(&state, store_path_hash).await.unwrap();
+            assert_eq!("nar/p4pclmv1gyja5kzc26npqpia1qqxrf0l.nar", narinfo.url);
+            assert_eq!(narinfo::Compression::None, narinfo.compression);
+            assert_eq!(Some(nar.nar_hash.clone()), narinfo.file_hash);
+            assert_eq!(Some(nar_data.len()), narinfo.file_size);
+
+            // The advertised URL is served by the server's own router
+            let request = Request::head(format!("/{}", narinfo.url)).body(Body::empty()).unwrap();
+            let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(Some(content_length.as_str()), response.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()));
+
+            let request = Request::get(format!("/{}", narinfo.url)).body(Body::empty()).unwrap();
+            let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(nar_data, body);
+        });
+    }
+}
@@ -2,29 +2,111 @@ pub mod binary_cache;
 pub mod v1;
 
 use std::path::PathBuf;
-use axum::Router;
+use std::sync::Arc;
+use axum::{
+    extract::Extension,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
 use serde::{Serialize, Deserialize};
 use serde_with::serde_as;
 
 use libnixstore::{Hash, StorePathHash};
-use crate::config::CompressionConfig;
+use crate::config::{CompressionConfig, CompressionType};
 use crate::narinfo::{self, NarInfo};
 use crate::nix_manifest::SpaceDelimitedList;
+use crate::State;
 
-/// The main application router.
-pub fn router() -> Router {
+/// Routes that read from the cache.
+///
+/// These are the binary cache routes used by Nix, which are public
+/// unless `require-auth-for-reads` is set.
+pub fn read_router() -> Router {
+    binary_cache::router()
+}
+
+/// Routes that push to the cache.
+///
+/// These always require authentication with the `push` scope.
+pub fn push_router() -> Router {
+    Router::new()
+        .nest("/_api", Router::new()
+            .nest("/v1", v1::push_router())
+        )
+}
+
+/// API routes that list the contents of the cache.
+///
+/// These always require authentication with the `pull` scope.
+pub fn pull_router() -> Router {
+    Router::new()
+        .nest("/_api", Router::new()
+            .nest("/v1", v1::pull_router())
+        )
+}
+
+/// Routes that delete from the cache.
+///
+/// These always require authentication with the `delete` scope, and
+/// are refused if no token key is configured.
+pub fn delete_router() -> Router {
+    Router::new()
+        .nest("/_api", Router::new()
+            .nest("/v1", v1::delete_router())
+        )
+}
+
+/// Routes that write to or administer the cache.
+///
+/// These always require authentication.
+pub fn write_router() -> Router {
     Router::new()
-        .merge(binary_cache::router())
         .nest("/_api", Router::new()
             .nest("/v1", v1::router())
         )
 }
 
-#[derive(Serialize, Deserialize)]
+/// Routes that are served without authentication.
+pub fn public_router() -> Router {
+    Router::new()
+        .route("/robots.txt", get(robots_txt))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+/// Tells crawlers what they may index.
+async fn robots_txt(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain")],
+        state.config.robots.robots_txt.clone(),
+    )
+}
+
+/// Reports that the server is running.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Reports whether the server can serve requests.
+///
+/// Fails with 503 Service Unavailable if the storage is unreachable.
+async fn readyz(Extension(state): Extension<Arc<State>>) -> Response {
+    match state.storage().health_check().await {
+        Ok(()) => "ok".into_response(),
+        Err(e) => {
+            tracing::warn!("Readiness check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "storage unavailable").into_response()
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UploadedChunk {
-    file_hash: Hash,
-    file_size: usize,
-    compression: CompressionConfig,
+    pub(crate) file_hash: Hash,
+    pub(crate) file_size: usize,
+    pub(crate) compression: CompressionConfig,
 }
 #[serde_as]
 #[derive(Serialize, Deserialize)]
@@ -69,16 +151,53 @@ pub struct UploadedNar {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ca: Option<String>,
 
-    chunks: Vec<UploadedChunk>,
+    /// When the store path was uploaded, in seconds since the Unix epoch.
+    ///
+    /// Not recorded for store paths uploaded by older versions.
+    #[serde(rename = "Created")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+
+    pub(crate) chunks: Vec<UploadedChunk>,
 }
 impl UploadedNar {
+    /// Returns the storage names of the chunks.
+    pub(crate) fn chunk_names(&self) -> impl Iterator<Item = String> + '_ {
+        self.chunks.iter().map(|chunk| chunk.file_hash.to_typed_base32())
+    }
+
+    /// Returns the compression of the file served at the narinfo URL.
+    ///
+    /// Only NARs in a single chunk are served as stored. NARs in several
+    /// chunks are served uncompressed, which only requires decompressing
+    /// the chunks instead of also recompressing them on every download.
+    pub(crate) fn compression(&self) -> CompressionType {
+        match self.chunks.as_slice() {
+            [chunk] => chunk.compression.r#type,
+            _ => CompressionType::None,
+        }
+    }
+
+    /// Returns the hash and size of the file served at the narinfo URL.
+    fn file_hash_and_size(&self) -> (Hash, usize) {
+        match self.chunks.as_slice() {
+            [chunk] => (chunk.file_hash.clone(), chunk.file_size),
+            _ => (self.nar_hash.clone(), self.nar_size),
+        }
+    }
+
     fn into_narinfo(self, store_path_hash: &StorePathHash) -> NarInfo {
+        let compression = self.compression();
+        let url = binary_cache::nar_url(store_path_hash, compression);
+        let (file_hash, file_size) = self.file_hash_and_size();
+
         NarInfo {
             store_path: PathBuf::from(self.store_path),
-            url: format!("nar/{}.nar", store_path_hash.as_str()),
-            compression: narinfo::Compression::None,
-            file_hash: None,
-            file_size: None,
+            url,
+            compression: compression.into(),
+            file_hash: Some(file_hash),
+            file_size: Some(file_size),
             nar_hash: self.nar_hash,
             nar_size: self.nar_size,
             system: self.system,
@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use async_compression::Level as CompressionLevel;
 use axum::extract::{Extension, Json};
 use tracing::instrument;
 
@@ -12,15 +13,23 @@ pub async fn get(
 ) -> ServerResult<Json<CacheConfig>> {
     let public_key = state.config.keypair.export_public_key();
     let retention_period_config = RetentionPeriodConfig::Global;
+    let compression_level = match state.config.compression.level() {
+        CompressionLevel::Precise(level) => Some(level),
+        _ => None,
+    };
 
     Ok(Json(CacheConfig {
         substituter_endpoint: None,
         api_endpoint: None,
         public_key: Some(public_key),
+        signing_key_name: Some(state.config.keypair.name().to_string()),
         is_public: Some(false),
         store_dir: Some(super::CACHE_STOREDIR.to_string()),
         priority: Some(super::CACHE_PRIORITY),
+        want_mass_query: Some(true),
         upstream_cache_key_names: None,
         retention_period: Some(retention_period_config),
+        compression: Some(state.config.compression.r#type.to_string()),
+        compression_level,
     }))
 }
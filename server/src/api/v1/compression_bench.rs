@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_compression::Level as CompressionLevel;
+use axum::extract::{Extension, Json};
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::task::spawn_blocking;
+use tracing::instrument;
+
+use libnixstore::StorePathHash;
+use common::v1::compression_bench::{CompressionResult, Response};
+use crate::api::binary_cache::download_uploaded_nar;
+use crate::compression::{get_compressor_fn, get_decompressor_fn};
+use crate::config::{CompressionConfig, CompressionType};
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::State;
+
+/// The maximum number of stored chunks to sample.
+const MAX_SAMPLE_CHUNKS: usize = 16;
+
+/// Number of NAR names to list at once when sampling.
+///
+/// The sample is usually full after the first NARs, so we don't
+/// list the whole cache.
+const SAMPLE_LIST_PAGE_SIZE: usize = 100;
+
+const COMPRESSION_TYPES: [CompressionType; 4] = [
+    CompressionType::None,
+    CompressionType::Brotli,
+    CompressionType::Zstd,
+    CompressionType::Xz,
+];
+
+/// Compresses a sample with every compression type.
+///
+/// The sample is the request body, or some stored chunks if the
+/// body is empty. The configured type uses the configured level,
+/// and the others use their default levels.
+#[instrument(skip_all)]
+pub async fn post(
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> ServerResult<Json<Response>> {
+    let sample = if body.is_empty() {
+        sample_stored_chunks(&state).await?
+    } else {
+        vec![body]
+    };
+
+    if sample.is_empty() {
+        return Err(ErrorKind::RequestError(anyhow::anyhow!("No sample given and no chunks are stored")).into());
+    }
+
+    let sample = Arc::new(sample);
+    let sample_size: usize = sample.iter().map(|chunk| chunk.len()).sum();
+
+    let mut results = BTreeMap::new();
+    for ctype in COMPRESSION_TYPES {
+        let config = CompressionConfig {
+            r#type: ctype,
+            level: if ctype == state.config.compression.r#type { state.config.compression.level } else { None },
+            ..Default::default()
+        };
+        let level = config.level();
+
+        // Compression is CPU-bound, so keep it off the executor
+        let sample = sample.clone();
+        let (compressed_size, elapsed) = spawn_blocking(move || {
+            futures::executor::block_on(compress_all(&sample, ctype, level))
+        })
+            .await
+            .unwrap()
+            .map_err(ServerError::request_error)?;
+
+        results.insert(ctype.to_string(), CompressionResult {
+            level: match level {
+                CompressionLevel::Precise(level) => Some(level),
+                _ => None,
+            },
+            compressed_size,
+            ratio: compressed_size as f64 / sample_size as f64,
+            ms: elapsed.as_secs_f64() * 1000.0,
+        });
+    }
+
+    Ok(Json(Response {
+        sample_size,
+        sample_chunks: sample.len(),
+        results,
+    }))
+}
+
+/// Returns the uncompressed content of some stored chunks.
+async fn sample_stored_chunks(state: &State) -> ServerResult<Vec<Bytes>> {
+    let backend = state.storage();
+    let mut sample = Vec::new();
+    let mut start_after = None;
+
+    loop {
+        let names = backend.list_nars_page(start_after, SAMPLE_LIST_PAGE_SIZE).await?;
+        start_after = match names.last() {
+            Some(last) => Some(last.clone()),
+            None => return Ok(sample),
+        };
+
+        for name in names {
+            if sample_nar_chunks(state, name, &mut sample).await? {
+                return Ok(sample);
+            }
+        }
+    }
+}
+
+/// Adds the chunks of a stored NAR to the sample.
+///
+/// Returns whether the sample is full.
+async fn sample_nar_chunks(state: &State, name: String, sample: &mut Vec<Bytes>) -> ServerResult<bool> {
+    let backend = state.storage();
+
+    let store_path_hash = match StorePathHash::new(name) {
+        Ok(store_path_hash) => store_path_hash,
+        Err(_) => return Ok(false),
+    };
+    let nar = match download_uploaded_nar(backend.as_ref().as_ref(), &store_path_hash).await {
+        Ok(nar) => nar,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    for chunk in nar.chunks {
+        let download = match backend.download_chunk(chunk.file_hash.to_typed_base32()).await? {
+            Some(download) => download,
+            None => continue,
+        };
+
+        let decompressor = get_decompressor_fn(chunk.compression.r#type);
+        let mut data = Vec::new();
+        decompressor(BufReader::new(download.into_async_read()))
+            .read_to_end(&mut data)
+            .await
+            .map_err(ServerError::storage_error)?;
+        sample.push(Bytes::from(data));
+
+        if sample.len() >= MAX_SAMPLE_CHUNKS {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Compresses each chunk separately like uploads do.
+///
+/// Returns the total compressed size and time.
+async fn compress_all(
+    sample: &[Bytes],
+    ctype: CompressionType,
+    level: CompressionLevel,
+) -> std::io::Result<(usize, Duration)> {
+    let mut compressed_size = 0;
+    let mut elapsed = Duration::ZERO;
+
+    for chunk in sample {
+        let start = Instant::now();
+        let mut compressed = Vec::new();
+        get_compressor_fn(ctype, level)(BufReader::new(Cursor::new(chunk.clone())))
+            .read_to_end(&mut compressed)
+            .await?;
+        elapsed += start.elapsed();
+        compressed_size += compressed.len();
+    }
+
+    Ok((compressed_size, elapsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::api::{UploadedChunk, UploadedNar};
+    use crate::testing::{uploaded_chunk, TestState};
+
+    #[test]
+    fn test_sample_stored_chunks() {
+        block_on(async {
+            let state = TestState::new("").await;
+            let backend = state.storage();
+            assert!(sample_stored_chunks(&state).await.unwrap().is_empty());
+
+            // More NARs than fit in the sample, one chunk each
+            for i in 0..MAX_SAMPLE_CHUNKS + 4 {
+                let data = format!("chunk {}", i);
+                let chunk = UploadedChunk {
+                    compression: CompressionConfig {
+                        r#type: CompressionType::None,
+                        ..CompressionConfig::default()
+                    },
+                    ..uploaded_chunk(data.as_bytes())
+                };
+                backend.upload_chunk(chunk.file_hash.to_typed_base32(), &mut Cursor::new(data.clone())).await.unwrap();
+
+                UploadedNar::builder()
+                    .store_path(format!("/nix/store/{:032}-test", i))
+                    .nar(data.as_bytes())
+                    .chunk(chunk)
+                    .store(&**backend)
+                    .await;
+            }
+
+            let sample = sample_stored_chunks(&state).await.unwrap();
+            assert_eq!(MAX_SAMPLE_CHUNKS, sample.len());
+            assert_eq!(b"chunk 0", sample[0].as_ref());
+        });
+    }
+
+    #[test]
+    fn test_compress_all() {
+        let sample = vec![
+            Bytes::from(b"Hello, world! ".repeat(100)),
+            Bytes::from_static(b"abc"),
+        ];
+
+        let (size, _) = block_on(compress_all(&sample, CompressionType::None, CompressionLevel::Default)).unwrap();
+        assert_eq!(1403, size);
+
+        let (size, _) = block_on(compress_all(&sample, CompressionType::Zstd, CompressionLevel::Default)).unwrap();
+        assert!(size < 1403);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use axum::extract::{Extension, Json, Path};
+use futures::future::join_all;
+use tracing::instrument;
+
+use common::v1::delete_path::Response;
+use libnixstore::StorePathHash;
+use crate::api::binary_cache::download_uploaded_nar;
+use crate::error::{ErrorKind, ServerResult};
+use crate::refs;
+use crate::State;
+
+/// Deletes a store path along with the chunks only it referenced.
+///
+/// - DELETE `/_api/v1/path/{storePathHash}`
+///
+/// The token must be granted the `delete` scope. Without a token key,
+/// every request would be let through, so deletions are refused.
+///
+/// The NAR is deleted first so that it's never visible with missing
+/// chunks. Chunks are only deleted once their references show that
+/// no other NAR uses them. Like garbage collection, this holds the
+/// GC lock exclusively throughout, so that uploads that reused a
+/// chunk or are storing this path have recorded their references
+/// first.
+#[instrument(skip_all)]
+pub async fn delete_path(
+    Extension(state): Extension<Arc<State>>,
+    Path(store_path_hash): Path<String>,
+) -> ServerResult<Json<Response>> {
+    if state.config.token_key.is_none() {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let policy = state.config.store_path_hash_policy();
+    let store_path_hash = StorePathHash::new_with_policy(store_path_hash, policy)
+        .map_err(|e| ErrorKind::RequestError(anyhow!(
+            "Could not parse store path hash : {}", e
+        )))?;
+
+    // Held until the chunks are deleted so that neither uploads that
+    // reused a chunk nor a re-upload of this path record references
+    // that the deletion then removes
+    let _guard = state.gc_lock.write().await;
+
+    let backend = state.storage();
+    let nar_name = store_path_hash.to_string();
+    let nar = download_uploaded_nar(&**backend, &store_path_hash).await?;
+
+    backend.delete_nar(nar_name.clone()).await?;
+    backend.delete_listing(nar_name.clone()).await?;
+    if let Some(cache) = &state.narinfo_cache {
+        cache.remove(&nar_name);
+    }
+    if let Some(index) = &state.index {
+        state.access_times.forget(&nar_name);
+        index.remove_store_path(nar_name.clone()).await?;
+    }
+
+    let orphaned = refs::remove_refs(&state, &nar_name, nar.chunk_names()).await?;
+    let chunks_freed = orphaned.len();
+    join_all(orphaned.into_iter().map(|chunk| backend.delete_chunk(chunk)))
+        .await
+        .into_iter()
+        .collect::<ServerResult<Vec<_>>>()?;
+
+    tracing::info!("Deleted {} and {} chunks", nar_name, chunks_freed);
+
+    Ok(Json(Response {
+        chunks_freed,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::api::UploadedNar;
+    use crate::testing::{uploaded_chunk, TestState, STORE_PATH_HASH, TOKEN_SECRET};
+
+    #[test]
+    fn test_concurrent_upload() {
+        block_on(async {
+            let state = TestState::new(&format!("token-hs256-secret-base64 = \"{}\"", TOKEN_SECRET)).await;
+            let backend = state.storage();
+
+            let chunk = uploaded_chunk(b"shared");
+            let chunk_name = chunk.file_hash.to_typed_base32();
+            backend.upload_chunk(chunk_name.clone(), &mut Cursor::new(b"shared")).await.unwrap();
+
+            let nar_name = STORE_PATH_HASH;
+            refs::add_refs(&state, nar_name, [chunk_name.clone()]).await.unwrap();
+            UploadedNar::builder().chunk(chunk).store(&**backend).await;
+
+            // An upload that found the chunk stored but hasn't recorded its reference yet
+            let upload_guard = state.gc_lock.read().await;
+
+            let delete = tokio::spawn(delete_path(
+                Extension(Arc::clone(&state)),
+                Path(nar_name.to_string()),
+            ));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!delete.is_finished());
+
+            refs::add_refs(&state, "nar-upload", [chunk_name.clone()]).await.unwrap();
+            drop(upload_guard);
+
+            let Json(response) = delete.await.unwrap().unwrap();
+            assert_eq!(0, response.chunks_freed);
+            assert!(backend.chunk_exists(chunk_name).await.unwrap());
+            assert!(!backend.nar_exists(nar_name.to_string()).await.unwrap());
+        });
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use anyhow::anyhow;
+use axum::extract::{Extension, Json};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tracing::instrument;
+
+use common::v1::get_missing_paths::{Request, Response};
+use libnixstore::StorePathHash;
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::State;
+
+/// Number of store paths to look up in the storage backend at once.
+const CONCURRENT_LOOKUPS: usize = 32;
+
+/// Returns the requested store paths that are not in the cache.
+///
+/// This saves clients a narinfo request per path when pushing
+/// large closures.
+#[instrument(skip_all)]
+pub async fn post(
+    Extension(state): Extension<Arc<State>>,
+    Json(request): Json<Request>,
+) -> ServerResult<Json<Response>> {
+    let backend = state.storage();
+
+    // The request only checks the character set of the hashes
+    let policy = state.config.store_path_hash_policy();
+    for store_path_hash in &request.store_path_hashes {
+        StorePathHash::new_with_policy(store_path_hash.to_string(), policy)
+            .map_err(|e| ErrorKind::RequestError(anyhow!(
+                "Could not parse store path hash : {}", e
+            )))?;
+    }
+
+    let missing_paths = stream::iter(request.store_path_hashes)
+        .map(|store_path_hash| {
+            let backend = backend.clone();
+            async move {
+                let exists = backend.nar_exists(store_path_hash.to_string()).await?;
+                Ok::<_, ServerError>((!exists).then_some(store_path_hash))
+            }
+        })
+        .buffered(CONCURRENT_LOOKUPS)
+        .try_filter_map(|missing| async move { Ok(missing) })
+        .try_collect()
+        .await?;
+
+    Ok(Json(Response {
+        missing_paths,
+    }))
+}
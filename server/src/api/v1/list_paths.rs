@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use axum::extract::{Extension, Json, Query};
+use tracing::instrument;
+
+use common::v1::list_paths::{Query as ListPathsQuery, Response};
+use crate::error::ServerResult;
+use crate::State;
+
+/// Maximum number of store path hashes listed at once.
+const MAX_LIMIT: usize = 10000;
+
+/// Lists the store path hashes of the NARs in the cache.
+///
+/// Unlike `/_api/v1/paths`, no NAR is read, so this is cheap enough
+/// to list whole caches. With a `limit`, hashes are listed in pages
+/// like `/_api/v1/paths`. Without one, all hashes are listed at once
+/// as older clients expect.
+#[instrument(skip_all)]
+pub async fn get(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<ListPathsQuery>,
+) -> ServerResult<Json<Response>> {
+    let backend = state.storage();
+
+    let limit = match query.limit {
+        Some(limit) => limit.clamp(1, MAX_LIMIT),
+        None if query.cursor.is_none() => {
+            let mut store_path_hashes = backend.list_nars().await?;
+            store_path_hashes.sort();
+
+            return Ok(Json(Response {
+                store_path_hashes,
+                next_cursor: None,
+            }));
+        }
+        None => MAX_LIMIT,
+    };
+
+    // Listing one more name shows whether another page follows
+    let mut store_path_hashes = backend.list_nars_page(query.cursor, limit + 1).await?;
+    let next_cursor = if store_path_hashes.len() > limit {
+        store_path_hashes.truncate(limit);
+        store_path_hashes.last().cloned()
+    } else {
+        None
+    };
+
+    Ok(Json(Response {
+        store_path_hashes,
+        next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::testing::TestState;
+
+    #[test]
+    fn test_pages() {
+        block_on(async {
+            let state = TestState::new("").await;
+
+            let hashes: Vec<_> = (1..=5).map(|i| format!("0000000000000000000000000000000{}", i)).collect();
+            for name in hashes.iter().rev() {
+                state.storage().upload_nar(name.clone(), &mut Cursor::new(b"{}")).await.unwrap();
+            }
+
+            let list = |cursor: Option<String>, limit| {
+                let query = ListPathsQuery { cursor, limit };
+                get(Extension(Arc::clone(&state)), Query(query))
+            };
+
+            let Json(all) = list(None, None).await.unwrap();
+            assert_eq!(hashes, all.store_path_hashes);
+            assert!(all.next_cursor.is_none());
+
+            let mut listed = Vec::new();
+            let mut page_sizes = Vec::new();
+            let mut cursor = None;
+            loop {
+                let Json(page) = list(cursor, Some(2)).await.unwrap();
+
+                page_sizes.push(page.store_path_hashes.len());
+                listed.extend(page.store_path_hashes);
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+
+            assert_eq!(hashes, listed);
+            assert_eq!(vec![2, 2, 1], page_sizes);
+        });
+    }
+}
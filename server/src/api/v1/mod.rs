@@ -1,14 +1,44 @@
 pub mod upload_path;
+pub mod delete_path;
 pub mod cache_config;
+pub mod list_paths;
+pub mod paths;
+pub mod get_missing_paths;
+pub mod stats;
+pub mod compression_bench;
 
 use axum::Router;
-use axum::routing::{get, put};
+use axum::middleware::from_extractor;
+use axum::routing::{delete, get, post, put};
+
+use crate::access::RejectWrites;
 
 pub const CACHE_PRIORITY: i32 = 80;
 pub const CACHE_STOREDIR: &str = "/nix/store";
 
+/// Routes that push to the cache.
+pub fn push_router() -> Router {
+    Router::new()
+        .route("/upload-path", put(upload_path::upload_path).route_layer(from_extractor::<RejectWrites>()))
+        .route("/admin/compression-bench", post(compression_bench::post))
+}
+
+/// Routes that list the contents of the cache.
+pub fn pull_router() -> Router {
+    Router::new()
+        .route("/list-paths", get(list_paths::get))
+        .route("/paths", get(paths::get))
+        .route("/stats", get(stats::get))
+}
+
+/// Routes that delete from the cache.
+pub fn delete_router() -> Router {
+    Router::new()
+        .route("/path/:store_path_hash", delete(delete_path::delete_path).route_layer(from_extractor::<RejectWrites>()))
+}
+
 pub fn router() -> Router {
     Router::new()
-        .route("/upload-path", put(upload_path::upload_path))
         .route("/cache-config", get(cache_config::get))
+        .route("/get-missing-paths", post(get_missing_paths::post))
 }
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use axum::extract::{Extension, Json, Query};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tracing::instrument;
+
+use libnixstore::StorePathHash;
+use common::v1::paths::{PathInfo, Query as PathsQuery, Response};
+use crate::api::binary_cache::download_uploaded_nar;
+use crate::error::{ErrorKind, ServerResult};
+use crate::State;
+
+/// Number of store paths listed if the client doesn't specify a limit.
+const DEFAULT_LIMIT: usize = 100;
+
+/// Maximum number of store paths listed at once.
+const MAX_LIMIT: usize = 1000;
+
+/// Number of NARs to read from the storage backend at once.
+const CONCURRENT_DOWNLOADS: usize = 32;
+
+/// Lists a page of store paths in the cache with their details.
+///
+/// Store paths are ordered by hash, and the next page starts after
+/// the `cursor` of the previous one. Store paths deleted while the
+/// page is read are skipped.
+#[instrument(skip_all)]
+pub async fn get(
+    Extension(state): Extension<Arc<State>>,
+    Query(query): Query<PathsQuery>,
+) -> ServerResult<Json<Response>> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let backend = state.storage();
+
+    let mut paths = Vec::new();
+    let mut cursor = query.cursor;
+    let next_cursor = loop {
+        // Listing one more name shows whether another page follows
+        let wanted = limit - paths.len();
+        let mut names = backend.list_nars_page(cursor.clone(), wanted + 1).await?;
+        let exhausted = names.len() <= wanted;
+        names.truncate(wanted);
+
+        if let Some(last) = names.last() {
+            cursor = Some(last.clone());
+        }
+        paths.extend(get_path_infos(&state, names).await?);
+
+        if exhausted {
+            break None;
+        }
+        if paths.len() == limit {
+            break cursor;
+        }
+    };
+
+    Ok(Json(Response {
+        paths,
+        next_cursor,
+    }))
+}
+
+/// Returns the details of stored NARs.
+///
+/// NARs that are not named after a store path hash or were deleted
+/// in the meantime are skipped.
+async fn get_path_infos(state: &State, names: Vec<String>) -> ServerResult<Vec<PathInfo>> {
+    let backend = state.storage();
+    let policy = state.config.store_path_hash_policy();
+
+    stream::iter(names)
+        .map(|name| {
+            let backend = backend.clone();
+            async move {
+                let store_path_hash = match StorePathHash::new_with_policy(name, policy) {
+                    Ok(store_path_hash) => store_path_hash,
+                    Err(_) => return Ok(None),
+                };
+
+                match download_uploaded_nar(&**backend, &store_path_hash).await {
+                    Ok(nar) => Ok(Some(PathInfo {
+                        store_path_hash: store_path_hash.to_string(),
+                        nar_size: nar.nar_size,
+                        references: nar.references,
+                        created: nar.created,
+                    })),
+                    Err(e) if matches!(e.kind(), ErrorKind::NotFound) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+        })
+        .buffered(CONCURRENT_DOWNLOADS)
+        .try_filter_map(|info| async move { Ok(info) })
+        .try_collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::api::UploadedNar;
+    use crate::testing::TestState;
+
+    #[test]
+    fn test_pages() {
+        block_on(async {
+            let state = TestState::new("").await;
+
+            let hashes: Vec<_> = (1..=5).map(|i| format!("0000000000000000000000000000000{}", i)).collect();
+            // Not a store path hash, so it's skipped
+            let names = hashes.iter().cloned().chain(["00000000000000000000000000000002x".to_string()]);
+            for name in names {
+                UploadedNar::builder()
+                    .store_path(format!("/nix/store/{}-hello", name))
+                    .store(&**state.storage())
+                    .await;
+            }
+
+            let mut listed = Vec::new();
+            let mut page_sizes = Vec::new();
+            let mut cursor = None;
+            loop {
+                let query = PathsQuery { cursor, limit: Some(2) };
+                let Json(page) = get(Extension(Arc::clone(&state)), Query(query)).await.unwrap();
+
+                page_sizes.push(page.paths.len());
+                listed.extend(page.paths.into_iter().map(|path| path.store_path_hash));
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+
+            assert_eq!(hashes, listed);
+            assert_eq!(vec![2, 2, 1], page_sizes);
+        });
+    }
+}
@@ -0,0 +1,17 @@
+use std::sync::Arc;
+use axum::extract::{Extension, Json};
+use tracing::instrument;
+
+use common::v1::stats::Response;
+use crate::error::ServerResult;
+use crate::State;
+
+/// Returns server statistics.
+#[instrument(skip_all)]
+pub async fn get(
+    Extension(state): Extension<Arc<State>>,
+) -> ServerResult<Json<Response>> {
+    Ok(Json(Response {
+        narinfo_cache: state.narinfo_cache.as_ref().map(|c| c.stats()),
+    }))
+}
@@ -1,40 +1,50 @@
+use std::collections::BTreeMap;
 use std::io;
 use std::io::Cursor;
-use std::marker::Unpin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use anyhow::anyhow;
-use async_compression::tokio::bufread::{BrotliEncoder, XzEncoder, ZstdEncoder};
-use async_compression::Level as CompressionLevel;
-use axum::{extract::{BodyStream, Extension, Json}, http::HeaderMap};
-use bytes::BytesMut;
+use axum::{extract::{BodyStream, Extension, Json}, http::{header::CONTENT_ENCODING, HeaderMap}};
+use bytes::{Bytes, BytesMut};
 use digest::Output as DigestOutput;
 use futures::future::join_all;
 use futures::StreamExt;
 use sha2::{Digest, Sha256};
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 use tokio::sync::{OnceCell, Semaphore};
-use tokio::task::spawn;
-use tokio_util::io::StreamReader;
+use tokio::task::{spawn, spawn_blocking, JoinHandle};
+use tokio_util::io::{ReaderStream, StreamReader};
 use tracing::instrument;
 
-use libnixstore::Hash;
+use libnixstore::{Hash, StorePathHash};
 use common::v1::header;
-use common::v1::upload_path::{Request, Response, ResponseKind};
-use crate::config::CompressionType;
+use common::v1::upload_path::{ChunkResult, Request, Response, ResponseKind};
+use crate::compression::{content_coding, get_compressor_fn, get_decompressor_fn, CompressorFn};
+use crate::config::{CompressionConfig, CompressionType, KeyBy, ListingGeneration};
 use crate::error::{ErrorKind, ServerError, ServerResult};
-use crate::State;
+use crate::{refs, State};
+use crate::access::Subject;
 use crate::chunking::{chunk_stream, read_chunk_async};
 use crate::stream::StreamHasher;
+use crate::nar_listing::{self, ListingStream};
+use crate::idempotency;
 use crate::api::{UploadedChunk, UploadedNar};
+use crate::api::binary_cache::download_uploaded_nar;
 
 /// Number of chunks to upload to the storage backend at once.
 const CONCURRENT_CHUNK_UPLOADS: usize = 10;
 
-/// The maximum size of the upload info JSON.
-const MAX_NAR_INFO_SIZE: usize = 1 * 1024 * 1024; // 1 MiB
+/// Bytes to receive between progress reports of chunked uploads.
+const PROGRESS_REPORT_BYTES: usize = 256 * 1024 * 1024; // 256 MiB
 
-type CompressorFn<C> = Box<dyn FnOnce(C) -> Box<dyn AsyncRead + Unpin + Send> + Send>;
+/// Minimum time between progress reports of chunked uploads.
+const PROGRESS_REPORT_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The result of uploading a chunk: the chunk, whether it was
+/// deduplicated, and its uncompressed size.
+type ChunkUploadResult = ServerResult<(UploadedChunk, bool, usize)>;
 
 /// Applies compression to a stream, computing hashes along the way.
 ///
@@ -51,17 +61,128 @@ struct CompressionStream {
 }
 
 /// Uploads a new object to the cache.
+///
+/// If `Idempotency-Key` is set and an upload of the same store path
+/// and NAR hash with the same key by the same subject succeeded
+/// recently, its response is returned and the body is discarded. Reusing
+/// a key for another upload is rejected with 422 Unprocessable Entity.
+///
+/// Per-chunk details are only included in the response if
+/// `X-Nixcache-Verbose-Response` is set to `1`.
+///
+/// A compressed body is decompressed before it's hashed if its
+/// compression is declared in `X-Nixcache-Upload-Compression`.
+///
+/// If the store path already exists, the body is still received in
+/// full and discarded. Clients must skip existing paths with
+/// `/_api/v1/get-missing-paths` first so that this only happens
+/// when the same path is uploaded concurrently.
 #[instrument(skip_all)]
 #[axum_macros::debug_handler]
 pub async fn upload_path(
     Extension(state): Extension<Arc<State>>,
+    subject: Option<Extension<Subject>>,
     headers: HeaderMap,
     stream: BodyStream,
 ) -> ServerResult<Json<Response>> {
-    let mut stream = StreamReader::new(
+    let subject = subject.map(|Extension(subject)| subject);
+
+    let idempotency_key = match headers.get(header::IDEMPOTENCY_KEY) {
+        Some(key) => {
+            let key = key.to_str()
+                .map_err(|_| ErrorKind::RequestError(anyhow!(
+                    "{} has invalid encoding",
+                    header::IDEMPOTENCY_KEY
+                )))?;
+
+            if key.len() > idempotency::MAX_KEY_LEN {
+                return Err(ErrorKind::RequestError(anyhow!(
+                    "{} is too long",
+                    header::IDEMPOTENCY_KEY
+                ))
+                .into());
+            }
+
+            // Keys are only unique per subject
+            let name = subject.as_ref().map(|subject| subject.name.as_str()).unwrap_or_default();
+            Some(format!("{}:{}", name, key))
+        }
+        None => None,
+    };
+
+    let verbose = headers
+        .get(header::VERBOSE_RESPONSE)
+        .map(|value| value.as_bytes() == b"1")
+        .unwrap_or(false);
+
+    let stream = StreamReader::new(
         stream.map(|r| r.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))),
     );
+    let mut stream = decompress_body(stream, &headers, state.config.upload.require_declared_compression)?;
+    let upload_info = read_upload_info(&state, &headers, &mut stream).await?;
+
+    let mut response = match idempotency_key {
+        Some(key) => {
+            let fingerprint = format!(
+                "{}:{}",
+                upload_info.store_path_hash.as_str(),
+                upload_info.nar_hash.to_typed_base32(),
+            );
+            let nar_size = upload_info.nar_size;
+
+            let mut body = Some(stream);
+            let result = state.upload_idempotency_keys
+                .run(key, fingerprint, || upload_path_limited(&state, subject, upload_info, body.take().unwrap()))
+                .await;
+
+            // Clients fail to read the response if the body is still being
+            // sent, so it's drained when the upload didn't read it
+            if let Some(stream) = body {
+                tokio::io::copy(&mut stream.take(nar_size as u64), &mut tokio::io::sink())
+                    .await
+                    .map_err(ServerError::request_error)?;
+            }
+
+            result?
+        }
+        None => upload_path_limited(&state, subject, upload_info, stream).await?,
+    };
+
+    if !verbose {
+        response.chunks = None;
+    }
+
+    Ok(Json(response))
+}
+
+/// Processes an upload within the subject's concurrent upload limit.
+async fn upload_path_limited(
+    state: &State,
+    subject: Option<Subject>,
+    upload_info: Request,
+    stream: impl AsyncRead + Send + Unpin + 'static,
+) -> ServerResult<Response> {
+    let limit = subject
+        .as_ref()
+        .and_then(|subject| subject.max_concurrent_uploads)
+        .or(state.config.upload.max_concurrent_uploads);
+    let _permit = match limit {
+        Some(limit) => {
+            let name = subject.as_ref().map(|subject| subject.name.as_str()).unwrap_or_default();
+            Some(state.upload_limits.try_acquire(name, limit)?)
+        }
+        None => None,
+    };
+
+    upload_path_new(upload_info, stream, state).await
+}
 
+/// Reads the upload info from the headers or the beginning of the body.
+async fn read_upload_info(
+    state: &State,
+    headers: &HeaderMap,
+    stream: &mut (dyn AsyncRead + Send + Unpin),
+) -> ServerResult<Request> {
     let upload_info: Request = {
         if let Some(preamble_size_bytes) = headers.get(header::NAR_INFO_PREAMBLE_SIZE) {
             // Read from the beginning of the PUT body
@@ -81,7 +202,7 @@ pub async fn upload_path(
                     ))
                 })?;
 
-            if preamble_size > MAX_NAR_INFO_SIZE {
+            if preamble_size > state.config.upload.max_narinfo_size {
                 return Err(ErrorKind::RequestError(anyhow!("Upload info is too large")).into());
             }
 
@@ -100,13 +221,98 @@ pub async fn upload_path(
             serde_json::from_slice(&preamble).map_err(ServerError::request_error)?
         } else if let Some(nar_info_bytes) = headers.get(header::NAR_INFO) {
             // Read from X-Attic-Nar-Info header
-            serde_json::from_slice(nar_info_bytes.as_bytes()).map_err(ServerError::request_error)?
+            parse_nar_info_header(nar_info_bytes.as_bytes(), state.config.upload.max_narinfo_size)?
         } else {
             return Err(ErrorKind::RequestError(anyhow!("{} must be set", header::NAR_INFO)).into());
         }
     };
 
-    upload_path_new(upload_info, stream, &state).await
+    // The upload info only checks the character set of the hash
+    let policy = state.config.store_path_hash_policy();
+    StorePathHash::new_with_policy(upload_info.store_path_hash.to_string(), policy)
+        .map_err(|e| ErrorKind::RequestError(anyhow!(
+            "Could not parse store path hash : {}", e
+        )))?;
+
+    Ok(upload_info)
+}
+
+/// Decompresses the upload body as declared by the client.
+///
+/// The declared compression must agree with `Content-Encoding` if
+/// both are set. Bodies that fail to decompress are rejected.
+fn decompress_body(
+    stream: impl AsyncRead + Send + Unpin + 'static,
+    headers: &HeaderMap,
+    require_declared: bool,
+) -> ServerResult<Box<dyn AsyncRead + Send + Unpin>> {
+    let declared = match headers.get(header::UPLOAD_COMPRESSION) {
+        Some(value) => {
+            let ctype: CompressionType = value
+                .to_str()
+                .map_err(|_| ErrorKind::RequestError(anyhow!(
+                    "{} has invalid encoding",
+                    header::UPLOAD_COMPRESSION
+                )))?
+                .parse()
+                .map_err(ErrorKind::RequestError)?;
+            Some(ctype)
+        }
+        None => None,
+    };
+
+    let content_encoding = headers
+        .get(CONTENT_ENCODING)
+        .map(|value| value.to_str().unwrap_or_default().trim().to_ascii_lowercase())
+        .filter(|coding| coding != "identity");
+
+    let ctype = match (declared, content_encoding) {
+        (Some(ctype), Some(coding)) if content_coding(ctype) != Some(coding.as_str()) => {
+            return Err(ErrorKind::RequestError(anyhow!(
+                "{} \"{}\" does not match Content-Encoding \"{}\"",
+                header::UPLOAD_COMPRESSION,
+                ctype,
+                coding
+            ))
+            .into());
+        }
+        (None, Some(coding)) if require_declared => {
+            return Err(ErrorKind::RequestError(anyhow!(
+                "Content-Encoding \"{}\" requires {} to be set",
+                coding,
+                header::UPLOAD_COMPRESSION
+            ))
+            .into());
+        }
+        (Some(ctype), _) => ctype,
+        (None, _) => return Ok(Box::new(stream)),
+    };
+
+    if ctype == CompressionType::None {
+        return Ok(Box::new(stream));
+    }
+
+    let decompressor = get_decompressor_fn(ctype);
+    let stream = ReaderStream::new(decompressor(BufReader::new(stream)))
+        .map(move |r| r.map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Body is not compressed with {} as declared: {}", ctype, e),
+        )));
+
+    Ok(Box::new(StreamReader::new(stream)))
+}
+
+/// Parses the upload info from the header.
+fn parse_nar_info_header(nar_info_bytes: &[u8], max_size: usize) -> ServerResult<Request> {
+    if nar_info_bytes.len() > max_size {
+        return Err(ErrorKind::RequestError(anyhow!(
+            "{} is too large",
+            header::NAR_INFO
+        ))
+        .into());
+    }
+
+    serde_json::from_slice(nar_info_bytes).map_err(ServerError::request_error)
 }
 
 /// Uploads a path when there is no matching NAR in the global cache.
@@ -114,60 +320,199 @@ async fn upload_path_new(
     upload_info: Request,
     stream: impl AsyncRead + Send + Unpin + 'static,
     state: &State,
-) -> ServerResult<Json<Response>> {
+) -> ServerResult<Response> {
+    // NARs are stored after all their chunks, so an existing NAR is complete
+    let nar_name = upload_info.store_path_hash.to_string();
+    if state.storage().nar_exists(nar_name.clone()).await? {
+        // Clients fail to read the response if the body is still being
+        // sent, so it's drained instead of rejecting the upload early
+        tokio::io::copy(&mut stream.take(upload_info.nar_size as u64), &mut tokio::io::sink())
+            .await
+            .map_err(ServerError::request_error)?;
+
+        return Ok(Response {
+            kind: ResponseKind::Deduplicated,
+            file_size: None,
+            frac_deduplicated: Some(1.0),
+            chunks: None,
+        });
+    }
+
+    let provisional = ProvisionalRefs::new(&nar_name);
+
+    if let Some(shared) = find_shared_nar(&upload_info, &provisional, state).await? {
+        return upload_path_shared(upload_info, stream, shared, provisional, state).await;
+    }
+
     let nar_size_threshold = state.config.chunking.nar_size_threshold;
 
+    let mut compression_config = state.config.compression.clone();
+    if upload_info.nar_size < state.config.compression_min_nar_size {
+        compression_config.r#type = CompressionType::None;
+        compression_config.level = None;
+    }
+
     if nar_size_threshold == 0 || upload_info.nar_size < nar_size_threshold {
-        upload_path_new_unchunked(upload_info, stream, state).await
+        upload_path_new_unchunked(upload_info, stream, compression_config, provisional, state).await
     } else {
-        upload_path_new_chunked(upload_info, stream, state).await
+        upload_path_new_chunked(upload_info, stream, compression_config, provisional, state).await
+    }
+}
+
+/// Finds a NAR stored for another store path with the same contents.
+///
+/// The chunks of the returned NAR are referenced by `provisional`.
+///
+/// Only used with `storage.key-by = "nar-hash"`.
+async fn find_shared_nar(
+    upload_info: &Request,
+    provisional: &ProvisionalRefs,
+    state: &State,
+) -> ServerResult<Option<UploadedNar>> {
+    let index = match &state.index {
+        Some(index) if state.config.key_by == KeyBy::NarHash => index,
+        _ => return Ok(None),
+    };
+
+    let backend = state.storage();
+    let policy = state.config.store_path_hash_policy();
+    for name in index.get_store_paths(upload_info.nar_hash.to_typed_base32()).await? {
+        let store_path_hash = match StorePathHash::new_with_policy(name, policy) {
+            Ok(store_path_hash) => store_path_hash,
+            Err(_) => continue,
+        };
+
+        match download_uploaded_nar(&**backend, &store_path_hash).await {
+            Ok(nar) if nar.nar_hash == upload_info.nar_hash && nar.nar_size == upload_info.nar_size => {
+                match provisional.reference_shared(state, &store_path_hash, &nar).await {
+                    Ok(true) => return Ok(Some(nar)),
+                    // The NAR was deleted in the meantime
+                    Ok(false) => provisional.release(state).await,
+                    Err(e) => {
+                        provisional.release(state).await;
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Stores a path whose NAR is already stored for another store path.
+///
+/// The body is still read to validate the NAR hash, but none of it
+/// is stored. The new path references the chunks of the shared NAR.
+///
+/// The manifest of the new path lists the shared chunks too, rather
+/// than pointing to a manifest keyed by the NAR hash. See
+/// `KeyBy::NarHash` for why.
+async fn upload_path_shared(
+    upload_info: Request,
+    stream: impl AsyncRead + Send + Unpin + 'static,
+    shared: UploadedNar,
+    provisional: ProvisionalRefs,
+    state: &State,
+) -> ServerResult<Response> {
+    let stream = stream.take(upload_info.nar_size as u64);
+    let (mut stream, nar_compute) = StreamHasher::new(stream, Sha256::new());
+    if let Err(e) = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await {
+        provisional.release(state).await;
+        return Err(ServerError::request_error(e));
+    }
+
+    let (nar_hash, nar_size) = nar_compute.get().unwrap();
+    let nar_hash = Hash::Sha256(nar_hash.as_slice().try_into().unwrap());
+    if shared.nar_hash != nar_hash || shared.nar_size != *nar_size {
+        provisional.release(state).await;
+        return Err(ErrorKind::RequestError(anyhow!("Bad NAR hash or size")).into());
+    }
+
+    let nar = UploadedNar {
+        nar_hash,
+        nar_size: *nar_size,
+        chunks: shared.chunks,
+        ca: upload_info.ca,
+        created: Some(unix_time_now()),
+        references: upload_info.references,
+        store_path: PathBuf::from(upload_info.store_path),
+        system: upload_info.system,
+    };
+    let data = serde_json::to_vec(&nar)
+        .map_err(ServerError::storage_error)?;
+
+    let nar_name = upload_info.store_path_hash.to_string();
+    provisional.store_nar(state, &nar_name, &nar, data).await?;
+
+    if let Some(index) = &state.index {
+        index.set_nar_hash(nar_name, nar.nar_hash.to_typed_base32()).await?;
     }
+
+    Ok(Response {
+        kind: ResponseKind::Deduplicated,
+        file_size: None,
+        frac_deduplicated: Some(1.0),
+        chunks: None,
+    })
 }
 
 /// Upload the entire NAR as a single chunk.
 async fn upload_path_new_unchunked(
     upload_info: Request,
     stream: impl AsyncRead + Send + Unpin + 'static,
+    compression_config: CompressionConfig,
+    provisional: ProvisionalRefs,
     state: &State,
-) -> ServerResult<Json<Response>> {
-    let compression_config = &state.config.compression;
-    let compression_type = compression_config.r#type;
-    let compression_level = compression_config.level();
-
+) -> ServerResult<Response> {
     let stream = stream.take(upload_info.nar_size as u64);
     let (stream, nar_compute) = StreamHasher::new(stream, Sha256::new());
+    let (mut stream, listing_compute) = ListingStream::new(
+        stream,
+        state.config.listing.generation == ListingGeneration::Eager,
+    );
 
-    // Compress
-    let compressor = get_compressor_fn(compression_type, compression_level);
-    let mut stream = CompressionStream::new(stream, compressor);
-
-    let buf = BytesMut::with_capacity(state.config.chunking.max_size);
-    let read = read_chunk_async(&mut stream.stream(), buf)
+    // Receive the whole NAR before compressing it, so that it can be
+    // compressed on the compression pool like a chunk
+    let mut data = Vec::with_capacity(upload_info.nar_size.min(state.config.chunking.max_size));
+    stream.read_to_end(&mut data)
         .await
         .map_err(ServerError::request_error)?;
 
-    // Confirm that the chunk hash is correct
+    // Confirm that the NAR hash is correct
     let (nar_hash, nar_size) = nar_compute.get().unwrap();
-    let (file_hash, file_size) = stream.file_hash_and_size().unwrap();
-
     let nar_hash = Hash::Sha256(nar_hash.as_slice().try_into().unwrap());
-    let file_hash = Hash::Sha256(file_hash.as_slice().try_into().unwrap());
 
     if upload_info.nar_hash != nar_hash || upload_info.nar_size != *nar_size {
         return Err(ErrorKind::RequestError(anyhow!("Bad chunk hash or size")).into());
     }
 
+    let (read, file_hash, file_size) = compress_in_memory(state, &compression_config, data.into())
+        .await
+        .map_err(ServerError::request_error)?;
+
     // Upload chunk
     let backend = state.storage();
+    let deduplicated = match provisional.upload_chunk_if_missing(state, &file_hash, read).await {
+        Ok(deduplicated) => deduplicated,
+        Err(e) => {
+            provisional.release(state).await;
+            return Err(e);
+        }
+    };
 
-    backend
-        .upload_chunk(file_hash.to_typed_base32(), &mut Cursor::new(read))
-        .await?;
-
+    let chunk_results = vec![ChunkResult {
+        file_hash: file_hash.clone(),
+        file_size,
+        deduplicated,
+    }];
     let chunks = vec![UploadedChunk {
         file_hash,
-        file_size: *file_size,
-        compression: compression_config.clone(),
+        file_size,
+        compression: compression_config,
     }];
 
     // Upload NAR
@@ -176,6 +521,7 @@ async fn upload_path_new_unchunked(
         nar_size: *nar_size,
         chunks,
         ca: upload_info.ca,
+        created: Some(unix_time_now()),
         references: upload_info.references,
         store_path: PathBuf::from(upload_info.store_path),
         system: upload_info.system,
@@ -183,29 +529,43 @@ async fn upload_path_new_unchunked(
     let data = serde_json::to_vec(&nar)
         .map_err(ServerError::storage_error)?;
 
-    backend
-        .upload_nar(upload_info.store_path_hash.to_string(), &mut Cursor::new(data))
-        .await?;
+    let nar_name = upload_info.store_path_hash.to_string();
+    provisional.store_nar(state, &nar_name, &nar, data).await?;
+
+    if let Some(listing) = listing_compute.get().and_then(Option::as_ref) {
+        if let Err(e) = nar_listing::store(&**backend, nar_name.clone(), listing).await {
+            tracing::warn!("Failed to store file listing: {}", e);
+        }
+    }
 
-    Ok(Json(Response {
+    if let Some(index) = &state.index {
+        index.set_nar_hash(nar_name, nar.nar_hash.to_typed_base32()).await?;
+    }
+
+    Ok(Response {
         kind: ResponseKind::Uploaded,
-        file_size: Some(*file_size),
-    }))
+        file_size: Some(file_size),
+        frac_deduplicated: Some(if deduplicated { 1.0 } else { 0.0 }),
+        chunks: Some(chunk_results),
+    })
 }
 
 /// Uploads chunked NAR.
 async fn upload_path_new_chunked(
     upload_info: Request,
     stream: impl AsyncRead + Send + Unpin + 'static,
+    compression_config: CompressionConfig,
+    provisional: ProvisionalRefs,
     state: &State,
-) -> ServerResult<Json<Response>> {
+) -> ServerResult<Response> {
     let chunking_config = &state.config.chunking;
-    let compression_config = &state.config.compression;
-    let compression_type = compression_config.r#type;
-    let compression_level = compression_config.level();
 
     let stream = stream.take(upload_info.nar_size as u64);
     let (stream, nar_compute) = StreamHasher::new(stream, Sha256::new());
+    let (stream, listing_compute) = ListingStream::new(
+        stream,
+        state.config.listing.generation == ListingGeneration::Eager,
+    );
     let mut chunks = chunk_stream(
         stream,
         chunking_config.min_size,
@@ -213,11 +573,33 @@ async fn upload_path_new_chunked(
         chunking_config.max_size,
     );
 
+    let provisional = Arc::new(provisional);
     let upload_chunk_limit = Arc::new(Semaphore::new(CONCURRENT_CHUNK_UPLOADS));
-    let mut futures = Vec::new();
+    let mut futures: Vec<JoinHandle<ChunkUploadResult>> = Vec::new();
+
+    let chunks_uploaded = Arc::new(AtomicUsize::new(0));
+    let mut progress = UploadProgress::new(upload_info.nar_size);
 
     while let Some(bytes) = chunks.next().await {
-        let data = bytes.map_err(ServerError::request_error)?;
+        let data = match bytes {
+            Ok(data) => data,
+            Err(e) => {
+                abandon_chunk_uploads(state, &provisional, futures).await;
+                return Err(ServerError::request_error(e));
+            }
+        };
+        let chunk_size = data.len();
+
+        if progress.update(data.len()) {
+            tracing::info!(
+                store_path_hash = upload_info.store_path_hash.as_str(),
+                bytes_received = progress.bytes_received,
+                nar_size = progress.nar_size,
+                chunks_received = progress.chunks_received,
+                chunks_uploaded = chunks_uploaded.load(Ordering::Relaxed),
+                "Upload in progress",
+            );
+        }
 
         // Wait for a permit before spawning
         //
@@ -226,34 +608,27 @@ async fn upload_path_new_chunked(
         let permit = upload_chunk_limit.clone().acquire_owned().await.unwrap();
         futures.push({
             let state = state.clone();
-
-            let compressor = get_compressor_fn(compression_type, compression_level);
-            let mut stream = CompressionStream::new(Cursor::new(data), compressor);
-            let buf = BytesMut::with_capacity(state.config.chunking.max_size);
+            let provisional = provisional.clone();
 
             let compression = compression_config.clone();
+            let chunks_uploaded = chunks_uploaded.clone();
             spawn(async move {
-                let read = read_chunk_async(&mut stream.stream(), buf)
+                let (read, file_hash, file_size) = compress_in_memory(&state, &compression, data)
                     .await
                     .map_err(ServerError::request_error)?;
 
-                let (file_hash, file_size) = stream.file_hash_and_size().unwrap();
-                let file_hash = Hash::Sha256(file_hash.as_slice().try_into().unwrap());
-
                 // Upload chunk
-                let backend = state.storage();
-                backend
-                    .upload_chunk(file_hash.to_typed_base32(), &mut Cursor::new(read))
-                    .await?;
+                let deduplicated = provisional.upload_chunk_if_missing(&state, &file_hash, read).await?;
 
                 let chunk = UploadedChunk {
                     file_hash,
-                    file_size: *file_size,
+                    file_size,
                     compression,
                 };
 
+                chunks_uploaded.fetch_add(1, Ordering::Relaxed);
                 drop(permit);
-                Ok(chunk)
+                Ok((chunk, deduplicated, chunk_size))
             })
         });
     }
@@ -263,15 +638,40 @@ async fn upload_path_new_chunked(
     let nar_hash = Hash::Sha256(nar_hash.as_slice().try_into().unwrap());
 
     if nar_hash != upload_info.nar_hash || *nar_size != upload_info.nar_size {
+        abandon_chunk_uploads(state, &provisional, futures).await;
         return Err(ErrorKind::RequestError(anyhow!("Bad NAR Hash or Size")).into());
     }
 
     // Wait for all uploads to complete
-    let chunks: Vec<UploadedChunk> = join_all(futures)
+    let results: Vec<ChunkUploadResult> = join_all(futures)
         .await
         .into_iter()
         .map(|join_result| join_result.unwrap())
-        .collect::<ServerResult<Vec<_>>>()?;
+        .collect();
+    let uploaded = match results.into_iter().collect::<ServerResult<Vec<_>>>() {
+        Ok(uploaded) => uploaded,
+        Err(e) => {
+            provisional.release(state).await;
+            return Err(e);
+        }
+    };
+
+    let mut deduplicated_size = 0;
+    let (chunks, chunk_results): (Vec<UploadedChunk>, Vec<ChunkResult>) = uploaded
+        .into_iter()
+        .map(|(chunk, deduplicated, chunk_size)| {
+            if deduplicated {
+                deduplicated_size += chunk_size;
+            }
+
+            let result = ChunkResult {
+                file_hash: chunk.file_hash.clone(),
+                file_size: chunk.file_size,
+                deduplicated,
+            };
+            (chunk, result)
+        })
+        .unzip();
 
     let file_size = chunks
         .iter()
@@ -283,6 +683,7 @@ async fn upload_path_new_chunked(
         nar_size: *nar_size,
         chunks,
         ca: upload_info.ca,
+        created: Some(unix_time_now()),
         references: upload_info.references,
         store_path: PathBuf::from(upload_info.store_path),
         system: upload_info.system,
@@ -291,28 +692,237 @@ async fn upload_path_new_chunked(
         .map_err(ServerError::storage_error)?;
 
     let backend = state.storage();
-    backend
-        .upload_nar(upload_info.store_path_hash.to_string(), &mut Cursor::new(data))
-        .await?;
+    let nar_name = upload_info.store_path_hash.to_string();
+    provisional.store_nar(state, &nar_name, &nar, data).await?;
+
+    if let Some(listing) = listing_compute.get().and_then(Option::as_ref) {
+        if let Err(e) = nar_listing::store(&**backend, nar_name.clone(), listing).await {
+            tracing::warn!("Failed to store file listing: {}", e);
+        }
+    }
 
-    Ok(Json(Response {
+    if let Some(index) = &state.index {
+        index.set_nar_hash(nar_name, nar.nar_hash.to_typed_base32()).await?;
+    }
+
+    Ok(Response {
         kind: ResponseKind::Uploaded,
         file_size: Some(file_size),
-    }))
+        frac_deduplicated: Some(deduplicated_size as f64 / (*nar_size).max(1) as f64),
+        chunks: Some(chunk_results),
+    })
+}
+
+/// References to chunks held while a NAR is uploaded.
+///
+/// Chunks are referenced as soon as they are stored or reused, so
+/// that nothing deletes them before the NAR references them. The
+/// name is unique to the upload, so concurrent uploads of the same
+/// path don't remove each other's references. Once the NAR is
+/// stored, its own references replace these.
+///
+/// References left behind by a crash only keep chunks from being
+/// deleted. `reindex` removes them.
+struct ProvisionalRefs {
+    name: String,
+    /// The referenced chunks, and whether this upload stored them.
+    chunks: Mutex<BTreeMap<String, bool>>,
 }
 
-/// Returns a compressor function that takes some stream as input.
-fn get_compressor_fn<C: AsyncBufRead + Unpin + Send + 'static>(
-    ctype: CompressionType,
-    level: CompressionLevel,
-) -> CompressorFn<C> {
-    match ctype {
-        CompressionType::None => Box::new(|c| Box::new(c)),
-        CompressionType::Brotli => {
-            Box::new(move |s| Box::new(BrotliEncoder::with_quality(s, level)))
+impl ProvisionalRefs {
+    fn new(nar_name: &str) -> Self {
+        Self {
+            name: format!("{}.upload-{:016x}", nar_name, fastrand::u64(..)),
+            chunks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Uploads a chunk unless it already exists.
+    ///
+    /// Returns whether the chunk was deduplicated.
+    ///
+    /// Checking for the chunk costs a request to the storage backend,
+    /// but saves uploading and storing it again when it exists, which
+    /// is common between versions of the same package. The check also
+    /// tells which chunks this upload stored, so that only those are
+    /// deleted if it fails, and gives `frac_deduplicated`.
+    async fn upload_chunk_if_missing(&self, state: &State, file_hash: &Hash, data: Bytes) -> ServerResult<bool> {
+        let backend = state.storage();
+        let name = file_hash.to_typed_base32();
+
+        {
+            // Deletions hold the GC lock exclusively, so an existing chunk
+            // can't be deleted between the check and the reference
+            let _guard = state.gc_lock.read().await;
+            refs::add_refs(state, &self.name, [name.clone()]).await?;
+            self.chunks.lock().unwrap().entry(name.clone()).or_insert(false);
+
+            if backend.chunk_exists(name.clone()).await? {
+                return Ok(true);
+            }
+        }
+
+        let size = data.len() as u64;
+        backend
+            .upload_chunk_with_size(name.clone(), &mut Cursor::new(data), size)
+            .await?;
+        self.chunks.lock().unwrap().insert(name, true);
+
+        Ok(false)
+    }
+
+    /// References the chunks of a NAR stored for another store path.
+    ///
+    /// Returns `false` if the NAR no longer exists. Its chunks may
+    /// have been deleted along with it.
+    async fn reference_shared(
+        &self,
+        state: &State,
+        store_path_hash: &StorePathHash,
+        nar: &UploadedNar,
+    ) -> ServerResult<bool> {
+        // The NAR is deleted before its chunks, so they are still stored
+        // if the NAR exists once they are referenced
+        let _guard = state.gc_lock.read().await;
+        refs::add_refs(state, &self.name, nar.chunk_names()).await?;
+        self.chunks.lock().unwrap().extend(nar.chunk_names().into_iter().map(|name| (name, false)));
+
+        state.storage().nar_exists(store_path_hash.to_string()).await
+    }
+
+    /// Stores the NAR, replacing the provisional references with its own.
+    ///
+    /// The chunks are released if the NAR can't be stored.
+    async fn store_nar(&self, state: &State, nar_name: &str, nar: &UploadedNar, data: Vec<u8>) -> ServerResult<()> {
+        let result = async {
+            // A deletion of this path removes the references under the same
+            // name, so it must not run between recording and storing them
+            let _guard = state.gc_lock.read().await;
+            refs::add_refs(state, nar_name, nar.chunk_names()).await?;
+            state.storage()
+                .upload_nar(nar_name.to_string(), &mut Cursor::new(data))
+                .await
+        }
+        .await;
+
+        if let Err(e) = result {
+            self.release(state).await;
+            return Err(e);
+        }
+
+        let chunks: Vec<_> = std::mem::take(&mut *self.chunks.lock().unwrap()).into_keys().collect();
+        if let Err(e) = refs::remove_refs(state, &self.name, chunks).await {
+            tracing::warn!("Failed to remove the provisional references of {}: {}", nar_name, e);
         }
-        CompressionType::Zstd => Box::new(move |s| Box::new(ZstdEncoder::with_quality(s, level))),
-        CompressionType::Xz => Box::new(move |s| Box::new(XzEncoder::with_quality(s, level))),
+
+        Ok(())
+    }
+
+    /// Removes the references of a failed upload and queues the
+    /// chunks it stored that are now unreferenced for deletion.
+    ///
+    /// Reused chunks are never deleted since NARs uploaded before
+    /// references were recorded may use them.
+    async fn release(&self, state: &State) {
+        let chunks = std::mem::take(&mut *self.chunks.lock().unwrap());
+        if chunks.is_empty() {
+            return;
+        }
+
+        let orphaned = match refs::remove_refs(state, &self.name, chunks.keys().cloned()).await {
+            Ok(orphaned) => orphaned,
+            Err(e) => {
+                tracing::warn!("Failed to remove the provisional references {}: {}", self.name, e);
+                return;
+            }
+        };
+
+        let stored = orphaned.into_iter().filter(|chunk| chunks[chunk]);
+        state.pending_deletions.push(stored);
+    }
+}
+
+/// Waits for the chunk uploads of a failed upload and releases the chunks.
+///
+/// No NAR will reference them, so they would be left as orphans.
+async fn abandon_chunk_uploads(
+    state: &State,
+    provisional: &ProvisionalRefs,
+    futures: Vec<JoinHandle<ChunkUploadResult>>,
+) {
+    join_all(futures).await;
+    provisional.release(state).await;
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Progress of a chunked upload.
+///
+/// Decides when progress is reported so that large uploads are
+/// observable without flooding the logs.
+struct UploadProgress {
+    nar_size: usize,
+    bytes_received: usize,
+    chunks_received: usize,
+    last_report_bytes: usize,
+    last_report: Instant,
+}
+
+impl UploadProgress {
+    fn new(nar_size: usize) -> Self {
+        Self {
+            nar_size,
+            bytes_received: 0,
+            chunks_received: 0,
+            last_report_bytes: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Records a received chunk, returning whether progress should be reported.
+    fn update(&mut self, len: usize) -> bool {
+        self.bytes_received += len;
+        self.chunks_received += 1;
+
+        if self.bytes_received - self.last_report_bytes < PROGRESS_REPORT_BYTES
+            || self.last_report.elapsed() < PROGRESS_REPORT_MIN_INTERVAL
+        {
+            return false;
+        }
+
+        self.last_report_bytes = self.bytes_received;
+        self.last_report = Instant::now();
+        true
+    }
+}
+
+/// Compresses data in memory, returning it along with the file hash and size.
+///
+/// The data is compressed on the compression pool if configured.
+async fn compress_in_memory(
+    state: &State,
+    compression: &CompressionConfig,
+    data: Bytes,
+) -> io::Result<(Bytes, Hash, usize)> {
+    let compressor = get_compressor_fn(compression.r#type, compression.level());
+    let stream = CompressionStream::new(Cursor::new(data), compressor);
+    let buf = BytesMut::with_capacity(state.config.chunking.max_size);
+
+    match state.compression_pool.clone() {
+        Some(pool) => {
+            // The data is in memory, so compression is purely CPU-bound
+            let _permit = pool.acquire_owned().await.unwrap();
+            spawn_blocking(move || futures::executor::block_on(stream.read_all(buf)))
+                .await
+                .unwrap()
+        }
+        None => stream.read_all(buf).await,
     }
 }
 
@@ -345,4 +955,220 @@ impl CompressionStream {
     fn file_hash_and_size(&self) -> Option<&(DigestOutput<Sha256>, usize)> {
         self.file_compute.get()
     }
+
+    /// Reads the whole compressed object, returning it along with
+    /// the file hash and size.
+    async fn read_all(mut self, buf: BytesMut) -> io::Result<(Bytes, Hash, usize)> {
+        let read = read_chunk_async(&mut self.stream(), buf).await?;
+
+        let (file_hash, file_size) = self.file_hash_and_size().unwrap();
+        let file_hash = Hash::Sha256(file_hash.as_slice().try_into().unwrap());
+
+        Ok((read, file_hash, *file_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderName, HeaderValue, StatusCode};
+    use axum::response::IntoResponse;
+    use tokio_test::block_on;
+
+    use axum::extract::Path;
+
+    use super::*;
+    use crate::api::v1::delete_path::delete_path;
+    use crate::gc;
+    use crate::testing::{request_for, TestState, SMALL_CHUNKS, STORE_PATH_HASH, TOKEN_SECRET};
+
+    #[test]
+    fn test_oversized_nar_info_header() {
+        let mut nar_info = b"{\"padding\":\"".to_vec();
+        nar_info.resize(1025, b'a');
+
+        let err = parse_nar_info_header(&nar_info, 1024).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+        assert_eq!(StatusCode::BAD_REQUEST, err.into_response().status());
+    }
+
+    #[test]
+    fn test_decompress_body() {
+        let data = b"Hello, world! Hello, world!".repeat(100);
+        let compressed = block_on(async {
+            let compressor = get_compressor_fn(CompressionType::Zstd, CompressionConfig::default().level());
+            let mut compressed = Vec::new();
+            compressor(BufReader::new(Cursor::new(data.clone())))
+                .read_to_end(&mut compressed)
+                .await
+                .unwrap();
+            compressed
+        });
+
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_static(value));
+            }
+            headers
+        };
+        let read = |body: Vec<u8>, headers: HeaderMap, require_declared: bool| block_on(async move {
+            let mut stream = decompress_body(Cursor::new(body), &headers, require_declared)?;
+            let mut read = Vec::new();
+            stream.read_to_end(&mut read).await.map_err(ServerError::request_error)?;
+            Ok::<_, ServerError>(read)
+        });
+
+        let declared = headers(&[(header::UPLOAD_COMPRESSION, "zstd"), ("content-encoding", "zstd")]);
+        assert_eq!(data, read(compressed.clone(), declared, true).unwrap());
+
+        // Undeclared compression is hashed as is unless required
+        let undeclared = headers(&[("content-encoding", "zstd")]);
+        assert_eq!(compressed, read(compressed.clone(), undeclared.clone(), false).unwrap());
+        let err = read(compressed.clone(), undeclared, true).unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, err.into_response().status());
+
+        let mismatched = headers(&[(header::UPLOAD_COMPRESSION, "xz"), ("content-encoding", "zstd")]);
+        let err = read(compressed.clone(), mismatched, false).unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+
+        // The body isn't actually compressed
+        let err = read(data, headers(&[(header::UPLOAD_COMPRESSION, "zstd")]), false).unwrap_err();
+        assert!(err.to_string().contains("as declared"));
+        assert_eq!(StatusCode::BAD_REQUEST, err.into_response().status());
+    }
+
+    #[test]
+    fn test_compression_pool() {
+        block_on(async {
+            for (chunking, data) in [
+                ("", crate::chunking::get_data(16 * 1024)),
+                (SMALL_CHUNKS, crate::chunking::get_data(512 * 1024)),
+            ] {
+                let state = TestState::new(&format!("compression-pool = {{ size = 1 }}\n{}", chunking)).await;
+                let request = request_for(&data);
+                upload_path_new(request.clone(), Cursor::new(data), &state).await.unwrap();
+
+                let nar = download_uploaded_nar(&**state.storage(), &request.store_path_hash).await.unwrap();
+                assert_eq!(chunking.is_empty(), nar.chunks.len() == 1);
+            }
+        });
+    }
+
+    #[test]
+    fn test_bad_hash_cleanup() {
+        block_on(async {
+            let state = TestState::new(SMALL_CHUNKS).await;
+
+            let data = crate::chunking::get_data(512 * 1024);
+            let request = Request {
+                nar_hash: Hash::sha256_from_bytes(b"something else"),
+                ..request_for(&data)
+            };
+
+            let err = upload_path_new(request.clone(), Cursor::new(data.clone()), &state).await.unwrap_err();
+            assert_eq!(StatusCode::BAD_REQUEST, err.into_response().status());
+
+            // The stored chunks are deleted in the background
+            assert!(gc::delete_pending(&state).await > 1);
+            assert!(state.storage().list_chunks().await.unwrap().is_empty());
+            assert!(state.storage().list_nars().await.unwrap().is_empty());
+
+            // The client disconnects midway
+            let body = StreamReader::new(futures::stream::iter(vec![
+                Ok(Bytes::from(data[..256 * 1024].to_vec())),
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "disconnected")),
+            ]));
+            let request = Request {
+                nar_hash: Hash::sha256_from_bytes(&data),
+                ..request
+            };
+            upload_path_new(request, body, &state).await.unwrap_err();
+
+            gc::delete_pending(&state).await;
+            assert!(state.storage().list_chunks().await.unwrap().is_empty());
+            assert!(state.storage().list_nars().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_provisional_refs() {
+        block_on(async {
+            let state = TestState::new(SMALL_CHUNKS).await;
+
+            let data = crate::chunking::get_data(512 * 1024);
+            let request = request_for(&data);
+            upload_path_new(request, Cursor::new(data), &state).await.unwrap();
+
+            // Only the NAR references the chunks once it's stored
+            let mut chunks: Vec<_> = state.storage().list_chunks().await.unwrap()
+                .into_iter()
+                .map(|chunk| chunk.name)
+                .collect();
+            chunks.sort();
+            assert!(chunks.len() > 1);
+            let orphaned = refs::remove_refs(&state, STORE_PATH_HASH, chunks.clone()).await.unwrap();
+            assert_eq!(chunks, orphaned);
+        });
+    }
+
+    #[test]
+    fn test_cleanup_keeps_reused_chunks() {
+        block_on(async {
+            let state = TestState::new(SMALL_CHUNKS).await;
+
+            let data = crate::chunking::get_data(512 * 1024);
+            let request = Request {
+                nar_hash: Hash::sha256_from_bytes(b"something else"),
+                ..request_for(&data)
+            };
+
+            upload_path_new(request, Cursor::new(data), &state).await.unwrap_err();
+
+            // Another upload reused one of the chunks before they were deleted
+            let chunks = state.storage().list_chunks().await.unwrap();
+            assert!(chunks.len() > 1);
+            let reused = chunks[0].name.clone();
+            refs::add_refs(&state, "nar-other", [reused.clone()]).await.unwrap();
+
+            assert_eq!(chunks.len() - 1, gc::delete_pending(&state).await);
+
+            let chunks = state.storage().list_chunks().await.unwrap();
+            assert_eq!(1, chunks.len());
+            assert_eq!(reused, chunks[0].name);
+        });
+    }
+
+    #[test]
+    fn test_delete_during_reupload() {
+        block_on(async {
+            let state = TestState::new(&format!("token-hs256-secret-base64 = \"{}\"\n{}", TOKEN_SECRET, SMALL_CHUNKS)).await;
+
+            let data = crate::chunking::get_data(512 * 1024);
+            let request = request_for(&data);
+
+            for _ in 0..10 {
+                upload_path_new(request.clone(), Cursor::new(data.clone()), &state).await.unwrap();
+
+                let delete = delete_path(
+                    Extension(Arc::clone(&state)),
+                    Path(request.store_path_hash.to_string()),
+                );
+                let reupload = upload_path_new(request.clone(), Cursor::new(data.clone()), &state);
+                let (deleted, reuploaded) = futures::join!(delete, reupload);
+                reuploaded.unwrap();
+
+                // Whichever ran last, a stored NAR has all of its chunks
+                match download_uploaded_nar(&**state.storage(), &request.store_path_hash).await {
+                    Ok(nar) => {
+                        for chunk in nar.chunk_names() {
+                            assert!(state.storage().chunk_exists(chunk).await.unwrap());
+                        }
+                    }
+                    Err(_) => {
+                        deleted.unwrap();
+                    }
+                }
+            }
+        });
+    }
 }
@@ -6,12 +6,14 @@
 use std::collections::VecDeque;
 use std::pin::Pin;
 use std::future::Future;
+use std::sync::Arc;
 use async_stream::try_stream;
 use bytes::{Bytes, BytesMut, BufMut};
 use fastcdc::ronomon::FastCDC;
 use futures::stream::{Stream, StreamExt, BoxStream};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::spawn;
+use tokio::sync::Semaphore;
 
 /// Greedily reads from a stream to fill a buffer.
 pub async fn read_chunk_async<S: AsyncRead + Unpin + Send>(
@@ -106,12 +108,18 @@ where
 ///
 /// ```
 ///
-/// TODO: Support range requests so we can have seekable NARs.
+/// If `budget` is set, a permit is held for each chunk from before
+/// it is opened until it is fully streamed, which bounds the chunks
+/// buffered across all merged streams sharing the semaphore. A stream
+/// only waits for a permit when it holds none, and otherwise skips
+/// prefetching while the budget is exhausted, so streams cannot
+/// deadlock each other.
 pub fn merge_chunks<C, F, S, Fut, E>(
     mut chunks: VecDeque<C>,
     streamer: F,
     streamer_arg: S,
     num_prefetch: usize,
+    budget: Option<Arc<Semaphore>>,
 ) -> Pin<Box<impl Stream<Item = Result<Bytes, E>>>>
 where
     F: Fn(C, S) -> Fut,
@@ -126,11 +134,11 @@ where
         if false {
             let chunk = chunks.pop_front().unwrap();
             let stream = spawn(streamer(chunk, streamer_arg.clone()));
-            streams.push_back(stream);
+            streams.push_back((stream, None));
         }
 
         loop {
-            if let Some(stream) = streams.pop_front() {
+            if let Some((stream, _permit)) = streams.pop_front() {
                 let mut stream = stream.await.unwrap()?;
                 while let Some(item) = stream.next().await {
                     let item = item?;
@@ -138,13 +146,21 @@ where
                 }
             }
 
-            while streams.len() < num_prefetch {
-                if let Some(chunk) = chunks.pop_front() {
-                    let stream = spawn(streamer(chunk, streamer_arg.clone()));
-                    streams.push_back(stream);
-                } else {
-                    break;
-                }
+            while streams.len() < num_prefetch && !chunks.is_empty() {
+                let permit = match &budget {
+                    Some(budget) if streams.is_empty() => {
+                        Some(budget.clone().acquire_owned().await.unwrap())
+                    }
+                    Some(budget) => match budget.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => break,
+                    },
+                    None => None,
+                };
+
+                let chunk = chunks.pop_front().unwrap();
+                let stream = spawn(streamer(chunk, streamer_arg.clone()));
+                streams.push_back((stream, permit));
             }
 
             if chunks.is_empty() && streams.is_empty() {
@@ -156,6 +172,23 @@ where
     Box::pin(s)
 }
 
+/// Returns some fake data.
+///
+/// The data is pseudo-random and the same for every call, so it
+/// chunks the same way every time.
+pub fn get_data(len: usize) -> Vec<u8> {
+    let mut state = 42u32;
+    let mut data = vec![0u8; len];
+
+    for (i, byte) in data.iter_mut().enumerate() {
+        (state, _) = state.overflowing_mul(1664525u32);
+        (state, _) = state.overflowing_add(1013904223u32);
+        *byte = ((state >> (i % 24)) & 0xff) as u8;
+    }
+
+    data
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -165,6 +198,7 @@ mod tests {
     use futures::future;
 
     use super::*;
+    use crate::config::ChunkingConfig;
 
     #[test]
     fn test_merge_chunks() {
@@ -194,7 +228,7 @@ mod tests {
             [chunk_a, chunk_b, chunk_c].into_iter().collect();
 
         let streamer = |c, _| future::ok(c);
-        let mut merged = merge_chunks(chunks, streamer, (), 2);
+        let mut merged = merge_chunks(chunks, streamer, (), 2, None);
 
         let bytes = block_on(async move {
             let mut bytes = BytesMut::with_capacity(100);
@@ -207,6 +241,29 @@ mod tests {
         assert_eq!(&*bytes, b"Hello, world!");
     }
 
+    /// Merges chunks concurrently with a budget of a single chunk.
+    #[test]
+    fn test_merge_chunks_budget() {
+        let budget = Arc::new(Semaphore::new(1));
+
+        let merged = |budget: Arc<Semaphore>| {
+            let chunks: VecDeque<&'static [u8]> = [&b"a"[..], b"b", b"c"].into_iter().collect();
+            let streamer = |c: &'static [u8], _| {
+                let stream: BoxStream<Result<Bytes, ()>> = Box::pin(futures::stream::once(future::ok(Bytes::from_static(c))));
+                future::ok(stream)
+            };
+
+            merge_chunks(chunks, streamer, (), 2, Some(budget))
+                .map(|item| item.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let (a, b) = block_on(future::join(merged(budget.clone()), merged(budget.clone())));
+        assert_eq!(a, b);
+        assert_eq!(3, a.len());
+        assert_eq!(1, budget.available_permits());
+    }
+
     /// Chunks and reconstructs a file.
     #[test]
     fn test_chunking_basic() {
@@ -233,17 +290,35 @@ mod tests {
         case(32 * 1024 * 1024 + 1);
     }
 
-    /// Returns some fake data.
-    fn get_data(len: usize) -> Vec<u8> {
-        let mut state = 42u32;
-        let mut data = vec![0u8; len];
+    /// Locks the cut points for the default chunking parameters.
+    ///
+    /// Deduplication relies on identical content producing identical
+    /// chunks across versions. If this fails, the chunking parameters
+    /// or the FastCDC implementation have changed, and existing chunks
+    /// will no longer be deduplicated against new uploads.
+    #[test]
+    fn test_chunking_golden() {
+        let config = ChunkingConfig::default();
+        let data = get_data(2 * 1024 * 1024);
+
+        assert_chunk_lengths(&data, &config, &[
+            53974, 74555, 60478, 71839, 87618, 27445, 35891, 88862,
+            57481, 47481, 63663, 44142, 84824, 34894, 95562, 68329,
+            105989, 44113, 46846, 43659, 78512, 56638, 60393, 41633,
+            67062, 18933, 90870, 47680, 37692, 49408, 43497, 187190,
+            69676, 10323,
+        ]);
+    }
 
-        for i in 0..data.len() {
-            (state, _) = state.overflowing_mul(1664525u32);
-            (state, _) = state.overflowing_add(1013904223u32);
-            data[i] = ((state >> (i % 24)) & 0xff) as u8;
-        }
+    /// Asserts the exact chunk lengths of some content.
+    fn assert_chunk_lengths(data: &[u8], config: &ChunkingConfig, expected: &[usize]) {
+        let lengths: Vec<usize> = block_on(async {
+            chunk_stream(Cursor::new(data), config.min_size, config.avg_size, config.max_size)
+                .map(|chunk| chunk.unwrap().len())
+                .collect()
+                .await
+        });
 
-        data
+        assert_eq!(expected, lengths.as_slice());
     }
 }
@@ -0,0 +1,100 @@
+//! Compression.
+//!
+//! Chunks are compressed individually on upload. The helpers here
+//! return boxed encoders and decoders for a configured compression
+//! type so that streams can be (re)compressed on the fly.
+
+use std::marker::Unpin;
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder,
+    XzDecoder, XzEncoder,
+    ZstdDecoder, ZstdEncoder,
+};
+use async_compression::Level as CompressionLevel;
+use tokio::io::{AsyncBufRead, AsyncRead};
+
+use crate::config::CompressionType;
+
+pub type CompressorFn<C> = Box<dyn FnOnce(C) -> Box<dyn AsyncRead + Unpin + Send> + Send>;
+
+/// Returns a compressor function that takes some stream as input.
+pub fn get_compressor_fn<C: AsyncBufRead + Unpin + Send + 'static>(
+    ctype: CompressionType,
+    level: CompressionLevel,
+) -> CompressorFn<C> {
+    match ctype {
+        CompressionType::None => Box::new(|c| Box::new(c)),
+        CompressionType::Brotli => {
+            Box::new(move |s| Box::new(BrotliEncoder::with_quality(s, level)))
+        }
+        CompressionType::Zstd => Box::new(move |s| Box::new(ZstdEncoder::with_quality(s, level))),
+        CompressionType::Xz => Box::new(move |s| Box::new(XzEncoder::with_quality(s, level))),
+    }
+}
+
+/// Returns a decompressor function that takes some stream as input.
+pub fn get_decompressor_fn<C: AsyncBufRead + Unpin + Send + 'static>(
+    ctype: CompressionType,
+) -> CompressorFn<C> {
+    match ctype {
+        CompressionType::None => Box::new(|c| Box::new(c)),
+        CompressionType::Brotli => Box::new(|s| Box::new(BrotliDecoder::new(s))),
+        CompressionType::Zstd => Box::new(|s| Box::new(ZstdDecoder::new(s))),
+        CompressionType::Xz => Box::new(|s| Box::new(XzDecoder::new(s))),
+    }
+}
+
+/// Returns the HTTP content coding of a compression type.
+///
+/// Uploads accept all of them, while downloads only offer `zstd` and
+/// `br` (see `binary_cache::accept_encoding`).
+pub fn content_coding(ctype: CompressionType) -> Option<&'static str> {
+    match ctype {
+        CompressionType::None => None,
+        CompressionType::Brotli => Some("br"),
+        CompressionType::Zstd => Some("zstd"),
+        CompressionType::Xz => Some("xz"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tokio::io::{AsyncReadExt, BufReader};
+    use tokio_test::block_on;
+
+    use super::*;
+
+    /// Compresses and decompresses some data with every type.
+    #[test]
+    fn test_compression_round_trip() {
+        let data = b"Hello, world! Hello, world! Hello, world!".repeat(100);
+
+        for ctype in [
+            CompressionType::None,
+            CompressionType::Brotli,
+            CompressionType::Zstd,
+            CompressionType::Xz,
+        ] {
+            let round_trip = block_on(async {
+                let compressor = get_compressor_fn(ctype, CompressionLevel::Default);
+                let mut compressed = Vec::new();
+                compressor(BufReader::new(Cursor::new(data.clone())))
+                    .read_to_end(&mut compressed)
+                    .await
+                    .unwrap();
+
+                let decompressor = get_decompressor_fn(ctype);
+                let mut decompressed = Vec::new();
+                decompressor(BufReader::new(Cursor::new(compressed)))
+                    .read_to_end(&mut decompressed)
+                    .await
+                    .unwrap();
+
+                decompressed
+            });
+
+            assert_eq!(data, round_trip, "{:?}", ctype);
+        }
+    }
+}
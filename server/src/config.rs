@@ -1,15 +1,24 @@
 use anyhow::{anyhow, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
 use std::fs::read_to_string;
+use std::fmt;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::str::FromStr;
 use serde::{Serialize, Deserialize};
+use serde::de::{self, DeserializeOwned, Visitor};
 use async_compression::Level as CompressionLevel;
 
+use libnixstore::StorePathHashPolicy;
 use common::signing::Keypair;
-use auth::{HS256Key, decode_token_hs256_secret_base64};
+use auth::{TokenKey, decode_token_hs256_secret_base64, decode_token_public_key};
+use crate::index::{IndexConfig, RedbIndexConfig};
 use crate::narinfo::Compression as NixCompression;
+use crate::narinfo::cache::NarInfoCacheConfig;
+use crate::storage::gcs::GcsStorageConfig;
 use crate::storage::local::LocalStorageConfig;
 use crate::storage::s3::S3StorageConfig;
+use crate::storage::webdav::WebDavStorageConfig;
 
 const CONFIG_PATH: &str = "/trestripes/nixcache/config.toml";
 
@@ -17,53 +26,279 @@ const CONFIG_PATH: &str = "/trestripes/nixcache/config.toml";
 pub struct Config {
     /// Socket address to listen on.
     pub listen: SocketAddr,
-    /// JSON Web Token HMAC secret.
-    pub token_hs256_secret: Option<HS256Key>,
+    /// Key that verifies JSON Web Tokens.
+    pub token_key: Option<TokenKey>,
+    /// Whether reading from the cache requires authentication.
+    pub require_auth_for_reads: bool,
+    /// Whether store path hashes of lengths other than 32 are accepted.
+    pub allow_nonstandard_hash_length: bool,
+    /// Whether writes are rejected.
+    pub read_only: bool,
     /// Storage.
     pub storage: StorageConfig,
+    /// How NARs of store paths are keyed.
+    pub key_by: KeyBy,
     /// Compression.
     pub compression: CompressionConfig,
+    /// The NAR size below which NARs are stored uncompressed.
+    pub compression_min_nar_size: usize,
+    /// Blocking thread pool for compression.
+    pub compression_pool: Option<CompressionPoolConfig>,
     /// Data chunking.
     pub chunking: ChunkingConfig,
+    /// Tracing.
+    pub tracing: TracingConfig,
+    /// Metadata index.
+    pub index: Option<IndexConfig>,
+    /// In-memory narinfo cache.
+    pub narinfo_cache: Option<NarInfoCacheConfig>,
+    /// File listings.
+    pub listing: ListingConfig,
+    /// Crawlers.
+    pub robots: RobotsConfig,
+    /// Uploads.
+    pub upload: UploadConfig,
+    /// Downloads.
+    pub download: DownloadConfig,
+    /// HTTP.
+    pub http: HttpConfig,
+    /// Garbage collection.
+    pub garbage_collection: GarbageCollectionConfig,
     /// Signing keypair.
     pub keypair: Keypair,
+    /// Unknown fields that were ignored in lenient mode.
+    pub unknown_fields: Vec<String>,
 }
 impl TryFrom<ConfigInfoVersioned> for Config {
     type Error = anyhow::Error;
     fn try_from(versioned: ConfigInfoVersioned) -> Result<Self> {
         let config: ConfigInfo = versioned.into();
 
-        let token_hs256_secret = config.token_hs256_secret
-            .map(|x| decode_token_hs256_secret_base64(&x)).transpose()?;
+        let token_key = match (config.token_hs256_secret, config.token_public_key) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "token-hs256-secret-base64 and token-rs256-public-key are mutually exclusive"
+                ));
+            }
+            (Some(secret), None) => Some(TokenKey::Hs256(decode_token_hs256_secret_base64(&secret)?)),
+            (None, Some(key)) => Some(decode_token_public_key(&key)?),
+            (None, None) => None,
+        };
+
+        if config.storage.key_by == KeyBy::NarHash && config.index.is_none() {
+            return Err(anyhow!("storage.key-by = \"nar-hash\" requires an index"));
+        }
+
+        let mut keypair = Keypair::from_str(&config.keypair)?;
+        if let Some(name) = &config.signing_key_name {
+            keypair = keypair.with_name(name)?;
+        }
 
         Ok(Self {
             listen: config.listen,
-            token_hs256_secret,
-            storage: config.storage,
-            compression: config.compression,
-            chunking: config.chunking,
-            keypair: Keypair::from_str(&config.keypair)?,
+            token_key,
+            require_auth_for_reads: config.require_auth_for_reads,
+            allow_nonstandard_hash_length: config.allow_nonstandard_hash_length,
+            read_only: config.read_only,
+            storage: config.storage.backend,
+            key_by: config.storage.key_by,
+            compression: CompressionConfig {
+                r#type: config.compression.r#type,
+                level: config.compression.level,
+            },
+            compression_min_nar_size: config.compression.min_nar_size,
+            compression_pool: config.compression_pool,
+            chunking: config.chunking.try_into()?,
+            tracing: config.tracing,
+            index: config.index,
+            narinfo_cache: config.narinfo_cache,
+            listing: config.listing,
+            robots: config.robots,
+            upload: config.upload,
+            download: config.download,
+            http: config.http,
+            garbage_collection: config.garbage_collection,
+            keypair,
+            unknown_fields: Vec::new(),
         })
     }
 }
+impl Config {
+    /// Returns how store path hashes in requests are validated.
+    pub fn store_path_hash_policy(&self) -> StorePathHashPolicy {
+        if self.allow_nonstandard_hash_length {
+            StorePathHashPolicy::AllowNonstandardLength
+        } else {
+            StorePathHashPolicy::Strict
+        }
+    }
+}
 
-pub async fn load(path: Option<PathBuf>) -> Result<Config> {
-    let path = match path {
-        Some(path) => path,
-        None => PathBuf::from(CONFIG_PATH),
-    };
-
-    tracing::info!("Using config at: '{}'", path.to_string_lossy());
+/// Returns the path of the config if none is given.
+pub fn default_path() -> PathBuf {
+    PathBuf::from(CONFIG_PATH)
+}
 
+/// Loads the config.
+///
+/// If `allow_unknown_fields` is set, unknown fields are ignored and
+/// recorded in `Config::unknown_fields` instead of failing the load.
+pub async fn load(path: &Path, allow_unknown_fields: bool) -> Result<Config> {
     if path.is_file() {
         let data = read_to_string(path)?;
-        let config: ConfigInfoVersioned = toml::from_str(&data)?;
-        config.try_into()
+        if allow_unknown_fields {
+            let (config, unknown_fields) = parse_lenient(path, &data)?;
+            let mut config: Config = config.try_into()?;
+            config.unknown_fields = unknown_fields;
+            Ok(config)
+        } else {
+            let config: ConfigInfoVersioned = parse(path, &data)?;
+            config.try_into()
+        }
     } else {
         Err(anyhow!("No config found."))
     }
 }
 
+/// Parses a config, detecting the format from the file extension.
+///
+/// Paths without a recognized extension are parsed as TOML.
+fn parse<T: DeserializeOwned>(path: &Path, data: &str) -> Result<T> {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+
+    let config = match extension {
+        Some("json") => serde_json::from_str(data)?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(data)?,
+        _ => toml::from_str(data)?,
+    };
+
+    Ok(config)
+}
+
+/// Parses a config, dropping unknown fields.
+///
+/// Returns the dotted paths of the dropped fields.
+fn parse_lenient(path: &Path, data: &str) -> Result<(ConfigInfoVersioned, Vec<String>)> {
+    let mut value: serde_json::Value = parse(path, data)?;
+
+    let mut unknown_fields = Vec::new();
+    drop_unknown_fields(&mut value, "", &mut unknown_fields);
+
+    Ok((serde_json::from_value(value)?, unknown_fields))
+}
+
+/// Drops the unknown fields of a section and the sections nested in it.
+fn drop_unknown_fields(value: &mut serde_json::Value, path: &str, unknown_fields: &mut Vec<String>) {
+    let section = match value.as_object_mut() {
+        Some(section) => section,
+        None => return,
+    };
+    let known = match section_fields(path, section) {
+        Some(known) => known,
+        None => return,
+    };
+
+    section.retain(|key, value| {
+        let field = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", path, key)
+        };
+
+        if known.contains(&key.as_str()) {
+            drop_unknown_fields(value, &field, unknown_fields);
+            true
+        } else {
+            unknown_fields.push(field);
+            false
+        }
+    });
+}
+
+/// Returns the known fields of a section, or `None` if it is kept as is.
+///
+/// The fields of `storage` and `index` depend on their `type`. Sections
+/// with an unknown `type` are kept so that parsing reports it.
+fn section_fields(
+    path: &str,
+    section: &serde_json::Map<String, serde_json::Value>,
+) -> Option<Vec<&'static str>> {
+    let section_type = section.get("type").and_then(|section_type| section_type.as_str());
+
+    let fields = match path {
+        "" => [&["version"][..], struct_fields::<ConfigInfo>()].concat(),
+        // `StorageConfigInfo` flattens the backend config, so its own
+        // fields can't be recorded
+        "storage" => {
+            let backend = match section_type? {
+                "local" => struct_fields::<LocalStorageConfig>(),
+                "s3" => struct_fields::<S3StorageConfig>(),
+                "gcs" => struct_fields::<GcsStorageConfig>(),
+                "webdav" => struct_fields::<WebDavStorageConfig>(),
+                _ => return None,
+            };
+            [&["type", "key-by"][..], backend].concat()
+        }
+        "index" => {
+            let index = match section_type? {
+                "redb" => struct_fields::<RedbIndexConfig>(),
+                _ => return None,
+            };
+            [&["type"][..], index].concat()
+        }
+        "compression" => struct_fields::<CompressionConfigInfo>().to_vec(),
+        "compression-pool" => struct_fields::<CompressionPoolConfig>().to_vec(),
+        "chunking" => struct_fields::<ChunkingConfigInfo>().to_vec(),
+        "tracing" => struct_fields::<TracingConfig>().to_vec(),
+        "narinfo-cache" => struct_fields::<NarInfoCacheConfig>().to_vec(),
+        "listing" => struct_fields::<ListingConfig>().to_vec(),
+        "robots" => struct_fields::<RobotsConfig>().to_vec(),
+        "upload" => struct_fields::<UploadConfig>().to_vec(),
+        "download" => struct_fields::<DownloadConfig>().to_vec(),
+        "http" => struct_fields::<HttpConfig>().to_vec(),
+        "garbage-collection" => struct_fields::<GarbageCollectionConfig>().to_vec(),
+        _ => return None,
+    };
+
+    Some(fields)
+}
+
+/// Returns the (renamed) field names of a struct.
+///
+/// This runs `T::deserialize` against a deserializer that only records
+/// the fields passed to `deserialize_struct`.
+fn struct_fields<T: DeserializeOwned>() -> &'static [&'static str] {
+    struct FieldsDeserializer<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> de::Deserializer<'de> for FieldsDeserializer<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> std::result::Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> std::result::Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldsDeserializer(&mut fields));
+    fields
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "version")]
 pub enum ConfigInfoVersioned {
@@ -91,20 +326,145 @@ pub struct ConfigInfo {
     #[serde(rename = "token-hs256-secret-base64")]
     pub token_hs256_secret: Option<String>,
 
+    /// Public key of an external JSON Web Token issuer.
+    ///
+    /// This is a PEM or JWK key. An RSA key verifies RS256 tokens and
+    /// a P-256 EC key verifies ES256 tokens. It can't be set along
+    /// with `token-hs256-secret-base64`.
+    #[serde(rename = "token-rs256-public-key")]
+    #[serde(default)]
+    pub token_public_key: Option<String>,
+
+    /// Whether reading from the cache requires authentication.
+    ///
+    /// By default, the binary cache routes used by Nix are public and
+    /// only the API requires a token. Set this for private caches.
+    #[serde(rename = "require-auth-for-reads")]
+    #[serde(default)]
+    pub require_auth_for_reads: bool,
+
+    /// Whether store path hashes of lengths other than 32 are accepted.
+    ///
+    /// This is only useful with custom Nix builds whose stores use
+    /// a different hash length. The character set is still validated.
+    #[serde(rename = "allow-nonstandard-hash-length")]
+    #[serde(default)]
+    pub allow_nonstandard_hash_length: bool,
+
+    /// Whether to reject all writes.
+    ///
+    /// Reads are still served. This is useful during maintenance and
+    /// for replicas sharing the storage of another server.
+    #[serde(rename = "read-only")]
+    #[serde(default)]
+    pub read_only: bool,
+
     /// Storage.
-    pub storage: StorageConfig,
+    pub storage: StorageConfigInfo,
 
     /// Compression.
     #[serde(default = "Default::default")]
-    pub compression: CompressionConfig,
+    pub compression: CompressionConfigInfo,
+
+    /// Blocking thread pool for compression.
+    ///
+    /// If unset, uploads are compressed on the async executor.
+    #[serde(rename = "compression-pool")]
+    #[serde(default)]
+    pub compression_pool: Option<CompressionPoolConfig>,
 
     /// Data chunking.
     #[serde(default = "Default::default")]
-    pub chunking: ChunkingConfig,
+    pub chunking: ChunkingConfigInfo,
+
+    /// Tracing.
+    #[serde(default = "Default::default")]
+    pub tracing: TracingConfig,
+
+    /// Metadata index.
+    ///
+    /// If unset, features that need an index are unavailable.
+    #[serde(default)]
+    pub index: Option<IndexConfig>,
+
+    /// In-memory narinfo cache.
+    ///
+    /// If unset, narinfos are always built from storage.
+    #[serde(rename = "narinfo-cache")]
+    #[serde(default)]
+    pub narinfo_cache: Option<NarInfoCacheConfig>,
+
+    /// File listings.
+    #[serde(default = "Default::default")]
+    pub listing: ListingConfig,
+
+    /// Crawlers.
+    #[serde(default = "Default::default")]
+    pub robots: RobotsConfig,
+
+    /// Uploads.
+    #[serde(default = "Default::default")]
+    pub upload: UploadConfig,
+
+    /// Downloads.
+    #[serde(default = "Default::default")]
+    pub download: DownloadConfig,
+
+    /// HTTP.
+    #[serde(default = "Default::default")]
+    pub http: HttpConfig,
+
+    /// Garbage collection of orphaned chunks.
+    #[serde(rename = "garbage-collection")]
+    #[serde(default = "Default::default")]
+    pub garbage_collection: GarbageCollectionConfig,
 
     /// Signing keypair.
     #[serde(rename = "signing_key")]
     pub keypair: String,
+
+    /// Name to sign with, overriding the name in the signing key.
+    ///
+    /// This must match the name clients have in `trusted-public-keys`.
+    #[serde(rename = "signing-key-name")]
+    #[serde(default)]
+    pub signing_key_name: Option<String>,
+}
+
+/// Storage configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageConfigInfo {
+    /// The storage backend.
+    #[serde(flatten)]
+    pub backend: StorageConfig,
+
+    /// How NARs of store paths are keyed.
+    #[serde(rename = "key-by")]
+    #[serde(default)]
+    pub key_by: KeyBy,
+}
+
+/// How NARs of store paths are keyed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum KeyBy {
+    /// Each store path is uploaded on its own.
+    #[serde(rename = "store-path")]
+    #[default]
+    StorePath,
+    /// Store paths with identical contents share a NAR.
+    ///
+    /// The index maps the hash of each store path to its NAR hash.
+    /// An upload whose NAR is already stored for another store path
+    /// reuses its chunks instead of storing them again.
+    ///
+    /// Note: Each store path still has a manifest of its own, which
+    /// lists the shared chunks along with the narinfo fields of the
+    /// path. Only the chunk data is shared. The manifest is about 100
+    /// bytes per chunk, so this is small next to the NAR, and it keeps
+    /// listing, deleting and reindexing store paths independent of
+    /// each other.
+    #[serde(rename = "nar-hash")]
+    NarHash,
 }
 
 /// File storage configuration.
@@ -117,9 +477,18 @@ pub enum StorageConfig {
     /// S3 file storage.
     #[serde(rename = "s3")]
     S3(S3StorageConfig),
+    /// Google Cloud Storage file storage.
+    #[serde(rename = "gcs")]
+    Gcs(GcsStorageConfig),
+    /// WebDAV file storage.
+    #[serde(rename = "webdav")]
+    WebDav(WebDavStorageConfig),
 }
 
 /// Compression configuration.
+///
+/// This is stored with each chunk, so it only describes how a chunk
+/// is compressed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompressionConfig {
     /// Compression type.
@@ -154,6 +523,52 @@ impl CompressionConfig {
         }
     }
 }
+
+/// Compression configuration as written in the config file.
+///
+/// Only the `CompressionConfig` part is stored with each chunk.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionConfigInfo {
+    /// Compression type.
+    pub r#type: CompressionType,
+
+    /// Compression level.
+    #[serde(default)]
+    pub level: Option<u32>,
+
+    /// The NAR size below which NARs are stored uncompressed.
+    ///
+    /// Tiny NARs gain little from compression. By default, all
+    /// NARs are compressed.
+    #[serde(rename = "min-nar-size")]
+    #[serde(default)]
+    pub min_nar_size: usize,
+}
+impl Default for CompressionConfigInfo {
+    fn default() -> Self {
+        let default = CompressionConfig::default();
+
+        Self {
+            r#type: default.r#type,
+            level: default.level,
+            min_nar_size: 0,
+        }
+    }
+}
+
+/// Compression thread pool configuration.
+///
+/// CPU-heavy compression (xz, high zstd levels) would otherwise run
+/// on the async executor and delay other requests.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionPoolConfig {
+    /// The maximum number of chunks and unchunked NARs compressed at once.
+    ///
+    /// By default, this is the number of available CPUs.
+    #[serde(default = "default_compression_pool_size")]
+    pub size: usize,
+}
+
 /// Compression type.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompressionType {
@@ -170,6 +585,52 @@ pub enum CompressionType {
     #[serde(rename = "xz")]
     Xz,
 }
+impl CompressionType {
+    /// Returns the file extension of NARs compressed with this type.
+    ///
+    /// Example: `zst` for `nar/{hash}.nar.zst`.
+    pub fn nar_extension(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Brotli => Some("br"),
+            Self::Zstd => Some("zst"),
+            Self::Xz => Some("xz"),
+        }
+    }
+
+    /// Parses the file extension of a compressed NAR.
+    pub fn from_nar_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "br" => Some(Self::Brotli),
+            "zst" => Some(Self::Zstd),
+            "xz" => Some(Self::Xz),
+            _ => None,
+        }
+    }
+}
+impl FromStr for CompressionType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "brotli" => Ok(Self::Brotli),
+            "zstd" => Ok(Self::Zstd),
+            "xz" => Ok(Self::Xz),
+            _ => Err(anyhow!("Invalid compression type \"{}\"", s)),
+        }
+    }
+}
+impl fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Brotli => "brotli",
+            Self::Zstd => "zstd",
+            Self::Xz => "xz",
+        })
+    }
+}
 impl From<CompressionType> for NixCompression {
     fn from(t: CompressionType) -> Self {
         match t {
@@ -188,12 +649,21 @@ impl From<CompressionType> for NixCompression {
 /// us to provide a new set of recommended "defaults" for newer
 /// deployments without affecting existing ones.
 ///
+/// Chunk sizes can be picked from a preset, and individual values
+/// set explicitly override the preset.
+///
 /// Warning: If you change any of the values here, it will be
 /// difficult to reuse existing chunks for newly-uploaded NARs
 /// since the cutpoints will be different. As a result, the
 /// deduplication ratio will suffer for a while after the change.
-#[derive(Debug, Clone, Deserialize)]
-pub struct ChunkingConfig {
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChunkingConfigInfo {
+    /// Chunk size preset.
+    ///
+    /// By default, the "balanced" preset is used.
+    #[serde(default)]
+    pub preset: Option<ChunkingPreset>,
+
     /// The minimum NAR size to trigger chunking.
     ///
     /// If 0, chunking is disabled entirely for newly-uploaded
@@ -203,31 +673,690 @@ pub struct ChunkingConfig {
     ///
     /// By default, the threshold is 128KB.
     #[serde(rename = "nar-size-threshold")]
-    pub nar_size_threshold: usize,
+    #[serde(default)]
+    pub nar_size_threshold: Option<usize>,
 
     /// The preferred minimum size of a chunk, in bytes.
     #[serde(rename = "min-size")]
-    pub min_size: usize,
+    #[serde(default)]
+    pub min_size: Option<usize>,
 
     /// The preferred average size of a chunk, in bytes.
     #[serde(rename = "avg-size")]
-    pub avg_size: usize,
+    #[serde(default)]
+    pub avg_size: Option<usize>,
 
     /// The preferred maximum size of a chunk, in bytes.
     #[serde(rename = "max-size")]
+    #[serde(default)]
+    pub max_size: Option<usize>,
+}
+impl TryFrom<ChunkingConfigInfo> for ChunkingConfig {
+    type Error = anyhow::Error;
+    fn try_from(info: ChunkingConfigInfo) -> Result<Self> {
+        let default = ChunkingConfig::default();
+        let (min_size, avg_size, max_size) = info.preset.unwrap_or_default().sizes();
+
+        let config = Self {
+            nar_size_threshold: info.nar_size_threshold.unwrap_or(default.nar_size_threshold),
+            min_size: info.min_size.unwrap_or(min_size),
+            avg_size: info.avg_size.unwrap_or(avg_size),
+            max_size: info.max_size.unwrap_or(max_size),
+        };
+
+        if config.min_size > config.avg_size || config.avg_size > config.max_size {
+            return Err(anyhow!(
+                "Chunk sizes must satisfy min-size <= avg-size <= max-size, got {}, {} and {}",
+                config.min_size, config.avg_size, config.max_size,
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// A set of chunk sizes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ChunkingPreset {
+    /// Small chunks (8 KiB / 32 KiB / 128 KiB).
+    ///
+    /// Favors deduplication: more of each NAR can be shared with
+    /// similar NARs, at the cost of more objects in storage and
+    /// more requests to reassemble a NAR.
+    #[serde(rename = "small")]
+    Small,
+    /// Medium chunks (16 KiB / 64 KiB / 256 KiB).
+    ///
+    /// A compromise suitable for most caches.
+    #[serde(rename = "balanced")]
+    #[default]
+    Balanced,
+    /// Large chunks (64 KiB / 256 KiB / 1 MiB).
+    ///
+    /// Favors throughput: fewer objects to store and fetch, at the
+    /// cost of deduplicating less between similar NARs.
+    #[serde(rename = "large")]
+    Large,
+}
+impl ChunkingPreset {
+    /// Returns the minimum, average and maximum chunk sizes.
+    pub fn sizes(self) -> (usize, usize, usize) {
+        match self {
+            Self::Small => (8192, 32768, 131072),
+            Self::Balanced => (16384, 65536, 262144),
+            Self::Large => (65536, 262144, 1048576),
+        }
+    }
+}
+
+/// Resolved data chunking configuration.
+///
+/// See `ChunkingConfigInfo`.
+#[derive(Debug, Clone)]
+pub struct ChunkingConfig {
+    /// The minimum NAR size to trigger chunking.
+    pub nar_size_threshold: usize,
+
+    /// The preferred minimum size of a chunk, in bytes.
+    pub min_size: usize,
+
+    /// The preferred average size of a chunk, in bytes.
+    pub avg_size: usize,
+
+    /// The preferred maximum size of a chunk, in bytes.
     pub max_size: usize,
 }
 impl Default for ChunkingConfig {
     fn default() -> Self {
+        let (min_size, avg_size, max_size) = ChunkingPreset::default().sizes();
         Self {
             nar_size_threshold: 65536,
-            min_size: 16384,
-            avg_size: 65536,
-            max_size: 262144,
+            min_size,
+            avg_size,
+            max_size,
+        }
+    }
+}
+
+/// File listing configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListingConfig {
+    /// When to build the file listings served as `.ls`.
+    #[serde(default)]
+    pub generation: ListingGeneration,
+}
+
+/// When to build file listings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ListingGeneration {
+    /// Build listings while NARs are uploaded.
+    ///
+    /// This adds some latency to uploads. Listings of NARs uploaded
+    /// before this was enabled are built lazily.
+    #[serde(rename = "eager")]
+    Eager,
+    /// Build listings on the first request and store them.
+    ///
+    /// The first request has to read the whole NAR.
+    #[serde(rename = "lazy")]
+    #[default]
+    Lazy,
+    /// Do not serve listings.
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
+/// Crawler configuration.
+///
+/// By default, crawlers are asked not to index the cache since
+/// enumerating narinfos wastes bandwidth.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RobotsConfig {
+    /// The content of `/robots.txt`.
+    #[serde(rename = "robots-txt")]
+    #[serde(default = "default_robots_txt")]
+    pub robots_txt: String,
+
+    /// Whether to set `X-Robots-Tag: noindex` on responses.
+    #[serde(default = "default_noindex")]
+    pub noindex: bool,
+}
+impl Default for RobotsConfig {
+    fn default() -> Self {
+        Self {
+            robots_txt: default_robots_txt(),
+            noindex: default_noindex(),
+        }
+    }
+}
+
+/// HTTP configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HttpConfig {
+    /// The response to `/`.
+    ///
+    /// Set this for uptime checkers that expect a specific body.
+    #[serde(rename = "home-response")]
+    #[serde(default)]
+    pub home_response: HomeResponse,
+
+    /// The maximum number of requests handled at once.
+    ///
+    /// Requests beyond the limit are rejected with 503 Service
+    /// Unavailable instead of queued. This bounds the total load,
+    /// while `upload.max-concurrent-uploads` (per subject) and
+    /// `download.reassembly-memory-budget` divide it up within the
+    /// limit. A request counts until its response starts, so uploads
+    /// count while their body is received but NAR downloads stop
+    /// counting once they start streaming. Health checks
+    /// (`/healthz` and `/readyz`) are not limited.
+    ///
+    /// If unset, the number of requests is unbounded.
+    #[serde(rename = "max-concurrent-requests")]
+    #[serde(default)]
+    pub max_concurrent_requests: Option<NonZeroUsize>,
+}
+
+/// The response to `/`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HomeResponse {
+    /// The server version as plain text.
+    #[default]
+    Version,
+    /// A JSON object with the status and the server version.
+    Json,
+    /// A custom message as plain text.
+    Message(String),
+}
+
+/// Upload configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadConfig {
+    /// The maximum size of the upload info, in bytes.
+    ///
+    /// This is the narinfo sent along with the NAR, either in a header
+    /// or at the beginning of the body. Paths with very long reference
+    /// lists may need a larger limit.
+    ///
+    /// By default, the limit is 1 MiB.
+    #[serde(rename = "max-narinfo-size")]
+    #[serde(default = "default_max_narinfo_size")]
+    pub max_narinfo_size: usize,
+
+    /// Whether to reject compressed bodies without a declared compression.
+    ///
+    /// Clients declare the compression of the upload body in
+    /// `X-Nixcache-Upload-Compression`, and the body is decompressed
+    /// before it's hashed. A body with a `Content-Encoding` but no
+    /// declaration would otherwise be hashed as is.
+    #[serde(rename = "require-declared-compression")]
+    #[serde(default)]
+    pub require_declared_compression: bool,
+
+    /// How long to remember idempotency keys of uploads, in seconds.
+    ///
+    /// A retried upload with the same `Idempotency-Key` within this
+    /// time returns the result of the first one.
+    #[serde(rename = "idempotency-key-ttl")]
+    #[serde(default = "default_idempotency_key_ttl")]
+    pub idempotency_key_ttl: u64,
+
+    /// The maximum number of concurrent uploads per authenticated subject.
+    ///
+    /// Tokens can override this with a claim. If unset, the number
+    /// of concurrent uploads is unlimited.
+    #[serde(rename = "max-concurrent-uploads")]
+    #[serde(default)]
+    pub max_concurrent_uploads: Option<usize>,
+}
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_narinfo_size: default_max_narinfo_size(),
+            require_declared_compression: false,
+            idempotency_key_ttl: default_idempotency_key_ttl(),
+            max_concurrent_uploads: None,
+        }
+    }
+}
+
+/// Download configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadConfig {
+    /// The number of bytes of a NAR to read ahead of the client.
+    ///
+    /// Chunks are then read from storage at storage speed into a
+    /// buffer of this size, instead of as fast as the client consumes
+    /// the NAR. This releases storage connections sooner when clients
+    /// are slow, at the cost of memory per download.
+    ///
+    /// If unset, chunks are read as the client consumes the NAR.
+    #[serde(rename = "prefetch-buffer-bytes")]
+    #[serde(default)]
+    pub prefetch_buffer_bytes: Option<usize>,
+
+    /// The memory for reassembling NARs from chunks, in bytes.
+    ///
+    /// Reassembly reads a few chunks ahead for each download. This
+    /// bounds the chunks read ahead across all downloads, assuming
+    /// chunks of the maximum chunk size, so that many concurrent
+    /// downloads cannot exhaust memory. Downloads wait for memory
+    /// to free up once the budget is used.
+    ///
+    /// If unset, the memory is unbounded.
+    #[serde(rename = "reassembly-memory-budget")]
+    #[serde(default)]
+    pub reassembly_memory_budget: Option<usize>,
+
+    /// The minimum number of chunks to download ahead when
+    /// reassembling a NAR.
+    ///
+    /// More chunks keep the NAR flowing on high-latency storage, at
+    /// the cost of memory per download. By default, at least 2 chunks
+    /// are downloaded ahead.
+    #[serde(rename = "nar-reassembly-prefetch")]
+    #[serde(default = "default_nar_reassembly_prefetch")]
+    pub nar_reassembly_prefetch: NonZeroUsize,
+
+    /// The maximum number of chunks to download ahead when
+    /// reassembling a NAR with `nar-reassembly-prefetch-bytes`.
+    ///
+    /// By default, at most 16 chunks are downloaded ahead.
+    #[serde(rename = "nar-reassembly-prefetch-max")]
+    #[serde(default = "default_nar_reassembly_prefetch_max")]
+    pub nar_reassembly_prefetch_max: usize,
+
+    /// The number of bytes to download ahead when reassembling a NAR.
+    ///
+    /// Each chunk is a separate request to the storage, so NARs of
+    /// small chunks download more chunks ahead to hide the latency.
+    /// The number of chunks is derived from the average chunk size
+    /// of each NAR, within the minimum and maximum above.
+    ///
+    /// If unset, `nar-reassembly-prefetch` chunks are downloaded ahead
+    /// regardless of their size.
+    #[serde(rename = "nar-reassembly-prefetch-bytes")]
+    #[serde(default)]
+    pub nar_reassembly_prefetch_bytes: Option<usize>,
+
+    /// Whether to recompress plain NARs for clients accepting zstd or br.
+    ///
+    /// Nix advertises every encoding its libcurl supports, so this
+    /// makes standard clients recompress every NAR of several chunks
+    /// on the fly. NARs requested with a compression extension are
+    /// recompressed regardless.
+    #[serde(rename = "recompress-for-accept-encoding")]
+    #[serde(default)]
+    pub recompress_for_accept_encoding: bool,
+}
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            prefetch_buffer_bytes: None,
+            reassembly_memory_budget: None,
+            nar_reassembly_prefetch: default_nar_reassembly_prefetch(),
+            nar_reassembly_prefetch_max: default_nar_reassembly_prefetch_max(),
+            nar_reassembly_prefetch_bytes: None,
+            recompress_for_accept_encoding: false,
         }
     }
 }
+impl DownloadConfig {
+    /// Returns the number of chunks to download ahead when
+    /// reassembling a NAR with chunks of this average size.
+    pub fn nar_reassembly_prefetch(&self, avg_chunk_size: usize) -> usize {
+        let min = self.nar_reassembly_prefetch.get();
+        let bytes = match self.nar_reassembly_prefetch_bytes {
+            Some(bytes) => bytes,
+            None => return min,
+        };
+        let max = self.nar_reassembly_prefetch_max.max(min);
+
+        (bytes / avg_chunk_size.max(1)).clamp(min, max)
+    }
+}
+
+/// Garbage collection configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GarbageCollectionConfig {
+    /// How often to delete orphaned chunks, in seconds.
+    ///
+    /// If unset, chunks are only deleted along with the last store
+    /// path referencing them.
+    #[serde(default)]
+    pub interval: Option<NonZeroU64>,
+
+    /// How old an orphaned chunk must be before it's deleted, in seconds.
+    ///
+    /// Uploads store their chunks before the NAR referencing them,
+    /// so this should be longer than the slowest upload. By default,
+    /// this is one hour.
+    #[serde(rename = "grace-period")]
+    #[serde(default = "default_gc_grace_period")]
+    pub grace_period: u64,
+}
+impl Default for GarbageCollectionConfig {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            grace_period: default_gc_grace_period(),
+        }
+    }
+}
+
+/// Tracing configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TracingConfig {
+    /// The OTLP endpoint to export spans to.
+    ///
+    /// Requires the server to be built with the `otlp` feature.
+    #[serde(rename = "otlp-endpoint")]
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+}
 
 fn default_listen_address() -> SocketAddr {
     "127.0.0.1:8080".parse().unwrap()
 }
+
+fn default_idempotency_key_ttl() -> u64 {
+    600
+}
+
+fn default_robots_txt() -> String {
+    "User-agent: *\nDisallow: /\n".to_string()
+}
+
+fn default_noindex() -> bool {
+    true
+}
+
+fn default_max_narinfo_size() -> usize {
+    1024 * 1024
+}
+
+fn default_nar_reassembly_prefetch() -> NonZeroUsize {
+    NonZeroUsize::new(2).unwrap()
+}
+
+fn default_nar_reassembly_prefetch_max() -> usize {
+    16
+}
+
+fn default_gc_grace_period() -> u64 {
+    60 * 60
+}
+
+fn default_compression_pool_size() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNING_KEY: &str = "demo.nixcache-0:vjg4zb3o8U3SapIoeG5dWZ9+G4OyqA96J2+nxuoMPCT3a7/zXWgXpuKr+rJWChlyTGeCV2aARebK+ffmh+u2fw==";
+
+    fn parse_config(path: &str, data: &str) -> Config {
+        let data = data.replace("@SIGNING_KEY@", SIGNING_KEY);
+        parse::<ConfigInfoVersioned>(Path::new(path), &data).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_parse_formats() {
+        let toml = r#"
+version = "v1"
+listen = "127.0.0.1:8080"
+signing_key = "@SIGNING_KEY@"
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+
+[compression]
+type = "xz"
+level = 6
+min-nar-size = 4096
+"#;
+
+        let json = r#"{
+  "version": "v1",
+  "listen": "127.0.0.1:8080",
+  "signing_key": "@SIGNING_KEY@",
+  "storage": { "type": "local", "path": "/tmp/nixcache" },
+  "compression": { "type": "xz", "level": 6, "min-nar-size": 4096 }
+}"#;
+
+        let yaml = r#"
+version: v1
+listen: 127.0.0.1:8080
+signing_key: "@SIGNING_KEY@"
+storage:
+  type: local
+  path: /tmp/nixcache
+compression:
+  type: xz
+  level: 6
+  min-nar-size: 4096
+"#;
+
+        for config in [
+            parse_config("config.toml", toml),
+            parse_config("config", toml),
+            parse_config("config.json", json),
+            parse_config("config.yaml", yaml),
+            parse_config("config.yml", yaml),
+        ] {
+            assert_eq!("127.0.0.1:8080".parse::<SocketAddr>().unwrap(), config.listen);
+            assert_eq!(CompressionType::Xz, config.compression.r#type);
+            assert_eq!(Some(6), config.compression.level);
+            assert_eq!(4096, config.compression_min_nar_size);
+            assert!(matches!(config.storage, StorageConfig::Local(_)));
+        }
+    }
+
+    #[test]
+    fn test_chunking_preset() {
+        let config = |chunking: &str| -> Result<ChunkingConfig> {
+            let toml = format!(r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+
+[chunking]
+{}
+"#, chunking).replace("@SIGNING_KEY@", SIGNING_KEY);
+
+            let config: ConfigInfoVersioned = parse(Path::new("config.toml"), &toml).unwrap();
+            Ok(Config::try_from(config)?.chunking)
+        };
+
+        let balanced = config("").unwrap();
+        assert_eq!((16384, 65536, 262144), (balanced.min_size, balanced.avg_size, balanced.max_size));
+
+        let large = config(r#"preset = "large""#).unwrap();
+        assert_eq!((65536, 262144, 1048576), (large.min_size, large.avg_size, large.max_size));
+
+        // Explicit values override the preset
+        let small = config("preset = \"small\"\nmax-size = 65536").unwrap();
+        assert_eq!((8192, 32768, 65536), (small.min_size, small.avg_size, small.max_size));
+
+        assert!(config(r#"preset = "small"
+min-size = 65536"#).is_err());
+    }
+
+    #[test]
+    fn test_home_response() {
+        let home_response = |http: &str| -> HomeResponse {
+            let toml = format!(r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+
+[http]
+{}
+"#, http);
+            parse_config("config.toml", &toml).http.home_response
+        };
+
+        assert_eq!(HomeResponse::Version, home_response(""));
+        assert_eq!(HomeResponse::Json, home_response(r#"home-response = "json""#));
+        assert_eq!(
+            HomeResponse::Message("OK".to_string()),
+            home_response(r#"home-response = { message = "OK" }"#),
+        );
+    }
+
+    #[test]
+    fn test_nar_reassembly_prefetch() {
+        let toml = |download: &str| format!(r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+
+[download]
+{}
+"#, download);
+
+        let prefetch = |download: &str, avg_chunk_size: usize| {
+            parse_config("config.toml", &toml(download)).download.nar_reassembly_prefetch(avg_chunk_size)
+        };
+        let adaptive = "nar-reassembly-prefetch-bytes = 1048576";
+        assert_eq!(2, prefetch("", 1024 * 1024));
+        assert_eq!(2, prefetch("", 65536));
+        assert_eq!(2, prefetch("", 1024));
+        assert_eq!(8, prefetch("nar-reassembly-prefetch = 8", 1024));
+        assert_eq!(2, prefetch(adaptive, 1024 * 1024));
+        assert_eq!(16, prefetch(adaptive, 65536));
+        assert_eq!(16, prefetch(adaptive, 1024));
+        assert_eq!(8, prefetch(&format!("{}\nnar-reassembly-prefetch = 8\nnar-reassembly-prefetch-max = 4", adaptive), 1024));
+        assert_eq!(4, prefetch("nar-reassembly-prefetch-bytes = 262144", 65536));
+
+        let toml = toml("nar-reassembly-prefetch = 0").replace("@SIGNING_KEY@", SIGNING_KEY);
+        assert!(parse::<ConfigInfoVersioned>(Path::new("config.toml"), &toml).is_err());
+    }
+
+    #[test]
+    fn test_compression_pool() {
+        let toml = |options: &str| format!(r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+{}
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+"#, options);
+
+        assert!(parse_config("config.toml", &toml("")).compression_pool.is_none());
+
+        let config = parse_config("config.toml", &toml("compression-pool = {}"));
+        assert!(config.compression_pool.unwrap().size >= 1);
+
+        let config = parse_config("config.toml", &toml("compression-pool = { size = 2 }"));
+        assert_eq!(2, config.compression_pool.unwrap().size);
+    }
+
+    #[test]
+    fn test_key_by() {
+        let toml = |storage: &str, index: &str| format!(r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+{}
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+{}
+"#, index, storage);
+
+        let config = parse_config("config.toml", &toml("", ""));
+        assert_eq!(KeyBy::StorePath, config.key_by);
+        assert!(matches!(config.storage, StorageConfig::Local(_)));
+
+        let index = r#"index = { type = "redb", path = "/tmp/nixcache.redb" }"#;
+        let config = parse_config("config.toml", &toml(r#"key-by = "nar-hash""#, index));
+        assert_eq!(KeyBy::NarHash, config.key_by);
+
+        let data = toml(r#"key-by = "nar-hash""#, "").replace("@SIGNING_KEY@", SIGNING_KEY);
+        let config = parse::<ConfigInfoVersioned>(Path::new("config.toml"), &data).unwrap();
+        assert!(Config::try_from(config).is_err());
+    }
+
+    #[test]
+    fn test_token_key() {
+        let toml = |options: &str| format!(r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+{}
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+"#, options);
+
+        let secret = r#"token-hs256-secret-base64 = "Qcy6MzWJJgREAjuSY06tk2KQTv9lsy4HwQAkSKGa/tE=""#;
+        let public_key = r#"token-rs256-public-key = """
+-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAELo/FZ0Y3nYX8E5OCwVRxRHWOgp+c
+uW5W1A6I6wjWDUGpPrRKkgkYIpeLM9mKLUVaZMe7skgALHYqbJ9jgYTq7g==
+-----END PUBLIC KEY-----
+""""#;
+
+        assert!(parse_config("config.toml", &toml("")).token_key.is_none());
+        assert!(matches!(parse_config("config.toml", &toml(secret)).token_key, Some(TokenKey::Hs256(_))));
+        assert!(matches!(parse_config("config.toml", &toml(public_key)).token_key, Some(TokenKey::Es256(_))));
+
+        let data = toml(&format!("{}\n{}", secret, public_key)).replace("@SIGNING_KEY@", SIGNING_KEY);
+        let config = parse::<ConfigInfoVersioned>(Path::new("config.toml"), &data).unwrap();
+        assert!(Config::try_from(config).is_err());
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let toml = r#"
+version = "v1"
+signing_key = "@SIGNING_KEY@"
+future-option = true
+
+[storage]
+type = "local"
+path = "/tmp/nixcache"
+future-storage-option = true
+
+[compression]
+type = "zstd"
+min-nar-size = 1024
+
+[upload]
+max-narinfo-size = 1048576
+future-upload-option = true
+"#.replace("@SIGNING_KEY@", SIGNING_KEY);
+
+        let path = Path::new("config.toml");
+        assert!(parse::<ConfigInfoVersioned>(path, &toml).is_err());
+
+        let (config, unknown_fields) = parse_lenient(path, &toml).unwrap();
+        assert_eq!(
+            vec![
+                "future-option".to_string(),
+                "storage.future-storage-option".to_string(),
+                "upload.future-upload-option".to_string(),
+            ],
+            unknown_fields,
+        );
+        assert_eq!(1024, Config::try_from(config).unwrap().compression_min_nar_size);
+    }
+}
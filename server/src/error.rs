@@ -1,3 +1,4 @@
+use std::any::Any;
 use std::fmt;
 use std::error::Error as StdError;
 use anyhow::{anyhow, Error as AnyError};
@@ -19,8 +20,20 @@ pub enum ErrorKind {
     InternalServerError,
     /// The URL you requested was not found.
     NotFound,
+    /// The resource was modified concurrently.
+    PreconditionFailed,
+    /// Too many concurrent requests.
+    TooManyRequests,
+    /// The server is handling too many requests.
+    Overloaded,
     /// Unauthorized.
     Unauthorized,
+    /// You do not have permission to do this.
+    Forbidden,
+    /// The server is in read-only mode.
+    ReadOnly,
+    /// The idempotency key was used for a different request.
+    IdempotencyKeyMismatch,
     /// Storage error: {0}
     StorageError(AnyError),
     /// General request error: {0}
@@ -40,7 +53,13 @@ impl ErrorKind {
         match self {
             Self::InternalServerError => self,
             Self::NotFound => self,
+            Self::PreconditionFailed => self,
+            Self::TooManyRequests => self,
+            Self::Overloaded => self,
             Self::Unauthorized => self,
+            Self::Forbidden => self,
+            Self::ReadOnly => self,
+            Self::IdempotencyKeyMismatch => self,
             Self::StorageError(_) => Self::InternalServerError,
             Self::RequestError(_) => self,
             Self::InvalidCompressionType { .. } => self,
@@ -54,7 +73,13 @@ impl ErrorKind {
         match self {
             Self::InternalServerError => "InternalServerError",
             Self::NotFound => "NotFound",
+            Self::PreconditionFailed => "PreconditionFailed",
+            Self::TooManyRequests => "TooManyRequests",
+            Self::Overloaded => "Overloaded",
             Self::Unauthorized => "Unauthorized",
+            Self::Forbidden => "Forbidden",
+            Self::ReadOnly => "ReadOnly",
+            Self::IdempotencyKeyMismatch => "IdempotencyKeyMismatch",
             Self::StorageError(_) => "StorageError",
             Self::RequestError(_) => "RequestError",
             Self::InvalidCompressionType { .. } => "InvalidCompressionType",
@@ -67,7 +92,13 @@ impl ErrorKind {
         match self {
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+            Self::IdempotencyKeyMismatch => StatusCode::UNPROCESSABLE_ENTITY,
             Self::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::RequestError(_) => StatusCode::BAD_REQUEST,
             Self::InvalidCompressionType { .. } => StatusCode::BAD_REQUEST,
@@ -103,6 +134,11 @@ impl ServerError {
     pub fn auth_error(error: JWTError) -> Self {
         ErrorKind::JWTError(error).into()
     }
+
+    /// Returns the kind of the error.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
 }
 impl fmt::Display for ServerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -142,3 +178,19 @@ impl IntoResponse for ServerError {
         (status_code, Json(error_response)).into_response()
     }
 }
+
+/// Turns a panic in a handler into an error response.
+///
+/// The panic is logged in the span of the request, and the client
+/// receives the same body as for any other internal error.
+pub fn panic_response(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+
+    tracing::error!("Request handler panicked: {}", message);
+
+    ServerError::from(ErrorKind::InternalServerError).into_response()
+}
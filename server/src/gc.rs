@@ -0,0 +1,241 @@
+//! Garbage collection of orphaned chunks.
+//!
+//! Chunks are normally deleted along with the last NAR referencing
+//! them. Interrupted uploads and failed deletions can still leave
+//! chunks behind that no NAR references. This finds them by reading
+//! every stored NAR and deletes them.
+//!
+//! Uploads store their chunks before the NAR, so a chunk that no NAR
+//! references may belong to an upload in progress. Chunks are only
+//! deleted once they are older than the grace period and have no
+//! recorded references (see `refs`). Uploads reference each chunk
+//! while holding the GC lock shared, as soon as they find it stored,
+//! so the deletion holds the lock exclusively to never remove a chunk
+//! between the check and the reference.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures::future::join_all;
+use tokio::sync::Notify;
+use tokio::time::{interval_at, sleep, Instant, MissedTickBehavior};
+
+use libnixstore::{StorePathHash, StorePathHashPolicy};
+use crate::api::binary_cache::download_uploaded_nar;
+use crate::error::{ErrorKind, ServerResult};
+use crate::refs;
+use crate::State;
+
+/// Time to wait for more chunks to delete before deleting a batch.
+const PENDING_DELETION_DELAY: Duration = Duration::from_secs(1);
+
+/// The result of a garbage collection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// The number of stored NARs.
+    pub nars: usize,
+    /// The number of stored chunks.
+    pub chunks: usize,
+    /// The number of chunks that were deleted.
+    pub chunks_deleted: usize,
+}
+
+/// Deletes the chunks that no NAR references.
+pub async fn collect(state: &State) -> ServerResult<GcReport> {
+    let grace_period = Duration::from_secs(state.config.garbage_collection.grace_period);
+    let cutoff = SystemTime::now().checked_sub(grace_period).unwrap_or(UNIX_EPOCH);
+    let backend = state.storage();
+
+    let chunks = backend.list_chunks().await?;
+    let mut report = GcReport {
+        chunks: chunks.len(),
+        ..Default::default()
+    };
+
+    let mut referenced = HashSet::new();
+    for name in backend.list_nars().await? {
+        // Uploads check the character set of any length of hash, so
+        // other names were not uploaded
+        let store_path_hash = match StorePathHash::new_with_policy(name, StorePathHashPolicy::AllowNonstandardLength) {
+            Ok(store_path_hash) => store_path_hash,
+            Err(e) => {
+                tracing::warn!("Skipping unexpected NAR: {}", e);
+                continue;
+            }
+        };
+        let nar = match download_uploaded_nar(&**backend, &store_path_hash).await {
+            Ok(nar) => nar,
+            // Deleted since it was listed
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound) => continue,
+            Err(e) => return Err(e),
+        };
+
+        referenced.extend(nar.chunk_names());
+        report.nars += 1;
+    }
+
+    let candidates: Vec<_> = chunks
+        .into_iter()
+        .filter(|chunk| chunk.last_modified < cutoff && !referenced.contains(&chunk.name))
+        .map(|chunk| chunk.name)
+        .collect();
+    if candidates.is_empty() {
+        return Ok(report);
+    }
+
+    let _guard = state.gc_lock.write().await;
+
+    let mut orphaned = Vec::new();
+    for chunk in candidates {
+        if !refs::is_referenced(state, &chunk).await? {
+            orphaned.push(chunk);
+        }
+    }
+
+    report.chunks_deleted = orphaned.len();
+    join_all(orphaned.into_iter().map(|chunk| backend.delete_chunk(chunk)))
+        .await
+        .into_iter()
+        .collect::<ServerResult<Vec<_>>>()?;
+
+    Ok(report)
+}
+
+/// Chunks stored by failed uploads, waiting to be deleted.
+///
+/// Deleting chunks holds the GC lock exclusively, which holds up all
+/// uploads. Instead of the failed requests, a background task deletes
+/// them in batches.
+#[derive(Debug, Default)]
+pub struct PendingDeletions {
+    chunks: Mutex<Vec<String>>,
+    notify: Notify,
+}
+
+impl PendingDeletions {
+    /// Queues chunks to be deleted.
+    pub fn push(&self, chunks: impl IntoIterator<Item = String>) {
+        let mut queue = self.chunks.lock().unwrap();
+        let len = queue.len();
+        queue.extend(chunks);
+
+        if queue.len() > len {
+            self.notify.notify_one();
+        }
+    }
+
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.chunks.lock().unwrap())
+    }
+}
+
+/// Deletes the queued chunks of failed uploads unless they are
+/// referenced by now.
+///
+/// Returns the number of chunks deleted. Failures are only logged
+/// since garbage collection removes leftover chunks eventually.
+pub async fn delete_pending(state: &State) -> usize {
+    let chunks = state.pending_deletions.take();
+    if chunks.is_empty() {
+        return 0;
+    }
+
+    // Uploads that reused the chunks have referenced them once we hold the lock
+    let _guard = state.gc_lock.write().await;
+    let backend = state.storage();
+    let mut deleted = 0;
+    for chunk in chunks {
+        let result = match refs::is_referenced(state, &chunk).await {
+            Ok(true) => continue,
+            Ok(false) => backend.delete_chunk(chunk.clone()).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => deleted += 1,
+            Err(e) => tracing::warn!("Failed to delete chunk {} of a failed upload: {}", chunk, e),
+        }
+    }
+
+    deleted
+}
+
+/// Deletes the queued chunks of failed uploads for as long as the
+/// server runs.
+pub async fn run_pending_deletions(state: Arc<State>) {
+    loop {
+        state.pending_deletions.notify.notified().await;
+
+        // Let other failed uploads queue their chunks for the same batch
+        sleep(PENDING_DELETION_DELAY).await;
+
+        let deleted = delete_pending(&state).await;
+        if deleted > 0 {
+            tracing::info!("Deleted {} chunks of failed uploads", deleted);
+        }
+    }
+}
+
+/// Collects garbage every `period` for as long as the server runs.
+pub async fn run_periodically(state: Arc<State>, period: Duration) {
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        match state.collect_garbage().await {
+            Ok(report) => tracing::info!(
+                "Garbage collection deleted {} of {} chunks ({} NARs)",
+                report.chunks_deleted,
+                report.chunks,
+                report.nars,
+            ),
+            Err(e) => tracing::warn!("Garbage collection failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::api::UploadedNar;
+    use crate::testing::{uploaded_chunk, TestState};
+
+    #[test]
+    fn test_collect() {
+        block_on(async {
+            let used = uploaded_chunk(b"used");
+            let orphaned = uploaded_chunk(b"orphaned");
+            let pending = uploaded_chunk(b"pending");
+
+            let gc_config = |grace_period: u64| format!("[garbage-collection]\ngrace-period = {}", grace_period);
+            let state = TestState::new(&gc_config(0)).await;
+            let backend = state.storage();
+
+            for chunk in [&used, &orphaned, &pending] {
+                let name = chunk.file_hash.to_typed_base32();
+                backend.upload_chunk(name, &mut Cursor::new(b"chunk")).await.unwrap();
+            }
+
+            UploadedNar::builder().chunk(used.clone()).store(&**backend).await;
+
+            // An upload in progress has recorded its references but not stored its NAR yet
+            refs::add_refs(&state, "nar-pending", [pending.file_hash.to_typed_base32()]).await.unwrap();
+
+            // Within the grace period
+            let report = state.reopen(&gc_config(3600)).await.collect_garbage().await.unwrap();
+            assert_eq!(GcReport { nars: 1, chunks: 3, chunks_deleted: 0 }, report);
+
+            let report = state.collect_garbage().await.unwrap();
+            assert_eq!(GcReport { nars: 1, chunks: 3, chunks_deleted: 1 }, report);
+
+            assert!(backend.chunk_exists(used.file_hash.to_typed_base32()).await.unwrap());
+            assert!(!backend.chunk_exists(orphaned.file_hash.to_typed_base32()).await.unwrap());
+            assert!(backend.chunk_exists(pending.file_hash.to_typed_base32()).await.unwrap());
+        });
+    }
+}
@@ -0,0 +1,136 @@
+//! Idempotency keys.
+//!
+//! Clients may set `Idempotency-Key` on uploads so that an upload
+//! retried after a timeout returns the result of the first attempt
+//! instead of being processed again. Results are kept in memory for
+//! a short time.
+//!
+//! Each key is tied to a fingerprint of its request, so that reusing
+//! a key for a different request is rejected instead of returning the
+//! result of the first one.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+use crate::error::{ErrorKind, ServerResult};
+
+/// The maximum length of an idempotency key.
+pub const MAX_KEY_LEN: usize = 255;
+
+/// A key's request.
+#[derive(Debug)]
+struct Entry<T> {
+    /// When the key was first seen.
+    inserted: Instant,
+    /// The fingerprint of the request.
+    fingerprint: String,
+    /// The result of the operation.
+    result: Arc<OnceCell<T>>,
+}
+
+impl<T> Entry<T> {
+    /// Returns whether a call is running or waiting for the operation.
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.result) > 1
+    }
+}
+
+/// Results of operations keyed by their idempotency key.
+#[derive(Debug)]
+pub struct IdempotencyKeys<T> {
+    entries: Mutex<HashMap<String, Entry<T>>>,
+    ttl: Duration,
+}
+
+impl<T: Clone> IdempotencyKeys<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Runs an operation unless one with the same key has succeeded.
+    ///
+    /// Concurrent calls with the same key wait for the first one to
+    /// finish. Failures are not recorded, so a call following a failed
+    /// one runs the operation again. A call whose fingerprint differs
+    /// from that of the key's earlier calls is rejected, unless they
+    /// all failed.
+    pub async fn run<F, Fut>(&self, key: String, fingerprint: String, f: F) -> ServerResult<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = ServerResult<T>>,
+    {
+        let cell = {
+            let now = Instant::now();
+            let mut entries = self.entries.lock().unwrap();
+
+            // Keep entries that are still in use
+            entries.retain(|_, entry| entry.in_use() || now.duration_since(entry.inserted) < self.ttl);
+
+            let entry = entries.entry(key).or_insert_with(|| Entry {
+                inserted: now,
+                fingerprint: fingerprint.clone(),
+                result: Arc::new(OnceCell::new()),
+            });
+
+            if entry.fingerprint != fingerprint {
+                if entry.in_use() || entry.result.initialized() {
+                    return Err(ErrorKind::IdempotencyKeyMismatch.into());
+                }
+
+                *entry = Entry {
+                    inserted: now,
+                    fingerprint,
+                    result: Arc::new(OnceCell::new()),
+                };
+            }
+
+            entry.result.clone()
+        };
+
+        cell.get_or_try_init(f).await.cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_test::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_idempotency_keys() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let runs = AtomicUsize::new(0);
+
+        let run = |key: &str, fingerprint: &str, ok: bool| {
+            let runs = &runs;
+            keys.run(key.to_string(), fingerprint.to_string(), move || async move {
+                let n = runs.fetch_add(1, Ordering::SeqCst);
+                if ok { Ok(n) } else { Err(ErrorKind::InternalServerError.into()) }
+            })
+        };
+
+        block_on(async {
+            // Failures are retried, even for another request
+            assert!(run("a", "x", false).await.is_err());
+            assert_eq!(1, run("a", "y", true).await.unwrap());
+
+            // Repeated keys return the first result
+            assert_eq!(1, run("a", "y", true).await.unwrap());
+            assert_eq!(2, run("b", "y", true).await.unwrap());
+
+            // Unless they are for another request
+            let err = run("a", "x", true).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::IdempotencyKeyMismatch));
+        });
+
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,528 @@
+//! Metadata index.
+//!
+//! The object store can only look up objects by name. The index
+//! holds metadata that needs other access patterns:
+//!
+//! - The NARs referencing each chunk
+//! - The NAR hash of each store path, and the reverse mapping
+//! - The last time each store path was accessed
+//!
+//! Access times are buffered in memory (see `AccessTimes`) so that
+//! downloads don't each write to the index.
+//!
+//! The index is optional. Without one, chunk references are stored
+//! as objects (see `refs`) and the other features are unavailable.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use redb::{
+    Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
+};
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+use crate::error::{ServerError, ServerResult};
+
+/// Chunk name -> Names of NARs referencing it.
+const CHUNK_REFS: MultimapTableDefinition<&str, &str> = MultimapTableDefinition::new("chunk_refs");
+
+/// Store path hash -> NAR hash.
+const NAR_HASHES: TableDefinition<&str, &str> = TableDefinition::new("nar_hashes");
+
+/// NAR hash -> Store path hashes.
+const NAR_HASH_PATHS: MultimapTableDefinition<&str, &str> = MultimapTableDefinition::new("nar_hash_paths");
+
+/// Store path hash -> Last access time, in seconds since the Unix epoch.
+const ACCESS_TIMES: TableDefinition<&str, u64> = TableDefinition::new("access_times");
+
+/// The chunk references and NAR hashes in an index.
+///
+/// Access times are not included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexSnapshot {
+    /// Chunk name -> Names of NARs referencing it.
+    pub chunk_refs: BTreeMap<String, BTreeSet<String>>,
+
+    /// Store path hash -> NAR hash.
+    pub nar_hashes: BTreeMap<String, String>,
+}
+
+/// Index configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum IndexConfig {
+    /// An embedded redb database.
+    #[serde(rename = "redb")]
+    Redb(RedbIndexConfig),
+}
+
+impl IndexConfig {
+    /// Returns how often buffered access times are written.
+    pub fn access_time_flush_interval(&self) -> Duration {
+        match self {
+            Self::Redb(config) => Duration::from_secs(config.access_time_flush_interval.get()),
+        }
+    }
+}
+
+/// redb index configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedbIndexConfig {
+    /// Path to the database file.
+    path: PathBuf,
+
+    /// How often to write the access times of store paths, in seconds.
+    ///
+    /// Access times recorded since the last write are lost if the
+    /// server stops. By default, they are written every minute.
+    #[serde(rename = "access-time-flush-interval")]
+    #[serde(default = "default_access_time_flush_interval")]
+    access_time_flush_interval: NonZeroU64,
+}
+
+#[async_trait::async_trait]
+pub trait Index: Send + Sync + Debug {
+    /// Records that a NAR references some chunks.
+    async fn add_chunk_refs(&self, nar: String, chunks: Vec<String>) -> ServerResult<()>;
+
+    /// Removes the references of a NAR to some chunks.
+    ///
+    /// Returns the chunks that are no longer referenced by any NAR.
+    async fn remove_chunk_refs(&self, nar: String, chunks: Vec<String>) -> ServerResult<Vec<String>>;
+
+    /// Returns whether any NAR references a chunk.
+    async fn has_chunk_refs(&self, chunk: String) -> ServerResult<bool>;
+
+    /// Records the NAR hash of a store path.
+    async fn set_nar_hash(&self, store_path_hash: String, nar_hash: String) -> ServerResult<()>;
+
+    /// Returns the NAR hash of a store path.
+    async fn get_nar_hash(&self, store_path_hash: String) -> ServerResult<Option<String>>;
+
+    /// Removes the NAR hash and access time of a deleted store path.
+    async fn remove_store_path(&self, store_path_hash: String) -> ServerResult<()>;
+
+    /// Returns the store paths with a NAR hash.
+    async fn get_store_paths(&self, nar_hash: String) -> ServerResult<Vec<String>>;
+
+    /// Records when store paths were last accessed, in seconds since
+    /// the Unix epoch.
+    async fn set_access_times(&self, times: Vec<(String, u64)>) -> ServerResult<()>;
+
+    /// Returns the last time a store path was accessed, in seconds
+    /// since the Unix epoch.
+    async fn last_accessed(&self, store_path_hash: String) -> ServerResult<Option<u64>>;
+
+    /// Returns all chunk references and NAR hashes.
+    async fn snapshot(&self) -> ServerResult<IndexSnapshot>;
+
+    /// Replaces all chunk references and NAR hashes.
+    async fn restore(&self, snapshot: IndexSnapshot) -> ServerResult<()>;
+}
+
+/// Access times that are not written to the index yet.
+///
+/// Downloads record access times here instead of writing them to
+/// the index one by one, and `run_access_time_flush` writes them
+/// in one transaction periodically.
+#[derive(Debug, Default)]
+pub struct AccessTimes {
+    times: Mutex<HashMap<String, u64>>,
+}
+
+impl AccessTimes {
+    /// Records that a store path was accessed now.
+    pub fn touch(&self, store_path_hash: String) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+
+        self.times.lock().unwrap().insert(store_path_hash, now);
+    }
+
+    /// Drops the access time of a deleted store path.
+    pub fn forget(&self, store_path_hash: &str) {
+        self.times.lock().unwrap().remove(store_path_hash);
+    }
+
+    /// Writes the access times to the index.
+    ///
+    /// Returns the number of access times written. If writing fails,
+    /// the access times are kept for the next attempt.
+    pub async fn flush(&self, index: &dyn Index) -> ServerResult<usize> {
+        let times: Vec<_> = std::mem::take(&mut *self.times.lock().unwrap())
+            .into_iter()
+            .collect();
+        if times.is_empty() {
+            return Ok(0);
+        }
+
+        let len = times.len();
+        if let Err(e) = index.set_access_times(times.clone()).await {
+            // Paths accessed since have newer times
+            let mut pending = self.times.lock().unwrap();
+            for (store_path_hash, time) in times {
+                pending.entry(store_path_hash).or_insert(time);
+            }
+            return Err(e);
+        }
+
+        Ok(len)
+    }
+}
+
+/// Writes buffered access times to the index for as long as the
+/// server runs.
+pub async fn run_access_time_flush(index: Arc<dyn Index>, access_times: Arc<AccessTimes>, period: Duration) {
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = access_times.flush(&*index).await {
+            tracing::warn!("Failed to record access times: {}", e);
+        }
+    }
+}
+
+/// Opens the index.
+pub async fn open(config: &IndexConfig) -> ServerResult<Arc<dyn Index>> {
+    match config {
+        IndexConfig::Redb(config) => {
+            let index = RedbIndex::open(config.path.clone()).await?;
+            Ok(Arc::new(index))
+        }
+    }
+}
+
+/// An index in an embedded redb database.
+#[derive(Clone)]
+pub struct RedbIndex {
+    db: Arc<Database>,
+}
+impl Debug for RedbIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedbIndex").finish()
+    }
+}
+impl RedbIndex {
+    /// Opens or creates the database.
+    pub async fn open(path: PathBuf) -> ServerResult<Self> {
+        spawn_blocking(move || Self::open_blocking(&path))
+            .await
+            .unwrap()
+    }
+
+    fn open_blocking(path: &Path) -> ServerResult<Self> {
+        let db = Database::create(path).map_err(db_error)?;
+
+        // Create all tables so reads never fail on missing ones
+        let txn = db.begin_write().map_err(db_error)?;
+        txn.open_multimap_table(CHUNK_REFS).map_err(db_error)?;
+        txn.open_table(NAR_HASHES).map_err(db_error)?;
+        txn.open_multimap_table(NAR_HASH_PATHS).map_err(db_error)?;
+        txn.open_table(ACCESS_TIMES).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Runs a blocking operation on the database.
+    async fn run<T, F>(&self, f: F) -> ServerResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, redb::Error> + Send + 'static,
+    {
+        let db = self.db.clone();
+        spawn_blocking(move || f(&db))
+            .await
+            .unwrap()
+            .map_err(db_error)
+    }
+}
+
+#[async_trait::async_trait]
+impl Index for RedbIndex {
+    async fn add_chunk_refs(&self, nar: String, chunks: Vec<String>) -> ServerResult<()> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut table = txn.open_multimap_table(CHUNK_REFS)?;
+                for chunk in &chunks {
+                    table.insert(chunk.as_str(), nar.as_str())?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_chunk_refs(&self, nar: String, chunks: Vec<String>) -> ServerResult<Vec<String>> {
+        self.run(move |db| {
+            let mut orphaned = Vec::new();
+
+            let txn = db.begin_write()?;
+            {
+                let mut table = txn.open_multimap_table(CHUNK_REFS)?;
+                for chunk in chunks {
+                    if !table.remove(chunk.as_str(), nar.as_str())? {
+                        continue;
+                    }
+                    if table.get(chunk.as_str())?.next().is_none() {
+                        orphaned.push(chunk);
+                    }
+                }
+            }
+            txn.commit()?;
+
+            Ok(orphaned)
+        })
+        .await
+    }
+
+    async fn has_chunk_refs(&self, chunk: String) -> ServerResult<bool> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_multimap_table(CHUNK_REFS)?;
+            let referenced = table.get(chunk.as_str())?.next().is_some();
+            Ok(referenced)
+        })
+        .await
+    }
+
+    async fn set_nar_hash(&self, store_path_hash: String, nar_hash: String) -> ServerResult<()> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut nar_hashes = txn.open_table(NAR_HASHES)?;
+                let mut nar_hash_paths = txn.open_multimap_table(NAR_HASH_PATHS)?;
+
+                let old = nar_hashes
+                    .insert(store_path_hash.as_str(), nar_hash.as_str())?
+                    .map(|old| old.value().to_string());
+                if let Some(old) = old {
+                    nar_hash_paths.remove(old.as_str(), store_path_hash.as_str())?;
+                }
+                nar_hash_paths.insert(nar_hash.as_str(), store_path_hash.as_str())?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_nar_hash(&self, store_path_hash: String) -> ServerResult<Option<String>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(NAR_HASHES)?;
+            let nar_hash = table
+                .get(store_path_hash.as_str())?
+                .map(|v| v.value().to_string());
+            Ok(nar_hash)
+        })
+        .await
+    }
+
+    async fn remove_store_path(&self, store_path_hash: String) -> ServerResult<()> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut nar_hashes = txn.open_table(NAR_HASHES)?;
+                let mut nar_hash_paths = txn.open_multimap_table(NAR_HASH_PATHS)?;
+                let mut access_times = txn.open_table(ACCESS_TIMES)?;
+
+                let old = nar_hashes
+                    .remove(store_path_hash.as_str())?
+                    .map(|old| old.value().to_string());
+                if let Some(old) = old {
+                    nar_hash_paths.remove(old.as_str(), store_path_hash.as_str())?;
+                }
+                access_times.remove(store_path_hash.as_str())?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_store_paths(&self, nar_hash: String) -> ServerResult<Vec<String>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_multimap_table(NAR_HASH_PATHS)?;
+            let paths = table
+                .get(nar_hash.as_str())?
+                .map(|v| v.map(|v| v.value().to_string()))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(paths)
+        })
+        .await
+    }
+
+    async fn set_access_times(&self, times: Vec<(String, u64)>) -> ServerResult<()> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let mut table = txn.open_table(ACCESS_TIMES)?;
+                for (store_path_hash, time) in &times {
+                    table.insert(store_path_hash.as_str(), time)?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn last_accessed(&self, store_path_hash: String) -> ServerResult<Option<u64>> {
+        self.run(move |db| {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(ACCESS_TIMES)?;
+            let time = table
+                .get(store_path_hash.as_str())?
+                .map(|v| v.value());
+            Ok(time)
+        })
+        .await
+    }
+
+    async fn snapshot(&self) -> ServerResult<IndexSnapshot> {
+        self.run(move |db| {
+            let mut snapshot = IndexSnapshot::default();
+
+            let txn = db.begin_read()?;
+            for entry in txn.open_multimap_table(CHUNK_REFS)?.iter()? {
+                let (chunk, nars) = entry?;
+                let nars = nars
+                    .map(|nar| nar.map(|nar| nar.value().to_string()))
+                    .collect::<Result<_, _>>()?;
+                snapshot.chunk_refs.insert(chunk.value().to_string(), nars);
+            }
+            for entry in txn.open_table(NAR_HASHES)?.iter()? {
+                let (store_path_hash, nar_hash) = entry?;
+                snapshot.nar_hashes.insert(store_path_hash.value().to_string(), nar_hash.value().to_string());
+            }
+
+            Ok(snapshot)
+        })
+        .await
+    }
+
+    async fn restore(&self, snapshot: IndexSnapshot) -> ServerResult<()> {
+        self.run(move |db| {
+            let txn = db.begin_write()?;
+            txn.delete_multimap_table(CHUNK_REFS)?;
+            txn.delete_table(NAR_HASHES)?;
+            txn.delete_multimap_table(NAR_HASH_PATHS)?;
+            {
+                let mut chunk_refs = txn.open_multimap_table(CHUNK_REFS)?;
+                for (chunk, nars) in &snapshot.chunk_refs {
+                    for nar in nars {
+                        chunk_refs.insert(chunk.as_str(), nar.as_str())?;
+                    }
+                }
+
+                let mut nar_hashes = txn.open_table(NAR_HASHES)?;
+                let mut nar_hash_paths = txn.open_multimap_table(NAR_HASH_PATHS)?;
+                for (store_path_hash, nar_hash) in &snapshot.nar_hashes {
+                    nar_hashes.insert(store_path_hash.as_str(), nar_hash.as_str())?;
+                    nar_hash_paths.insert(nar_hash.as_str(), store_path_hash.as_str())?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn db_error(error: impl Into<redb::Error>) -> ServerError {
+    ServerError::storage_error(error.into())
+}
+
+fn default_access_time_flush_interval() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_redb_index() {
+        let dir = TestDir::new("index");
+        let path = dir.path().join("index.redb");
+
+        block_on(async {
+            let index = RedbIndex::open(path.clone()).await.unwrap();
+            let chunks = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+            index.add_chunk_refs("nar-a".to_string(), chunks(&["x", "y"])).await.unwrap();
+            index.add_chunk_refs("nar-b".to_string(), chunks(&["y"])).await.unwrap();
+
+            let orphaned = index.remove_chunk_refs("nar-a".to_string(), chunks(&["x", "y"])).await.unwrap();
+            assert_eq!(chunks(&["x"]), orphaned);
+            assert!(!index.has_chunk_refs("x".to_string()).await.unwrap());
+            assert!(index.has_chunk_refs("y".to_string()).await.unwrap());
+
+            index.set_nar_hash("path-a".to_string(), "hash-1".to_string()).await.unwrap();
+            index.set_nar_hash("path-b".to_string(), "hash-1".to_string()).await.unwrap();
+            index.set_nar_hash("path-b".to_string(), "hash-2".to_string()).await.unwrap();
+            assert_eq!(Some("hash-2".to_string()), index.get_nar_hash("path-b".to_string()).await.unwrap());
+            assert_eq!(chunks(&["path-a"]), index.get_store_paths("hash-1".to_string()).await.unwrap());
+
+            assert_eq!(None, index.last_accessed("path-a".to_string()).await.unwrap());
+            index.set_access_times(vec![("path-a".to_string(), 1000)]).await.unwrap();
+            assert_eq!(Some(1000), index.last_accessed("path-a".to_string()).await.unwrap());
+
+            let mut snapshot = index.snapshot().await.unwrap();
+            assert_eq!(chunks(&["nar-b"]), snapshot.chunk_refs["y"].iter().cloned().collect::<Vec<_>>());
+            assert_eq!(2, snapshot.nar_hashes.len());
+
+            snapshot.chunk_refs.remove("y");
+            snapshot.nar_hashes.insert("path-a".to_string(), "hash-3".to_string());
+            index.restore(snapshot.clone()).await.unwrap();
+            assert_eq!(snapshot, index.snapshot().await.unwrap());
+            assert_eq!(chunks(&["path-a"]), index.get_store_paths("hash-3".to_string()).await.unwrap());
+            assert!(index.get_store_paths("hash-1".to_string()).await.unwrap().is_empty());
+            assert!(index.last_accessed("path-a".to_string()).await.unwrap().is_some());
+
+            index.remove_store_path("path-a".to_string()).await.unwrap();
+            assert_eq!(None, index.get_nar_hash("path-a".to_string()).await.unwrap());
+            assert!(index.get_store_paths("hash-3".to_string()).await.unwrap().is_empty());
+            assert_eq!(None, index.last_accessed("path-a".to_string()).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_access_times() {
+        let dir = TestDir::new("index");
+        let path = dir.path().join("index.redb");
+
+        block_on(async {
+            let index = RedbIndex::open(path).await.unwrap();
+            let access_times = AccessTimes::default();
+
+            access_times.touch("path-a".to_string());
+            access_times.touch("path-a".to_string());
+            access_times.touch("path-b".to_string());
+            access_times.forget("path-b");
+            assert_eq!(None, index.last_accessed("path-a".to_string()).await.unwrap());
+
+            assert_eq!(1, access_times.flush(&index).await.unwrap());
+            assert!(index.last_accessed("path-a".to_string()).await.unwrap().is_some());
+            assert_eq!(None, index.last_accessed("path-b".to_string()).await.unwrap());
+            assert_eq!(0, access_times.flush(&index).await.unwrap());
+        });
+    }
+}
@@ -8,20 +8,53 @@ pub mod nix_manifest;
 pub mod stream;
 pub mod access;
 pub mod finally;
+pub mod compression;
+pub mod telemetry;
+pub mod recompress;
+pub mod reindex;
+pub mod self_test;
+pub mod refs;
+pub mod index;
+pub mod limits;
+pub mod nar_listing;
+pub mod idempotency;
+pub mod gc;
+
+#[cfg(test)]
+pub(crate) mod testing;
 
 use anyhow::Result;
 use std::sync::Arc;
-use axum::{routing::get, Server, Router, extract::Extension, http::Uri};
+use std::time::Duration;
+use tokio::sync::{RwLock, Semaphore};
+use axum::{
+    routing::get, Server, Router, Json, extract::Extension, BoxError,
+    error_handling::HandleErrorLayer, middleware::FromExtractorLayer,
+    http::{HeaderName, HeaderValue, Uri, Response},
+    response::IntoResponse,
+};
+use serde_json::json;
+use tower::ServiceBuilder;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
 use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::trace::TraceLayer;
 
-use crate::config::{Config, StorageConfig};
-use crate::error::{ErrorKind, ServerResult};
+use common::signing::is_conventional_name;
+use crate::config::{Config, HomeResponse, StorageConfig};
+use crate::error::{panic_response, ErrorKind, ServerError, ServerResult};
 use crate::storage::{
     StorageBackend,
-    local::LocalBackend, s3::S3Backend,
+    gcs::GcsBackend, local::LocalBackend, s3::S3Backend, webdav::WebDavBackend,
 };
-use crate::access::RequireAuth;
+use crate::access::{AnyScope, Delete, Pull, Push, RequireAuth};
+use crate::gc::GcReport;
+use crate::index::Index;
+use crate::limits::KeyedSemaphore;
+use crate::narinfo::cache::NarInfoCache;
+use crate::idempotency::IdempotencyKeys;
+use common::v1::upload_path::Response as UploadPathResponse;
 
 /// Global server state.
 #[derive(Debug, Clone)]
@@ -30,52 +63,158 @@ pub struct State {
     config: Config,
     /// Handle to the storage backend.
     storage: Arc<Box<dyn StorageBackend>>,
+    /// Permits to compress on the blocking thread pool.
+    ///
+    /// `None` if compression runs on the async executor.
+    compression_pool: Option<Arc<Semaphore>>,
+    /// The metadata index, if configured.
+    index: Option<Arc<dyn Index>>,
+    /// Access times to write to the index.
+    access_times: Arc<index::AccessTimes>,
+    /// Concurrent uploads per subject.
+    upload_limits: Arc<KeyedSemaphore>,
+    /// Recently served narinfos, if enabled.
+    narinfo_cache: Option<Arc<NarInfoCache>>,
+    /// Results of recent uploads by idempotency key.
+    upload_idempotency_keys: Arc<IdempotencyKeys<UploadPathResponse>>,
+    /// Chunks that may be buffered for reassembly, if limited.
+    reassembly_budget: Option<Arc<Semaphore>>,
+    /// Held for reading by uploads while they reference chunks, and
+    /// for writing while chunks are deleted.
+    gc_lock: Arc<RwLock<()>>,
+    /// Chunks of failed uploads to delete in the background.
+    pending_deletions: Arc<gc::PendingDeletions>,
 }
 impl State {
     async fn new(config: Config) -> Result<Arc<Self>> {
-        let storage = Arc::new(match &config.storage {
-            StorageConfig::Local(config) => {
-                let backend = LocalBackend::new(config.clone()).await?;
-                let boxed: Box<dyn StorageBackend> = Box::new(backend);
-                boxed
-            },
-            StorageConfig::S3(config) => {
-                let backend = S3Backend::new(config.clone()).await?;
-                let boxed: Box<dyn StorageBackend> = Box::new(backend);
-                boxed
-            },
-        });
+        let storage = open_storage(&config.storage).await?;
+
+        let compression_pool = config.compression_pool.as_ref().map(|c| Arc::new(Semaphore::new(c.size)));
+
+        let index = match &config.index {
+            Some(index_config) => Some(index::open(index_config).await?),
+            None => None,
+        };
+
+        let narinfo_cache = config.narinfo_cache.as_ref().map(|c| Arc::new(NarInfoCache::new(c)));
+        let reassembly_budget = reassembly_budget(&config);
+        let upload_idempotency_keys = Arc::new(IdempotencyKeys::new(
+            Duration::from_secs(config.upload.idempotency_key_ttl),
+        ));
 
         Ok(Arc::new(Self {
             config,
             storage,
+            compression_pool,
+            index,
+            access_times: Arc::new(index::AccessTimes::default()),
+            upload_limits: Arc::new(KeyedSemaphore::default()),
+            narinfo_cache,
+            upload_idempotency_keys,
+            reassembly_budget,
+            gc_lock: Arc::new(RwLock::new(())),
+            pending_deletions: Arc::new(gc::PendingDeletions::default()),
         }))
     }
     /// Returns a handle to the storage backend.
     fn storage(&self) -> Arc<Box<dyn StorageBackend>> {
         Arc::clone(&self.storage)
     }
+    /// Deletes the chunks that no NAR references.
+    ///
+    /// This can run while the server is serving requests.
+    pub async fn collect_garbage(&self) -> ServerResult<GcReport> {
+        if self.config.read_only {
+            return Err(ErrorKind::ReadOnly.into());
+        }
+
+        gc::collect(self).await
+    }
+}
+
+/// Opens the storage backend.
+async fn open_storage(config: &StorageConfig) -> Result<Arc<Box<dyn StorageBackend>>> {
+    Ok(Arc::new(match config {
+        StorageConfig::Local(config) => {
+            let backend = LocalBackend::new(config.clone()).await?;
+            let boxed: Box<dyn StorageBackend> = Box::new(backend);
+            boxed
+        },
+        StorageConfig::S3(config) => {
+            let backend = S3Backend::new(config.clone()).await?;
+            let boxed: Box<dyn StorageBackend> = Box::new(backend);
+            boxed
+        },
+        StorageConfig::Gcs(config) => {
+            let backend = GcsBackend::new(config.clone()).await?;
+            let boxed: Box<dyn StorageBackend> = Box::new(backend);
+            boxed
+        },
+        StorageConfig::WebDav(config) => {
+            let backend = WebDavBackend::new(config.clone()).await?;
+            let boxed: Box<dyn StorageBackend> = Box::new(backend);
+            boxed
+        },
+    }))
+}
+
+/// Returns the permits for chunks buffered for reassembly, if limited.
+fn reassembly_budget(config: &Config) -> Option<Arc<Semaphore>> {
+    config.download.reassembly_memory_budget.map(|budget| {
+        let chunks = (budget / config.chunking.max_size).max(1);
+        Arc::new(Semaphore::new(chunks))
+    })
 }
 
 /// Runs the API server.
 pub async fn run_api_server(config: Config) -> Result<()> {
     tracing::info!("Starting API server...");
 
-    if config.token_hs256_secret.is_none() {
+    if config.token_key.is_none() {
         tracing::warn!("Authentication is disabled, anyone will be able to access this cache.");
     }
 
+    let key_name = config.keypair.name();
+    if !is_conventional_name(key_name) {
+        tracing::warn!(
+            "Signing key name '{}' does not follow the '<host>-<n>' convention. Make sure clients trust a key with this exact name.",
+            key_name
+        );
+    }
+
+    if config.token_key.is_some() && !config.require_auth_for_reads {
+        tracing::info!("Reads are public, only the API requires authentication.");
+    }
+
+    if config.read_only {
+        tracing::warn!("Read-only mode is enabled, uploads and deletions will be rejected.");
+    }
+
     let listen = config.listen;
     let state = State::new(config).await?;
 
-    let rest = Router::new()
-        .merge(api::router())
-        .fallback(fallback)
-        .layer(axum::middleware::from_extractor_with_state::<RequireAuth, Arc<State>>(Arc::clone(&state)))
-        .route("/", get(home))
-        .layer(Extension(state))
-        .layer(TraceLayer::new_for_http())
-        .layer(CatchPanicLayer::new());
+    match state.config.garbage_collection.interval {
+        Some(_) if state.config.read_only => {
+            tracing::warn!("Garbage collection is disabled in read-only mode.");
+        }
+        Some(interval) => {
+            let period = Duration::from_secs(interval.get());
+            tokio::spawn(gc::run_periodically(Arc::clone(&state), period));
+        }
+        None => {}
+    }
+
+    tokio::spawn(gc::run_pending_deletions(Arc::clone(&state)));
+
+    if let (Some(index), Some(index_config)) = (&state.index, &state.config.index) {
+        tokio::spawn(index::run_access_time_flush(
+            Arc::clone(index),
+            Arc::clone(&state.access_times),
+            index_config.access_time_flush_interval(),
+        ));
+    }
+
+    let rest = router(state);
 
     tracing::info!("Listening on {:?}...", listen);
     Server::bind(&listen).serve(rest.into_make_service()).await?;
@@ -83,9 +222,75 @@ pub async fn run_api_server(config: Config) -> Result<()> {
     Ok(())
 }
 
+/// Builds the application router.
+///
+/// Writes always require authentication, while reads only do with
+/// `require-auth-for-reads`. Uploads and compression benchmarks require
+/// the `push` scope, while listings and authenticated reads require the
+/// `pull` scope, and deletions the `delete` scope. Deletions are refused
+/// if no token key is configured. In read-only mode, uploads and
+/// deletions are rejected after authentication. With
+/// `http.max-concurrent-requests`, requests beyond the limit are
+/// rejected before anything else.
+fn router(state: Arc<State>) -> Router {
+    let mut reads = api::read_router();
+    if state.config.require_auth_for_reads {
+        reads = reads.layer(require_auth::<Pull>(&state));
+    }
+
+    let noindex = state.config.robots.noindex;
+    let max_concurrent_requests = state.config.http.max_concurrent_requests;
+
+    let mut router = Router::new()
+        .merge(api::push_router().layer(require_auth::<Push>(&state)))
+        .merge(api::pull_router().layer(require_auth::<Pull>(&state)))
+        .merge(api::delete_router().layer(require_auth::<Delete>(&state)))
+        .merge(api::write_router().layer(require_auth::<AnyScope>(&state)))
+        .merge(reads)
+        .route("/", get(home))
+        .fallback(fallback);
+
+    // The semaphore is shared by all routes the layer is applied to
+    if let Some(max) = max_concurrent_requests {
+        router = router.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    ServerError::from(ErrorKind::Overloaded)
+                }))
+                .layer(LoadShedLayer::new())
+                .layer(GlobalConcurrencyLimitLayer::new(max.get())),
+        );
+    }
+
+    // Health checks must not fail because the server is busy
+    router
+        .merge(api::public_router())
+        .layer(Extension(state))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            HeaderName::from_static("x-robots-tag"),
+            move |_: &Response<_>| noindex.then(|| HeaderValue::from_static("noindex")),
+        ))
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::make_request_span))
+}
+
+/// Requires a token that was granted the scope of `S`.
+fn require_auth<S>(state: &Arc<State>) -> FromExtractorLayer<RequireAuth<S>, Arc<State>> {
+    axum::middleware::from_extractor_with_state(Arc::clone(state))
+}
+
 /// The home route.
-async fn home() -> String {
-    format!("Nixcache {}", env!("CARGO_PKG_VERSION"))
+async fn home(Extension(state): Extension<Arc<State>>) -> axum::response::Response {
+    let version = env!("CARGO_PKG_VERSION");
+
+    match &state.config.http.home_response {
+        HomeResponse::Version => format!("Nixcache {}", version).into_response(),
+        HomeResponse::Json => Json(json!({
+            "status": "ok",
+            "version": version,
+        })).into_response(),
+        HomeResponse::Message(message) => message.clone().into_response(),
+    }
 }
 
 /// The fallback route.
@@ -93,3 +298,464 @@ async fn home() -> String {
 async fn fallback(_: Uri) -> ServerResult<()> {
     Err(ErrorKind::NotFound.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use axum::body::Body;
+    use axum::http::{Method, Request, StatusCode};
+    use jwt_simple::prelude::{Claims, Duration as JwtDuration};
+    use tokio_test::block_on;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing::{request_for, TestState, TOKEN_SECRET};
+    use auth::{MACLike, Scope, TokenClaims};
+    use common::v1::get_missing_paths::{Request as GetMissingPathsRequest, Response as GetMissingPathsResponse};
+    use common::v1::header;
+    use common::v1::upload_path::Request as UploadPathRequest;
+    use libnixstore::{StorePathHash, StorePathHashPolicy};
+
+    /// A router over test state, which is removed on drop.
+    struct TestRouter {
+        router: Router,
+        _state: TestState,
+    }
+
+    impl std::ops::Deref for TestRouter {
+        type Target = Router;
+
+        fn deref(&self) -> &Router {
+            &self.router
+        }
+    }
+
+    async fn test_router(require_auth_for_reads: bool) -> TestRouter {
+        test_router_with(&format!("require-auth-for-reads = {}", require_auth_for_reads)).await
+    }
+
+    async fn test_router_with(options: &str) -> TestRouter {
+        let state = TestState::new(&format!(r#"
+listen = "127.0.0.1:8080"
+token-hs256-secret-base64 = "{}"
+{}
+"#, TOKEN_SECRET, options)).await;
+
+        TestRouter {
+            router: router(Arc::clone(&state)),
+            _state: state,
+        }
+    }
+
+    /// The credentials sent with a request.
+    #[derive(Debug, Clone, Copy)]
+    enum Token {
+        None,
+        Valid,
+        /// A valid token granted only the `delete` scope.
+        ValidDelete,
+        /// A valid token granted only some scopes.
+        Scoped(&'static [Scope]),
+        Invalid,
+    }
+
+    /// Returns the bearer token to send.
+    fn token(token: Token) -> Option<String> {
+        match token {
+            Token::None => None,
+            Token::Valid => {
+                let key = auth::decode_token_hs256_secret_base64(TOKEN_SECRET).unwrap();
+                let claims = Claims::with_custom_claims(TokenClaims::default(), JwtDuration::from_hours(1));
+                Some(key.authenticate(claims).unwrap())
+            }
+            Token::ValidDelete => {
+                let key = auth::decode_token_hs256_secret_base64(TOKEN_SECRET).unwrap();
+                let custom = TokenClaims { scopes: Some(vec![Scope::Delete]), ..Default::default() };
+                let claims = Claims::with_custom_claims(custom, JwtDuration::from_hours(1));
+                Some(key.authenticate(claims).unwrap())
+            }
+            Token::Scoped(scopes) => {
+                let key = auth::decode_token_hs256_secret_base64(TOKEN_SECRET).unwrap();
+                let custom = TokenClaims { scopes: Some(scopes.to_vec()), ..Default::default() };
+                let claims = Claims::with_custom_claims(custom, JwtDuration::from_hours(1));
+                Some(key.authenticate(claims).unwrap())
+            }
+            Token::Invalid => Some("not-a-token".to_string()),
+        }
+    }
+
+    async fn status(router: &Router, method: Method, uri: &str, token: Token) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = self::token(token) {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+
+        router.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    /// Returns the status of each route for a token.
+    async fn statuses(router: &Router, routes: &[(Method, &str)], token: Token) -> Vec<StatusCode> {
+        let mut statuses = Vec::new();
+        for (method, uri) in routes {
+            statuses.push(status(router, method.clone(), uri, token).await);
+        }
+        statuses
+    }
+
+    const READS: &[(Method, &str)] = &[
+        (Method::GET, "/nix-cache-info"),
+        (Method::GET, "/00000000000000000000000000000000.narinfo"),
+        (Method::HEAD, "/00000000000000000000000000000000.narinfo"),
+        (Method::GET, "/00000000000000000000000000000000.ls"),
+        (Method::GET, "/nar/00000000000000000000000000000000.nar"),
+    ];
+
+    /// The statuses of `READS` on an empty cache when let through.
+    const READ_STATUSES: &[StatusCode] = &[
+        StatusCode::OK,
+        StatusCode::NOT_FOUND,
+        StatusCode::NOT_FOUND,
+        StatusCode::NOT_FOUND,
+        StatusCode::NOT_FOUND,
+    ];
+
+    const WRITES: &[(Method, &str)] = &[
+        (Method::PUT, "/_api/v1/upload-path"),
+        (Method::GET, "/_api/v1/cache-config"),
+        (Method::GET, "/_api/v1/list-paths"),
+        (Method::GET, "/_api/v1/stats"),
+        (Method::POST, "/_api/v1/admin/compression-bench"),
+        (Method::DELETE, "/_api/v1/path/00000000000000000000000000000000"),
+    ];
+
+    /// The statuses of `WRITES` on an empty cache with `Token::Valid`.
+    ///
+    /// The requests have no body, and the token lacks the `delete` scope.
+    const WRITE_STATUSES: &[StatusCode] = &[
+        StatusCode::BAD_REQUEST,
+        StatusCode::OK,
+        StatusCode::OK,
+        StatusCode::OK,
+        StatusCode::BAD_REQUEST,
+        StatusCode::FORBIDDEN,
+    ];
+
+    const PUBLIC: &[(Method, &str)] = &[
+        (Method::GET, "/"),
+        (Method::GET, "/robots.txt"),
+        (Method::GET, "/healthz"),
+        (Method::GET, "/readyz"),
+    ];
+
+    #[test]
+    fn test_public_reads() {
+        block_on(async {
+            let router = test_router(false).await;
+
+            assert_eq!(StatusCode::OK, status(&router, Method::GET, "/nix-cache-info", Token::None).await);
+
+            assert_eq!(READ_STATUSES, statuses(&router, READS, Token::None).await);
+            assert_eq!(READ_STATUSES, statuses(&router, READS, Token::Valid).await);
+            assert_eq!(vec![StatusCode::UNAUTHORIZED; 6], statuses(&router, WRITES, Token::None).await);
+            assert_eq!(vec![StatusCode::UNAUTHORIZED; 6], statuses(&router, WRITES, Token::Invalid).await);
+            assert_eq!(WRITE_STATUSES, statuses(&router, WRITES, Token::Valid).await);
+            assert_eq!(vec![StatusCode::OK; 4], statuses(&router, PUBLIC, Token::None).await);
+        });
+    }
+
+    #[test]
+    fn test_require_auth_for_reads() {
+        block_on(async {
+            let router = test_router(true).await;
+
+            assert_eq!(StatusCode::OK, status(&router, Method::GET, "/nix-cache-info", Token::Valid).await);
+
+            assert_eq!(vec![StatusCode::UNAUTHORIZED; 5], statuses(&router, READS, Token::None).await);
+            assert_eq!(vec![StatusCode::UNAUTHORIZED; 5], statuses(&router, READS, Token::Invalid).await);
+            assert_eq!(READ_STATUSES, statuses(&router, READS, Token::Valid).await);
+            assert_eq!(vec![StatusCode::FORBIDDEN; 5], statuses(&router, READS, Token::ValidDelete).await);
+            assert_eq!(vec![StatusCode::UNAUTHORIZED; 6], statuses(&router, WRITES, Token::None).await);
+            assert_eq!(WRITE_STATUSES, statuses(&router, WRITES, Token::Valid).await);
+            assert_eq!(vec![StatusCode::OK; 4], statuses(&router, PUBLIC, Token::None).await);
+        });
+    }
+
+    #[test]
+    fn test_store_path_hash_policy() {
+        block_on(async {
+            let strict = test_router(false).await;
+            let relaxed = test_router_with("allow-nonstandard-hash-length = true").await;
+
+            let short = "/0000000000000000.narinfo";
+            let standard = "/00000000000000000000000000000000.narinfo";
+            let invalid = "/eeeeeeeeeeeeeeee.narinfo";
+
+            assert_eq!(StatusCode::BAD_REQUEST, status(&strict, Method::GET, short, Token::None).await);
+            assert_eq!(StatusCode::NOT_FOUND, status(&strict, Method::GET, standard, Token::None).await);
+
+            assert_eq!(StatusCode::NOT_FOUND, status(&relaxed, Method::GET, short, Token::None).await);
+            assert_eq!(StatusCode::NOT_FOUND, status(&relaxed, Method::GET, standard, Token::None).await);
+            assert_eq!(StatusCode::BAD_REQUEST, status(&relaxed, Method::GET, invalid, Token::None).await);
+
+            // Uploads follow the same policy
+            async fn upload(router: &Router) -> StatusCode {
+                let request = upload_request("0000000000000000", b"nix-archive-1")
+                    .body(Body::from(&b"nix-archive-1"[..]))
+                    .unwrap();
+                router.clone().oneshot(request).await.unwrap().status()
+            }
+            assert_eq!(StatusCode::BAD_REQUEST, upload(&strict).await);
+            assert_eq!(StatusCode::OK, upload(&relaxed).await);
+            assert_eq!(StatusCode::OK, status(&relaxed, Method::GET, short, Token::None).await);
+        });
+    }
+
+    #[test]
+    fn test_delete_permission() {
+        block_on(async {
+            let router = test_router(false).await;
+            let uri = "/_api/v1/path/00000000000000000000000000000000";
+
+            assert_eq!(StatusCode::FORBIDDEN, status(&router, Method::DELETE, uri, Token::Valid).await);
+            assert_eq!(StatusCode::NOT_FOUND, status(&router, Method::DELETE, uri, Token::ValidDelete).await);
+
+            // Without a token key, deletions would otherwise be anonymous
+            let state = TestState::new("").await;
+            let anonymous = super::router(Arc::clone(&state));
+            assert_eq!(StatusCode::FORBIDDEN, status(&anonymous, Method::DELETE, uri, Token::None).await);
+        });
+    }
+
+    #[test]
+    fn test_scopes() {
+        block_on(async {
+            let public = test_router(false).await;
+            let private = test_router(true).await;
+            let upload = "/_api/v1/upload-path";
+            let bench = "/_api/v1/admin/compression-bench";
+            let listings = ["/_api/v1/list-paths", "/_api/v1/paths", "/_api/v1/stats"];
+            let push = Token::Scoped(&[Scope::Push]);
+            let pull = Token::Scoped(&[Scope::Pull]);
+
+            assert_eq!(StatusCode::FORBIDDEN, status(&public, Method::PUT, upload, pull).await);
+            assert_eq!(StatusCode::FORBIDDEN, status(&public, Method::PUT, upload, Token::Scoped(&[])).await);
+            assert_eq!(StatusCode::FORBIDDEN, status(&public, Method::POST, bench, pull).await);
+            assert_eq!(READ_STATUSES, statuses(&public, READS, push).await);
+            for uri in listings {
+                assert_eq!(StatusCode::FORBIDDEN, status(&public, Method::GET, uri, push).await);
+                assert_eq!(StatusCode::OK, status(&public, Method::GET, uri, pull).await);
+            }
+
+            assert_eq!(StatusCode::FORBIDDEN, status(&private, Method::GET, "/nix-cache-info", push).await);
+            assert_eq!(StatusCode::OK, status(&private, Method::GET, "/nix-cache-info", pull).await);
+            assert_eq!(StatusCode::OK, status(&private, Method::GET, "/_api/v1/cache-config", pull).await);
+            assert_eq!(StatusCode::UNAUTHORIZED, status(&private, Method::GET, "/nix-cache-info", Token::None).await);
+        });
+    }
+
+    #[test]
+    fn test_push_only_token() {
+        block_on(async {
+            let router = test_router(true).await;
+            let push = Token::Scoped(&[Scope::Push]);
+
+            // The routes used by `nixcache push`
+            assert_eq!(StatusCode::OK, status(&router, Method::GET, "/_api/v1/cache-config", push).await);
+            // Both are let through and reject the empty body
+            assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, status(&router, Method::POST, "/_api/v1/get-missing-paths", push).await);
+            assert_eq!(StatusCode::BAD_REQUEST, status(&router, Method::PUT, "/_api/v1/upload-path", push).await);
+        });
+    }
+
+    #[test]
+    fn test_max_concurrent_requests() {
+        block_on(async {
+            let router = test_router_with("http = { max-concurrent-requests = 1 }").await;
+
+            // Requests release their permit once they are handled
+            for _ in 0..3 {
+                assert_eq!(StatusCode::OK, status(&router, Method::GET, "/nix-cache-info", Token::None).await);
+            }
+
+            // An upload whose body never arrives
+            let (_sender, body) = Body::channel();
+            let request = upload_request("p4pclmv1gyja5kzc26npqpia1qqxrf0l", b"nar")
+                .body(body)
+                .unwrap();
+            let mut upload = Box::pin(router.clone().oneshot(request));
+            assert!(futures::poll!(&mut upload).is_pending());
+
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status(&router, Method::GET, "/nix-cache-info", Token::None).await);
+            for uri in ["/healthz", "/readyz"] {
+                assert_eq!(StatusCode::OK, status(&router, Method::GET, uri, Token::None).await);
+            }
+
+            drop(upload);
+            assert_eq!(StatusCode::OK, status(&router, Method::GET, "/nix-cache-info", Token::None).await);
+        });
+    }
+
+    /// Returns a request to upload a NAR as a store path.
+    fn upload_request(store_path_hash: &str, nar: &[u8]) -> axum::http::request::Builder {
+        let nar_info = UploadPathRequest {
+            store_path_hash: StorePathHash::new_with_policy(
+                store_path_hash.to_string(),
+                StorePathHashPolicy::AllowNonstandardLength,
+            ).unwrap(),
+            store_path: format!("/nix/store/{}-test", store_path_hash),
+            ..request_for(nar)
+        };
+
+        Request::builder()
+            .method(Method::PUT)
+            .uri("/_api/v1/upload-path")
+            .header("Authorization", format!("Bearer {}", token(Token::Valid).unwrap()))
+            .header(header::NAR_INFO, serde_json::to_string(&nar_info).unwrap())
+    }
+
+    #[test]
+    fn test_get_missing_paths() {
+        block_on(async {
+            let router = test_router(false).await;
+
+            let request = upload_request("p4pclmv1gyja5kzc26npqpia1qqxrf0l", b"nix-archive-1")
+                .body(Body::from(&b"nix-archive-1"[..]))
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+
+            let uploaded = StorePathHash::new("p4pclmv1gyja5kzc26npqpia1qqxrf0l".to_string()).unwrap();
+            let missing = StorePathHash::new("j5p0j1w27aqdzncpw73k95byvhh5prw2".to_string()).unwrap();
+            let body = GetMissingPathsRequest {
+                store_path_hashes: vec![uploaded, missing.clone()],
+            };
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/_api/v1/get-missing-paths")
+                .header("Authorization", format!("Bearer {}", token(Token::Valid).unwrap()))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let response: GetMissingPathsResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(vec![missing], response.missing_paths);
+        });
+    }
+
+    #[test]
+    fn test_verbose_upload_response() {
+        block_on(async {
+            let router = test_router(false).await;
+
+            let upload = |store_path_hash, nar: &'static [u8], verbose: bool| {
+                let mut request = upload_request(store_path_hash, nar);
+                if verbose {
+                    request = request.header(header::VERBOSE_RESPONSE, "1");
+                }
+                let request = request.body(Body::from(nar)).unwrap();
+
+                let router = router.clone();
+                async move {
+                    let response = router.oneshot(request).await.unwrap();
+                    assert_eq!(StatusCode::OK, response.status());
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+                }
+            };
+
+            let response = upload("p4pclmv1gyja5kzc26npqpia1qqxrf0l", b"nix-archive-1", true).await;
+            let chunks = response["chunks"].as_array().unwrap();
+            assert_eq!(1, chunks.len());
+            assert_eq!(Some(false), chunks[0]["deduplicated"].as_bool());
+
+            let response = upload("j5p0j1w27aqdzncpw73k95byvhh5prw2", b"nix-archive-2", false).await;
+            assert!(response.get("frac_deduplicated").is_some());
+            assert!(response.get("chunks").is_none());
+        });
+    }
+
+    #[test]
+    fn test_idempotent_upload() {
+        block_on(async {
+            let router = test_router(false).await;
+
+            // Returns the status and whether the body was read
+            let upload = |store_path_hash, nar: &'static [u8]| {
+                let read = Arc::new(AtomicBool::new(false));
+                let body = {
+                    let read = read.clone();
+                    Body::wrap_stream(futures::stream::once(async move {
+                        read.store(true, Ordering::SeqCst);
+                        Ok::<_, std::io::Error>(nar)
+                    }))
+                };
+                let request = upload_request(store_path_hash, nar)
+                    .header(header::IDEMPOTENCY_KEY, "retry")
+                    .body(body)
+                    .unwrap();
+
+                let router = router.clone();
+                async move {
+                    let status = router.oneshot(request).await.unwrap().status();
+                    (status, read.load(Ordering::SeqCst))
+                }
+            };
+
+            assert_eq!((StatusCode::OK, true), upload("p4pclmv1gyja5kzc26npqpia1qqxrf0l", b"nix-archive-1").await);
+
+            // A retry gets the first response, and its body is drained
+            assert_eq!((StatusCode::OK, true), upload("p4pclmv1gyja5kzc26npqpia1qqxrf0l", b"nix-archive-1").await);
+
+            // The key can't be reused for another upload
+            let status = upload("j5p0j1w27aqdzncpw73k95byvhh5prw2", b"nix-archive-2").await;
+            assert_eq!((StatusCode::UNPROCESSABLE_ENTITY, true), status);
+        });
+    }
+
+    #[test]
+    fn test_read_only() {
+        block_on(async {
+            let router = test_router_with("read-only = true").await;
+            let delete = "/_api/v1/path/00000000000000000000000000000000";
+
+            assert_eq!(READ_STATUSES, statuses(&router, READS, Token::None).await);
+            assert_eq!(StatusCode::OK, status(&router, Method::GET, "/_api/v1/cache-config", Token::Valid).await);
+            assert_eq!(StatusCode::UNAUTHORIZED, status(&router, Method::PUT, "/_api/v1/upload-path", Token::None).await);
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status(&router, Method::PUT, "/_api/v1/upload-path", Token::Valid).await);
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status(&router, Method::DELETE, delete, Token::ValidDelete).await);
+
+            // POST routes that don't modify the cache reject the empty body
+            assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, status(&router, Method::POST, "/_api/v1/get-missing-paths", Token::Valid).await);
+            assert_eq!(StatusCode::BAD_REQUEST, status(&router, Method::POST, "/_api/v1/admin/compression-bench", Token::Valid).await);
+        });
+    }
+
+    async fn panicking() -> &'static str {
+        panic!("boom")
+    }
+
+    #[test]
+    fn test_panic_response() {
+        block_on(async {
+            let router: Router = Router::new()
+                .route("/panic", get(panicking))
+                .layer(CatchPanicLayer::custom(panic_response));
+
+            let request = Request::builder().uri("/panic").body(Body::empty()).unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(500, body["code"]);
+            assert_eq!("InternalServerError", body["error"]);
+        });
+    }
+}
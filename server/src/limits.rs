@@ -0,0 +1,113 @@
+//! Per-subject concurrency limits.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{ErrorKind, ServerResult};
+
+/// Semaphores keyed by the authenticated subject.
+///
+/// Instead of a semaphore per subject, we count the permits held by
+/// each subject and compare the count with the limit of the current
+/// request. A changed limit applies to the next request, and subjects
+/// without permits take up no memory.
+#[derive(Debug, Default)]
+pub struct KeyedSemaphore {
+    /// Subject -> Number of permits held.
+    held: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// A permit of a subject, released on drop.
+#[derive(Debug)]
+pub struct KeyedPermit {
+    held: Arc<Mutex<HashMap<String, usize>>>,
+    subject: String,
+}
+
+impl KeyedSemaphore {
+    /// Acquires a permit for a subject without waiting.
+    ///
+    /// Fails with `ErrorKind::TooManyRequests` if the subject holds
+    /// `limit` permits or more.
+    pub fn try_acquire(&self, subject: &str, limit: usize) -> ServerResult<KeyedPermit> {
+        let mut held = self.held.lock().unwrap();
+
+        let count = held.get(subject).copied().unwrap_or(0);
+        if count >= limit {
+            return Err(ErrorKind::TooManyRequests.into());
+        }
+        held.insert(subject.to_string(), count + 1);
+
+        Ok(KeyedPermit {
+            held: Arc::clone(&self.held),
+            subject: subject.to_string(),
+        })
+    }
+
+    /// Returns the number of subjects holding permits.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+}
+
+impl Drop for KeyedPermit {
+    fn drop(&mut self) {
+        let mut held = self.held.lock().unwrap();
+
+        if let Some(count) = held.get_mut(&self.subject) {
+            *count -= 1;
+            if *count == 0 {
+                held.remove(&self.subject);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyed_semaphore() {
+        let semaphores = KeyedSemaphore::default();
+
+        let a1 = semaphores.try_acquire("a", 2).unwrap();
+        let _a2 = semaphores.try_acquire("a", 2).unwrap();
+        assert!(semaphores.try_acquire("a", 2).is_err());
+
+        // Other subjects are unaffected
+        let _b1 = semaphores.try_acquire("b", 2).unwrap();
+
+        drop(a1);
+        let _a3 = semaphores.try_acquire("a", 2).unwrap();
+    }
+
+    #[test]
+    fn test_changed_limit() {
+        let semaphores = KeyedSemaphore::default();
+
+        let a1 = semaphores.try_acquire("a", 1).unwrap();
+        assert!(semaphores.try_acquire("a", 1).is_err());
+        let a2 = semaphores.try_acquire("a", 2).unwrap();
+
+        // Permits held under the old limit count against a lower one
+        drop(a1);
+        assert!(semaphores.try_acquire("a", 1).is_err());
+        drop(a2);
+        let _a3 = semaphores.try_acquire("a", 1).unwrap();
+    }
+
+    #[test]
+    fn test_eviction() {
+        let semaphores = KeyedSemaphore::default();
+
+        let permits: Vec<_> = (0..100)
+            .map(|i| semaphores.try_acquire(&format!("subject-{}", i), 1).unwrap())
+            .collect();
+        assert_eq!(100, semaphores.len());
+
+        drop(permits);
+        assert_eq!(0, semaphores.len());
+    }
+}
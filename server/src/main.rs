@@ -1,8 +1,10 @@
 use anyhow::Result;
 use std::path::PathBuf;
-use clap::Parser;
+use clap::{ArgAction, Parser, Subcommand};
 
-use server::{run_api_server, config};
+use common::logging::log_level;
+use server::{run_api_server, config, recompress, reindex, self_test, telemetry};
+use server::config::CompressionType;
 
 /// Nixcached - nixcache server.
 #[derive(Parser, Debug)]
@@ -11,20 +13,84 @@ struct Args {
     /// Path to the 'config.toml'.
     #[arg(short, long)]
     config: Option<PathBuf>,
+
+    /// Warn about unknown config fields instead of failing.
+    #[arg(long)]
+    allow_unknown_config_fields: bool,
+
+    /// Log more details (-v for debug, -vv for trace).
+    ///
+    /// `RUST_LOG` takes precedence if set.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log warnings and errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Recompress stored NARs with another compression type.
+    Recompress {
+        /// The compression type to recompress to.
+        #[arg(long)]
+        to: CompressionType,
+
+        /// Only report the change in stored size.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Rebuild the index, or the chunk references without one, from the stored NARs.
+    ///
+    /// The server must be stopped.
+    Reindex {
+        /// Only report discrepancies between the index and the stored NARs.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Check that data round-trips through the configured storage.
+    ///
+    /// Synthetic data is chunked, compressed, uploaded, downloaded
+    /// and reassembled. Exits with an error on any mismatch.
+    SelfTest {
+        /// The size of the data, in bytes.
+        #[arg(long, default_value_t = 16 * 1024 * 1024)]
+        size: usize,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    // Tracing is configured in the config, so we log about it afterwards
+    let config_path = args.config.unwrap_or_else(config::default_path);
+    let config = config::load(&config_path, args.allow_unknown_config_fields).await?;
+
+    telemetry::init(&config.tracing, log_level(args.verbose, args.quiet))?;
     dump_version();
-    tracing_subscriber::fmt::init();
 
-    let config = config::load(args.config).await?;
+    tracing::info!("Using config at: '{}'", config_path.to_string_lossy());
+    for field in &config.unknown_fields {
+        tracing::warn!("Ignoring unknown config field '{}'", field);
+    }
+
+    let result = match args.command {
+        Some(Command::Recompress { to, dry_run }) => recompress::run(config, to, dry_run).await,
+        Some(Command::Reindex { dry_run }) => reindex::run(config, dry_run).await,
+        Some(Command::SelfTest { size }) => self_test::run(config, size).await,
+        None => run_api_server(config).await,
+    };
 
-    run_api_server(config).await?;
+    telemetry::shutdown().await;
 
-    Ok(())
+    result
 }
 
 fn dump_version() {
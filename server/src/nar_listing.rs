@@ -0,0 +1,469 @@
+//! NAR file listings.
+//!
+//! Nix can list the files in a store path without downloading it
+//! through `{storePathHash}.ls`, which contains a JSON listing of the
+//! NAR:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "root": {
+//!     "type": "directory",
+//!     "entries": {
+//!       "hello": { "type": "regular", "size": 5, "executable": true, "narOffset": 400 },
+//!       "link": { "type": "symlink", "target": "hello" }
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! A NAR is a sequence of length-prefixed strings padded to 8 bytes.
+//! `ListingBuilder` splits the NAR into these strings as data is fed
+//! to it, skipping over file contents, so that listings can be built
+//! while a NAR is streamed.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::OnceCell;
+
+use crate::error::{ServerError, ServerResult};
+use crate::storage::StorageBackend;
+
+/// The magic string at the beginning of every NAR.
+const NAR_MAGIC: &[u8] = b"nix-archive-1";
+
+/// The maximum length of strings other than file contents.
+///
+/// File names and symlink targets are much shorter in practice.
+const MAX_STRING_LEN: u64 = 64 * 1024;
+
+/// The maximum directory depth.
+const MAX_DEPTH: usize = 256;
+
+/// A file listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Listing {
+    /// The version of the listing format.
+    pub version: u32,
+
+    /// The root of the NAR.
+    pub root: Entry,
+}
+
+/// An entry in a file listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Entry {
+    Regular {
+        size: u64,
+
+        #[serde(default)]
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        executable: bool,
+
+        /// The offset of the file contents in the NAR.
+        #[serde(rename = "narOffset")]
+        nar_offset: u64,
+    },
+    Symlink {
+        target: String,
+    },
+    Directory {
+        entries: BTreeMap<String, Entry>,
+    },
+}
+
+/// A string in a NAR.
+#[derive(Debug)]
+enum Token {
+    String(Vec<u8>),
+    /// File contents, which are skipped.
+    Contents { size: u64, offset: u64 },
+}
+
+#[derive(Debug)]
+enum TokenizerState {
+    /// Reading the length of the next string.
+    Length { buf: [u8; 8], filled: usize },
+    /// Reading a string.
+    String { buf: Vec<u8>, remaining: usize, padding: u64 },
+    /// Skipping file contents or padding.
+    Skip { remaining: u64 },
+}
+
+/// Builds the file listing of a NAR as it's streamed.
+#[derive(Debug)]
+pub struct ListingBuilder {
+    tokens: Vec<Token>,
+    state: TokenizerState,
+    offset: u64,
+}
+
+impl ListingBuilder {
+    pub fn new() -> Self {
+        Self {
+            tokens: Vec::new(),
+            state: TokenizerState::Length { buf: [0; 8], filled: 0 },
+            offset: 0,
+        }
+    }
+
+    /// Feeds the next part of the NAR.
+    pub fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let mut string_len = None;
+            let consumed = match &mut self.state {
+                TokenizerState::Length { buf, filled } => {
+                    let n = (buf.len() - *filled).min(data.len());
+                    buf[*filled..*filled + n].copy_from_slice(&data[..n]);
+                    *filled += n;
+
+                    if *filled == buf.len() {
+                        string_len = Some(u64::from_le_bytes(*buf));
+                    }
+
+                    n
+                }
+                TokenizerState::String { buf, remaining, padding } => {
+                    let n = (*remaining).min(data.len());
+                    buf.extend_from_slice(&data[..n]);
+                    *remaining -= n;
+
+                    if *remaining == 0 {
+                        let padding = *padding;
+                        self.tokens.push(Token::String(std::mem::take(buf)));
+                        self.state = TokenizerState::Skip { remaining: padding };
+                    }
+
+                    n
+                }
+                TokenizerState::Skip { remaining } => {
+                    let n = (*remaining).min(data.len() as u64);
+                    *remaining -= n;
+                    n as usize
+                }
+            };
+
+            data = &data[consumed..];
+            self.offset += consumed as u64;
+
+            if let Some(len) = string_len {
+                self.start_string(len)?;
+            }
+
+            if let TokenizerState::Skip { remaining: 0 } = self.state {
+                self.state = TokenizerState::Length { buf: [0; 8], filled: 0 };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the listing once the whole NAR has been fed.
+    pub fn finish(self) -> Result<Listing> {
+        match self.state {
+            TokenizerState::Length { filled: 0, .. } => {}
+            _ => return Err(anyhow!("The NAR is truncated")),
+        }
+
+        let mut tokens = Tokens(self.tokens.into_iter());
+        tokens.expect(NAR_MAGIC)?;
+        let root = parse_node(&mut tokens, 0)?;
+
+        if tokens.0.next().is_some() {
+            return Err(anyhow!("Trailing data after the NAR"));
+        }
+
+        Ok(Listing {
+            version: 1,
+            root,
+        })
+    }
+
+    /// Starts reading a string of some length.
+    ///
+    /// `self.offset` is right after the length.
+    fn start_string(&mut self, len: u64) -> Result<()> {
+        let padding = (8 - len % 8) % 8;
+        let is_contents = matches!(self.tokens.last(), Some(Token::String(s)) if s == b"contents");
+
+        self.state = if is_contents {
+            self.tokens.push(Token::Contents {
+                size: len,
+                offset: self.offset,
+            });
+            TokenizerState::Skip { remaining: len + padding }
+        } else if len > MAX_STRING_LEN {
+            return Err(anyhow!("String of {} bytes is too long", len));
+        } else if len == 0 {
+            self.tokens.push(Token::String(Vec::new()));
+            TokenizerState::Skip { remaining: 0 }
+        } else {
+            TokenizerState::String {
+                buf: Vec::with_capacity(len as usize),
+                remaining: len as usize,
+                padding,
+            }
+        };
+
+        Ok(())
+    }
+}
+
+impl Default for ListingBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Tokens(std::vec::IntoIter<Token>);
+
+impl Tokens {
+    fn next_string(&mut self) -> Result<Vec<u8>> {
+        match self.0.next() {
+            Some(Token::String(s)) => Ok(s),
+            Some(Token::Contents { .. }) => Err(anyhow!("Unexpected file contents")),
+            None => Err(anyhow!("Unexpected end of NAR")),
+        }
+    }
+
+    fn next_contents(&mut self) -> Result<(u64, u64)> {
+        match self.0.next() {
+            Some(Token::Contents { size, offset }) => Ok((size, offset)),
+            _ => Err(anyhow!("Expected file contents")),
+        }
+    }
+
+    fn expect(&mut self, expected: &[u8]) -> Result<()> {
+        let s = self.next_string()?;
+        if s != expected {
+            return Err(anyhow!(
+                "Expected \"{}\", got \"{}\"",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&s),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn parse_node(tokens: &mut Tokens, depth: usize) -> Result<Entry> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("The NAR is nested too deeply"));
+    }
+
+    tokens.expect(b"(")?;
+    tokens.expect(b"type")?;
+
+    let entry = match tokens.next_string()?.as_slice() {
+        b"regular" => {
+            let mut executable = false;
+            let mut tag = tokens.next_string()?;
+            if tag == b"executable" {
+                tokens.expect(b"")?;
+                executable = true;
+                tag = tokens.next_string()?;
+            }
+            if tag != b"contents" {
+                return Err(anyhow!("Expected \"contents\""));
+            }
+
+            let (size, nar_offset) = tokens.next_contents()?;
+            Entry::Regular { size, executable, nar_offset }
+        }
+        b"symlink" => {
+            tokens.expect(b"target")?;
+            let target = String::from_utf8_lossy(&tokens.next_string()?).to_string();
+            Entry::Symlink { target }
+        }
+        b"directory" => {
+            let mut entries = BTreeMap::new();
+            loop {
+                match tokens.next_string()?.as_slice() {
+                    b")" => return Ok(Entry::Directory { entries }),
+                    b"entry" => {
+                        tokens.expect(b"(")?;
+                        tokens.expect(b"name")?;
+                        let name = String::from_utf8_lossy(&tokens.next_string()?).to_string();
+                        tokens.expect(b"node")?;
+                        let node = parse_node(tokens, depth + 1)?;
+                        tokens.expect(b")")?;
+
+                        entries.insert(name, node);
+                    }
+                    other => return Err(anyhow!(
+                        "Unexpected \"{}\" in directory",
+                        String::from_utf8_lossy(other),
+                    )),
+                }
+            }
+        }
+        other => return Err(anyhow!(
+            "Unknown node type \"{}\"",
+            String::from_utf8_lossy(other),
+        )),
+    };
+
+    tokens.expect(b")")?;
+    Ok(entry)
+}
+
+/// Stores the file listing of a NAR.
+pub async fn store(backend: &dyn StorageBackend, name: String, listing: &Listing) -> ServerResult<()> {
+    let data = serde_json::to_vec(listing)
+        .map_err(ServerError::storage_error)?;
+    backend.upload_listing(name, data.into()).await
+}
+
+/// Stream filter that builds the file listing of the NAR being read.
+///
+/// The listing is available once EOF is reached. If the NAR cannot be
+/// parsed, the listing is `None` and the stream is unaffected.
+pub struct ListingStream<R: AsyncRead + Unpin> {
+    inner: R,
+    builder: Option<ListingBuilder>,
+    finalized: Arc<OnceCell<Option<Listing>>>,
+}
+
+impl<R: AsyncRead + Unpin> ListingStream<R> {
+    /// Creates a new stream.
+    ///
+    /// If `enabled` is false, the stream passes data through without
+    /// building a listing.
+    pub fn new(inner: R, enabled: bool) -> (Self, Arc<OnceCell<Option<Listing>>>) {
+        let finalized = Arc::new(OnceCell::new());
+
+        (
+            Self {
+                inner,
+                builder: if enabled { Some(ListingBuilder::new()) } else { None },
+                finalized: finalized.clone(),
+            },
+            finalized,
+        )
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ListingStream<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        let old_filled = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read_len = buf.filled().len() - old_filled;
+
+        if let Poll::Ready(Ok(())) = r {
+            if read_len == 0 {
+                // EOF
+                if let Some(builder) = self.builder.take() {
+                    let listing = match builder.finish() {
+                        Ok(listing) => Some(listing),
+                        Err(e) => {
+                            tracing::warn!("Failed to build file listing: {}", e);
+                            None
+                        }
+                    };
+                    let _ = self.finalized.set(listing);
+                }
+            } else if let Some(builder) = &mut self.builder {
+                let filled = buf.filled();
+                if let Err(e) = builder.update(&filled[filled.len() - read_len..]) {
+                    tracing::warn!("Failed to build file listing: {}", e);
+                    self.builder = None;
+                    let _ = self.finalized.set(None);
+                }
+            }
+        }
+
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a NAR string.
+    fn string(nar: &mut Vec<u8>, s: &[u8]) {
+        nar.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        nar.extend_from_slice(s);
+        nar.resize(nar.len() + (8 - s.len() % 8) % 8, 0);
+    }
+
+    fn strings(nar: &mut Vec<u8>, ss: &[&[u8]]) {
+        for s in ss {
+            string(nar, s);
+        }
+    }
+
+    /// Returns a NAR with an executable, a symlink and an empty file.
+    fn get_nar() -> Vec<u8> {
+        let mut nar = Vec::new();
+        strings(&mut nar, &[b"nix-archive-1", b"(", b"type", b"directory"]);
+
+        strings(&mut nar, &[b"entry", b"(", b"name", b"bin", b"node", b"(", b"type", b"directory"]);
+        strings(&mut nar, &[b"entry", b"(", b"name", b"hello", b"node"]);
+        strings(&mut nar, &[b"(", b"type", b"regular", b"executable", b"", b"contents", b"Hello, world!", b")"]);
+        strings(&mut nar, &[b")", b")", b")"]);
+
+        strings(&mut nar, &[b"entry", b"(", b"name", b"empty", b"node"]);
+        strings(&mut nar, &[b"(", b"type", b"regular", b"contents", b"", b")"]);
+        strings(&mut nar, &[b")"]);
+
+        strings(&mut nar, &[b"entry", b"(", b"name", b"link", b"node"]);
+        strings(&mut nar, &[b"(", b"type", b"symlink", b"target", b"bin/hello", b")"]);
+        strings(&mut nar, &[b")"]);
+
+        strings(&mut nar, &[b")"]);
+        nar
+    }
+
+    #[test]
+    fn test_listing() {
+        let nar = get_nar();
+
+        // Feed in odd-sized pieces to exercise all state transitions
+        for piece_size in [1, 3, 7, 8, 13, nar.len()] {
+            let mut builder = ListingBuilder::new();
+            for piece in nar.chunks(piece_size) {
+                builder.update(piece).unwrap();
+            }
+            let listing = builder.finish().unwrap();
+
+            let json = serde_json::to_value(&listing).unwrap();
+            let hello = &json["root"]["entries"]["bin"]["entries"]["hello"];
+            assert_eq!("regular", hello["type"]);
+            assert_eq!(13, hello["size"]);
+            assert_eq!(true, hello["executable"]);
+
+            let offset = hello["narOffset"].as_u64().unwrap() as usize;
+            assert_eq!(b"Hello, world!", &nar[offset..offset + 13]);
+
+            let empty = &json["root"]["entries"]["empty"];
+            assert_eq!(0, empty["size"]);
+            assert!(empty.get("executable").is_none());
+
+            assert_eq!("bin/hello", json["root"]["entries"]["link"]["target"]);
+        }
+    }
+
+    #[test]
+    fn test_listing_truncated() {
+        let nar = get_nar();
+
+        let mut builder = ListingBuilder::new();
+        builder.update(&nar[..nar.len() - 4]).unwrap();
+        assert!(builder.finish().is_err());
+
+        let mut builder = ListingBuilder::new();
+        builder.update(&nar[..nar.len() - 16]).unwrap();
+        assert!(builder.finish().is_err());
+    }
+}
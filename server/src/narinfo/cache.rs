@@ -0,0 +1,111 @@
+//! In-memory narinfo cache.
+//!
+//! Narinfos rarely change for a given store path hash, so recently
+//! served ones can be kept in memory. This saves a storage round-trip
+//! and a manifest parse on the hot path, and keeps them available
+//! during brief backend outages.
+//!
+//! Entries are invalidated when a path is deleted by this server.
+//! Narinfos also change when `recompress` rewrites the chunks of a
+//! NAR, which runs in a separate process, so entries expire after a
+//! TTL to pick up such changes. Expired entries are refreshed from
+//! storage but kept, so that they can still be served while storage
+//! is unavailable.
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use lru::LruCache;
+use serde::Deserialize;
+
+use common::v1::stats::NarInfoCacheStats;
+use super::NarInfo;
+
+/// Narinfo cache configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NarInfoCacheConfig {
+    /// The maximum number of narinfos to keep.
+    pub size: NonZeroUsize,
+
+    /// How long before a narinfo is refreshed from storage, in seconds.
+    ///
+    /// Narinfos rewritten by `recompress` are served from the cache
+    /// until they expire. Expired narinfos are still served if storage
+    /// fails. By default, they expire after 5 minutes.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+}
+
+/// A cached narinfo.
+#[derive(Debug, Clone)]
+pub struct CachedNarInfo {
+    pub narinfo: NarInfo,
+    /// Whether the narinfo is older than the TTL and should be refreshed.
+    pub expired: bool,
+}
+
+/// An LRU cache of narinfos keyed by store path hash.
+#[derive(Debug)]
+pub struct NarInfoCache {
+    inner: Mutex<LruCache<String, (Instant, NarInfo)>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NarInfoCache {
+    pub fn new(config: &NarInfoCacheConfig) -> Self {
+        Self {
+            inner: Mutex::new(LruCache::new(config.size)),
+            ttl: Duration::from_secs(config.ttl),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a cached narinfo, expired or not.
+    ///
+    /// Only unexpired narinfos count as hits.
+    pub fn get(&self, store_path_hash: &str) -> Option<CachedNarInfo> {
+        let cached = self.inner.lock().unwrap()
+            .get(store_path_hash)
+            .map(|(inserted, narinfo)| CachedNarInfo {
+                narinfo: narinfo.clone(),
+                expired: self.is_expired(*inserted),
+            });
+
+        let hit = matches!(cached, Some(CachedNarInfo { expired: false, .. }));
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        cached
+    }
+
+    /// Caches a narinfo.
+    pub fn insert(&self, store_path_hash: String, narinfo: NarInfo) {
+        self.inner.lock().unwrap().put(store_path_hash, (Instant::now(), narinfo));
+    }
+
+    /// Removes a narinfo, for example when its path is deleted.
+    pub fn remove(&self, store_path_hash: &str) {
+        self.inner.lock().unwrap().pop(store_path_hash);
+    }
+
+    /// Returns the hit and miss counts.
+    pub fn stats(&self) -> NarInfoCacheStats {
+        NarInfoCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.inner.lock().unwrap().len(),
+        }
+    }
+
+    fn is_expired(&self, inserted: Instant) -> bool {
+        inserted.elapsed() >= self.ttl
+    }
+}
+
+fn default_ttl() -> u64 {
+    5 * 60
+}
@@ -52,12 +52,14 @@ use common::{mime, Keypair};
 use crate::error::{ErrorKind, ServerError, ServerResult};
 use crate::nix_manifest::{self, SpaceDelimitedList};
 
+pub mod cache;
+
 #[cfg(test)]
 mod tests;
 
 /// NAR information.
 #[serde_as]
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NarInfo {
     /// The full store path being cached, including the store directory.
     ///
@@ -85,14 +87,14 @@ pub struct NarInfo {
 
     /// The hash of the compressed file.
     ///
-    /// We don't know the file hash if it's chunked.
+    /// We don't know the file hash if it's chunked and recompressed.
     #[serde(rename = "FileHash")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_hash: Option<Hash>,
 
     /// The size of the compressed file.
     ///
-    /// We may not know the file size if it's chunked.
+    /// We don't know the file size if it's chunked and recompressed.
     #[serde(rename = "FileSize")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_size: Option<usize>,
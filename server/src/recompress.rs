@@ -0,0 +1,197 @@
+//! Recompression of stored NARs.
+//!
+//! Chunks keep the compression they were uploaded with. After the
+//! compression config changes, this migrates existing chunks to the
+//! new type and rewrites the NAR metadata to point to them.
+//!
+//! The old chunks are left in place since they may still be referenced
+//! by NARs that failed to migrate.
+//!
+//! Running servers with a narinfo cache keep serving the old narinfos
+//! until they expire (see `narinfo-cache.ttl`). Restart them to serve
+//! the new ones right away.
+
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, BufReader};
+
+use libnixstore::Hash;
+use crate::api::{UploadedChunk, UploadedNar};
+use crate::compression::{get_compressor_fn, get_decompressor_fn};
+use crate::config::{CompressionConfig, CompressionType, Config};
+use crate::error::{ErrorKind, ServerError};
+use crate::storage::StorageBackend;
+use crate::stream::StreamHasher;
+use crate::{refs, State};
+
+/// Recompresses all stored NARs with a compression type.
+///
+/// With `dry_run`, nothing is uploaded and only the change in
+/// stored size is reported.
+pub async fn run(config: Config, to: CompressionType, dry_run: bool) -> Result<()> {
+    let compression = if config.compression.r#type == to {
+        config.compression.clone()
+    } else {
+        CompressionConfig {
+            r#type: to,
+            level: None,
+            ..config.compression.clone()
+        }
+    };
+
+    let state = State::new(config).await?;
+    let backend = state.storage();
+
+    // Chunks are deduplicated, so the same chunk may appear in many NARs
+    let mut recompressed: HashMap<String, UploadedChunk> = HashMap::new();
+    let mut old_size = 0;
+    let mut new_size = 0;
+    let mut num_nars = 0;
+
+    for name in backend.list_nars().await? {
+        let etag = backend.get_nar_etag(name.clone()).await?;
+        let download = match backend.download_nar(name.clone()).await? {
+            Some(download) => download,
+            None => {
+                tracing::warn!("{} was deleted concurrently, skipping", name);
+                continue;
+            }
+        };
+        let data = download
+            .read_to_end()
+            .await
+            .map_err(ServerError::storage_error)?;
+        let mut nar: UploadedNar = serde_json::from_slice(&data)
+            .map_err(ServerError::storage_error)?;
+
+        if nar.chunks.iter().all(|chunk| chunk.compression.r#type == to) {
+            continue;
+        }
+
+        let old_chunks: Vec<String> = nar.chunk_names().collect();
+        let mut chunks = Vec::with_capacity(nar.chunks.len());
+        for chunk in nar.chunks {
+            if chunk.compression.r#type == to {
+                chunks.push(chunk);
+                continue;
+            }
+
+            let key = chunk.file_hash.to_typed_base32();
+            if let Some(new_chunk) = recompressed.get(&key) {
+                chunks.push(new_chunk.clone());
+                continue;
+            }
+
+            let new_chunk = recompress_chunk(backend.clone(), &chunk, &compression, dry_run).await?;
+            old_size += chunk.file_size;
+            new_size += new_chunk.file_size;
+
+            recompressed.insert(key, new_chunk.clone());
+            chunks.push(new_chunk);
+        }
+        nar.chunks = chunks;
+
+        if !dry_run {
+            let data = serde_json::to_vec(&nar)
+                .map_err(ServerError::storage_error)?;
+            refs::add_refs(&state, &name, nar.chunk_names()).await?;
+            let r = backend
+                .upload_nar_if_match(name.clone(), &mut Cursor::new(data), etag)
+                .await;
+
+            match r {
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::PreconditionFailed) => {
+                    tracing::warn!("{} was modified concurrently, skipping", name);
+
+                    // Keep the references the NAR had before or needs after the modification
+                    let current_chunks = stored_chunk_names(&**backend, &name).await?;
+                    let unused = nar
+                        .chunk_names()
+                        .filter(|chunk| !old_chunks.contains(chunk) && !current_chunks.contains(chunk));
+                    refs::remove_refs(&state, &name, unused).await?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            let new_chunks: HashSet<String> = nar.chunk_names().collect();
+            let stale_chunks = old_chunks.into_iter().filter(|chunk| !new_chunks.contains(chunk));
+            refs::remove_refs(&state, &name, stale_chunks).await?;
+        }
+
+        tracing::info!("Recompressed {}", name);
+        num_nars += 1;
+    }
+
+    tracing::info!(
+        "{}{} NARs, {} chunks: {} -> {} bytes ({:+})",
+        if dry_run { "[dry run] " } else { "" },
+        num_nars,
+        recompressed.len(),
+        old_size,
+        new_size,
+        new_size as i64 - old_size as i64,
+    );
+
+    Ok(())
+}
+
+/// Returns the chunks that a stored NAR references.
+///
+/// A NAR that was deleted references no chunks.
+async fn stored_chunk_names(backend: &dyn StorageBackend, name: &str) -> Result<HashSet<String>> {
+    let download = match backend.download_nar(name.to_string()).await? {
+        Some(download) => download,
+        None => return Ok(HashSet::new()),
+    };
+    let data = download
+        .read_to_end()
+        .await
+        .map_err(ServerError::storage_error)?;
+    let nar: UploadedNar = serde_json::from_slice(&data)
+        .map_err(ServerError::storage_error)?;
+
+    Ok(nar.chunk_names().collect())
+}
+
+/// Recompresses a single chunk, returning the new chunk.
+async fn recompress_chunk(
+    backend: Arc<Box<dyn StorageBackend>>,
+    chunk: &UploadedChunk,
+    compression: &CompressionConfig,
+    dry_run: bool,
+) -> Result<UploadedChunk> {
+    let stream = backend
+        .download_chunk(chunk.file_hash.to_typed_base32())
+        .await?
+        .ok_or_else(|| anyhow!("Chunk {} does not exist", chunk.file_hash.to_typed_base32()))?
+        .into_async_read();
+
+    let decompressor = get_decompressor_fn(chunk.compression.r#type);
+    let compressor = get_compressor_fn(compression.r#type, compression.level());
+    let stream = compressor(BufReader::new(decompressor(BufReader::new(stream))));
+    let (mut stream, file_compute) = StreamHasher::new(stream, Sha256::new());
+
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await?;
+
+    let (file_hash, file_size) = file_compute.get().unwrap();
+    let file_hash = Hash::Sha256(file_hash.as_slice().try_into().unwrap());
+
+    if !dry_run {
+        let size = data.len() as u64;
+        backend
+            .upload_chunk_with_size(file_hash.to_typed_base32(), &mut Cursor::new(data), size)
+            .await?;
+    }
+
+    Ok(UploadedChunk {
+        file_hash,
+        file_size: *file_size,
+        compression: compression.clone(),
+    })
+}
@@ -0,0 +1,252 @@
+//! Chunk references.
+//!
+//! Chunks are deduplicated, so a chunk may be referenced by many
+//! NARs. For each chunk, we record the set of NARs that reference
+//! it, and a chunk may only be removed once that set is empty.
+//!
+//! If an index is configured, the sets are stored in the index.
+//! Otherwise, they are stored next to the chunks and updated with
+//! conditional uploads, retrying when another request modified
+//! them in the meantime.
+//!
+//! A NAR that is overwritten by a re-upload keeps its references
+//! to chunks it no longer uses. This only prevents those chunks
+//! from being removed and never causes data loss.
+
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures::future::join_all;
+use serde::{Serialize, Deserialize};
+
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::storage::StorageBackend;
+use crate::State;
+
+/// The maximum number of attempts to update the references of a chunk.
+const MAX_UPDATE_ATTEMPTS: usize = 10;
+
+/// The NARs referencing a chunk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChunkRefs {
+    /// Names of the NARs.
+    nars: BTreeSet<String>,
+}
+
+/// Records that a NAR references some chunks.
+///
+/// This must be done before the NAR itself is uploaded so that
+/// no NAR is ever visible without its references.
+pub async fn add_refs(
+    state: &State,
+    nar: &str,
+    chunks: impl IntoIterator<Item = String>,
+) -> ServerResult<()> {
+    let chunks = unique(chunks);
+
+    match &state.index {
+        Some(index) => index.add_chunk_refs(nar.to_string(), chunks.into_iter().collect()).await,
+        None => add_object_refs(&**state.storage(), nar, chunks).await,
+    }
+}
+
+/// Removes the references of a NAR to some chunks.
+///
+/// Returns the chunks that are no longer referenced by any NAR.
+/// Chunks that were not referenced by the NAR are never returned.
+pub async fn remove_refs(
+    state: &State,
+    nar: &str,
+    chunks: impl IntoIterator<Item = String>,
+) -> ServerResult<Vec<String>> {
+    let chunks = unique(chunks);
+
+    match &state.index {
+        Some(index) => index.remove_chunk_refs(nar.to_string(), chunks.into_iter().collect()).await,
+        None => remove_object_refs(&**state.storage(), nar, chunks).await,
+    }
+}
+
+/// Returns whether any NAR references a chunk.
+pub async fn is_referenced(state: &State, chunk: &str) -> ServerResult<bool> {
+    match &state.index {
+        Some(index) => index.has_chunk_refs(chunk.to_string()).await,
+        None => has_object_refs(&**state.storage(), chunk).await,
+    }
+}
+
+/// Returns the references stored next to some chunks.
+///
+/// Chunks without references are left out.
+pub async fn snapshot_object_refs(
+    state: &State,
+    chunks: impl IntoIterator<Item = String>,
+) -> ServerResult<BTreeMap<String, BTreeSet<String>>> {
+    let backend = state.storage();
+    let mut snapshot = BTreeMap::new();
+    for chunk in unique(chunks) {
+        let refs = read_object_refs(&**backend, &chunk).await?;
+        if !refs.nars.is_empty() {
+            snapshot.insert(chunk, refs.nars);
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Replaces the references stored next to a chunk.
+///
+/// Only used to rebuild the references while the server is stopped.
+pub async fn restore_object_refs(
+    state: &State,
+    chunk: &str,
+    nars: BTreeSet<String>,
+) -> ServerResult<()> {
+    update(&**state.storage(), chunk.to_string(), |refs| {
+        if refs.nars == nars {
+            return false;
+        }
+        refs.nars = nars.clone();
+        true
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn add_object_refs(
+    backend: &dyn StorageBackend,
+    nar: &str,
+    chunks: BTreeSet<String>,
+) -> ServerResult<()> {
+    let futures = chunks.into_iter().map(|chunk| {
+        update(backend, chunk, |refs| refs.nars.insert(nar.to_string()))
+    });
+
+    join_all(futures)
+        .await
+        .into_iter()
+        .collect::<ServerResult<Vec<_>>>()?;
+
+    Ok(())
+}
+
+async fn remove_object_refs(
+    backend: &dyn StorageBackend,
+    nar: &str,
+    chunks: BTreeSet<String>,
+) -> ServerResult<Vec<String>> {
+    let futures = chunks.into_iter().map(|chunk| async move {
+        // A chunk whose references never included the NAR may be used by
+        // a NAR uploaded before references were recorded, so it's kept
+        let mut removed = false;
+        let refs = update(backend, chunk.clone(), |refs| {
+            removed = refs.nars.remove(nar);
+            removed
+        })
+        .await?;
+        Ok::<_, ServerError>((removed && refs.nars.is_empty()).then_some(chunk))
+    });
+
+    let orphaned = join_all(futures)
+        .await
+        .into_iter()
+        .collect::<ServerResult<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    Ok(orphaned)
+}
+
+async fn has_object_refs(backend: &dyn StorageBackend, chunk: &str) -> ServerResult<bool> {
+    Ok(!read_object_refs(backend, chunk).await?.nars.is_empty())
+}
+
+async fn read_object_refs(backend: &dyn StorageBackend, chunk: &str) -> ServerResult<ChunkRefs> {
+    match backend.download_chunk_refs(chunk.to_string()).await? {
+        Some((data, _)) => serde_json::from_slice(&data).map_err(ServerError::storage_error),
+        None => Ok(ChunkRefs::default()),
+    }
+}
+
+/// Atomically updates the references of a chunk.
+///
+/// `f` returns whether the references were modified.
+async fn update<F>(backend: &dyn StorageBackend, chunk: String, mut f: F) -> ServerResult<ChunkRefs>
+where
+    F: FnMut(&mut ChunkRefs) -> bool,
+{
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let (mut refs, etag) = match backend.download_chunk_refs(chunk.clone()).await? {
+            Some((data, etag)) => {
+                let refs: ChunkRefs = serde_json::from_slice(&data)
+                    .map_err(ServerError::storage_error)?;
+                (refs, Some(etag))
+            }
+            None => (ChunkRefs::default(), None),
+        };
+
+        if !f(&mut refs) {
+            return Ok(refs);
+        }
+
+        let data = serde_json::to_vec(&refs)
+            .map_err(ServerError::storage_error)?;
+
+        match backend.upload_chunk_refs_if_match(chunk.clone(), Bytes::from(data), etag).await {
+            Ok(()) => return Ok(refs),
+            Err(e) if matches!(e.kind(), ErrorKind::PreconditionFailed) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(ErrorKind::StorageError(anyhow!(
+        "Too much contention updating the references of chunk {}",
+        chunk
+    ))
+    .into())
+}
+
+fn unique(chunks: impl IntoIterator<Item = String>) -> BTreeSet<String> {
+    chunks.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::storage::local::{LocalBackend, LocalStorageConfig};
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_refs() {
+        let dir = TestDir::new("refs");
+        let config: LocalStorageConfig = serde_json::from_value(serde_json::json!({
+            "path": dir.path(),
+        }))
+        .unwrap();
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+            let chunks = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+            add_object_refs(&backend, "nar-a", unique(chunks(&["x", "y", "y"]))).await.unwrap();
+            add_object_refs(&backend, "nar-b", unique(chunks(&["y", "z"]))).await.unwrap();
+
+            let orphaned = remove_object_refs(&backend, "nar-a", unique(chunks(&["x", "y"]))).await.unwrap();
+            assert_eq!(chunks(&["x"]), orphaned);
+
+            let orphaned = remove_object_refs(&backend, "nar-b", unique(chunks(&["y", "z"]))).await.unwrap();
+            assert_eq!(chunks(&["y", "z"]), orphaned);
+
+            // No references were recorded for "w"
+            let orphaned = remove_object_refs(&backend, "nar-b", unique(chunks(&["w"]))).await.unwrap();
+            assert!(orphaned.is_empty());
+
+            assert!(!has_object_refs(&backend, "y").await.unwrap());
+            assert!(!has_object_refs(&backend, "w").await.unwrap());
+        });
+    }
+}
@@ -0,0 +1,211 @@
+//! Rebuilding of the index.
+//!
+//! The index can drift from the stored NARs after a crash, and is
+//! empty when enabled on an existing cache. This rebuilds the chunk
+//! references and NAR hashes from the NAR metadata in storage and
+//! reports where the old index differed.
+//!
+//! Without an index, the chunk references stored next to the chunks
+//! are rebuilt instead.
+//!
+//! The server must not be running since the index is replaced as
+//! a whole.
+
+use anyhow::Result;
+use std::collections::BTreeSet;
+use displaydoc::Display;
+
+use crate::api::UploadedNar;
+use crate::config::Config;
+use crate::error::ServerError;
+use crate::index::IndexSnapshot;
+use crate::{refs, State};
+
+/// A difference between the index and the stored NARs.
+#[derive(Debug, Display, PartialEq, Eq)]
+enum Discrepancy {
+    /// Chunk {chunk} is referenced by {nar} but the index lacks the reference
+    MissingChunkRef { chunk: String, nar: String },
+
+    /// The index has a reference from {nar} to chunk {chunk} that does not exist
+    StaleChunkRef { chunk: String, nar: String },
+
+    /// The index lacks the NAR hash of {store_path_hash} ({nar_hash})
+    MissingNarHash { store_path_hash: String, nar_hash: String },
+
+    /// The index has a NAR hash for {store_path_hash} which does not exist ({nar_hash})
+    StaleNarHash { store_path_hash: String, nar_hash: String },
+
+    /// The index has the wrong NAR hash for {store_path_hash}: {indexed} instead of {actual}
+    WrongNarHash { store_path_hash: String, indexed: String, actual: String },
+}
+
+/// Rebuilds the index, or the chunk references without one, from the
+/// stored NARs.
+///
+/// With `dry_run`, the index is left untouched and only the
+/// discrepancies are reported.
+pub async fn run(config: Config, dry_run: bool) -> Result<()> {
+    let state = State::new(config).await?;
+    let backend = state.storage();
+
+    let mut rebuilt = IndexSnapshot::default();
+    for name in backend.list_nars().await? {
+        let download = match backend.download_nar(name.clone()).await? {
+            Some(download) => download,
+            None => {
+                tracing::warn!("{} was deleted concurrently, skipping", name);
+                continue;
+            }
+        };
+        let data = download
+            .read_to_end()
+            .await
+            .map_err(ServerError::storage_error)?;
+        let nar: UploadedNar = serde_json::from_slice(&data)
+            .map_err(ServerError::storage_error)?;
+
+        for chunk in nar.chunk_names() {
+            rebuilt.chunk_refs.entry(chunk).or_default().insert(name.clone());
+        }
+        rebuilt.nar_hashes.insert(name, nar.nar_hash.to_typed_base32());
+    }
+
+    let old = match &state.index {
+        Some(index) => index.snapshot().await?,
+        None => {
+            let chunks = backend.list_chunks()
+                .await?
+                .into_iter()
+                .map(|chunk| chunk.name)
+                .chain(rebuilt.chunk_refs.keys().cloned());
+
+            // NAR hashes are only stored in the index
+            IndexSnapshot {
+                chunk_refs: refs::snapshot_object_refs(&state, chunks).await?,
+                nar_hashes: rebuilt.nar_hashes.clone(),
+            }
+        }
+    };
+    let discrepancies = diff(&old, &rebuilt);
+    for discrepancy in &discrepancies {
+        tracing::warn!("{}", discrepancy);
+    }
+
+    if !dry_run && !discrepancies.is_empty() {
+        match &state.index {
+            Some(index) => index.restore(rebuilt.clone()).await?,
+            None => {
+                let chunks: BTreeSet<&String> = old.chunk_refs
+                    .keys()
+                    .chain(rebuilt.chunk_refs.keys())
+                    .collect();
+                for chunk in chunks {
+                    let nars = rebuilt.chunk_refs.get(chunk).cloned().unwrap_or_default();
+                    refs::restore_object_refs(&state, chunk, nars).await?;
+                }
+            }
+        }
+    }
+
+    tracing::info!(
+        "{}{} NARs, {} chunks, {} discrepancies{}",
+        if dry_run { "[dry run] " } else { "" },
+        rebuilt.nar_hashes.len(),
+        rebuilt.chunk_refs.len(),
+        discrepancies.len(),
+        if dry_run || discrepancies.is_empty() { "" } else { " fixed" },
+    );
+
+    Ok(())
+}
+
+/// Returns the differences between an index and a rebuilt one.
+fn diff(old: &IndexSnapshot, rebuilt: &IndexSnapshot) -> Vec<Discrepancy> {
+    let refs = |snapshot: &IndexSnapshot| {
+        snapshot.chunk_refs
+            .iter()
+            .flat_map(|(chunk, nars)| nars.iter().map(move |nar| (chunk.clone(), nar.clone())))
+            .collect::<BTreeSet<_>>()
+    };
+    let old_refs = refs(old);
+    let rebuilt_refs = refs(rebuilt);
+
+    let mut discrepancies = Vec::new();
+
+    for (chunk, nar) in rebuilt_refs.difference(&old_refs) {
+        discrepancies.push(Discrepancy::MissingChunkRef { chunk: chunk.clone(), nar: nar.clone() });
+    }
+    for (chunk, nar) in old_refs.difference(&rebuilt_refs) {
+        discrepancies.push(Discrepancy::StaleChunkRef { chunk: chunk.clone(), nar: nar.clone() });
+    }
+
+    for (store_path_hash, actual) in &rebuilt.nar_hashes {
+        match old.nar_hashes.get(store_path_hash) {
+            None => discrepancies.push(Discrepancy::MissingNarHash {
+                store_path_hash: store_path_hash.clone(),
+                nar_hash: actual.clone(),
+            }),
+            Some(indexed) if indexed != actual => discrepancies.push(Discrepancy::WrongNarHash {
+                store_path_hash: store_path_hash.clone(),
+                indexed: indexed.clone(),
+                actual: actual.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (store_path_hash, nar_hash) in &old.nar_hashes {
+        if !rebuilt.nar_hashes.contains_key(store_path_hash) {
+            discrepancies.push(Discrepancy::StaleNarHash {
+                store_path_hash: store_path_hash.clone(),
+                nar_hash: nar_hash.clone(),
+            });
+        }
+    }
+
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(chunk_refs: &[(&str, &str)], nar_hashes: &[(&str, &str)]) -> IndexSnapshot {
+        let mut snapshot = IndexSnapshot::default();
+        for (chunk, nar) in chunk_refs {
+            snapshot.chunk_refs.entry(chunk.to_string()).or_default().insert(nar.to_string());
+        }
+        for (store_path_hash, nar_hash) in nar_hashes {
+            snapshot.nar_hashes.insert(store_path_hash.to_string(), nar_hash.to_string());
+        }
+        snapshot
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = snapshot(
+            &[("x", "nar-a"), ("y", "nar-gone")],
+            &[("nar-a", "hash-old"), ("nar-gone", "hash-gone")],
+        );
+        let rebuilt = snapshot(
+            &[("x", "nar-a"), ("x", "nar-b")],
+            &[("nar-a", "hash-a"), ("nar-b", "hash-b")],
+        );
+
+        assert!(diff(&rebuilt, &rebuilt).is_empty());
+        assert_eq!(
+            vec![
+                Discrepancy::MissingChunkRef { chunk: "x".to_string(), nar: "nar-b".to_string() },
+                Discrepancy::StaleChunkRef { chunk: "y".to_string(), nar: "nar-gone".to_string() },
+                Discrepancy::WrongNarHash {
+                    store_path_hash: "nar-a".to_string(),
+                    indexed: "hash-old".to_string(),
+                    actual: "hash-a".to_string(),
+                },
+                Discrepancy::MissingNarHash { store_path_hash: "nar-b".to_string(), nar_hash: "hash-b".to_string() },
+                Discrepancy::StaleNarHash { store_path_hash: "nar-gone".to_string(), nar_hash: "hash-gone".to_string() },
+            ],
+            diff(&old, &rebuilt),
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Self-test of the storage pipeline.
+//!
+//! Synthetic data is chunked, compressed and uploaded with the
+//! configured settings, then downloaded and reassembled the way NARs
+//! are served. This catches misconfigured deployments, such as bad
+//! storage credentials, without a client.
+//!
+//! Chunks that did not exist before the test are deleted afterwards.
+
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Instant;
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+
+use libnixstore::Hash;
+use crate::api::UploadedChunk;
+use crate::api::binary_cache::{num_prefetch, stream_chunk_decompressed};
+use crate::chunking::{chunk_stream, get_data, merge_chunks};
+use crate::compression::get_compressor_fn;
+use crate::config::Config;
+use crate::storage::StorageBackend;
+use crate::{open_storage, reassembly_budget};
+
+/// Round-trips `size` bytes of synthetic data through storage.
+///
+/// Only the storage backend is opened, so this can run next to a
+/// server that holds the index open.
+///
+/// Fails if the reassembled data differs from the original.
+pub async fn run(config: Config, size: usize) -> Result<()> {
+    let backend = open_storage(&config.storage).await?;
+
+    let mut created = Vec::new();
+    let result = round_trip(&config, &backend, size, &mut created).await;
+
+    // Also clean up after a failed test
+    let mut cleanup = Ok(());
+    for name in created {
+        if let Err(e) = backend.delete_chunk(name.clone()).await {
+            tracing::warn!("Failed to delete chunk {} of the self-test: {}", name, e);
+            cleanup = Err(e);
+        }
+    }
+
+    result?;
+    cleanup?;
+
+    Ok(())
+}
+
+/// Uploads the data in chunks, then downloads and compares it.
+///
+/// The chunks that did not exist before are added to `created`
+/// before they are uploaded.
+async fn round_trip(
+    config: &Config,
+    backend: &Arc<Box<dyn StorageBackend>>,
+    size: usize,
+    created: &mut Vec<String>,
+) -> Result<()> {
+    let chunking = &config.chunking;
+    let compression = &config.compression;
+
+    let data = get_data(size);
+
+    let start = Instant::now();
+    let mut chunks = Vec::new();
+    let mut stream = chunk_stream(
+        Cursor::new(data.clone()),
+        chunking.min_size,
+        chunking.avg_size,
+        chunking.max_size,
+    );
+    while let Some(chunk) = stream.next().await {
+        let compressor = get_compressor_fn(compression.r#type, compression.level());
+        let mut compressed = Vec::new();
+        compressor(Cursor::new(chunk?))
+            .read_to_end(&mut compressed)
+            .await?;
+
+        let file_hash = Hash::sha256_from_bytes(&compressed);
+        let file_size = compressed.len();
+        let name = file_hash.to_typed_base32();
+        if !backend.chunk_exists(name.clone()).await? {
+            created.push(name.clone());
+        }
+
+        backend
+            .upload_chunk_with_size(name, &mut Cursor::new(compressed), file_size as u64)
+            .await?;
+
+        chunks.push(UploadedChunk {
+            file_hash,
+            file_size,
+            compression: compression.clone(),
+        });
+    }
+    let upload_time = start.elapsed();
+
+    let num_chunks = chunks.len();
+    let file_size: usize = chunks.iter().map(|chunk| chunk.file_size).sum();
+
+    let start = Instant::now();
+    let reassembled = reassemble(config, backend, chunks).await?;
+    let download_time = start.elapsed();
+
+    if reassembled != data {
+        return Err(anyhow!("Reassembled data does not match the uploaded data"));
+    }
+
+    tracing::info!(
+        "Self-test passed: {} bytes in {} chunks, {} bytes stored ({})",
+        size,
+        num_chunks,
+        file_size,
+        compression.r#type,
+    );
+    tracing::info!(
+        "Uploaded in {:.2?}, downloaded and reassembled in {:.2?}",
+        upload_time,
+        download_time,
+    );
+
+    Ok(())
+}
+
+/// Downloads and reassembles chunks like a NAR is served.
+async fn reassemble(
+    config: &Config,
+    backend: &Arc<Box<dyn StorageBackend>>,
+    chunks: Vec<UploadedChunk>,
+) -> Result<Vec<u8>> {
+    let num_prefetch = num_prefetch(&config.download, &chunks);
+    let mut stream = merge_chunks(
+        VecDeque::from(chunks),
+        stream_chunk_decompressed,
+        Arc::clone(backend),
+        num_prefetch,
+        reassembly_budget(config),
+    );
+
+    let mut data = Vec::new();
+    while let Some(bytes) = stream.next().await {
+        data.extend_from_slice(&bytes?);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::testing::{TestDir, TestState};
+
+    #[test]
+    fn test_run() {
+        block_on(async {
+            let state = TestState::new("").await;
+            run(state.config.clone(), 1024 * 1024).await.unwrap();
+
+            // The chunks are cleaned up
+            assert!(state.storage().list_chunks().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_run_with_open_index() {
+        let index_dir = TestDir::new("self-test-index");
+
+        block_on(async {
+            let index = index_dir.path().join("index.redb");
+            let state = TestState::new(&format!(r#"index = {{ type = "redb", path = "{}" }}"#, index.display())).await;
+
+            // The server holds the index open
+            run(state.config.clone(), 1024 * 1024).await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,1137 @@
+use anyhow::{anyhow, Result};
+use std::io::Error as IoError;
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Serialize, Deserialize};
+use aws_sdk_s3::primitives::DateTimeFormat;
+use aws_smithy_client::conns;
+use axum::http::{header, HeaderMap, Method, Request, Response, StatusCode};
+use bytes::{Bytes, BytesMut};
+use futures::stream::StreamExt;
+use hyper::Body;
+use jwt_simple::prelude::{Claims, Duration as JwtDuration, RS256KeyPair, RSAKeyPairLike};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Mutex;
+
+use crate::finally::Finally;
+use crate::chunking::read_chunk_async;
+use crate::error::{ErrorKind, ServerResult, ServerError};
+use super::{StorageBackend, HEALTH_CHECK_NAME, RemoteFile, Download, ChunkInfo, parse_timestamp};
+
+/// The chunk size for each request in a resumable upload.
+///
+/// Must be a multiple of 256 KiB.
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Number of chunk requests in a row that may persist nothing.
+///
+/// GCS may persist nothing of a chunk, in which case we send it again,
+/// but an upload that keeps making no progress is given up.
+const MAX_STALLED_REQUESTS: usize = 3;
+
+/// The OAuth 2.0 scope for reading and writing objects.
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Characters to escape in object names.
+///
+/// Object names are a single path segment in the JSON API, so `/`
+/// must be escaped too.
+const OBJECT_NAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The Google Cloud Storage remote file storage backend.
+#[derive(Debug)]
+pub struct GcsBackend {
+    client: hyper::Client<conns::Https, Body>,
+    config: GcsStorageConfig,
+    /// The service account to authenticate as.
+    ///
+    /// Requests are anonymous if `None`, which is only useful with
+    /// an emulator.
+    credentials: Option<GcsCredentialsConfig>,
+    /// The current access token.
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+/// Google Cloud Storage remote file storage configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GcsStorageConfig {
+    /// The name of the bucket.
+    bucket: String,
+    /// Custom GCS endpoint.
+    ///
+    /// Set this if you are using an emulator (e.g., fake-gcs-server).
+    #[serde(default = "default_endpoint")]
+    endpoint: String,
+    /// Service account credentials.
+    ///
+    /// If unset, the service account key file at
+    /// `GOOGLE_APPLICATION_CREDENTIALS` is used.
+    credentials: Option<GcsCredentialsConfig>,
+
+    /// Files of at least this size are uploaded with resumable uploads.
+    #[serde(rename = "resumable-upload-threshold")]
+    #[serde(default = "default_resumable_upload_threshold")]
+    resumable_upload_threshold: usize,
+
+    /// Dir name for chunks.
+    #[serde(default = "default_chunks_dir_name")]
+    chunks: String,
+    /// Dir name for NARs.
+    #[serde(default = "default_nars_dir_name")]
+    nars: String,
+    /// Dir name for chunk references.
+    #[serde(default = "default_refs_dir_name")]
+    refs: String,
+    /// Dir name for file listings.
+    #[serde(default = "default_listings_dir_name")]
+    listings: String,
+}
+
+/// GCS credential configuration.
+///
+/// The fields are named like in service account key files, so the
+/// relevant parts of a key file can be copied as they are.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GcsCredentialsConfig {
+    /// Email address of the service account.
+    client_email: String,
+    /// PEM-encoded private key of the service account.
+    private_key: String,
+    /// ID of the private key.
+    private_key_id: Option<String>,
+    /// OAuth 2.0 token endpoint.
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+/// Reference to a file in a Google Cloud Storage bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcsRemoteFile {
+    /// Name of the bucket.
+    pub bucket: String,
+    /// Name of the object.
+    pub key: String,
+}
+
+/// An OAuth 2.0 access token.
+#[derive(Debug)]
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+/// Response of the token endpoint.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Custom claims of the token request.
+#[derive(Debug, Serialize, Deserialize)]
+struct ScopeClaims {
+    scope: String,
+}
+
+/// Object metadata.
+#[derive(Debug, Deserialize)]
+struct Object {
+    name: Option<String>,
+    generation: Option<String>,
+    #[serde(rename = "timeCreated")]
+    time_created: Option<String>,
+}
+
+/// A page of objects.
+#[derive(Debug, Deserialize)]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<Object>,
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+}
+
+impl GcsBackend {
+    pub async fn new(config: GcsStorageConfig) -> Result<Self> {
+        let credentials = match &config.credentials {
+            Some(credentials) => Some(credentials.clone()),
+            None => match std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+                Some(path) => {
+                    let data = tokio::fs::read(&path).await?;
+                    Some(serde_json::from_slice(&data)?)
+                }
+                None if config.endpoint != default_endpoint() => {
+                    tracing::warn!("No GCS credentials configured, sending anonymous requests");
+                    None
+                }
+                None => {
+                    return Err(anyhow!("No GCS credentials configured and GOOGLE_APPLICATION_CREDENTIALS is not set"));
+                }
+            },
+        };
+
+        if let Some(credentials) = &credentials {
+            // Fail early on invalid keys
+            RS256KeyPair::from_pem(&credentials.private_key)?;
+        }
+
+        if config.resumable_upload_threshold == 0 {
+            return Err(anyhow!("resumable-upload-threshold must be positive"));
+        }
+
+        Ok(Self {
+            client: hyper::Client::builder().build(conns::https()),
+            config,
+            credentials,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Returns an access token, requesting a new one if needed.
+    async fn get_access_token(&self, credentials: &GcsCredentialsConfig) -> ServerResult<String> {
+        let mut access_token = self.access_token.lock().await;
+
+        // Refresh a bit early so the token doesn't expire in flight
+        if let Some(token) = &*access_token {
+            if token.expires_at > Instant::now() + Duration::from_secs(60) {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let mut key = RS256KeyPair::from_pem(&credentials.private_key)
+            .map_err(ErrorKind::StorageError)?;
+        if let Some(key_id) = &credentials.private_key_id {
+            key = key.with_key_id(key_id);
+        }
+
+        let claims = Claims::with_custom_claims(
+            ScopeClaims { scope: SCOPE.to_string() },
+            JwtDuration::from_hours(1),
+        )
+            .with_issuer(&credentials.client_email)
+            .with_audience(&credentials.token_uri);
+        let assertion = key.sign(claims)
+            .map_err(ErrorKind::StorageError)?;
+
+        let body = format!(
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}",
+            assertion,
+        );
+        let req = Request::post(&credentials.token_uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .map_err(ServerError::storage_error)?;
+
+        let requested_at = Instant::now();
+        let res = self.client.request(req)
+            .await
+            .map_err(ServerError::storage_error)?;
+        if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(ServerError::storage_error)?;
+        let res: TokenResponse = serde_json::from_slice(&body)
+            .map_err(ServerError::storage_error)?;
+
+        *access_token = Some(AccessToken {
+            token: res.access_token.clone(),
+            expires_at: requested_at + Duration::from_secs(res.expires_in),
+        });
+
+        Ok(res.access_token)
+    }
+
+    /// Sends an authenticated request.
+    async fn send(&self, method: Method, uri: String, headers: &[(header::HeaderName, String)], body: Body) -> ServerResult<Response<Body>> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri);
+
+        if let Some(credentials) = &self.credentials {
+            let token = self.get_access_token(credentials).await?;
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        let req = req.body(body)
+            .map_err(ServerError::storage_error)?;
+
+        self.client.request(req)
+            .await
+            .map_err(ServerError::storage_error)
+    }
+
+    async fn upload_file(
+        &self,
+        name: String,
+        mut stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        let buf = BytesMut::with_capacity(self.config.resumable_upload_threshold);
+        let first_chunk = read_chunk_async(&mut stream, buf)
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        if first_chunk.len() < self.config.resumable_upload_threshold {
+            // do a simple upload
+            let res = self.send(
+                Method::POST,
+                self.upload_uri(&name, "media", None),
+                &[],
+                Body::from(first_chunk),
+            ).await?;
+            if !res.status().is_success() {
+                return Err(error_response(res).await);
+            }
+
+            return Ok(self.remote_file(name));
+        }
+
+        let res = self.send(
+            Method::POST,
+            self.upload_uri(&name, "resumable", None),
+            &[(header::CONTENT_LENGTH, "0".to_string())],
+            Body::empty(),
+        ).await?;
+        if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        let session_uri = res.headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| ErrorKind::StorageError(anyhow!("Resumable upload has no session URI")))?
+            .to_string();
+
+        let cleanup = Finally::new({
+            let client = self.client.clone();
+            let session_uri = session_uri.clone();
+
+            async move {
+                tracing::warn!("Upload was interrupted - Cancelling resumable upload");
+
+                let req = Request::delete(session_uri)
+                    .header(header::CONTENT_LENGTH, "0")
+                    .body(Body::empty())
+                    .unwrap();
+
+                // Cancelled sessions respond with 499
+                if let Err(e) = client.request(req).await {
+                    tracing::warn!("Failed to cancel resumable upload: {}", e);
+                }
+            }
+        });
+
+        let mut offset = 0;
+        let mut stalled = 0;
+        let mut pending = BytesMut::from(first_chunk.as_ref());
+
+        loop {
+            // Read ahead to know whether this is the last chunk
+            while pending.len() <= CHUNK_SIZE {
+                let buf = BytesMut::with_capacity(CHUNK_SIZE);
+                let more = read_chunk_async(&mut stream, buf)
+                    .await
+                    .map_err(ServerError::storage_error)?;
+
+                if more.is_empty() {
+                    break;
+                }
+                pending.extend_from_slice(&more);
+            }
+
+            let last = pending.len() <= CHUNK_SIZE;
+            let chunk = if last {
+                pending.split().freeze()
+            } else {
+                pending.split_to(CHUNK_SIZE).freeze()
+            };
+
+            let end = offset + chunk.len();
+            let total = last.then_some(end);
+            let range = content_range(offset, chunk.len(), total);
+
+            let res = self.send(
+                Method::PUT,
+                session_uri.clone(),
+                &[(header::CONTENT_RANGE, range)],
+                Body::from(chunk.clone()),
+            ).await?;
+
+            if last && res.status().is_success() {
+                break;
+            } else if res.status() != StatusCode::PERMANENT_REDIRECT {
+                return Err(error_response(res).await);
+            }
+
+            // GCS may persist only part of a chunk, so we send the rest again
+            let persisted = persisted_size(res.headers())?;
+            if persisted < offset || persisted > end {
+                return Err(ErrorKind::StorageError(anyhow!(
+                    "Resumable upload persisted {} bytes after sending bytes {}-{}",
+                    persisted,
+                    offset,
+                    end,
+                )).into());
+            }
+
+            if persisted == offset {
+                stalled += 1;
+                if stalled >= MAX_STALLED_REQUESTS {
+                    return Err(ErrorKind::StorageError(anyhow!(
+                        "Resumable upload persisted nothing after {} requests at offset {}",
+                        stalled,
+                        offset,
+                    )).into());
+                }
+            } else {
+                stalled = 0;
+            }
+            if persisted < end {
+                let mut rest = BytesMut::from(&chunk[persisted - offset..]);
+                rest.extend_from_slice(&pending);
+                pending = rest;
+            }
+            offset = persisted;
+        }
+
+        cleanup.cancel();
+
+        Ok(self.remote_file(name))
+    }
+
+    async fn download_file(&self, name: String) -> ServerResult<Option<Download>> {
+        self.download_file_with(name, &[]).await
+    }
+    async fn download_file_range(&self, name: String, range: Range<u64>) -> ServerResult<Option<Download>> {
+        let range = format!("bytes={}-{}", range.start, range.end - 1);
+        self.download_file_with(name, &[(header::RANGE, range)]).await
+    }
+    async fn download_file_with(&self, name: String, headers: &[(header::HeaderName, String)]) -> ServerResult<Option<Download>> {
+        let res = self.send(Method::GET, self.media_uri(&name), headers, Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        let stream = StreamExt::map(res.into_body(), |item| {
+            item.map_err(IoError::other)
+        });
+
+        Ok(Some(Download::Stream(Box::pin(stream))))
+    }
+
+    async fn delete_file(&self, name: String) -> ServerResult<()> {
+        let res = self.send(Method::DELETE, self.object_uri(&name), &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND || res.status().is_success() {
+            Ok(())
+        } else {
+            Err(error_response(res).await)
+        }
+    }
+
+    /// Downloads a small file into memory along with its generation.
+    ///
+    /// Returns `None` if the file does not exist.
+    async fn download_small_file(&self, name: String) -> ServerResult<Option<(Bytes, String)>> {
+        let res = self.send(Method::GET, self.media_uri(&name), &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        let generation = res.headers()
+            .get("x-goog-generation")
+            .and_then(|generation| generation.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let data = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        Ok(Some((data, generation)))
+    }
+
+    /// Uploads a small file with a generation precondition.
+    ///
+    /// We use object generations as ETags since they change whenever
+    /// an object is overwritten.
+    async fn upload_file_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data)
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        // Generation 0 matches only if the object does not exist
+        let generation = etag.unwrap_or_else(|| "0".to_string());
+        let res = self.send(
+            Method::POST,
+            self.upload_uri(&name, "media", Some(&generation)),
+            &[],
+            Body::from(data),
+        ).await?;
+
+        if res.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(ErrorKind::PreconditionFailed.into());
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        Ok(self.remote_file(name))
+    }
+
+    async fn get_etag(&self, name: String) -> ServerResult<Option<String>> {
+        let uri = format!("{}?fields=generation", self.object_uri(&name));
+        let res = self.send(Method::GET, uri, &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(ServerError::storage_error)?;
+        let object: Object = serde_json::from_slice(&body)
+            .map_err(ServerError::storage_error)?;
+
+        Ok(object.generation)
+    }
+
+    async fn file_exists(&self, name: String) -> ServerResult<bool> {
+        Ok(self.get_etag(name).await?.is_some())
+    }
+
+    fn remote_file(&self, name: String) -> RemoteFile {
+        RemoteFile::Gcs(GcsRemoteFile {
+            bucket: self.config.bucket.clone(),
+            key: name,
+        })
+    }
+
+    fn object_uri(&self, name: &str) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.config.endpoint,
+            encode(&self.config.bucket),
+            encode(name),
+        )
+    }
+    fn media_uri(&self, name: &str) -> String {
+        format!("{}?alt=media", self.object_uri(name))
+    }
+    fn upload_uri(&self, name: &str, upload_type: &str, if_generation_match: Option<&str>) -> String {
+        let mut uri = format!(
+            "{}/upload/storage/v1/b/{}/o?uploadType={}&name={}",
+            self.config.endpoint,
+            encode(&self.config.bucket),
+            upload_type,
+            encode(name),
+        );
+        if let Some(generation) = if_generation_match {
+            uri.push_str(&format!("&ifGenerationMatch={}", encode(generation)));
+        }
+        uri
+    }
+
+    fn get_chunk_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.chunks, p)
+    }
+    fn get_nar_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.nars, p)
+    }
+    fn get_refs_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.refs, p)
+    }
+    fn get_listing_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.listings, p)
+    }
+    /// Lists all objects whose names start with `prefix`.
+    async fn list_objects(&self, prefix: &str) -> ServerResult<Vec<Object>> {
+        self.list_objects_from(prefix, None, None).await
+    }
+    /// Lists objects whose names start with `prefix` in lexicographic order.
+    ///
+    /// Listing starts at `start_offset`, inclusive, if set and stops
+    /// after `limit` objects if set.
+    async fn list_objects_from(
+        &self,
+        prefix: &str,
+        start_offset: Option<&str>,
+        limit: Option<usize>,
+    ) -> ServerResult<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut uri = format!(
+                "{}/storage/v1/b/{}/o?prefix={}&fields=items(name,timeCreated),nextPageToken",
+                self.config.endpoint,
+                encode(&self.config.bucket),
+                encode(prefix),
+            );
+            if let Some(start_offset) = start_offset {
+                uri.push_str(&format!("&startOffset={}", encode(start_offset)));
+            }
+            if let Some(limit) = limit {
+                uri.push_str(&format!("&maxResults={}", limit));
+            }
+            if let Some(page_token) = &page_token {
+                uri.push_str(&format!("&pageToken={}", encode(page_token)));
+            }
+
+            let res = self.send(Method::GET, uri, &[], Body::empty()).await?;
+            if !res.status().is_success() {
+                return Err(error_response(res).await);
+            }
+
+            let body = hyper::body::to_bytes(res.into_body())
+                .await
+                .map_err(ServerError::storage_error)?;
+            let page: ObjectList = serde_json::from_slice(&body)
+                .map_err(ServerError::storage_error)?;
+
+            objects.extend(page.items);
+            if limit.is_some_and(|limit| objects.len() >= limit) {
+                break;
+            }
+
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        if let Some(limit) = limit {
+            objects.truncate(limit);
+        }
+
+        Ok(objects)
+    }
+}
+#[async_trait::async_trait]
+impl StorageBackend for GcsBackend {
+    async fn upload_chunk(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file(self.get_chunk_path(&name), stream).await
+    }
+    async fn upload_nar(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file(self.get_nar_path(&name), stream).await
+    }
+
+    async fn download_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file(self.get_chunk_path(&name)).await
+    }
+    async fn download_chunk_range(
+        &self,
+        name: String,
+        range: Range<u64>,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file_range(self.get_chunk_path(&name), range).await
+    }
+    async fn presign_chunk(
+        &self,
+        _name: String,
+    ) -> ServerResult<Option<String>> {
+        Ok(None)
+    }
+    async fn delete_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_chunk_path(&name)).await
+    }
+    async fn chunk_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        self.file_exists(self.get_chunk_path(&name)).await
+    }
+    async fn download_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file(self.get_nar_path(&name)).await
+    }
+    async fn delete_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_nar_path(&name)).await
+    }
+    async fn nar_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        self.file_exists(self.get_nar_path(&name)).await
+    }
+    async fn health_check(&self) -> ServerResult<()> {
+        self.file_exists(self.get_nar_path(HEALTH_CHECK_NAME)).await?;
+        Ok(())
+    }
+    async fn upload_nar_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file_if_match(self.get_nar_path(&name), stream, etag).await
+    }
+    async fn get_nar_etag(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>> {
+        self.get_etag(self.get_nar_path(&name)).await
+    }
+    async fn download_chunk_refs(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<(Bytes, String)>> {
+        self.download_small_file(self.get_refs_path(&name)).await
+    }
+    async fn upload_chunk_refs_if_match(
+        &self,
+        name: String,
+        data: Bytes,
+        etag: Option<String>,
+    ) -> ServerResult<()> {
+        self.upload_file_if_match(self.get_refs_path(&name), &mut data.as_ref(), etag).await?;
+        Ok(())
+    }
+    async fn upload_listing(
+        &self,
+        name: String,
+        data: Bytes,
+    ) -> ServerResult<()> {
+        self.upload_file(self.get_listing_path(&name), &mut data.as_ref()).await?;
+        Ok(())
+    }
+    async fn download_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Bytes>> {
+        let file = self.download_small_file(self.get_listing_path(&name)).await?;
+        Ok(file.map(|(data, _)| data))
+    }
+    async fn delete_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_listing_path(&name)).await
+    }
+    async fn list_nars(&self) -> ServerResult<Vec<String>> {
+        let prefix = self.get_nar_path("");
+        let names = self.list_objects(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|object| Some(object.name?.strip_prefix(&prefix)?.to_string()))
+            .collect();
+
+        Ok(names)
+    }
+    async fn list_nars_page(
+        &self,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> ServerResult<Vec<String>> {
+        let prefix = self.get_nar_path("");
+        let start_offset = start_after.as_ref().map(|name| self.get_nar_path(name));
+
+        // The start offset itself is listed if it exists
+        let names = self.list_objects_from(&prefix, start_offset.as_deref(), Some(limit + 1))
+            .await?
+            .into_iter()
+            .filter_map(|object| Some(object.name?.strip_prefix(&prefix)?.to_string()))
+            .filter(|name| match &start_after {
+                Some(start_after) => name > start_after,
+                None => true,
+            })
+            .take(limit)
+            .collect();
+
+        Ok(names)
+    }
+    async fn list_chunks(&self) -> ServerResult<Vec<ChunkInfo>> {
+        let prefix = self.get_chunk_path("");
+        let chunks = self.list_objects(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|object| {
+                let name = object.name?.strip_prefix(&prefix)?.to_string();
+                // Objects without a known age are treated as new
+                let last_modified = object.time_created
+                    .and_then(|t| parse_timestamp(&t, DateTimeFormat::DateTimeWithOffset))
+                    .unwrap_or_else(SystemTime::now);
+
+                Some(ChunkInfo { name, last_modified })
+            })
+            .collect();
+
+        Ok(chunks)
+    }
+}
+
+/// Turns an unexpected response into an error.
+async fn error_response(res: Response<Body>) -> ServerError {
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .unwrap_or_default();
+
+    ErrorKind::StorageError(anyhow!(
+        "GCS request failed with {}: {}",
+        status,
+        String::from_utf8_lossy(&body),
+    )).into()
+}
+
+/// Returns the `Content-Range` of a chunk in a resumable upload.
+///
+/// `total` is only known for the last chunk.
+fn content_range(offset: usize, len: usize, total: Option<usize>) -> String {
+    let total = total.map(|total| total.to_string()).unwrap_or_else(|| "*".to_string());
+    if len == 0 {
+        format!("bytes */{}", total)
+    } else {
+        format!("bytes {}-{}/{}", offset, offset + len - 1, total)
+    }
+}
+
+/// Returns the number of bytes that a resumable upload persisted.
+///
+/// This is given by the `Range` header of a 308 response, which is
+/// missing if no bytes were persisted yet.
+fn persisted_size(headers: &HeaderMap) -> ServerResult<usize> {
+    let range = match headers.get(header::RANGE) {
+        Some(range) => range,
+        None => return Ok(0),
+    };
+
+    range.to_str()
+        .ok()
+        .and_then(|range| range.strip_prefix("bytes=0-"))
+        .and_then(|end| end.parse::<usize>().ok())
+        .map(|end| end + 1)
+        .ok_or_else(|| ErrorKind::StorageError(anyhow!("Invalid range of resumable upload: {:?}", range)).into())
+}
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, OBJECT_NAME).to_string()
+}
+
+fn default_endpoint() -> String {
+    "https://storage.googleapis.com".to_string()
+}
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+fn default_resumable_upload_threshold() -> usize {
+    CHUNK_SIZE
+}
+fn default_chunks_dir_name() -> String {
+    "chunks".to_string()
+}
+fn default_nars_dir_name() -> String {
+    "nars".to_string()
+}
+fn default_refs_dir_name() -> String {
+    "refs".to_string()
+}
+fn default_listings_dir_name() -> String {
+    "listings".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use axum::extract::Extension;
+    use axum::http::{HeaderValue, Uri};
+    use axum::response::IntoResponse;
+    use axum::Router;
+    use percent_encoding::percent_decode_str;
+    use tokio_test::block_on;
+
+    use super::*;
+
+    /// Size that resumable upload requests are persisted in.
+    const PERSIST_UNIT: usize = 256 * 1024;
+
+    /// An in-memory GCS JSON API.
+    #[derive(Debug, Default)]
+    struct MockGcs {
+        endpoint: String,
+        objects: HashMap<String, Bytes>,
+        /// Resumable upload sessions with the object name and the persisted data.
+        sessions: HashMap<String, (String, Vec<u8>)>,
+        /// Number of upcoming chunk requests that persist nothing.
+        stalls: usize,
+        /// Number of chunk requests received.
+        chunk_requests: usize,
+    }
+
+    type SharedMockGcs = Arc<StdMutex<MockGcs>>;
+
+    async fn handle(
+        Extension(mock): Extension<SharedMockGcs>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> axum::response::Response {
+        let mut mock = mock.lock().unwrap();
+        let query: HashMap<_, _> = uri.query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (k.to_string(), percent_decode_str(v).decode_utf8_lossy().to_string()))
+            .collect();
+        let path = uri.path().to_string();
+
+        if let Some(id) = path.strip_prefix("/session/") {
+            return mock.put_chunk(id, &method, &headers, body);
+        }
+
+        if path.starts_with("/upload/") {
+            let name = query["name"].clone();
+            if query["uploadType"] == "resumable" {
+                let id = mock.sessions.len().to_string();
+                mock.sessions.insert(id.clone(), (name, Vec::new()));
+                let location = format!("{}/session/{}", mock.endpoint, id);
+                return (StatusCode::OK, [(header::LOCATION, location)]).into_response();
+            }
+
+            if let Some(generation) = query.get("ifGenerationMatch") {
+                let exists = mock.objects.contains_key(&name);
+                if (generation == "0") == exists {
+                    return StatusCode::PRECONDITION_FAILED.into_response();
+                }
+            }
+            mock.objects.insert(name, body);
+            return StatusCode::OK.into_response();
+        }
+
+        let name = path.rsplit('/').next().unwrap();
+        let name = percent_decode_str(name).decode_utf8_lossy().to_string();
+        let data = match mock.objects.get(&name) {
+            Some(data) => data.clone(),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        };
+
+        match (method, query.get("alt").map(String::as_str)) {
+            (Method::DELETE, _) => {
+                mock.objects.remove(&name);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (Method::GET, Some("media")) => {
+                let range = headers.get(header::RANGE)
+                    .and_then(|range| range.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+                    .map(|(start, end)| start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1);
+                match range {
+                    Some(range) => (StatusCode::PARTIAL_CONTENT, data.slice(range)).into_response(),
+                    None => (StatusCode::OK, [("x-goog-generation", "1")], data).into_response(),
+                }
+            }
+            (Method::GET, _) => r#"{"generation":"1"}"#.into_response(),
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    impl MockGcs {
+        /// Handles a request of a resumable upload session.
+        ///
+        /// Chunks that don't finish the upload are only persisted in
+        /// part, to make the client send the rest again.
+        fn put_chunk(&mut self, id: &str, method: &Method, headers: &HeaderMap, body: Bytes) -> axum::response::Response {
+            if method == Method::DELETE {
+                self.sessions.remove(id);
+                return StatusCode::from_u16(499).unwrap().into_response();
+            }
+
+            self.chunk_requests += 1;
+            let (name, persisted) = self.sessions.get_mut(id).unwrap();
+
+            let range = headers[header::CONTENT_RANGE].to_str().unwrap().strip_prefix("bytes ").unwrap();
+            let (range, total) = range.split_once('/').unwrap();
+            let start = range.split_once('-').map_or(persisted.len(), |(start, _)| start.parse().unwrap());
+            if start != persisted.len() {
+                return (StatusCode::BAD_REQUEST, "Chunk does not start at the persisted size").into_response();
+            }
+
+            let persisted_range = |persisted: &[u8]| {
+                let mut headers = HeaderMap::new();
+                if !persisted.is_empty() {
+                    let range = format!("bytes=0-{}", persisted.len() - 1);
+                    headers.insert(header::RANGE, HeaderValue::from_str(&range).unwrap());
+                }
+                (StatusCode::PERMANENT_REDIRECT, headers).into_response()
+            };
+
+            if self.stalls > 0 {
+                self.stalls -= 1;
+                return persisted_range(&*persisted);
+            }
+
+            if total.parse() == Ok(start + body.len()) {
+                persisted.extend_from_slice(&body);
+                let (name, data) = (name.clone(), std::mem::take(persisted));
+                self.objects.insert(name, data.into());
+                return StatusCode::OK.into_response();
+            }
+
+            let len = (body.len() / 2 / PERSIST_UNIT).max(1) * PERSIST_UNIT;
+            persisted.extend_from_slice(&body[..len]);
+            persisted_range(&*persisted)
+        }
+    }
+
+    /// Starts a mock GCS server and returns a backend using it.
+    async fn mock_backend() -> (GcsBackend, SharedMockGcs) {
+        let mock = SharedMockGcs::default();
+        let router = Router::new()
+            .fallback(handle)
+            .layer(Extension(mock.clone()));
+
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(router.into_make_service());
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        mock.lock().unwrap().endpoint = endpoint.clone();
+
+        let config: GcsStorageConfig = toml::from_str(&format!(r#"
+bucket = "test"
+endpoint = "{}"
+resumable-upload-threshold = 1024
+"#, endpoint)).unwrap();
+
+        (GcsBackend::new(config).await.unwrap(), mock)
+    }
+
+    async fn download(download: Option<Download>) -> Vec<u8> {
+        let mut data = Vec::new();
+        download.unwrap()
+            .into_async_read()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_upload_download() {
+        block_on(async {
+            let (backend, _mock) = mock_backend().await;
+            let data = b"Hello, world!".to_vec();
+
+            assert!(!backend.chunk_exists("abc".to_string()).await.unwrap());
+            assert!(backend.download_chunk("abc".to_string()).await.unwrap().is_none());
+
+            backend.upload_chunk("abc".to_string(), &mut data.as_slice()).await.unwrap();
+            assert!(backend.chunk_exists("abc".to_string()).await.unwrap());
+            assert_eq!(data, download(backend.download_chunk("abc".to_string()).await.unwrap()).await);
+            assert_eq!(b"world", download(backend.download_chunk_range("abc".to_string(), 7..12).await.unwrap()).await.as_slice());
+
+            backend.delete_chunk("abc".to_string()).await.unwrap();
+            assert!(!backend.chunk_exists("abc".to_string()).await.unwrap());
+            backend.delete_chunk("abc".to_string()).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_upload_if_match() {
+        block_on(async {
+            let (backend, _mock) = mock_backend().await;
+
+            backend.upload_nar_if_match("abc".to_string(), &mut b"1".as_slice(), None).await.unwrap();
+            let err = backend.upload_nar_if_match("abc".to_string(), &mut b"2".as_slice(), None).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::PreconditionFailed));
+
+            let etag = backend.get_nar_etag("abc".to_string()).await.unwrap();
+            backend.upload_nar_if_match("abc".to_string(), &mut b"2".as_slice(), etag).await.unwrap();
+            assert_eq!(b"2", download(backend.download_nar("abc".to_string()).await.unwrap()).await.as_slice());
+        });
+    }
+
+    #[test]
+    fn test_resumable_upload() {
+        block_on(async {
+            let (backend, mock) = mock_backend().await;
+            let data = crate::chunking::get_data(2 * CHUNK_SIZE + 1000);
+
+            // The first chunk request persists nothing and gets no `Range`
+            mock.lock().unwrap().stalls = 1;
+            backend.upload_nar("abc".to_string(), &mut data.as_slice()).await.unwrap();
+
+            assert!(mock.lock().unwrap().chunk_requests > 4);
+            assert_eq!(data, download(backend.download_nar("abc".to_string()).await.unwrap()).await);
+        });
+    }
+
+    #[test]
+    fn test_resumable_upload_without_progress() {
+        block_on(async {
+            let (backend, mock) = mock_backend().await;
+            let data = crate::chunking::get_data(2 * CHUNK_SIZE);
+
+            mock.lock().unwrap().stalls = usize::MAX;
+            let err = backend.upload_nar("abc".to_string(), &mut data.as_slice()).await.unwrap_err();
+            assert!(err.to_string().contains("persisted nothing"));
+            assert_eq!(MAX_STALLED_REQUESTS, mock.lock().unwrap().chunk_requests);
+            assert!(!backend.nar_exists("abc".to_string()).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!("bytes 0-9/*", content_range(0, 10, None));
+        assert_eq!("bytes 10-14/15", content_range(10, 5, Some(15)));
+        assert_eq!("bytes */10", content_range(10, 0, Some(10)));
+    }
+
+    #[test]
+    fn test_persisted_size() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(0, persisted_size(&headers).unwrap());
+
+        headers.insert(header::RANGE, "bytes=0-262143".parse().unwrap());
+        assert_eq!(262144, persisted_size(&headers).unwrap());
+
+        headers.insert(header::RANGE, "bytes=10-20".parse().unwrap());
+        assert!(persisted_size(&headers).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!("nars%2Fabc-1.nar", encode("nars/abc-1.nar"));
+    }
+}
@@ -1,15 +1,42 @@
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::io::{ErrorKind as IoErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use serde::Deserialize;
-use tokio::io::{self, AsyncRead};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt};
 use tokio::fs::{self, File};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::task::spawn_blocking;
 
-use crate::error::{ServerError, ServerResult};
-use super::{StorageBackend, RemoteFile, Download};
+use crate::error::{ErrorKind, ServerError, ServerResult};
+use crate::finally::Finally;
+use super::{StorageBackend, RemoteFile, Download, ChunkInfo};
+
+/// Distinguishes temporary files of concurrent uploads.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Name of the file that is locked while NARs and chunk references
+/// are written.
+const META_LOCK_FILE_NAME: &str = ".meta.lock";
 
 #[derive(Debug)]
 pub struct LocalBackend {
     config: LocalStorageConfig,
+    /// Serializes writes to NARs and chunk references in this process.
+    ///
+    /// Other processes sharing the directory are excluded by locking
+    /// a file as well (see `lock_meta`).
+    meta_lock: Mutex<()>,
+}
+
+/// Exclusive access to NARs and chunk references.
+struct MetaLockGuard<'a> {
+    /// The locked file, which is unlocked when closed.
+    _file: std::fs::File,
+    _guard: MutexGuard<'a, ()>,
 }
 
 /// Reference to a file in local storage.
@@ -24,6 +51,9 @@ pub struct LocalRemoteFile {
 #[derive(Debug, Clone, Deserialize)]
 pub struct LocalStorageConfig {
     /// The directory to store all files under.
+    ///
+    /// A leading `~` and environment variables (`$VAR` or `${VAR}`)
+    /// are expanded.
     path: PathBuf,
     /// Dir name for chunks.
     #[serde(default = "default_chunks_dir_name")]
@@ -31,6 +61,28 @@ pub struct LocalStorageConfig {
     /// Dir name for NARs.
     #[serde(default = "default_nars_dir_name")]
     nars: String,
+    /// Dir name for chunk references.
+    #[serde(default = "default_refs_dir_name")]
+    refs: String,
+    /// Dir name for file listings.
+    #[serde(default = "default_listings_dir_name")]
+    listings: String,
+    /// Levels of subdirectories to shard chunks, NARs, chunk
+    /// references and file listings into.
+    ///
+    /// Each level is named after the next two characters of the
+    /// hash in the file name, so `sha256:abcd...` is stored at
+    /// `chunks/ab/cd/sha256:abcd...` with two levels. Changing
+    /// this on an existing cache makes existing files unreachable.
+    #[serde(rename = "shard-levels")]
+    #[serde(default)]
+    shard_levels: usize,
+    /// Whether to flush files to disk before reporting an upload as done.
+    ///
+    /// Without this, files acknowledged shortly before a power loss
+    /// may be lost. Disabling it improves upload throughput.
+    #[serde(default = "default_fsync")]
+    fsync: bool,
 }
 impl Default for LocalStorageConfig {
     fn default() -> Self {
@@ -38,40 +90,155 @@ impl Default for LocalStorageConfig {
             path: "/tmp/_nixcache".into(),
             chunks: default_chunks_dir_name(),
             nars: default_nars_dir_name(),
+            refs: default_refs_dir_name(),
+            listings: default_listings_dir_name(),
+            shard_levels: 0,
+            fsync: default_fsync(),
         }
     }
 }
 
 impl LocalBackend {
-    pub async fn new(config: LocalStorageConfig) -> Result<Self> {
+    pub async fn new(mut config: LocalStorageConfig) -> Result<Self> {
+        config.path = expand_path(&config.path)?;
+
         fs::create_dir_all(&config.path.join(&config.chunks))
             .await?;
         fs::create_dir_all(&config.path.join(&config.nars))
             .await?;
+        fs::create_dir_all(&config.path.join(&config.refs))
+            .await?;
+        fs::create_dir_all(&config.path.join(&config.listings))
+            .await?;
 
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            meta_lock: Mutex::new(()),
+        })
     }
     fn get_chunk_path(&self, p: &str) -> PathBuf {
-        self.config.path.join(&self.config.chunks).join(p)
+        self.get_sharded_path(&self.config.chunks, p)
     }
     fn get_nar_path(&self, p: &str) -> PathBuf {
-        self.config.path.join(&self.config.nars).join(p)
+        self.get_sharded_path(&self.config.nars, p)
+    }
+    /// Returns the path of a file inside its shard subdirectories.
+    ///
+    /// Shards are named after the part of the name following the
+    /// hash type, if any.
+    fn get_sharded_path(&self, dir: &str, p: &str) -> PathBuf {
+        let mut path = self.config.path.join(dir);
+        let hash = p.rsplit(':').next().unwrap_or(p);
+        for level in 0..self.config.shard_levels {
+            match hash.get(level * 2..level * 2 + 2) {
+                Some(shard) => path.push(shard),
+                None => break,
+            }
+        }
+        path.join(p)
+    }
+    fn get_refs_path(&self, p: &str) -> PathBuf {
+        self.get_sharded_path(&self.config.refs, p)
+    }
+    fn get_listing_path(&self, p: &str) -> PathBuf {
+        self.get_sharded_path(&self.config.listings, p)
+    }
+    /// Returns the innermost shard subdirectories of a directory.
+    ///
+    /// Without sharding, this is the directory itself.
+    async fn list_shards(&self, dir: &str) -> ServerResult<Vec<PathBuf>> {
+        let mut dirs = vec![self.config.path.join(dir)];
+        for _ in 0..self.config.shard_levels {
+            let mut shards = Vec::new();
+            for dir in dirs {
+                shards.extend(list_dir(&dir).await?.into_iter().map(|name| dir.join(name)));
+            }
+            dirs = shards;
+        }
+
+        Ok(dirs)
     }
+    /// Uploads a file atomically.
+    ///
+    /// The file is written to a temporary file in the same directory
+    /// and renamed into place once complete, so readers never observe
+    /// a partial file. Temporary files start with `.` so they are not
+    /// listed.
     async fn upload(
         &self,
         path: PathBuf,
-        mut stream: &mut (dyn AsyncRead + Unpin + Send),
+        stream: &mut (dyn AsyncRead + Unpin + Send),
     ) -> ServerResult<()> {
-        let mut file = File::create(path)
-            .await
-            .map_err(ServerError::storage_error)?;
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let temp_path = path.with_file_name(format!(
+            ".{}.tmp.{}.{}",
+            file_name,
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+
+        if self.config.shard_levels > 0 {
+            fs::create_dir_all(path.parent().unwrap())
+                .await
+                .map_err(ServerError::storage_error)?;
+        }
+
+        let cleanup_path = temp_path.clone();
+        let cleanup = Finally::new(async move {
+            let _ = fs::remove_file(cleanup_path).await;
+        });
 
-        io::copy(&mut stream, &mut file)
+        write_file(&temp_path, stream, self.config.fsync).await?;
+        fs::rename(temp_path, &path)
             .await
             .map_err(ServerError::storage_error)?;
 
+        // Make the rename itself durable
+        if self.config.fsync {
+            sync_dir(path.parent().unwrap()).await?;
+        }
+
+        cleanup.cancel();
+
         Ok(())
     }
+    /// Locks NARs and chunk references for writing.
+    ///
+    /// Conditional uploads compare the ETag and replace the file while
+    /// holding the lock. An advisory lock on a file in the storage
+    /// directory also excludes other processes sharing it, such as
+    /// several servers or a `reindex` run. The operating system
+    /// releases it if a process dies.
+    async fn lock_meta(&self) -> ServerResult<MetaLockGuard<'_>> {
+        let guard = self.meta_lock.lock().await;
+
+        let path = self.config.path.join(META_LOCK_FILE_NAME);
+        let file = spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            file.lock()?;
+            Ok::<_, io::Error>(file)
+        })
+        .await
+        .map_err(ServerError::storage_error)?
+        .map_err(ServerError::storage_error)?;
+
+        Ok(MetaLockGuard {
+            _file: file,
+            _guard: guard,
+        })
+    }
+    /// Returns the ETag of a file, which is the SHA-256 hash of its content.
+    async fn get_etag(&self, path: PathBuf) -> ServerResult<Option<String>> {
+        match fs::read(path).await {
+            Ok(data) => Ok(Some(hex::encode(Sha256::digest(data)))),
+            Err(e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ServerError::storage_error(e)),
+        }
+    }
 }
 #[async_trait::async_trait]
 impl StorageBackend for LocalBackend {
@@ -90,30 +257,214 @@ impl StorageBackend for LocalBackend {
         name: String,
         stream: &mut (dyn AsyncRead + Unpin + Send),
     ) -> ServerResult<RemoteFile> {
+        let _lock = self.lock_meta().await?;
         self.upload(self.get_nar_path(&name), stream).await?;
         Ok(RemoteFile::Local(LocalRemoteFile {
             name
         }))
     }
+    async fn upload_nar_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        let _lock = self.lock_meta().await?;
+        let path = self.get_nar_path(&name);
+
+        if self.get_etag(path.clone()).await? != etag {
+            return Err(ErrorKind::PreconditionFailed.into());
+        }
+
+        self.upload(path, stream).await?;
+        Ok(RemoteFile::Local(LocalRemoteFile {
+            name
+        }))
+    }
     async fn download_chunk(
         &self,
         name: String,
-    ) -> ServerResult<Download> {
-        let file = File::open(self.get_chunk_path(&name))
-            .await
+    ) -> ServerResult<Option<Download>> {
+        let file = open_if_exists(self.get_chunk_path(&name)).await?;
+
+        Ok(file.map(|f| Download::AsyncRead(Box::new(f))))
+    }
+    async fn download_chunk_range(
+        &self,
+        name: String,
+        range: Range<u64>,
+    ) -> ServerResult<Option<Download>> {
+        let mut file = match open_if_exists(self.get_chunk_path(&name)).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        file.seek(SeekFrom::Start(range.start)).await
             .map_err(ServerError::storage_error)?;
 
-        Ok(Download::AsyncRead(Box::new(file)))
+        Ok(Some(Download::AsyncRead(Box::new(file.take(range.end - range.start)))))
+    }
+    async fn presign_chunk(
+        &self,
+        _name: String,
+    ) -> ServerResult<Option<String>> {
+        Ok(None)
+    }
+    async fn delete_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        remove_if_exists(self.get_chunk_path(&name)).await
+    }
+    async fn chunk_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        file_exists(self.get_chunk_path(&name)).await
     }
     async fn download_nar(
         &self,
         name: String,
-    ) -> ServerResult<Download> {
-        let file = File::open(self.get_nar_path(&name))
-            .await
-            .map_err(ServerError::storage_error)?;
+    ) -> ServerResult<Option<Download>> {
+        let file = open_if_exists(self.get_nar_path(&name)).await?;
+
+        Ok(file.map(|f| Download::AsyncRead(Box::new(f))))
+    }
+    async fn delete_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        let _lock = self.lock_meta().await?;
+        remove_if_exists(self.get_nar_path(&name)).await
+    }
+    async fn nar_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        file_exists(self.get_nar_path(&name)).await
+    }
+    async fn get_nar_etag(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>> {
+        self.get_etag(self.get_nar_path(&name)).await
+    }
+    async fn download_chunk_refs(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<(Bytes, String)>> {
+        match fs::read(self.get_refs_path(&name)).await {
+            Ok(data) => {
+                let etag = hex::encode(Sha256::digest(&data));
+                Ok(Some((data.into(), etag)))
+            }
+            Err(e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ServerError::storage_error(e)),
+        }
+    }
+    async fn upload_chunk_refs_if_match(
+        &self,
+        name: String,
+        data: Bytes,
+        etag: Option<String>,
+    ) -> ServerResult<()> {
+        let _lock = self.lock_meta().await?;
+        let path = self.get_refs_path(&name);
+
+        if self.get_etag(path.clone()).await? != etag {
+            return Err(ErrorKind::PreconditionFailed.into());
+        }
+
+        self.upload(path, &mut data.as_ref()).await
+    }
+    async fn upload_listing(
+        &self,
+        name: String,
+        data: Bytes,
+    ) -> ServerResult<()> {
+        self.upload(self.get_listing_path(&name), &mut data.as_ref()).await
+    }
+    async fn download_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Bytes>> {
+        match fs::read(self.get_listing_path(&name)).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ServerError::storage_error(e)),
+        }
+    }
+    async fn delete_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        remove_if_exists(self.get_listing_path(&name)).await
+    }
+    async fn list_nars(&self) -> ServerResult<Vec<String>> {
+        let mut names = Vec::new();
+        for dir in self.list_shards(&self.config.nars).await? {
+            names.extend(list_dir(&dir).await?);
+        }
+
+        Ok(names)
+    }
+    async fn list_nars_page(
+        &self,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> ServerResult<Vec<String>> {
+        let root = self.config.path.join(&self.config.nars);
+        let mut dirs = self.list_shards(&self.config.nars).await?;
+        dirs.sort();
+
+        let mut names = Vec::new();
+        for dir in dirs {
+            // All names in a shard start with the shard names
+            let shard: String = dir
+                .strip_prefix(&root)
+                .unwrap_or(&dir)
+                .iter()
+                .map(|component| component.to_string_lossy())
+                .collect();
+            if let Some(start_after) = &start_after {
+                if shard.as_str() < start_after.get(..shard.len()).unwrap_or(start_after) {
+                    continue;
+                }
+            }
+
+            let mut shard_names = list_dir(&dir).await?;
+            if let Some(start_after) = &start_after {
+                shard_names.retain(|name| name > start_after);
+            }
+            shard_names.sort();
+            names.extend(shard_names);
+
+            if names.len() >= limit {
+                break;
+            }
+        }
+        names.truncate(limit);
+
+        Ok(names)
+    }
+    async fn list_chunks(&self) -> ServerResult<Vec<ChunkInfo>> {
+        let mut chunks = Vec::new();
+        for dir in self.list_shards(&self.config.chunks).await? {
+            for name in list_dir(&dir).await? {
+                let metadata = match fs::metadata(dir.join(&name)).await {
+                    Ok(metadata) => metadata,
+                    // Deleted since it was listed
+                    Err(e) if e.kind() == IoErrorKind::NotFound => continue,
+                    Err(e) => return Err(ServerError::storage_error(e)),
+                };
+                let last_modified = metadata.modified()
+                    .map_err(ServerError::storage_error)?;
 
-        Ok(Download::AsyncRead(Box::new(file)))
+                chunks.push(ChunkInfo { name, last_modified });
+            }
+        }
+
+        Ok(chunks)
     }
 }
 
@@ -123,3 +474,381 @@ fn default_chunks_dir_name() -> String {
 fn default_nars_dir_name() -> String {
     "nars".to_string()
 }
+fn default_refs_dir_name() -> String {
+    "refs".to_string()
+}
+fn default_listings_dir_name() -> String {
+    "listings".to_string()
+}
+fn default_fsync() -> bool {
+    true
+}
+
+/// Expands a leading `~` and environment variables in a path.
+///
+/// The expanded path must be absolute.
+fn expand_path(path: &Path) -> Result<PathBuf> {
+    let path = path.to_str()
+        .ok_or_else(|| anyhow!("Storage path {:?} is not valid UTF-8", path))?;
+
+    let home = || std::env::var("HOME")
+        .map_err(|_| anyhow!("Cannot expand ~ in storage path: HOME is not set"));
+
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&home()?);
+        rest = &rest[1..];
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, len) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced.find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable in storage path {:?}", path))?;
+            (&braced[..end], end + 2)
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+
+        if name.is_empty() {
+            return Err(anyhow!("Empty variable name in storage path {:?}", path));
+        }
+
+        let value = std::env::var(name)
+            .map_err(|_| anyhow!("Cannot expand ${} in storage path: it is not set", name))?;
+        expanded.push_str(&value);
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+
+    let expanded = PathBuf::from(expanded);
+    if !expanded.is_absolute() {
+        return Err(anyhow!("Storage path {:?} is not absolute (expanded to {:?})", path, expanded));
+    }
+
+    Ok(expanded)
+}
+
+/// Returns the names of the entries in a directory.
+///
+/// In-progress uploads are skipped.
+async fn list_dir(dir: &Path) -> ServerResult<Vec<String>> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(ServerError::storage_error)?;
+
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(ServerError::storage_error)? {
+        match entry.file_name().to_str() {
+            Some(name) if !name.starts_with('.') => names.push(name.to_string()),
+            _ => {}
+        }
+    }
+
+    Ok(names)
+}
+
+/// Writes a stream to a file and flushes it to disk.
+async fn write_file(path: &Path, mut stream: &mut (dyn AsyncRead + Unpin + Send), fsync: bool) -> ServerResult<()> {
+    let mut file = File::create(path)
+        .await
+        .map_err(ServerError::storage_error)?;
+
+    io::copy(&mut stream, &mut file)
+        .await
+        .map_err(ServerError::storage_error)?;
+
+    if fsync {
+        file.sync_all()
+            .await
+            .map_err(ServerError::storage_error)?;
+    }
+
+    Ok(())
+}
+
+/// Flushes a directory's entries to disk.
+async fn sync_dir(path: &Path) -> ServerResult<()> {
+    File::open(path)
+        .await
+        .map_err(ServerError::storage_error)?
+        .sync_all()
+        .await
+        .map_err(ServerError::storage_error)
+}
+
+/// Returns whether a file exists.
+async fn file_exists(path: PathBuf) -> ServerResult<bool> {
+    match fs::metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == IoErrorKind::NotFound => Ok(false),
+        Err(e) => Err(ServerError::storage_error(e)),
+    }
+}
+
+/// Removes a file, succeeding if it does not exist.
+async fn remove_if_exists(path: PathBuf) -> ServerResult<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == IoErrorKind::NotFound => Ok(()),
+        Err(e) => Err(ServerError::storage_error(e)),
+    }
+}
+
+/// Opens a file, or returns `None` if it does not exist.
+async fn open_if_exists(path: PathBuf) -> ServerResult<Option<File>> {
+    match File::open(path).await {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ServerError::storage_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tokio_test::block_on;
+
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_upload_nar_if_match() {
+        let dir = TestDir::new("local");
+        let config = LocalStorageConfig {
+            path: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+            let name = "nar".to_string();
+
+            // Must not exist yet
+            backend.upload_nar_if_match(name.clone(), &mut Cursor::new(b"v1"), None).await.unwrap();
+            let etag = backend.get_nar_etag(name.clone()).await.unwrap();
+            assert!(etag.is_some());
+
+            let err = backend.upload_nar_if_match(name.clone(), &mut Cursor::new(b"v2"), None).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::PreconditionFailed));
+
+            // Matching ETag
+            backend.upload_nar_if_match(name.clone(), &mut Cursor::new(b"v2"), etag.clone()).await.unwrap();
+
+            // Stale ETag
+            let err = backend.upload_nar_if_match(name.clone(), &mut Cursor::new(b"v3"), etag).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::PreconditionFailed));
+
+            assert_eq!(vec![name], backend.list_nars().await.unwrap());
+        });
+    }
+
+    /// Increments a number stored as a NAR with conditional uploads.
+    async fn increment(backend: &LocalBackend, name: String) {
+        loop {
+            let etag = backend.get_nar_etag(name.clone()).await.unwrap();
+            let data = backend.download_nar(name.clone()).await.unwrap().unwrap()
+                .read_to_end().await.unwrap();
+            let count: usize = std::str::from_utf8(&data).unwrap().parse().unwrap();
+            let data = (count + 1).to_string();
+
+            match backend.upload_nar_if_match(name.clone(), &mut Cursor::new(data), etag).await {
+                Ok(_) => return,
+                Err(e) if matches!(e.kind(), ErrorKind::PreconditionFailed) => continue,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_upload_nar_if_match_shared_dir() {
+        let dir = TestDir::new("shared-dir");
+        let config = LocalStorageConfig {
+            path: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            // Like two server processes using the same directory
+            let backends = [
+                LocalBackend::new(config.clone()).await.unwrap(),
+                LocalBackend::new(config).await.unwrap(),
+            ];
+            let name = "counter".to_string();
+            backends[0].upload_nar(name.clone(), &mut Cursor::new(b"0")).await.unwrap();
+
+            let futures = (0..20).map(|i| increment(&backends[i % 2], name.clone()));
+            futures::future::join_all(futures).await;
+
+            let data = backends[1].download_nar(name).await.unwrap().unwrap().read_to_end().await.unwrap();
+            assert_eq!(b"20", data.as_slice());
+        });
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = TestDir::new("delete");
+        let config = LocalStorageConfig {
+            path: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+
+            backend.upload_nar("nar".to_string(), &mut Cursor::new(b"nar")).await.unwrap();
+            backend.upload_chunk("chunk".to_string(), &mut Cursor::new(b"chunk")).await.unwrap();
+
+            assert!(backend.nar_exists("nar".to_string()).await.unwrap());
+            assert!(backend.chunk_exists("chunk".to_string()).await.unwrap());
+
+            for _ in 0..2 {
+                backend.delete_nar("nar".to_string()).await.unwrap();
+                backend.delete_chunk("chunk".to_string()).await.unwrap();
+            }
+
+            assert!(!backend.nar_exists("nar".to_string()).await.unwrap());
+            assert!(!backend.chunk_exists("chunk".to_string()).await.unwrap());
+
+            assert!(backend.download_nar("nar".to_string()).await.unwrap().is_none());
+            assert!(backend.download_chunk("chunk".to_string()).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_sharding() {
+        let dir = TestDir::new("sharding");
+        let path = dir.path();
+        let config = LocalStorageConfig {
+            path: path.to_path_buf(),
+            shard_levels: 2,
+            ..Default::default()
+        };
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+
+            let chunk = "sha256:0bnq5k4i7f1cha08hcp83v05fa6pnj9z5xhbxk74vqqha2k7riif".to_string();
+            let nar = "p4pclmv1gyja5kzc26npqpia1qqxrf0l".to_string();
+
+            backend.upload_chunk(chunk.clone(), &mut Cursor::new(b"chunk")).await.unwrap();
+            backend.upload_nar(nar.clone(), &mut Cursor::new(b"nar")).await.unwrap();
+            backend.upload_chunk_refs_if_match(chunk.clone(), Bytes::from_static(b"refs"), None).await.unwrap();
+            backend.upload_listing(nar.clone(), Bytes::from_static(b"listing")).await.unwrap();
+
+            assert!(path.join("chunks/0b/nq").join(&chunk).is_file());
+            assert!(path.join("nars/p4/pc").join(&nar).is_file());
+            assert!(path.join("refs/0b/nq").join(&chunk).is_file());
+            assert!(path.join("listings/p4/pc").join(&nar).is_file());
+
+            assert!(backend.download_chunk_refs(chunk.clone()).await.unwrap().is_some());
+            assert!(backend.download_listing(nar.clone()).await.unwrap().is_some());
+
+            assert!(backend.chunk_exists(chunk.clone()).await.unwrap());
+            assert_eq!(vec![nar.clone()], backend.list_nars().await.unwrap());
+            assert_eq!(vec![nar.clone()], backend.list_nars_page(None, 10).await.unwrap());
+            assert!(backend.list_nars_page(Some(nar), 10).await.unwrap().is_empty());
+
+            let chunks = backend.list_chunks().await.unwrap();
+            assert_eq!(vec![chunk], chunks.into_iter().map(|c| c.name).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_list_nars_page() {
+        let dir = TestDir::new("list-nars");
+        let config = LocalStorageConfig {
+            path: dir.path().to_path_buf(),
+            shard_levels: 1,
+            ..Default::default()
+        };
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+
+            let nars = ["0a1", "0a2", "0b1", "1a1", "zz9"];
+            for nar in nars.iter().rev() {
+                backend.upload_nar(nar.to_string(), &mut Cursor::new(b"nar")).await.unwrap();
+            }
+
+            let page = |start_after: Option<&str>, limit| {
+                backend.list_nars_page(start_after.map(str::to_string), limit)
+            };
+            assert_eq!(nars[..3], page(None, 3).await.unwrap());
+            assert_eq!(nars[3..], page(Some("0b1"), 3).await.unwrap());
+            assert_eq!(nars[2..4], page(Some("0a3"), 2).await.unwrap());
+            assert_eq!(nars[3..], page(Some("1"), 10).await.unwrap());
+            assert!(page(Some("zz9"), 10).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_interrupted_upload() {
+        let dir = TestDir::new("interrupted");
+        let path = dir.path();
+        let config = LocalStorageConfig {
+            path: path.to_path_buf(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+
+            let mut stream = tokio_test::io::Builder::new()
+                .read(b"partial")
+                .read_error(std::io::Error::other("disconnected"))
+                .build();
+            assert!(backend.upload_chunk("chunk".to_string(), &mut stream).await.is_err());
+            assert!(!backend.chunk_exists("chunk".to_string()).await.unwrap());
+
+            // The temporary file is removed in the background
+            let chunks = path.join(&backend.config.chunks);
+            for _ in 0..100 {
+                if std::fs::read_dir(&chunks).unwrap().next().is_none() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            assert!(std::fs::read_dir(&chunks).unwrap().next().is_none());
+        });
+    }
+
+    #[test]
+    fn test_download_missing() {
+        let dir = TestDir::new("missing");
+        let config = LocalStorageConfig {
+            path: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            let backend = LocalBackend::new(config).await.unwrap();
+            assert!(backend.download_nar("missing".to_string()).await.unwrap().is_none());
+            assert!(backend.download_chunk("missing".to_string()).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_expand_path() {
+        std::env::set_var("NIXCACHE_TEST_DATA_DIR", "/srv/data");
+        let home = std::env::var("HOME").unwrap();
+
+        let expand = |path: &str| expand_path(Path::new(path)).map(|p| p.to_str().unwrap().to_string());
+
+        assert_eq!("/var/cache", expand("/var/cache").unwrap());
+        assert_eq!(format!("{}/cache", home), expand("~/cache").unwrap());
+        assert_eq!("/srv/data/cache", expand("$NIXCACHE_TEST_DATA_DIR/cache").unwrap());
+        assert_eq!("/srv/data-cache", expand("${NIXCACHE_TEST_DATA_DIR}-cache").unwrap());
+        assert_eq!("/var/~cache", expand("/var/~cache").unwrap());
+
+        assert!(expand("relative/cache").is_err());
+        assert!(expand("$NIXCACHE_TEST_UNSET_DIR/cache").is_err());
+        assert!(expand("${NIXCACHE_TEST_DATA_DIR/cache").is_err());
+        assert!(expand("/var/$/cache").is_err());
+    }
+}
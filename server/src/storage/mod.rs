@@ -1,9 +1,15 @@
+pub mod gcs;
 pub mod local;
 pub mod s3;
+pub mod webdav;
 
+use std::ops::Range;
+use std::time::SystemTime;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
 use bytes::Bytes;
 use futures::stream::BoxStream;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
 
 use crate::error::ServerResult;
 
@@ -11,6 +17,22 @@ use crate::error::ServerResult;
 pub enum RemoteFile {
     Local(local::LocalRemoteFile),
     S3(s3::S3RemoteFile),
+    Gcs(gcs::GcsRemoteFile),
+    WebDav(webdav::WebDavRemoteFile),
+}
+
+/// The name of the NAR looked up by health checks.
+///
+/// This is not a store path hash, so no NAR can have this name.
+const HEALTH_CHECK_NAME: &str = "health-check";
+
+/// A stored chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    /// The name of the chunk.
+    pub name: String,
+    /// When the chunk was last written.
+    pub last_modified: SystemTime,
 }
 
 /// Way to download a file.
@@ -18,6 +40,29 @@ pub enum Download {
     Stream(BoxStream<'static, std::io::Result<Bytes>>),
     AsyncRead(Box<dyn AsyncRead + Unpin + Send>),
 }
+impl Download {
+    /// Returns the download as an `AsyncRead`.
+    pub fn into_async_read(self) -> Box<dyn AsyncRead + Unpin + Send> {
+        match self {
+            Self::AsyncRead(stream) => stream,
+            Self::Stream(stream) => Box::new(StreamReader::new(stream)),
+        }
+    }
+
+    /// Reads the whole file into memory.
+    pub async fn read_to_end(self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.into_async_read().read_to_end(&mut data).await?;
+        Ok(data)
+    }
+}
+
+/// Parses a timestamp returned by a storage service.
+fn parse_timestamp(s: &str, format: DateTimeFormat) -> Option<SystemTime> {
+    DateTime::from_str(s, format)
+        .ok()
+        .and_then(|t| SystemTime::try_from(t).ok())
+}
 
 #[async_trait::async_trait]
 pub trait StorageBackend: Send + Sync + std::fmt::Debug {
@@ -27,11 +72,51 @@ pub trait StorageBackend: Send + Sync + std::fmt::Debug {
         name: String,
         stream: &mut (dyn AsyncRead + Unpin + Send),
     ) -> ServerResult<RemoteFile>;
-    /// Downloads a chunk.
+    /// Uploads a chunk whose size is known in advance.
+    ///
+    /// Backends that upload large files in parts can use the size to
+    /// choose the part size.
+    async fn upload_chunk_with_size(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        _size: u64,
+    ) -> ServerResult<RemoteFile> {
+        self.upload_chunk(name, stream).await
+    }
+    /// Downloads a chunk, or returns `None` if it does not exist.
     async fn download_chunk(
         &self,
         name: String,
-    ) -> ServerResult<Download>;
+    ) -> ServerResult<Option<Download>>;
+    /// Downloads a byte range of a chunk, or returns `None` if it does not exist.
+    ///
+    /// The range must not be empty or extend past the end of the chunk.
+    async fn download_chunk_range(
+        &self,
+        name: String,
+        range: Range<u64>,
+    ) -> ServerResult<Option<Download>>;
+    /// Returns a URL that clients can download a chunk from directly.
+    ///
+    /// Returns `None` if the backend does not support this or it is
+    /// disabled, in which case the chunk is streamed through the server.
+    async fn presign_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>>;
+    /// Deletes a chunk.
+    ///
+    /// Deleting a chunk that does not exist succeeds.
+    async fn delete_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<()>;
+    /// Returns whether a chunk exists.
+    async fn chunk_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool>;
 
     /// Uploads a NAR.
     async fn upload_nar(
@@ -39,9 +124,106 @@ pub trait StorageBackend: Send + Sync + std::fmt::Debug {
         name: String,
         stream: &mut (dyn AsyncRead + Unpin + Send),
     ) -> ServerResult<RemoteFile>;
-    /// Downloads a NAR.
+    /// Downloads a NAR, or returns `None` if it does not exist.
     async fn download_nar(
         &self,
         name: String,
-    ) -> ServerResult<Download>;
+    ) -> ServerResult<Option<Download>>;
+    /// Deletes a NAR.
+    ///
+    /// Deleting a NAR that does not exist succeeds.
+    async fn delete_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<()>;
+    /// Returns whether a NAR exists.
+    async fn nar_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool>;
+    /// Returns the ETag of a NAR, or `None` if it does not exist.
+    ///
+    /// The ETag changes whenever the NAR is overwritten.
+    async fn get_nar_etag(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>>;
+    /// Uploads a NAR only if it is unchanged since `etag` was obtained.
+    ///
+    /// If `etag` is `None`, the NAR must not exist yet. Fails with
+    /// `ErrorKind::PreconditionFailed` if the NAR was modified in
+    /// the meantime, in which case the caller should read it again
+    /// and retry.
+    async fn upload_nar_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile>;
+    /// Downloads the references of a chunk along with their ETag.
+    ///
+    /// Returns `None` if no references were recorded.
+    async fn download_chunk_refs(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<(Bytes, String)>>;
+    /// Uploads the references of a chunk only if they are unchanged
+    /// since `etag` was obtained.
+    ///
+    /// See `upload_nar_if_match`.
+    async fn upload_chunk_refs_if_match(
+        &self,
+        name: String,
+        data: Bytes,
+        etag: Option<String>,
+    ) -> ServerResult<()>;
+    /// Uploads the file listing of a NAR.
+    async fn upload_listing(
+        &self,
+        name: String,
+        data: Bytes,
+    ) -> ServerResult<()>;
+    /// Downloads the file listing of a NAR, or returns `None` if it does not exist.
+    async fn download_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Bytes>>;
+    /// Deletes the file listing of a NAR.
+    ///
+    /// Deleting a listing that does not exist succeeds.
+    async fn delete_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<()>;
+    /// Lists the names of all stored NARs.
+    async fn list_nars(&self) -> ServerResult<Vec<String>>;
+    /// Lists the names of stored NARs in lexicographic order.
+    ///
+    /// Only names after `start_after` are listed, and at most `limit`
+    /// of them. By default, all NARs are listed to take a page, so
+    /// backends that can list in order should override this.
+    async fn list_nars_page(
+        &self,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> ServerResult<Vec<String>> {
+        let mut names = self.list_nars().await?;
+        if let Some(start_after) = &start_after {
+            names.retain(|name| name > start_after);
+        }
+        names.sort();
+        names.truncate(limit);
+
+        Ok(names)
+    }
+    /// Lists all stored chunks.
+    async fn list_chunks(&self) -> ServerResult<Vec<ChunkInfo>>;
+
+    /// Checks that the storage is reachable.
+    ///
+    /// Remote backends look up a NAR that never exists, which is
+    /// cheap but makes a round-trip.
+    async fn health_check(&self) -> ServerResult<()> {
+        Ok(())
+    }
 }
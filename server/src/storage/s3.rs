@@ -1,30 +1,70 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
-use serde::{Serialize, Deserialize};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use serde::{de, Serialize, Deserialize, Deserializer};
 use aws_sdk_s3::{
     operation::get_object::builders::GetObjectFluentBuilder,
+    operation::get_object::GetObjectError,
     config::Builder as S3ConfigBuilder,
-    types::{CompletedMultipartUpload, CompletedPart},
+    types::{CompletedMultipartUpload, CompletedPart, Part, ServerSideEncryption, StorageClass},
     config::{Credentials, Region},
+    presigning::PresigningConfig,
     Client,
 };
-use bytes::BytesMut;
+use aws_sdk_s3::error::SdkError;
+use aws_smithy_client::{conns, hyper_ext};
+use axum::http::{header, HeaderValue, StatusCode};
+use bytes::{Bytes, BytesMut};
 use futures::future::join_all;
 use futures::stream::StreamExt;
-use tokio::io::AsyncRead;
+use md5::{Digest, Md5};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::finally::Finally;
 use crate::chunking::read_chunk_async;
-use crate::error::{ServerResult, ServerError};
-use super::{StorageBackend, RemoteFile, Download};
+use crate::error::{ErrorKind, ServerResult, ServerError};
+use super::{StorageBackend, HEALTH_CHECK_NAME, RemoteFile, Download, ChunkInfo};
 
-/// The chunk size for each part in a multipart upload.
-const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// The default size of the first parts in a multipart upload.
+const DEFAULT_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The smallest part size S3 accepts, except for the last part.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The largest part size S3 accepts.
+const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// The largest number of parts S3 accepts in a multipart upload.
+const MAX_PARTS: u64 = 10_000;
+
+/// The number of parts after which the part size doubles.
+///
+/// S3 allows at most 10,000 parts in an upload. Doubling the part
+/// size every 1,000 parts lets uploads of unknown size get close to
+/// the 5 TiB object size limit even with the smallest part size.
+const PARTS_PER_SIZE_STEP: i32 = 1000;
+
+/// How long presigned download URLs are valid.
+///
+/// Clients follow redirects right away, so this only needs to cover
+/// slow requests.
+const PRESIGNED_URL_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// When an upload was interrupted, and the ID of its multipart upload.
+type PendingUpload = (Instant, String);
 
 /// The S3 remote file storage backend.
 #[derive(Debug)]
 pub struct S3Backend {
     client: Client,
     config: S3StorageConfig,
+    /// Multipart uploads of interrupted uploads, by key.
+    ///
+    /// Only used with `resume-multipart-uploads`.
+    pending_uploads: Arc<Mutex<HashMap<String, PendingUpload>>>,
 }
 
 /// S3 remote file storage configuration.
@@ -41,12 +81,93 @@ pub struct S3StorageConfig {
     /// S3 credentials.
     credentials: Option<S3CredentialsConfig>,
 
+    /// Idle timeout for pooled connections, in seconds.
+    ///
+    /// Set this lower than the idle timeout of the S3 endpoint to
+    /// avoid reusing connections the endpoint has already closed.
+    #[serde(rename = "pool-idle-timeout")]
+    pool_idle_timeout: Option<u64>,
+    /// Maximum number of idle pooled connections.
+    #[serde(rename = "pool-max-idle")]
+    pool_max_idle: Option<usize>,
+
+    /// Server-side encryption of uploaded objects.
+    ///
+    /// If unset, the default encryption of the bucket applies.
+    sse: Option<S3SseConfig>,
+
+    /// Storage class of uploaded objects, like `STANDARD_IA`.
+    ///
+    /// If unset, the default storage class of the bucket applies.
+    #[serde(rename = "storage-class")]
+    storage_class: Option<String>,
+    /// Storage class of chunks, overriding `storage-class`.
+    ///
+    /// Chunks are the bulk of the data.
+    #[serde(rename = "chunk-storage-class")]
+    chunk_storage_class: Option<String>,
+    /// Storage class of NARs, overriding `storage-class`.
+    #[serde(rename = "nar-storage-class")]
+    nar_storage_class: Option<String>,
+
+    /// Redirect downloads of NARs stored in a single chunk to presigned URLs.
+    ///
+    /// Clients then download directly from the bucket instead of through
+    /// the server, so the bucket must be reachable by them.
+    #[serde(rename = "redirect-downloads")]
+    #[serde(default)]
+    redirect_downloads: bool,
+
+    /// Maximum number of times to retry an upload request on transient errors.
+    #[serde(rename = "max-retries")]
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    /// Delay before the first retry, in milliseconds.
+    ///
+    /// The delay doubles after each retry, with random jitter.
+    #[serde(rename = "base-delay-ms")]
+    #[serde(default = "default_base_delay_ms")]
+    base_delay_ms: u64,
+
+    /// The size of the first parts in a multipart upload, in bytes.
+    ///
+    /// Files smaller than this are uploaded in a single request. The
+    /// part size doubles every 1,000 parts to stay within the part
+    /// limit of S3. Must be between 5 MiB and 5 GiB.
+    #[serde(rename = "multipart-part-size")]
+    #[serde(default = "default_multipart_part_size")]
+    #[serde(deserialize_with = "deserialize_part_size")]
+    multipart_part_size: u64,
+
+    /// Keep the multipart uploads of interrupted uploads and resume them.
+    ///
+    /// When the same file is uploaded again, parts that are already in
+    /// the bucket are not sent again. Uploads that are not retried within
+    /// `resume-multipart-upload-ttl` are aborted. Uploads interrupted
+    /// by a restart of the server are left behind, so the bucket should
+    /// still have a lifecycle rule to abort incomplete multipart uploads.
+    #[serde(rename = "resume-multipart-uploads")]
+    #[serde(default)]
+    resume_multipart_uploads: bool,
+    /// How long to keep the multipart upload of an interrupted upload, in seconds.
+    ///
+    /// Expired uploads are aborted when the next multipart upload starts.
+    #[serde(rename = "resume-multipart-upload-ttl")]
+    #[serde(default = "default_resume_multipart_upload_ttl")]
+    resume_multipart_upload_ttl: u64,
+
     /// Dir name for chunks.
     #[serde(default = "default_chunks_dir_name")]
     chunks: String,
     /// Dir name for NARs.
     #[serde(default = "default_nars_dir_name")]
     nars: String,
+    /// Dir name for chunk references.
+    #[serde(default = "default_refs_dir_name")]
+    refs: String,
+    /// Dir name for file listings.
+    #[serde(default = "default_listings_dir_name")]
+    listings: String,
 }
 
 /// S3 credential configuration.
@@ -58,6 +179,25 @@ pub struct S3CredentialsConfig {
     secret_access_key: String,
 }
 
+/// S3 server-side encryption configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum S3SseConfig {
+    /// Encryption with keys managed by S3.
+    #[serde(rename = "aes256")]
+    Aes256,
+
+    /// Encryption with keys managed by AWS KMS.
+    #[serde(rename = "aws:kms")]
+    AwsKms {
+        /// The KMS key to use.
+        ///
+        /// If unset, the AWS managed key of the bucket is used.
+        #[serde(rename = "kms-key-id")]
+        kms_key_id: Option<String>,
+    },
+}
+
 /// Reference to a file in an S3-compatible storage bucket.
 ///
 /// We store the region and bucket to facilitate migration.
@@ -81,8 +221,44 @@ impl S3Backend {
         Ok(Self {
             client: Client::from_conf(s3_config),
             config,
+            pending_uploads: Arc::new(Mutex::new(HashMap::new())),
         })
     }
+    /// Returns the server-side encryption and KMS key ID to upload with.
+    fn sse(&self) -> (Option<ServerSideEncryption>, Option<String>) {
+        match &self.config.sse {
+            None => (None, None),
+            Some(S3SseConfig::Aes256) => (Some(ServerSideEncryption::Aes256), None),
+            Some(S3SseConfig::AwsKms { kms_key_id }) => {
+                (Some(ServerSideEncryption::AwsKms), kms_key_id.clone())
+            }
+        }
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.config.max_retries,
+            base_delay: Duration::from_millis(self.config.base_delay_ms),
+        }
+    }
+    /// Returns the storage class to upload a file with.
+    ///
+    /// The class is chosen by the prefix of the file.
+    fn storage_class(&self, name: &str) -> Option<StorageClass> {
+        let is_in = |dir: &str| name.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'));
+
+        let override_class = if is_in(&self.config.chunks) {
+            &self.config.chunk_storage_class
+        } else if is_in(&self.config.nars) {
+            &self.config.nar_storage_class
+        } else {
+            &None
+        };
+
+        override_class
+            .as_ref()
+            .or(self.config.storage_class.as_ref())
+            .map(|class| StorageClass::from(class.as_str()))
+    }
     async fn config_builder(config: &S3StorageConfig) -> ServerResult<S3ConfigBuilder> {
         let mut builder = S3ConfigBuilder::new();
 
@@ -100,30 +276,58 @@ impl S3Backend {
             builder = builder.endpoint_url(endpoint).force_path_style(true);
         }
 
+        if config.pool_idle_timeout.is_some() || config.pool_max_idle.is_some() {
+            let mut hyper_builder = hyper::Client::builder();
+            if let Some(timeout) = config.pool_idle_timeout {
+                hyper_builder.pool_idle_timeout(Duration::from_secs(timeout));
+            }
+            if let Some(max_idle) = config.pool_max_idle {
+                hyper_builder.pool_max_idle_per_host(max_idle);
+            }
+
+            let connector = hyper_ext::Adapter::builder()
+                .hyper_builder(hyper_builder)
+                .build(conns::https());
+            builder = builder.http_connector(connector);
+        }
+
         Ok(builder)
     }
 
+    /// Uploads a file, in parts if it's large.
+    ///
+    /// If the size of the file is known, the parts are made large
+    /// enough to stay within the part limit of S3.
     async fn upload_file(
         &self,
         name: String,
         mut stream: &mut (dyn AsyncRead + Unpin + Send),
+        size: Option<u64>,
     ) -> ServerResult<RemoteFile> {
-        let buf = BytesMut::with_capacity(CHUNK_SIZE);
+        let base_part_size = base_part_size(self.config.multipart_part_size, size);
+        let first_part_size = part_size(base_part_size, 1);
+        let buf = BytesMut::with_capacity(first_part_size);
         let first_chunk = read_chunk_async(&mut stream, buf)
             .await
             .map_err(ServerError::storage_error)?;
+        let (sse, kms_key_id) = self.sse();
+        let storage_class = self.storage_class(&name);
 
-        if first_chunk.len() < CHUNK_SIZE {
+        if first_chunk.len() < first_part_size {
             // do a normal PutObject
-            let put_object = self
-                .client
-                .put_object()
-                .bucket(&self.config.bucket)
-                .key(&name)
-                .body(first_chunk.into())
-                .send()
-                .await
-                .map_err(ServerError::storage_error)?;
+            let put_object = retry(self.retry_policy(), || {
+                self.client
+                    .put_object()
+                    .bucket(&self.config.bucket)
+                    .key(&name)
+                    .set_server_side_encryption(sse.clone())
+                    .set_ssekms_key_id(kms_key_id.clone())
+                    .set_storage_class(storage_class.clone())
+                    .body(first_chunk.clone().into())
+                    .send()
+            })
+            .await
+            .map_err(ServerError::storage_error)?;
 
             tracing::debug!("put_object -> {:#?}", put_object);
 
@@ -134,37 +338,42 @@ impl S3Backend {
             }));
         }
 
-        let multipart = self
-            .client
-            .create_multipart_upload()
-            .bucket(&self.config.bucket)
-            .key(&name)
-            .send()
-            .await
-            .map_err(ServerError::storage_error)?;
+        let (upload_id, mut uploaded_parts) = match self.resume_multipart_upload(&name).await {
+            Some(resumed) => resumed,
+            None => {
+                let multipart = self
+                    .client
+                    .create_multipart_upload()
+                    .bucket(&self.config.bucket)
+                    .key(&name)
+                    .set_server_side_encryption(sse)
+                    .set_ssekms_key_id(kms_key_id)
+                    .set_storage_class(storage_class)
+                    .send()
+                    .await
+                    .map_err(ServerError::storage_error)?;
 
-        let upload_id = multipart.upload_id().unwrap();
+                (multipart.upload_id().unwrap().to_owned(), HashMap::new())
+            }
+        };
 
         let cleanup = Finally::new({
             let bucket = self.config.bucket.clone();
             let client = self.client.clone();
-            let upload_id = upload_id.to_owned();
+            let upload_id = upload_id.clone();
             let name = name.clone();
+            let pending_uploads = self.config.resume_multipart_uploads
+                .then(|| self.pending_uploads.clone());
 
             async move {
-                tracing::warn!("Upload was interrupted - Aborting multipart upload");
-
-                let r = client
-                    .abort_multipart_upload()
-                    .bucket(bucket)
-                    .key(name)
-                    .upload_id(upload_id)
-                    .send()
-                    .await;
-
-                if let Err(e) = r {
-                    tracing::warn!("Failed to abort multipart upload: {}", e);
+                if let Some(pending_uploads) = pending_uploads {
+                    tracing::warn!("Upload was interrupted - Keeping multipart upload to resume");
+                    pending_uploads.lock().unwrap().insert(name, (Instant::now(), upload_id));
+                    return;
                 }
+
+                tracing::warn!("Upload was interrupted - Aborting multipart upload");
+                abort_multipart_upload(&client, &bucket, name, upload_id).await;
             }
         });
         let mut part_number = 1;
@@ -175,7 +384,7 @@ impl S3Backend {
             let chunk = if part_number == 1 {
                 first_chunk.take().unwrap()
             } else {
-                let buf = BytesMut::with_capacity(CHUNK_SIZE);
+                let buf = BytesMut::with_capacity(part_size(base_part_size, part_number));
                 read_chunk_async(&mut stream, buf)
                     .await
                     .map_err(ServerError::storage_error)?
@@ -185,16 +394,47 @@ impl S3Backend {
                 break;
             }
 
-            let client = self.client.clone();
-            let fut = tokio::task::spawn({
-                client
-                    .upload_part()
-                    .bucket(&self.config.bucket)
-                    .key(&name)
-                    .upload_id(upload_id)
+            if let Some(part) = uploaded_parts.remove(&part_number).filter(|part| part_matches(part, &chunk)) {
+                tracing::debug!("Part {} was already uploaded", part_number);
+
+                let completed_part = CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(str::to_string))
                     .part_number(part_number)
-                    .body(chunk.clone().into())
-                    .send()
+                    .build();
+                parts.push(tokio::task::spawn(async move { Ok(completed_part) }));
+                part_number += 1;
+                continue;
+            }
+
+            let fut = tokio::task::spawn({
+                let client = self.client.clone();
+                let bucket = self.config.bucket.clone();
+                let name = name.clone();
+                let upload_id = upload_id.clone();
+
+                let upload = retry(self.retry_policy(), move || {
+                    client
+                        .upload_part()
+                        .bucket(&bucket)
+                        .key(&name)
+                        .upload_id(&upload_id)
+                        .part_number(part_number)
+                        .body(chunk.clone().into())
+                        .send()
+                });
+
+                async move {
+                    upload.await.map(|part| {
+                        CompletedPart::builder()
+                            .set_e_tag(part.e_tag().map(str::to_string))
+                            .part_number(part_number)
+                            .set_checksum_crc32(part.checksum_crc32().map(str::to_string))
+                            .set_checksum_crc32_c(part.checksum_crc32_c().map(str::to_string))
+                            .set_checksum_sha1(part.checksum_sha1().map(str::to_string))
+                            .set_checksum_sha256(part.checksum_sha256().map(str::to_string))
+                            .build()
+                    })
+                }
             });
 
             parts.push(fut);
@@ -206,36 +446,23 @@ impl S3Backend {
             .into_iter()
             .map(|join_result| join_result.unwrap())
             .collect::<std::result::Result<Vec<_>, _>>()
-            .map_err(ServerError::storage_error)?
-            .into_iter()
-            .enumerate()
-            .map(|(idx, part)| {
-                let part_number = idx + 1;
-                CompletedPart::builder()
-                    .set_e_tag(part.e_tag().map(str::to_string))
-                    .set_part_number(Some(part_number as i32))
-                    .set_checksum_crc32(part.checksum_crc32().map(str::to_string))
-                    .set_checksum_crc32_c(part.checksum_crc32_c().map(str::to_string))
-                    .set_checksum_sha1(part.checksum_sha1().map(str::to_string))
-                    .set_checksum_sha256(part.checksum_sha256().map(str::to_string))
-                    .build()
-            })
-            .collect::<Vec<_>>();
+            .map_err(ServerError::storage_error)?;
 
         let completed_multipart_upload = CompletedMultipartUpload::builder()
             .set_parts(Some(completed_parts))
             .build();
 
-        let completion = self
-            .client
-            .complete_multipart_upload()
-            .bucket(&self.config.bucket)
-            .key(&name)
-            .upload_id(upload_id)
-            .multipart_upload(completed_multipart_upload)
-            .send()
-            .await
-            .map_err(ServerError::storage_error)?;
+        let completion = retry(self.retry_policy(), || {
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.config.bucket)
+                .key(&name)
+                .upload_id(&upload_id)
+                .multipart_upload(completed_multipart_upload.clone())
+                .send()
+        })
+        .await
+        .map_err(ServerError::storage_error)?;
 
         tracing::debug!("complete_multipart_upload -> {:#?}", completion);
 
@@ -248,7 +475,85 @@ impl S3Backend {
         }))
     }
 
-    async fn download_file(&self, name: String) -> ServerResult<Download> {
+    /// Takes over the multipart upload of an interrupted upload of a file.
+    ///
+    /// Returns the upload ID and the parts already uploaded, by part number.
+    async fn resume_multipart_upload(&self, name: &str) -> Option<(String, HashMap<i32, Part>)> {
+        if !self.config.resume_multipart_uploads {
+            return None;
+        }
+
+        for (expired_name, upload_id) in self.take_expired_uploads() {
+            tracing::info!("Aborting expired multipart upload of {}", expired_name);
+            tokio::task::spawn({
+                let client = self.client.clone();
+                let bucket = self.config.bucket.clone();
+                async move { abort_multipart_upload(&client, &bucket, expired_name, upload_id).await }
+            });
+        }
+
+        let (_, upload_id) = self.pending_uploads.lock().unwrap().remove(name)?;
+
+        let mut parts = HashMap::new();
+        let mut marker = None;
+        loop {
+            let list_parts = self
+                .client
+                .list_parts()
+                .bucket(&self.config.bucket)
+                .key(name)
+                .upload_id(&upload_id)
+                .set_part_number_marker(marker)
+                .send()
+                .await;
+
+            let list_parts = match list_parts {
+                Ok(list_parts) => list_parts,
+                Err(e) => {
+                    tracing::warn!("Failed to list parts of multipart upload - Starting over: {}", e);
+                    return None;
+                }
+            };
+
+            for part in list_parts.parts().unwrap_or_default() {
+                parts.insert(part.part_number(), part.clone());
+            }
+
+            if !list_parts.is_truncated() {
+                break;
+            }
+            marker = list_parts.next_part_number_marker().map(str::to_string);
+        }
+
+        tracing::info!("Resuming multipart upload with {} parts already uploaded", parts.len());
+
+        Some((upload_id, parts))
+    }
+
+    /// Removes the interrupted uploads that were kept for too long.
+    ///
+    /// Returns the keys and upload IDs of the removed uploads.
+    fn take_expired_uploads(&self) -> Vec<(String, String)> {
+        let ttl = Duration::from_secs(self.config.resume_multipart_upload_ttl);
+        let now = Instant::now();
+        let mut pending_uploads = self.pending_uploads.lock().unwrap();
+
+        let expired: Vec<String> = pending_uploads
+            .iter()
+            .filter(|(_, (interrupted, _))| now.duration_since(*interrupted) >= ttl)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|name| {
+                let (_, upload_id) = pending_uploads.remove(&name)?;
+                Some((name, upload_id))
+            })
+            .collect()
+    }
+
+    async fn download_file(&self, name: String) -> ServerResult<Option<Download>> {
         let req = self
             .client
             .get_object()
@@ -257,14 +562,162 @@ impl S3Backend {
 
         self.get_download(req).await
     }
-    async fn get_download(&self, req: GetObjectFluentBuilder) -> ServerResult<Download> {
-        let output = req.send().await.map_err(ServerError::storage_error)?;
+    async fn download_file_range(&self, name: String, range: Range<u64>) -> ServerResult<Option<Download>> {
+        let req = self
+            .client
+            .get_object()
+            .bucket(&self.config.bucket)
+            .key(&name)
+            .range(format!("bytes={}-{}", range.start, range.end - 1));
+
+        self.get_download(req).await
+    }
+    async fn get_download(&self, req: GetObjectFluentBuilder) -> ServerResult<Option<Download>> {
+        let output = match req.send().await {
+            Ok(output) => output,
+            Err(e) if is_no_such_key(&e) => return Ok(None),
+            Err(e) => return Err(ServerError::storage_error(e)),
+        };
 
         let stream = StreamExt::map(output.body, |item| {
             item.map_err(|e| IoError::new(IoErrorKind::Other, e))
         });
 
-        Ok(Download::Stream(Box::pin(stream)))
+        Ok(Some(Download::Stream(Box::pin(stream))))
+    }
+
+    /// Deletes a file.
+    ///
+    /// S3 treats deleting a missing key as a success.
+    async fn delete_file(&self, name: String) -> ServerResult<()> {
+        let delete_object = self
+            .client
+            .delete_object()
+            .bucket(&self.config.bucket)
+            .key(&name)
+            .send()
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        tracing::debug!("delete_object -> {:#?}", delete_object);
+
+        Ok(())
+    }
+
+    /// Downloads a small file into memory along with its ETag.
+    ///
+    /// Returns `None` if the file does not exist.
+    async fn download_small_file(&self, name: String) -> ServerResult<Option<(Bytes, String)>> {
+        let get_object = self
+            .client
+            .get_object()
+            .bucket(&self.config.bucket)
+            .key(&name)
+            .send()
+            .await;
+
+        let output = match get_object {
+            Ok(output) => output,
+            Err(e) if is_no_such_key(&e) => return Ok(None),
+            Err(e) => return Err(ServerError::storage_error(e)),
+        };
+
+        let etag = output.e_tag().unwrap_or_default().to_string();
+        let data = output.body
+            .collect()
+            .await
+            .map_err(ServerError::storage_error)?
+            .into_bytes();
+
+        Ok(Some((data, etag)))
+    }
+
+    /// Uploads a small file with a precondition header.
+    async fn upload_file_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data)
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        let (header_name, header_value) = match etag {
+            Some(etag) => (header::IF_MATCH, etag),
+            None => (header::IF_NONE_MATCH, "*".to_string()),
+        };
+        let header_value = HeaderValue::from_str(&header_value)
+            .map_err(ServerError::storage_error)?;
+        let (sse, kms_key_id) = self.sse();
+        let storage_class = self.storage_class(&name);
+
+        let put_object = self
+            .client
+            .put_object()
+            .bucket(&self.config.bucket)
+            .key(&name)
+            .set_server_side_encryption(sse)
+            .set_ssekms_key_id(kms_key_id)
+            .set_storage_class(storage_class)
+            .body(data.into())
+            .customize()
+            .await
+            .map_err(ServerError::storage_error)?
+            .mutate_request(|req| {
+                req.headers_mut().insert(header_name, header_value);
+            })
+            .send()
+            .await;
+
+        match put_object {
+            Ok(put_object) => {
+                tracing::debug!("put_object -> {:#?}", put_object);
+            }
+            Err(e) if is_precondition_failed(&e) => {
+                return Err(ErrorKind::PreconditionFailed.into());
+            }
+            Err(e) => return Err(ServerError::storage_error(e)),
+        }
+
+        Ok(RemoteFile::S3(S3RemoteFile {
+            region: self.config.region.clone(),
+            bucket: self.config.bucket.clone(),
+            key: name,
+        }))
+    }
+
+    async fn file_exists(&self, name: String) -> ServerResult<bool> {
+        let head_object = self
+            .client
+            .head_object()
+            .bucket(&self.config.bucket)
+            .key(&name)
+            .send()
+            .await;
+
+        match head_object {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(e)) if e.err().is_not_found() => Ok(false),
+            Err(e) => Err(ServerError::storage_error(e)),
+        }
+    }
+
+    async fn get_etag(&self, name: String) -> ServerResult<Option<String>> {
+        let head_object = self
+            .client
+            .head_object()
+            .bucket(&self.config.bucket)
+            .key(&name)
+            .send()
+            .await;
+
+        match head_object {
+            Ok(head_object) => Ok(head_object.e_tag().map(str::to_string)),
+            Err(SdkError::ServiceError(e)) if e.err().is_not_found() => Ok(None),
+            Err(e) => Err(ServerError::storage_error(e)),
+        }
     }
 
     fn get_chunk_path(&self, p: &str) -> String {
@@ -273,6 +726,12 @@ impl S3Backend {
     fn get_nar_path(&self, p: &str) -> String {
         format!("{}/{}", self.config.nars, p)
     }
+    fn get_refs_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.refs, p)
+    }
+    fn get_listing_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.listings, p)
+    }
 }
 #[async_trait::async_trait]
 impl StorageBackend for S3Backend {
@@ -281,28 +740,318 @@ impl StorageBackend for S3Backend {
         name: String,
         stream: &mut (dyn AsyncRead + Unpin + Send),
     ) -> ServerResult<RemoteFile> {
-        self.upload_file(self.get_chunk_path(&name), stream).await
+        self.upload_file(self.get_chunk_path(&name), stream, None).await
+    }
+    async fn upload_chunk_with_size(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        size: u64,
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file(self.get_chunk_path(&name), stream, Some(size)).await
     }
     async fn upload_nar(
         &self,
         name: String,
         stream: &mut (dyn AsyncRead + Unpin + Send),
     ) -> ServerResult<RemoteFile> {
-        self.upload_file(self.get_nar_path(&name), stream).await
+        self.upload_file(self.get_nar_path(&name), stream, None).await
     }
 
     async fn download_chunk(
         &self,
         name: String,
-    ) -> ServerResult<Download> {
+    ) -> ServerResult<Option<Download>> {
         self.download_file(self.get_chunk_path(&name)).await
     }
+    async fn download_chunk_range(
+        &self,
+        name: String,
+        range: Range<u64>,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file_range(self.get_chunk_path(&name), range).await
+    }
+    async fn presign_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>> {
+        if !self.config.redirect_downloads {
+            return Ok(None);
+        }
+
+        let presigning = PresigningConfig::expires_in(PRESIGNED_URL_EXPIRY)
+            .map_err(ServerError::storage_error)?;
+        let presigned = self
+            .client
+            .get_object()
+            .bucket(&self.config.bucket)
+            .key(self.get_chunk_path(&name))
+            .presigned(presigning)
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        Ok(Some(presigned.uri().to_string()))
+    }
+    async fn delete_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_chunk_path(&name)).await
+    }
+    async fn chunk_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        self.file_exists(self.get_chunk_path(&name)).await
+    }
     async fn download_nar(
         &self,
         name: String,
-    ) -> ServerResult<Download> {
+    ) -> ServerResult<Option<Download>> {
         self.download_file(self.get_nar_path(&name)).await
     }
+    async fn delete_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_nar_path(&name)).await
+    }
+    async fn nar_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        self.file_exists(self.get_nar_path(&name)).await
+    }
+    async fn health_check(&self) -> ServerResult<()> {
+        self.file_exists(self.get_nar_path(HEALTH_CHECK_NAME)).await?;
+        Ok(())
+    }
+    async fn upload_nar_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file_if_match(self.get_nar_path(&name), stream, etag).await
+    }
+    async fn get_nar_etag(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>> {
+        self.get_etag(self.get_nar_path(&name)).await
+    }
+    async fn download_chunk_refs(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<(Bytes, String)>> {
+        self.download_small_file(self.get_refs_path(&name)).await
+    }
+    async fn upload_chunk_refs_if_match(
+        &self,
+        name: String,
+        data: Bytes,
+        etag: Option<String>,
+    ) -> ServerResult<()> {
+        self.upload_file_if_match(self.get_refs_path(&name), &mut data.as_ref(), etag).await?;
+        Ok(())
+    }
+    async fn upload_listing(
+        &self,
+        name: String,
+        data: Bytes,
+    ) -> ServerResult<()> {
+        self.upload_file(self.get_listing_path(&name), &mut data.as_ref(), None).await?;
+        Ok(())
+    }
+    async fn download_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Bytes>> {
+        let file = self.download_small_file(self.get_listing_path(&name)).await?;
+        Ok(file.map(|(data, _)| data))
+    }
+    async fn delete_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_listing_path(&name)).await
+    }
+    async fn list_nars(&self) -> ServerResult<Vec<String>> {
+        let prefix = self.get_nar_path("");
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+
+        let mut names = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(ServerError::storage_error)?;
+            for object in page.contents().unwrap_or_default() {
+                if let Some(name) = object.key().and_then(|key| key.strip_prefix(&prefix)) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+    async fn list_nars_page(
+        &self,
+        start_after: Option<String>,
+        limit: usize,
+    ) -> ServerResult<Vec<String>> {
+        // Objects are listed in lexicographic order of their keys
+        let prefix = self.get_nar_path("");
+        let mut request = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(&prefix)
+            .max_keys(limit.min(1000) as i32);
+        if let Some(start_after) = &start_after {
+            request = request.start_after(self.get_nar_path(start_after));
+        }
+        let mut pages = request.into_paginator().send();
+
+        let mut names = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(ServerError::storage_error)?;
+            for object in page.contents().unwrap_or_default() {
+                if let Some(name) = object.key().and_then(|key| key.strip_prefix(&prefix)) {
+                    names.push(name.to_string());
+                }
+            }
+
+            if names.len() >= limit {
+                break;
+            }
+        }
+        names.truncate(limit);
+
+        Ok(names)
+    }
+    async fn list_chunks(&self) -> ServerResult<Vec<ChunkInfo>> {
+        let prefix = self.get_chunk_path("");
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+
+        let mut chunks = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(ServerError::storage_error)?;
+            for object in page.contents().unwrap_or_default() {
+                if let Some(name) = object.key().and_then(|key| key.strip_prefix(&prefix)) {
+                    // Objects without a known age are treated as new
+                    let last_modified = object.last_modified()
+                        .and_then(|t| SystemTime::try_from(*t).ok())
+                        .unwrap_or_else(SystemTime::now);
+
+                    chunks.push(ChunkInfo {
+                        name: name.to_string(),
+                        last_modified,
+                    });
+                }
+            }
+        }
+
+        Ok(chunks)
+    }
+}
+
+/// Aborts a multipart upload, logging failures.
+async fn abort_multipart_upload(client: &Client, bucket: &str, name: String, upload_id: String) {
+    let r = client
+        .abort_multipart_upload()
+        .bucket(bucket)
+        .key(name)
+        .upload_id(upload_id)
+        .send()
+        .await;
+
+    if let Err(e) = r {
+        tracing::warn!("Failed to abort multipart upload: {}", e);
+    }
+}
+
+/// Returns whether a `GetObject` request failed because the object does not exist.
+fn is_no_such_key(error: &SdkError<GetObjectError>) -> bool {
+    matches!(error, SdkError::ServiceError(e) if e.err().is_no_such_key())
+}
+
+/// Returns whether a request failed because of a precondition header.
+fn is_precondition_failed<E>(error: &SdkError<E>) -> bool {
+    error
+        .raw_response()
+        .map(|r| r.http().status() == StatusCode::PRECONDITION_FAILED)
+        .unwrap_or(false)
+}
+
+/// How to retry S3 requests.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+}
+
+/// Runs an S3 request, retrying transient errors with exponential backoff.
+///
+/// The request must be idempotent.
+async fn retry<T, E, F, Fut>(policy: RetryPolicy, mut request: F) -> Result<T, SdkError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(e) if retries < policy.max_retries && is_retryable(&e) => {
+                let delay = policy.base_delay.saturating_mul(1 << retries.min(16));
+                let delay = delay.mul_f64(0.5 + fastrand::f64() / 2.0);
+                retries += 1;
+
+                tracing::warn!(
+                    "S3 request failed, retrying in {:?} ({}/{}): {}",
+                    delay, retries, policy.max_retries, e,
+                );
+                tokio::time::sleep(delay).await;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// Returns whether a failed S3 request may succeed if retried.
+///
+/// S3 signals throttling with `503 Slow Down`.
+fn is_retryable<E>(error: &SdkError<E>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(e) => {
+            let status = e.raw().http().status();
+            status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+fn default_base_delay_ms() -> u64 {
+    100
+}
+fn default_multipart_part_size() -> u64 {
+    DEFAULT_PART_SIZE
+}
+fn default_resume_multipart_upload_ttl() -> u64 {
+    24 * 60 * 60
 }
 
 fn default_chunks_dir_name() -> String {
@@ -311,3 +1060,305 @@ fn default_chunks_dir_name() -> String {
 fn default_nars_dir_name() -> String {
     "nars".to_string()
 }
+fn default_refs_dir_name() -> String {
+    "refs".to_string()
+}
+fn default_listings_dir_name() -> String {
+    "listings".to_string()
+}
+
+/// Deserializes a multipart part size within the limits of S3.
+fn deserialize_part_size<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let size = u64::deserialize(deserializer)?;
+    if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&size) {
+        return Err(de::Error::custom(format!(
+            "multipart-part-size must be between {} and {} bytes",
+            MIN_PART_SIZE, MAX_PART_SIZE,
+        )));
+    }
+    Ok(size)
+}
+
+/// Returns the size of the first parts in a multipart upload.
+///
+/// Files of a known size that would need more parts than S3 allows
+/// start with larger parts.
+fn base_part_size(configured: u64, size: Option<u64>) -> u64 {
+    match size {
+        Some(size) => configured.max((size + MAX_PARTS - 1) / MAX_PARTS).min(MAX_PART_SIZE),
+        None => configured,
+    }
+}
+
+/// Returns the size of a part in a multipart upload.
+///
+/// The size starts at `base` and doubles every `PARTS_PER_SIZE_STEP`
+/// parts, up to the largest part size S3 accepts.
+fn part_size(base: u64, part_number: i32) -> usize {
+    let step = ((part_number - 1) / PARTS_PER_SIZE_STEP).clamp(0, 32) as u32;
+    base.saturating_mul(1 << step).min(MAX_PART_SIZE) as usize
+}
+
+/// Returns whether an uploaded part has the given content.
+///
+/// S3 uses the MD5 hash of a part as its ETag, except with SSE-KMS
+/// where such parts are never reused.
+fn part_matches(part: &Part, data: &[u8]) -> bool {
+    let md5 = hex::encode(Md5::digest(data));
+
+    part.size() == data.len() as i64
+        && part.e_tag().map(|etag| etag.trim_matches('"')) == Some(md5.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_s3::types::error::NoSuchKey;
+    use aws_smithy_http::{body::SdkBody, operation};
+    use axum::http::Response;
+
+    use super::*;
+
+    fn service_error(err: GetObjectError, status: StatusCode) -> SdkError<GetObjectError> {
+        let response = Response::builder()
+            .status(status)
+            .body(SdkBody::empty())
+            .unwrap();
+
+        SdkError::service_error(err, operation::Response::new(response))
+    }
+
+    #[test]
+    fn test_is_no_such_key() {
+        let err = service_error(
+            GetObjectError::NoSuchKey(NoSuchKey::builder().build()),
+            StatusCode::NOT_FOUND,
+        );
+        assert!(is_no_such_key(&err));
+
+        let err = service_error(
+            GetObjectError::unhandled("InternalError"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+        assert!(!is_no_such_key(&err));
+    }
+
+    #[test]
+    fn test_sse_config() {
+        let config: S3StorageConfig = toml::from_str(r#"
+region = "us-east-1"
+bucket = "nixcache"
+"#).unwrap();
+        assert!(config.sse.is_none());
+
+        let config: S3StorageConfig = toml::from_str(r#"
+region = "us-east-1"
+bucket = "nixcache"
+
+[sse]
+type = "aws:kms"
+kms-key-id = "arn:aws:kms:us-east-1:123456789012:key/example"
+"#).unwrap();
+        assert!(matches!(
+            config.sse,
+            Some(S3SseConfig::AwsKms { kms_key_id: Some(ref id) }) if id.ends_with("key/example")
+        ));
+
+        let config: S3StorageConfig = toml::from_str(r#"
+region = "us-east-1"
+bucket = "nixcache"
+sse = { type = "aes256" }
+"#).unwrap();
+        assert!(matches!(config.sse, Some(S3SseConfig::Aes256)));
+    }
+
+    #[test]
+    fn test_storage_class() {
+        let config: S3StorageConfig = toml::from_str(r#"
+region = "us-east-1"
+bucket = "nixcache"
+storage-class = "STANDARD_IA"
+chunk-storage-class = "INTELLIGENT_TIERING"
+"#).unwrap();
+        let backend = tokio_test::block_on(S3Backend::new(config)).unwrap();
+
+        assert_eq!(Some(StorageClass::IntelligentTiering), backend.storage_class("chunks/sha256:abc"));
+        assert_eq!(Some(StorageClass::StandardIa), backend.storage_class("nars/abc"));
+        assert_eq!(Some(StorageClass::StandardIa), backend.storage_class("chunksx/abc"));
+        assert_eq!(Some(StorageClass::StandardIa), backend.storage_class("refs/abc"));
+    }
+
+    #[test]
+    fn test_presign_chunk() {
+        let config = |redirect: bool| -> S3StorageConfig {
+            toml::from_str(&format!(r#"
+region = "us-east-1"
+bucket = "nixcache"
+endpoint = "https://s3.example.com"
+redirect-downloads = {}
+
+[credentials]
+access_key_id = "AKIDEXAMPLE"
+secret_access_key = "secret"
+"#, redirect)).unwrap()
+        };
+
+        tokio_test::block_on(async {
+            let backend = S3Backend::new(config(false)).await.unwrap();
+            assert_eq!(None, backend.presign_chunk("sha256:abc".to_string()).await.unwrap());
+
+            let backend = S3Backend::new(config(true)).await.unwrap();
+            let url = backend.presign_chunk("sha256:abc".to_string()).await.unwrap().unwrap();
+            assert!(url.contains("/chunks/sha256%3Aabc?"), "{}", url);
+            assert!(url.contains("X-Amz-Signature="), "{}", url);
+            assert!(url.contains("X-Amz-Expires=3600"), "{}", url);
+        });
+    }
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+        };
+
+        tokio_test::block_on(async {
+            let mut attempts = 0;
+            let r = retry(policy, || {
+                attempts += 1;
+                let r = if attempts <= 2 {
+                    Err(service_error(GetObjectError::unhandled("SlowDown"), StatusCode::SERVICE_UNAVAILABLE))
+                } else {
+                    Ok(attempts)
+                };
+                async move { r }
+            })
+            .await;
+            assert_eq!(3, r.unwrap());
+
+            let mut attempts = 0;
+            let r: Result<(), _> = retry(policy, || {
+                attempts += 1;
+                async { Err(service_error(GetObjectError::unhandled("AccessDenied"), StatusCode::FORBIDDEN)) }
+            })
+            .await;
+            assert!(r.is_err());
+            assert_eq!(1, attempts);
+
+            let mut attempts = 0;
+            let r: Result<(), _> = retry(policy, || {
+                attempts += 1;
+                async { Err(service_error(GetObjectError::unhandled("InternalError"), StatusCode::INTERNAL_SERVER_ERROR)) }
+            })
+            .await;
+            assert!(r.is_err());
+            assert_eq!(4, attempts);
+        });
+    }
+
+    #[test]
+    fn test_multipart_part_size() {
+        let config = |extra: &str| -> Result<S3StorageConfig, _> {
+            toml::from_str(&format!(r#"
+region = "us-east-1"
+bucket = "nixcache"
+{}
+"#, extra))
+        };
+
+        assert_eq!(DEFAULT_PART_SIZE, config("").unwrap().multipart_part_size);
+        assert_eq!(16 * 1024 * 1024, config("multipart-part-size = 16777216").unwrap().multipart_part_size);
+        assert!(config("multipart-part-size = 1048576").is_err());
+        assert!(config("multipart-part-size = 10737418240").is_err());
+
+        let mib = 1024 * 1024;
+        assert_eq!(8 * mib, part_size(DEFAULT_PART_SIZE, 1));
+        assert_eq!(8 * mib, part_size(DEFAULT_PART_SIZE, 1000));
+        assert_eq!(16 * mib, part_size(DEFAULT_PART_SIZE, 1001));
+        assert_eq!(4096 * mib, part_size(DEFAULT_PART_SIZE, 10000));
+        assert_eq!(MAX_PART_SIZE as usize, part_size(MAX_PART_SIZE, 10000));
+
+        // The smallest part size still covers close to the 5 TiB limit
+        let total: u64 = (1..=10000).map(|n| part_size(MIN_PART_SIZE, n) as u64).sum();
+        assert!(total > 4 * 1024 * 1024 * mib as u64);
+    }
+
+    #[test]
+    fn test_part_count_limit() {
+        // Returns the number of parts a file is uploaded in
+        let num_parts = |base: u64, size: u64| -> u64 {
+            let mut remaining = size;
+            let mut part_number = 1;
+            while remaining > 0 {
+                remaining = remaining.saturating_sub(part_size(base, part_number) as u64);
+                part_number += 1;
+            }
+            (part_number - 1) as u64
+        };
+
+        let gib = 1024 * 1024 * 1024;
+
+        // At the boundary with the default part size
+        assert_eq!(DEFAULT_PART_SIZE, base_part_size(DEFAULT_PART_SIZE, Some(MAX_PARTS * DEFAULT_PART_SIZE)));
+        assert!(base_part_size(DEFAULT_PART_SIZE, Some(MAX_PARTS * DEFAULT_PART_SIZE + 1)) > DEFAULT_PART_SIZE);
+
+        // Crossing it with a known size
+        let size = 200 * gib;
+        let base = base_part_size(DEFAULT_PART_SIZE, Some(size));
+        assert!(base > DEFAULT_PART_SIZE);
+        assert!(num_parts(base, size) <= MAX_PARTS);
+        assert_eq!(MAX_PART_SIZE, base_part_size(DEFAULT_PART_SIZE, Some(u64::MAX / 2)));
+
+        // Crossing it with an unknown size
+        assert_eq!(DEFAULT_PART_SIZE, base_part_size(DEFAULT_PART_SIZE, None));
+        assert!(num_parts(DEFAULT_PART_SIZE, size) <= MAX_PARTS);
+    }
+
+    #[test]
+    fn test_take_expired_uploads() {
+        let new_backend = |ttl: u64| {
+            let config: S3StorageConfig = toml::from_str(&format!(r#"
+region = "us-east-1"
+bucket = "nixcache"
+resume-multipart-uploads = true
+resume-multipart-upload-ttl = {}
+"#, ttl)).unwrap();
+            tokio_test::block_on(S3Backend::new(config)).unwrap()
+        };
+        let interrupt = |backend: &S3Backend, name: &str| {
+            let upload_id = format!("{}-upload", name);
+            backend.pending_uploads.lock().unwrap().insert(name.to_string(), (Instant::now(), upload_id));
+        };
+
+        let backend = new_backend(60);
+        interrupt(&backend, "nars/a");
+        assert!(backend.take_expired_uploads().is_empty());
+        assert_eq!(1, backend.pending_uploads.lock().unwrap().len());
+
+        let backend = new_backend(0);
+        interrupt(&backend, "nars/a");
+        interrupt(&backend, "nars/b");
+        let mut expired = backend.take_expired_uploads();
+        expired.sort();
+        assert_eq!(vec![
+            ("nars/a".to_string(), "nars/a-upload".to_string()),
+            ("nars/b".to_string(), "nars/b-upload".to_string()),
+        ], expired);
+        assert!(backend.pending_uploads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_part_matches() {
+        let part = Part::builder()
+            .part_number(1)
+            .e_tag("\"5d41402abc4b2a76b9719d911017c592\"")
+            .size(5)
+            .build();
+
+        assert!(part_matches(&part, b"hello"));
+        assert!(!part_matches(&part, b"world"));
+        assert!(!part_matches(&part, b"hello world"));
+    }
+}
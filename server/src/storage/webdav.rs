@@ -0,0 +1,982 @@
+use anyhow::{anyhow, Result};
+use std::io::Error as IoError;
+use std::ops::Range;
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+use aws_sdk_s3::primitives::DateTimeFormat;
+use aws_smithy_client::conns;
+use axum::headers::{Authorization, HeaderMapExt};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use bytes::{Bytes, BytesMut};
+use futures::stream::StreamExt;
+use hyper::Body;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+use crate::chunking::read_chunk_async;
+use crate::error::{ErrorKind, ServerResult, ServerError};
+use super::{StorageBackend, HEALTH_CHECK_NAME, RemoteFile, Download, ChunkInfo, parse_timestamp};
+
+/// The size of each piece of an upload sent to the server.
+const UPLOAD_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The body of `PROPFIND` requests.
+///
+/// Only the names and ages of the files are needed, so we ask for
+/// as little as possible.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/><getlastmodified/></prop></propfind>"#;
+
+/// Characters to escape in path segments.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The WebDAV remote file storage backend.
+#[derive(Debug)]
+pub struct WebDavBackend {
+    client: hyper::Client<conns::Https, Body>,
+    config: WebDavStorageConfig,
+    /// The `Authorization` header sent with every request.
+    authorization: Option<HeaderValue>,
+}
+
+/// WebDAV remote file storage configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebDavStorageConfig {
+    /// URL of the collection to store files under.
+    ///
+    /// Example: `https://cloud.example.com/remote.php/dav/files/alice/nixcache`
+    endpoint: String,
+    /// Basic authentication credentials.
+    credentials: Option<WebDavCredentialsConfig>,
+
+    /// Dir name for chunks.
+    #[serde(default = "default_chunks_dir_name")]
+    chunks: String,
+    /// Dir name for NARs.
+    #[serde(default = "default_nars_dir_name")]
+    nars: String,
+    /// Dir name for chunk references.
+    #[serde(default = "default_refs_dir_name")]
+    refs: String,
+    /// Dir name for file listings.
+    #[serde(default = "default_listings_dir_name")]
+    listings: String,
+}
+
+/// WebDAV credential configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebDavCredentialsConfig {
+    username: String,
+    password: String,
+}
+
+/// Reference to a file on a WebDAV server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebDavRemoteFile {
+    /// URL of the file.
+    pub url: String,
+}
+
+impl WebDavBackend {
+    pub async fn new(config: WebDavStorageConfig) -> Result<Self> {
+        let authorization = config.credentials.as_ref().map(|credentials| {
+            let mut headers = HeaderMap::new();
+            headers.typed_insert(Authorization::basic(&credentials.username, &credentials.password));
+            headers.remove(header::AUTHORIZATION).unwrap()
+        });
+
+        let backend = Self {
+            client: hyper::Client::builder().build(conns::https()),
+            config,
+            authorization,
+        };
+
+        backend.create_collection("").await?;
+        for dir in [
+            &backend.config.chunks,
+            &backend.config.nars,
+            &backend.config.refs,
+            &backend.config.listings,
+        ] {
+            backend.create_collection(dir).await?;
+        }
+
+        Ok(backend)
+    }
+
+    /// Sends a request with the configured credentials.
+    async fn send(&self, method: Method, name: &str, headers: &[(header::HeaderName, String)], body: Body) -> ServerResult<Response<Body>> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(self.file_uri(name));
+
+        if let Some(authorization) = &self.authorization {
+            req = req.header(header::AUTHORIZATION, authorization.clone());
+        }
+        for (name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        let req = req.body(body)
+            .map_err(ServerError::storage_error)?;
+
+        self.client.request(req)
+            .await
+            .map_err(ServerError::storage_error)
+    }
+
+    /// Creates a collection unless it already exists.
+    async fn create_collection(&self, name: &str) -> ServerResult<()> {
+        let res = self.send(Method::from_bytes(b"MKCOL").unwrap(), name, &[], Body::empty()).await?;
+
+        // 405 Method Not Allowed means that the collection exists
+        if res.status().is_success() || res.status() == StatusCode::METHOD_NOT_ALLOWED {
+            Ok(())
+        } else {
+            Err(error_response(res).await)
+        }
+    }
+
+    /// Uploads a file, streaming it to the server.
+    async fn upload_file(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        let res = self.put(&name, stream, &[]).await?;
+        if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        Ok(self.remote_file(&name))
+    }
+
+    /// Sends a `PUT` request with the contents of a stream.
+    async fn put(
+        &self,
+        name: &str,
+        mut stream: &mut (dyn AsyncRead + Unpin + Send),
+        headers: &[(header::HeaderName, String)],
+    ) -> ServerResult<Response<Body>> {
+        let (mut sender, body) = Body::channel();
+        let res = self.send(Method::PUT, name, headers, body);
+
+        let feed = async move {
+            let r: ServerResult<()> = async {
+                loop {
+                    let buf = BytesMut::with_capacity(UPLOAD_BUFFER_SIZE);
+                    let data = read_chunk_async(&mut stream, buf)
+                        .await
+                        .map_err(ServerError::storage_error)?;
+                    if data.is_empty() {
+                        return Ok(());
+                    }
+
+                    sender.send_data(data)
+                        .await
+                        .map_err(ServerError::storage_error)?;
+                }
+            }
+            .await;
+
+            // Otherwise the body would end normally and the server
+            // would store a truncated file
+            if r.is_err() {
+                sender.abort();
+            }
+            r
+        };
+
+        let (res, fed) = futures::join!(res, feed);
+        match res {
+            // The server may respond without reading the whole body
+            Ok(res) if !res.status().is_success() => Ok(res),
+            res => {
+                fed?;
+                res
+            }
+        }
+    }
+
+    async fn download_file(&self, name: String) -> ServerResult<Option<Download>> {
+        let res = self.send(Method::GET, &name, &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        Ok(Some(Download::Stream(body_stream(res))))
+    }
+
+    async fn download_file_range(&self, name: String, range: Range<u64>) -> ServerResult<Option<Download>> {
+        let value = format!("bytes={}-{}", range.start, range.end - 1);
+        let res = self.send(Method::GET, &name, &[(header::RANGE, value)], Body::empty()).await?;
+
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::PARTIAL_CONTENT => Ok(Some(Download::Stream(body_stream(res)))),
+            StatusCode::OK => {
+                // The server ignored the range, so skip to it ourselves
+                let mut reader = StreamReader::new(body_stream(res));
+                tokio::io::copy(&mut (&mut reader).take(range.start), &mut tokio::io::sink())
+                    .await
+                    .map_err(ServerError::storage_error)?;
+
+                Ok(Some(Download::AsyncRead(Box::new(reader.take(range.end - range.start)))))
+            }
+            _ => Err(error_response(res).await),
+        }
+    }
+
+    async fn delete_file(&self, name: String) -> ServerResult<()> {
+        let res = self.send(Method::DELETE, &name, &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND || res.status().is_success() {
+            Ok(())
+        } else {
+            Err(error_response(res).await)
+        }
+    }
+
+    /// Downloads a small file into memory along with its ETag.
+    ///
+    /// Returns `None` if the file does not exist.
+    async fn download_small_file(&self, name: String) -> ServerResult<Option<(Bytes, String)>> {
+        let res = self.send(Method::GET, &name, &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        let etag = etag(&res)?;
+        let data = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(ServerError::storage_error)?;
+
+        Ok(Some((data, etag)))
+    }
+
+    /// Uploads a small file with an ETag precondition.
+    async fn upload_file_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        let precondition = match etag {
+            Some(etag) => (header::IF_MATCH, etag),
+            None => (header::IF_NONE_MATCH, "*".to_string()),
+        };
+
+        let res = self.put(&name, stream, &[precondition]).await?;
+        if res.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(ErrorKind::PreconditionFailed.into());
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        Ok(self.remote_file(&name))
+    }
+
+    async fn get_etag(&self, name: String) -> ServerResult<Option<String>> {
+        let res = self.send(Method::HEAD, &name, &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !res.status().is_success() {
+            return Err(error_response(res).await);
+        }
+
+        Ok(Some(etag(&res)?))
+    }
+
+    async fn file_exists(&self, name: String) -> ServerResult<bool> {
+        let res = self.send(Method::HEAD, &name, &[], Body::empty()).await?;
+
+        if res.status() == StatusCode::NOT_FOUND {
+            Ok(false)
+        } else if res.status().is_success() {
+            Ok(true)
+        } else {
+            Err(error_response(res).await)
+        }
+    }
+
+    fn remote_file(&self, name: &str) -> RemoteFile {
+        RemoteFile::WebDav(WebDavRemoteFile {
+            url: self.file_uri(name),
+        })
+    }
+
+    fn file_uri(&self, name: &str) -> String {
+        format!("{}/{}", self.config.endpoint.trim_end_matches('/'), encode_path(name))
+    }
+
+    fn get_chunk_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.chunks, p)
+    }
+    fn get_nar_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.nars, p)
+    }
+    fn get_refs_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.refs, p)
+    }
+    fn get_listing_path(&self, p: &str) -> String {
+        format!("{}/{}", self.config.listings, p)
+    }
+
+    /// Lists the files in a collection.
+    ///
+    /// Returns the decoded file names along with the entries.
+    async fn list_files(&self, collection: &str) -> ServerResult<Vec<(String, Entry)>> {
+        let res = self.send(
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            collection,
+            &[
+                (header::HeaderName::from_static("depth"), "1".to_string()),
+                (header::CONTENT_TYPE, "application/xml".to_string()),
+            ],
+            Body::from(PROPFIND_BODY),
+        ).await?;
+        if res.status() != StatusCode::MULTI_STATUS {
+            return Err(error_response(res).await);
+        }
+
+        // Large collections have long listings, so we parse them as they arrive
+        let mut parser = EntryParser::default();
+        let mut body = res.into_body();
+        while let Some(data) = body.next().await {
+            parser.feed(&data.map_err(ServerError::storage_error)?)?;
+        }
+
+        // The collection itself is listed with a trailing slash
+        let files = parser.finish()?
+            .into_iter()
+            .filter(|entry| !entry.href.ends_with('/'))
+            .filter_map(|entry| {
+                let name = entry.href.rsplit('/').next()?;
+                let name = percent_decode_str(name).decode_utf8_lossy().into_owned();
+                Some((name, entry))
+            })
+            .collect();
+
+        Ok(files)
+    }
+}
+#[async_trait::async_trait]
+impl StorageBackend for WebDavBackend {
+    async fn upload_chunk(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file(self.get_chunk_path(&name), stream).await
+    }
+    async fn upload_nar(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file(self.get_nar_path(&name), stream).await
+    }
+
+    async fn download_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file(self.get_chunk_path(&name)).await
+    }
+    async fn download_chunk_range(
+        &self,
+        name: String,
+        range: Range<u64>,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file_range(self.get_chunk_path(&name), range).await
+    }
+    async fn presign_chunk(
+        &self,
+        _name: String,
+    ) -> ServerResult<Option<String>> {
+        Ok(None)
+    }
+    async fn delete_chunk(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_chunk_path(&name)).await
+    }
+    async fn chunk_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        self.file_exists(self.get_chunk_path(&name)).await
+    }
+    async fn download_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Download>> {
+        self.download_file(self.get_nar_path(&name)).await
+    }
+    async fn delete_nar(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_nar_path(&name)).await
+    }
+    async fn nar_exists(
+        &self,
+        name: String,
+    ) -> ServerResult<bool> {
+        self.file_exists(self.get_nar_path(&name)).await
+    }
+    async fn health_check(&self) -> ServerResult<()> {
+        self.file_exists(self.get_nar_path(HEALTH_CHECK_NAME)).await?;
+        Ok(())
+    }
+    async fn upload_nar_if_match(
+        &self,
+        name: String,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        etag: Option<String>,
+    ) -> ServerResult<RemoteFile> {
+        self.upload_file_if_match(self.get_nar_path(&name), stream, etag).await
+    }
+    async fn get_nar_etag(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<String>> {
+        self.get_etag(self.get_nar_path(&name)).await
+    }
+    async fn download_chunk_refs(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<(Bytes, String)>> {
+        self.download_small_file(self.get_refs_path(&name)).await
+    }
+    async fn upload_chunk_refs_if_match(
+        &self,
+        name: String,
+        data: Bytes,
+        etag: Option<String>,
+    ) -> ServerResult<()> {
+        self.upload_file_if_match(self.get_refs_path(&name), &mut data.as_ref(), etag).await?;
+        Ok(())
+    }
+    async fn upload_listing(
+        &self,
+        name: String,
+        data: Bytes,
+    ) -> ServerResult<()> {
+        self.upload_file(self.get_listing_path(&name), &mut data.as_ref()).await?;
+        Ok(())
+    }
+    async fn download_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<Option<Bytes>> {
+        let file = self.download_small_file(self.get_listing_path(&name)).await?;
+        Ok(file.map(|(data, _)| data))
+    }
+    async fn delete_listing(
+        &self,
+        name: String,
+    ) -> ServerResult<()> {
+        self.delete_file(self.get_listing_path(&name)).await
+    }
+    async fn list_nars(&self) -> ServerResult<Vec<String>> {
+        let names = self.list_files(&self.get_nar_path(""))
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        Ok(names)
+    }
+    async fn list_chunks(&self) -> ServerResult<Vec<ChunkInfo>> {
+        let chunks = self.list_files(&self.get_chunk_path(""))
+            .await?
+            .into_iter()
+            .map(|(name, entry)| {
+                // Files without a known age are treated as new
+                let last_modified = entry.last_modified
+                    .and_then(|t| parse_timestamp(&t, DateTimeFormat::HttpDate))
+                    .unwrap_or_else(SystemTime::now);
+
+                ChunkInfo { name, last_modified }
+            })
+            .collect();
+
+        Ok(chunks)
+    }
+}
+
+/// Turns an unexpected response into an error.
+async fn error_response(res: Response<Body>) -> ServerError {
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .unwrap_or_default();
+
+    ErrorKind::StorageError(anyhow!(
+        "WebDAV request failed with {}: {}",
+        status,
+        String::from_utf8_lossy(&body),
+    )).into()
+}
+
+/// Returns the ETag of a response.
+///
+/// Conditional uploads depend on ETags, so servers without
+/// them are not supported.
+fn etag(res: &Response<Body>) -> ServerResult<String> {
+    res.headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(|etag| etag.to_string())
+        .ok_or_else(|| ErrorKind::StorageError(anyhow!("The WebDAV server did not return an ETag")).into())
+}
+
+fn body_stream(res: Response<Body>) -> futures::stream::BoxStream<'static, std::io::Result<Bytes>> {
+    StreamExt::map(res.into_body(), |item| {
+        item.map_err(IoError::other)
+    })
+    .boxed()
+}
+
+/// An entry in a `PROPFIND` response.
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    /// The path of the file or collection.
+    href: String,
+    /// The `getlastmodified` property, if returned.
+    last_modified: Option<String>,
+}
+
+/// Parses the entries of a `PROPFIND` response as it is received.
+///
+/// Received data is parsed up to the last complete `response` element,
+/// so only the entries and an incomplete element are kept in memory.
+#[derive(Debug, Default)]
+struct EntryParser {
+    /// Received data that is not parsed yet.
+    buf: Vec<u8>,
+    /// Whether anything was parsed yet.
+    started: bool,
+    entries: Vec<Entry>,
+    /// The local name of the element whose text is being parsed.
+    element: Option<String>,
+}
+
+impl EntryParser {
+    /// Adds received data.
+    fn feed(&mut self, data: &[u8]) -> ServerResult<()> {
+        self.buf.extend_from_slice(data);
+
+        if let Some(end) = last_response_end(&self.buf) {
+            let rest = self.buf.split_off(end);
+            let complete = std::mem::replace(&mut self.buf, rest);
+            self.parse(&complete)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parses the rest of the data and returns the entries.
+    fn finish(mut self) -> ServerResult<Vec<Entry>> {
+        let rest = std::mem::take(&mut self.buf);
+        self.parse(&rest)?;
+
+        Ok(self.entries)
+    }
+
+    fn parse(&mut self, data: &[u8]) -> ServerResult<()> {
+        let mut xml = std::str::from_utf8(data)
+            .map_err(ServerError::storage_error)?;
+
+        // Fragments can't have a declaration
+        if !self.started {
+            self.started = true;
+            xml = xml.trim_start_matches('\u{feff}').trim_start();
+            if xml.starts_with("<?xml") {
+                let end = xml.find("?>")
+                    .ok_or_else(|| ErrorKind::StorageError(anyhow!("Invalid XML declaration in PROPFIND response")))?;
+                xml = &xml[end + 2..];
+            }
+        }
+
+        for token in Tokenizer::from_fragment(xml, 0..xml.len()) {
+            match token.map_err(ServerError::storage_error)? {
+                Token::ElementStart { local, .. } => {
+                    if local.as_str() == "response" {
+                        self.entries.push(Entry { href: String::new(), last_modified: None });
+                    }
+                    self.element = Some(local.to_string());
+                }
+                Token::ElementEnd { end: ElementEnd::Open, .. } => {}
+                Token::ElementEnd { .. } => self.element = None,
+                Token::Text { text } => match (self.element.as_deref(), self.entries.last_mut()) {
+                    (Some("href"), Some(entry)) => {
+                        entry.href = text.as_str().trim().replace("&amp;", "&");
+                    }
+                    (Some("getlastmodified"), Some(entry)) => {
+                        entry.last_modified = Some(text.as_str().trim().to_string());
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the end of the last closing tag of a `response` element.
+///
+/// The tag may have any namespace prefix, like `</d:response>`.
+fn last_response_end(data: &[u8]) -> Option<usize> {
+    const TAG: &[u8] = b"response>";
+
+    (TAG.len()..=data.len()).rev().find(|&end| {
+        let start = end - TAG.len();
+        if &data[start..end] != TAG {
+            return false;
+        }
+
+        match data[..start].iter().rposition(|&b| b == b'<') {
+            Some(open) => {
+                let prefix = &data[open + 1..start];
+                prefix.first() == Some(&b'/')
+                    && (prefix.len() == 1 || prefix.ends_with(b":"))
+                    && !prefix.iter().any(u8::is_ascii_whitespace)
+            }
+            None => false,
+        }
+    })
+}
+
+/// Escapes each segment of a path.
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn default_chunks_dir_name() -> String {
+    "chunks".to_string()
+}
+fn default_nars_dir_name() -> String {
+    "nars".to_string()
+}
+fn default_refs_dir_name() -> String {
+    "refs".to_string()
+}
+fn default_listings_dir_name() -> String {
+    "listings".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex as StdMutex};
+    use axum::body::StreamBody;
+    use axum::extract::Extension;
+    use axum::http::Uri;
+    use axum::response::IntoResponse;
+    use axum::Router;
+    use tokio_test::block_on;
+
+    use super::*;
+
+    /// An in-memory WebDAV server.
+    #[derive(Debug, Default)]
+    struct MockWebDav {
+        /// Files by path, with their ETags.
+        files: HashMap<String, (Bytes, String)>,
+        /// Paths of the collections, without trailing slashes.
+        collections: HashSet<String>,
+        next_etag: usize,
+        /// Whether to respond to range requests with the whole file.
+        ignore_ranges: bool,
+    }
+
+    type SharedMockWebDav = Arc<StdMutex<MockWebDav>>;
+
+    async fn handle(
+        Extension(mock): Extension<SharedMockWebDav>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> axum::response::Response {
+        let mut mock = mock.lock().unwrap();
+        let path = uri.path().trim_end_matches('/').to_string();
+        let parent = path.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default();
+
+        match method.as_str() {
+            "MKCOL" => {
+                if mock.collections.insert(path) {
+                    StatusCode::CREATED.into_response()
+                } else {
+                    StatusCode::METHOD_NOT_ALLOWED.into_response()
+                }
+            }
+            "PROPFIND" => {
+                if !mock.collections.contains(&path) {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+
+                let mut xml = format!(r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:"><d:response><d:href>{}/</d:href></d:response>"#, path);
+                for file in mock.files.keys().filter(|file| file.starts_with(&format!("{}/", path))) {
+                    xml.push_str(&format!(
+                        "<d:response><d:href>{}</d:href><d:propstat><d:prop><d:getlastmodified>Mon, 16 Dec 2019 23:48:18 GMT</d:getlastmodified></d:prop></d:propstat></d:response>\n",
+                        file,
+                    ));
+                }
+                xml.push_str("</d:multistatus>");
+
+                // Sent in small pieces to split elements
+                let pieces: Vec<_> = xml.as_bytes()
+                    .chunks(7)
+                    .map(|piece| Ok::<_, IoError>(Bytes::copy_from_slice(piece)))
+                    .collect();
+                (StatusCode::MULTI_STATUS, StreamBody::new(futures::stream::iter(pieces))).into_response()
+            }
+            "PUT" => {
+                if !mock.collections.contains(&parent) {
+                    return StatusCode::CONFLICT.into_response();
+                }
+
+                let etag = mock.files.get(&path).map(|(_, etag)| etag.as_str());
+                let if_match = headers.get(header::IF_MATCH).map(|v| v.to_str().unwrap());
+                let if_none_match = headers.get(header::IF_NONE_MATCH).is_some();
+                if (if_match.is_some() && if_match != etag) || (if_none_match && etag.is_some()) {
+                    return StatusCode::PRECONDITION_FAILED.into_response();
+                }
+
+                mock.next_etag += 1;
+                let etag = format!("\"{}\"", mock.next_etag);
+                mock.files.insert(path, (body, etag));
+                StatusCode::CREATED.into_response()
+            }
+            _ => {
+                let (data, etag) = match mock.files.get(&path) {
+                    Some(file) => file.clone(),
+                    None => return StatusCode::NOT_FOUND.into_response(),
+                };
+
+                match method {
+                    Method::HEAD => (StatusCode::OK, [(header::ETAG, etag)]).into_response(),
+                    Method::DELETE => {
+                        mock.files.remove(&path);
+                        StatusCode::NO_CONTENT.into_response()
+                    }
+                    Method::GET => {
+                        let range = headers.get(header::RANGE)
+                            .filter(|_| !mock.ignore_ranges)
+                            .and_then(|range| range.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+                            .map(|(start, end)| start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1);
+                        match range {
+                            Some(range) => (StatusCode::PARTIAL_CONTENT, data.slice(range)).into_response(),
+                            None => (StatusCode::OK, [(header::ETAG, etag)], data).into_response(),
+                        }
+                    }
+                    _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+                }
+            }
+        }
+    }
+
+    /// Starts a mock WebDAV server and returns a backend using it.
+    async fn mock_backend() -> (WebDavBackend, SharedMockWebDav) {
+        let mock = SharedMockWebDav::default();
+        let router = Router::new()
+            .fallback(handle)
+            .layer(Extension(mock.clone()));
+
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(router.into_make_service());
+        let endpoint = format!("http://{}/dav", server.local_addr());
+        tokio::spawn(server);
+
+        let config: WebDavStorageConfig = toml::from_str(&format!("endpoint = {:?}", endpoint)).unwrap();
+
+        (WebDavBackend::new(config).await.unwrap(), mock)
+    }
+
+    async fn download(download: Option<Download>) -> Vec<u8> {
+        let mut data = Vec::new();
+        download.unwrap()
+            .into_async_read()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_create_collections() {
+        block_on(async {
+            let (backend, mock) = mock_backend().await;
+
+            let mut collections: Vec<_> = mock.lock().unwrap().collections.iter().cloned().collect();
+            collections.sort();
+            assert_eq!(vec!["/dav", "/dav/chunks", "/dav/listings", "/dav/nars", "/dav/refs"], collections);
+
+            // Existing collections are fine
+            WebDavBackend::new(backend.config.clone()).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_upload_download() {
+        block_on(async {
+            let (backend, mock) = mock_backend().await;
+            let name = "sha256:abc".to_string();
+            let data = b"Hello, world!".to_vec();
+
+            assert!(!backend.chunk_exists(name.clone()).await.unwrap());
+            assert!(backend.download_chunk(name.clone()).await.unwrap().is_none());
+
+            backend.upload_chunk(name.clone(), &mut data.as_slice()).await.unwrap();
+            assert!(mock.lock().unwrap().files.contains_key("/dav/chunks/sha256%3Aabc"));
+            assert!(backend.chunk_exists(name.clone()).await.unwrap());
+            assert_eq!(data, download(backend.download_chunk(name.clone()).await.unwrap()).await);
+
+            // Servers may ignore ranges
+            for ignore_ranges in [false, true] {
+                mock.lock().unwrap().ignore_ranges = ignore_ranges;
+                let range = download(backend.download_chunk_range(name.clone(), 7..12).await.unwrap()).await;
+                assert_eq!(b"world", range.as_slice());
+            }
+
+            backend.delete_chunk(name.clone()).await.unwrap();
+            assert!(!backend.chunk_exists(name.clone()).await.unwrap());
+            backend.delete_chunk(name).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_upload_if_match() {
+        block_on(async {
+            let (backend, _mock) = mock_backend().await;
+            let refs = |data: &'static [u8]| Bytes::from_static(data);
+
+            backend.upload_chunk_refs_if_match("abc".to_string(), refs(b"1"), None).await.unwrap();
+            let err = backend.upload_chunk_refs_if_match("abc".to_string(), refs(b"2"), None).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::PreconditionFailed));
+
+            let (data, etag) = backend.download_chunk_refs("abc".to_string()).await.unwrap().unwrap();
+            assert_eq!(b"1", data.as_ref());
+            backend.upload_chunk_refs_if_match("abc".to_string(), refs(b"2"), Some(etag.clone())).await.unwrap();
+
+            // The ETag changed with the upload
+            let err = backend.upload_chunk_refs_if_match("abc".to_string(), refs(b"3"), Some(etag)).await.unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::PreconditionFailed));
+            let (data, _) = backend.download_chunk_refs("abc".to_string()).await.unwrap().unwrap();
+            assert_eq!(b"2", data.as_ref());
+
+            backend.upload_nar_if_match("abc".to_string(), &mut b"nar".as_slice(), None).await.unwrap();
+            let etag = backend.get_nar_etag("abc".to_string()).await.unwrap();
+            assert!(etag.is_some());
+            backend.upload_nar_if_match("abc".to_string(), &mut b"nar".as_slice(), etag).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_list() {
+        block_on(async {
+            let (backend, _mock) = mock_backend().await;
+
+            for name in ["sha256:abc", "a&b"] {
+                backend.upload_chunk(name.to_string(), &mut b"chunk".as_slice()).await.unwrap();
+            }
+            backend.upload_nar("nar".to_string(), &mut b"nar".as_slice()).await.unwrap();
+
+            let mut chunks: Vec<_> = backend.list_chunks().await.unwrap()
+                .into_iter()
+                .map(|chunk| chunk.name)
+                .collect();
+            chunks.sort();
+            assert_eq!(vec!["a&b", "sha256:abc"], chunks);
+            assert_eq!(vec!["nar"], backend.list_nars().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_entry_parser_pieces() {
+        let xml = r#"<?xml version="1.0"?>
+<D:multistatus xmlns:D="DAV:"><D:response><D:href>/a</D:href></D:response><D:response><D:href>/b</D:href></D:response></D:multistatus>"#;
+
+        for piece_size in [1, 3, xml.len()] {
+            let mut parser = EntryParser::default();
+            for piece in xml.as_bytes().chunks(piece_size) {
+                parser.feed(piece).unwrap();
+            }
+
+            let hrefs: Vec<_> = parser.finish().unwrap().into_iter().map(|entry| entry.href).collect();
+            assert_eq!(vec!["/a", "/b"], hrefs, "{}", piece_size);
+        }
+
+        assert_eq!(None, last_response_end(b"<d:response><d:href>/a</d:href>"));
+        assert_eq!(Some(14), last_response_end(b"<a></response><b>"));
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response><d:href>/dav/nixcache/nars/</d:href><d:propstat/></d:response>
+  <d:response>
+    <d:href>/dav/nixcache/nars/abc</d:href>
+    <d:propstat>
+      <d:prop><d:getlastmodified>Mon, 16 Dec 2019 23:48:18 GMT</d:getlastmodified></d:prop>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>
+      /dav/nixcache/nars/a%26b
+    </d:href>
+  </d:response>
+</d:multistatus>"#;
+
+        let parse_entries = |xml: &str| {
+            let mut parser = EntryParser::default();
+            parser.feed(xml.as_bytes()).unwrap();
+            parser.finish().unwrap()
+        };
+        let entry = |href: &str, last_modified: Option<&str>| Entry {
+            href: href.to_string(),
+            last_modified: last_modified.map(|t| t.to_string()),
+        };
+
+        assert_eq!(
+            vec![
+                entry("/dav/nixcache/nars/", None),
+                entry("/dav/nixcache/nars/abc", Some("Mon, 16 Dec 2019 23:48:18 GMT")),
+                entry("/dav/nixcache/nars/a%26b", None),
+            ],
+            parse_entries(xml),
+        );
+
+        let last_modified = parse_timestamp("Mon, 16 Dec 2019 23:48:18 GMT", DateTimeFormat::HttpDate).unwrap();
+        assert_eq!(1576540098, last_modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs());
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!("chunks/sha256%3Aabc", encode_path("chunks/sha256:abc"));
+        assert_eq!("nars/", encode_path("nars/"));
+    }
+}
@@ -1,9 +1,13 @@
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use async_stream::stream;
+use bytes::Bytes;
 use digest::{Digest, Output as DigestOutput};
+use futures::stream::{BoxStream, Stream, StreamExt};
 use tokio::io::{AsyncRead, ReadBuf};
-use tokio::sync::OnceCell;
+use tokio::sync::{mpsc, OnceCell, Semaphore};
+use tokio::task::spawn;
 
 /// Stream filter that hashes the bytes that have been read.
 ///
@@ -67,6 +71,43 @@ impl<R: AsyncRead + Unpin, D: Digest + Unpin> AsyncRead for StreamHasher<R, D> {
     }
 }
 
+/// Reads a stream ahead of its consumer into a buffer of up to `budget` bytes.
+///
+/// The inner stream is driven by a separate task so that it proceeds at
+/// its own pace, while the consumer drains the buffer at its own. The
+/// budget is checked after each item is read, so an item larger than
+/// the budget is still passed through on its own.
+///
+/// The task stops when the returned stream is dropped.
+pub fn prefetch<S, E>(stream: S, budget: usize) -> BoxStream<'static, Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send + 'static,
+{
+    let budget = budget.clamp(1, u32::MAX as usize);
+    let semaphore = Arc::new(Semaphore::new(budget));
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    spawn(async move {
+        let mut stream = Box::pin(stream);
+        while let Some(item) = stream.next().await {
+            let len = item.as_ref().map_or(1, |bytes| bytes.len().clamp(1, budget));
+            let permit = semaphore.clone().acquire_many_owned(len as u32).await.unwrap();
+
+            if sender.send((item, permit)).is_err() {
+                // The consumer is gone
+                break;
+            }
+        }
+    });
+
+    Box::pin(stream! {
+        while let Some((item, _permit)) = receiver.recv().await {
+            yield item;
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -101,4 +142,24 @@ mod tests {
         assert_eq!(expected.len(), *count);
         eprintln!("finalized = {:x?}", finalized);
     }
+
+    #[test]
+    fn test_prefetch() {
+        let chunks: Vec<Result<Bytes, ()>> = vec![
+            Ok(Bytes::from_static(b"hello")),
+            Ok(Bytes::from_static(b" ")),
+            Ok(Bytes::from_static(b"world")),
+        ];
+
+        // Items larger than the budget must not block
+        let prefetched: Vec<_> = block_on(async {
+            prefetch(futures::stream::iter(chunks), 2).collect().await
+        });
+        let data: Vec<u8> = prefetched
+            .into_iter()
+            .flat_map(|chunk| chunk.unwrap())
+            .collect();
+
+        assert_eq!(b"hello world", data.as_slice());
+    }
 }
@@ -0,0 +1,122 @@
+//! Tracing setup.
+//!
+//! With the `otlp` feature, spans can also be exported to an
+//! OpenTelemetry collector. Incoming `traceparent` headers are
+//! honored so that cache requests nest under the caller's trace.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::Result;
+use axum::http::Request;
+use tracing::Span;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::prelude::*;
+
+use crate::config::TracingConfig;
+
+/// Initializes the global tracing subscriber.
+///
+/// `RUST_LOG` takes precedence over `level` if set.
+pub fn init(config: &TracingConfig, level: LevelFilter) -> Result<()> {
+    #[cfg(feature = "otlp")]
+    let otlp = match &config.otlp_endpoint {
+        Some(endpoint) => Some(tracing_opentelemetry::layer().with_tracer(otlp::tracer(endpoint)?)),
+        None => None,
+    };
+
+    #[cfg(not(feature = "otlp"))]
+    let otlp: Option<tracing_subscriber::layer::Identity> = None;
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::builder().with_default_directive(level.into()).from_env_lossy())
+        .with(tracing_subscriber::fmt::layer())
+        .with(otlp)
+        .init();
+
+    #[cfg(not(feature = "otlp"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("OTLP export is configured but this build lacks the \"otlp\" feature.");
+    }
+
+    Ok(())
+}
+
+/// Exports the remaining spans.
+///
+/// Spans are exported in batches, so the last ones would be lost
+/// if the process exited without this.
+pub async fn shutdown() {
+    #[cfg(feature = "otlp")]
+    {
+        // This blocks until the batch exporter finishes
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+    }
+}
+
+/// Creates the span of an HTTP request.
+///
+/// With the `otlp` feature, the span is parented to the trace
+/// context in the request headers if there is one.
+pub fn make_request_span<B>(request: &Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        id = %request_id(request),
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+    );
+
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry_http::HeaderExtractor;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        span.set_parent(parent);
+    }
+
+    span
+}
+
+/// Returns the ID of a request for logs.
+///
+/// An `X-Request-Id` set by a reverse proxy is used if present so
+/// that logs can be correlated across both.
+fn request_id<B>(request: &Request<B>) -> String {
+    static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+    request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string())
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use anyhow::Result;
+    use opentelemetry::sdk::{propagation::TraceContextPropagator, trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+
+    /// Creates a tracer exporting to an OTLP endpoint.
+    pub fn tracer(endpoint: &str) -> Result<trace::Tracer> {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint);
+
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", "nixcached"),
+            ])))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+
+        Ok(tracer)
+    }
+}
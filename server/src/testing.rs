@@ -0,0 +1,211 @@
+//! Test fixtures.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use libnixstore::{Hash, StorePathHash};
+use common::v1::upload_path::Request;
+use crate::api::{UploadedChunk, UploadedNar};
+use crate::config::{self, CompressionConfig};
+use crate::storage::StorageBackend;
+use crate::State;
+
+/// The signing key of test configs.
+pub const SIGNING_KEY: &str = "demo.nixcache-0:vjg4zb3o8U3SapIoeG5dWZ9+G4OyqA96J2+nxuoMPCT3a7/zXWgXpuKr+rJWChlyTGeCV2aARebK+ffmh+u2fw==";
+
+/// The HS256 token secret of test configs that require authentication.
+pub const TOKEN_SECRET: &str = "dGVzdC1zZWNyZXQtZm9yLXRoZS1hY2Nlc3MtY29udHJvbC10ZXN0cw==";
+
+/// The store path of test NARs.
+pub const STORE_PATH: &str = "/nix/store/p4pclmv1gyja5kzc26npqpia1qqxrf0l-ruby-2.7.3";
+
+/// The hash part of [`STORE_PATH`].
+pub const STORE_PATH_HASH: &str = "p4pclmv1gyja5kzc26npqpia1qqxrf0l";
+
+/// Extra TOML that splits every NAR into small chunks.
+pub const SMALL_CHUNKS: &str = r#"
+[chunking]
+nar-size-threshold = 1
+preset = "small"
+"#;
+
+/// Returns a request to upload `nar` as the NAR of [`STORE_PATH`].
+pub fn request_for(nar: &[u8]) -> Request {
+    Request {
+        store_path_hash: StorePathHash::new(STORE_PATH_HASH.to_string()).unwrap(),
+        store_path: STORE_PATH.to_string(),
+        references: Vec::new(),
+        system: None,
+        deriver: None,
+        sigs: Vec::new(),
+        ca: None,
+        nar_hash: Hash::sha256_from_bytes(nar),
+        nar_size: nar.len(),
+    }
+}
+
+/// Returns the metadata of a chunk stored as `data`.
+pub fn uploaded_chunk(data: &[u8]) -> UploadedChunk {
+    UploadedChunk {
+        file_hash: Hash::sha256_from_bytes(data),
+        file_size: data.len(),
+        compression: CompressionConfig::default(),
+    }
+}
+
+/// Builds the metadata of an uploaded NAR.
+///
+/// The NAR is of [`STORE_PATH`] and has no chunks unless set otherwise.
+pub struct UploadedNarBuilder(UploadedNar);
+
+impl UploadedNar {
+    /// Starts building test metadata.
+    pub fn builder() -> UploadedNarBuilder {
+        UploadedNarBuilder(UploadedNar {
+            store_path: STORE_PATH.into(),
+            nar_hash: Hash::sha256_from_bytes(b"nar"),
+            nar_size: 3,
+            references: Vec::new(),
+            system: None,
+            ca: None,
+            created: None,
+            chunks: Vec::new(),
+        })
+    }
+}
+
+impl UploadedNarBuilder {
+    /// Sets the store path.
+    pub fn store_path(mut self, store_path: impl Into<PathBuf>) -> Self {
+        self.0.store_path = store_path.into();
+        self
+    }
+
+    /// Sets the hash and size to those of `nar`.
+    pub fn nar(mut self, nar: &[u8]) -> Self {
+        self.0.nar_hash = Hash::sha256_from_bytes(nar);
+        self.0.nar_size = nar.len();
+        self
+    }
+
+    /// Appends a chunk.
+    pub fn chunk(mut self, chunk: UploadedChunk) -> Self {
+        self.0.chunks.push(chunk);
+        self
+    }
+
+    /// Appends several chunks.
+    pub fn chunks(mut self, chunks: impl IntoIterator<Item = UploadedChunk>) -> Self {
+        self.0.chunks.extend(chunks);
+        self
+    }
+
+    pub fn build(self) -> UploadedNar {
+        self.0
+    }
+
+    /// Stores the metadata under the hash part of the store path.
+    pub async fn store(self, backend: &dyn StorageBackend) -> UploadedNar {
+        let nar = self.0;
+        let file_name = nar.store_path.file_name().unwrap().to_str().unwrap();
+        let name = file_name.split('-').next().unwrap().to_string();
+
+        let data = serde_json::to_vec(&nar).unwrap();
+        backend.upload_nar(name, &mut std::io::Cursor::new(data)).await.unwrap();
+
+        nar
+    }
+}
+
+/// A temporary directory that is removed on drop.
+///
+/// It is also removed when an assertion fails, since the test
+/// unwinds through the drop.
+#[derive(Debug)]
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates an empty directory that is unique to this call.
+    pub fn new(name: &str) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "nixcache-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+
+    /// Returns the path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Server state with local storage in a temporary directory.
+#[derive(Debug)]
+pub struct TestState {
+    state: Arc<State>,
+    dir: TestDir,
+}
+
+impl TestState {
+    /// Builds the state from a minimal config and some extra TOML.
+    ///
+    /// The extra TOML comes before the `[storage]` section, so it may
+    /// set top-level options as well as add other sections.
+    pub async fn new(extra: &str) -> Self {
+        let dir = TestDir::new("state");
+        let state = load_state(dir.path(), extra).await;
+
+        Self { state, dir }
+    }
+
+    /// Builds another state over the same storage with a different config.
+    pub async fn reopen(&self, extra: &str) -> Arc<State> {
+        load_state(self.dir.path(), extra).await
+    }
+
+    /// Returns the temporary directory.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Deref for TestState {
+    type Target = Arc<State>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+async fn load_state(dir: &Path, extra: &str) -> Arc<State> {
+    let toml = format!(r#"
+version = "v1"
+signing_key = "{}"
+{}
+
+[storage]
+type = "local"
+path = "{}"
+"#, SIGNING_KEY, extra, dir.join("storage").display());
+
+    let path = dir.join("config.toml");
+    std::fs::write(&path, toml).unwrap();
+    let config = config::load(&path, false).await.unwrap();
+
+    State::new(config).await.unwrap()
+}